@@ -1,19 +1,29 @@
 extern crate mephisto;
 
-use clap::Parser;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use clap::{Parser, Subcommand, ValueEnum};
 use mephisto::codegen::codegen_js::JSCodeGenerator;
-use mephisto::module_loader::NativeFileLoader;
+use mephisto::diagnostic::Diagnostic;
+use mephisto::emit::Stage;
+use mephisto::formatter;
+use mephisto::ir::fold::FoldOptions;
+use mephisto::lsp;
+use mephisto::module_loader::{BuiltinFileLoader, FileLoader, NativeFileLoader};
 use crate::mephisto::Mephisto;
 use colored::Colorize;
 use mephisto::codegen::codegen_wat::WATCodeGenerator;
 use mephisto::codegen::CodeGenerator;
 
 #[derive(Parser, Debug)]
-#[command(author, version, about)]
+#[command(author, version, about, subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Input file
-    #[arg(short, long)]
-    input: String,
+    #[arg(short, long, required = true)]
+    input: Option<String>,
 
     /// Output file, stdout if not present
     #[arg(short, long)]
@@ -22,31 +32,131 @@ struct Args {
     /// Output target, default is js
     #[arg(short, long, default_value = "js")]
     target: String,
+
+    /// Print an intermediate stage of the compilation instead of the generated code
+    #[arg(long, value_enum)]
+    emit: Option<Emit>,
+
+    /// Do not fold `x * 0` to 0, it is NaN when x is NaN or infinite
+    #[arg(long)]
+    preserve_nan: bool,
+
+    /// Print what the optimizing passes did
+    #[arg(short, long)]
+    verbose: bool,
+
+    /// Module search path, can be repeated. Tried before the paths from MEPHISTO_PATH
+    #[arg(short, long, global = true)]
+    path: Vec<PathBuf>,
+
+    /// How diagnostics are printed to stderr
+    #[arg(long, value_enum, default_value_t = MessageFormat::Human, global = true)]
+    message_format: MessageFormat,
 }
 
-fn main() {
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Rewrite source files in the canonical layout
+    Fmt {
+        /// Files to format
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+
+        /// Do not write anything, exit with 1 if a file is not formatted
+        #[arg(long)]
+        check: bool,
+    },
+
+    /// Serve the Language Server Protocol over stdin and stdout
+    Lsp,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum Emit {
+    /// Token stream of every module
+    Tokens,
+    /// JSON AST of every module
+    Ast,
+    /// Symbol table scopes of every module
+    Symbols,
+    /// IR the backends generate code from, printed as code
+    Ir,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum MessageFormat {
+    /// Source snippets with the offending code underlined
+    Human,
+    /// One JSON object per line
+    Json,
+}
+
+// Exit codes. Invalid command line arguments exit with 2, same as clap does
+const EXIT_COMPILATION_FAILED: u8 = 1;
+const EXIT_USAGE: u8 = 2;
+const EXIT_IO: u8 = 3;
+
+fn main() -> ExitCode {
     let args = Args::parse();
 
-    let loader = NativeFileLoader;
+    if let Some(Command::Fmt { files, check }) = &args.command {
+        return fmt(files, *check, args.message_format);
+    }
+
+    if let Some(Command::Lsp) = &args.command {
+        let loader = BuiltinFileLoader::new(NativeFileLoader::with_env_search_paths(args.path.clone()));
+
+        // Exiting without a shutdown request is an error in the protocol
+        return match lsp::run(loader, std::io::stdin().lock(), std::io::stdout().lock()) {
+            Ok(true) => ExitCode::SUCCESS,
+            Ok(false) => ExitCode::FAILURE,
+            Err(error) => {
+                eprintln!("{}: {}", "error".red().bold(), error);
+                ExitCode::from(EXIT_IO)
+            }
+        };
+    }
+
+    let input = args.input.clone().unwrap_or_default();
+
+    let loader = BuiltinFileLoader::new(NativeFileLoader::with_env_search_paths(args.path.clone()));
+
+    let mut mephisto = Mephisto::new(loader);
+
+    mephisto.set_fold_options(FoldOptions { preserve_nan: args.preserve_nan });
+    mephisto.set_verbose(args.verbose);
+
+    if let Some(emit) = args.emit {
+        return emit_stage(mephisto, &input, emit, &args);
+    }
+
     let codegen: Box<dyn CodeGenerator> = match args.target.as_str() {
         "js" => Box::new(JSCodeGenerator::new()),
         "wasm" => Box::new(WATCodeGenerator::new()),
-        _ => panic!("Unknown target: {}", args.target),
+        _ => {
+            eprintln!("{}: unknown target \"{}\", expected js or wasm", "error".red().bold(), args.target);
+            return ExitCode::from(EXIT_USAGE);
+        }
     };
 
-    let mut mephisto = Mephisto::new(loader);
-
     if args.target.as_str() == "wasm" {
         println!("{}", "WASM compilation is not ready yet, the result module will not work".red().bold());
     }
 
-    println!("{} {}", "Compiling".green(), args.input);
+    println!("{} {}", "Compiling".green(), input);
     println!("{} {}", "Target".green(), args.target);
 
     // We want to calculate elapsed time
     let start = std::time::Instant::now();
 
-    let compilation_result = mephisto.compile(&args.input, codegen);
+    let compilation_result = mephisto.compile(&input, codegen);
+
+    let warnings = mephisto.warnings();
+    print_diagnostics(warnings, &mephisto, args.message_format);
+
+    if !warnings.is_empty() && args.message_format == MessageFormat::Human {
+        eprintln!("{}: {} warning(s)", "warning".yellow().bold(), warnings.len());
+    }
 
     match compilation_result {
         Ok(res) => {
@@ -57,14 +167,117 @@ fn main() {
             let elapsed = format!("{}m {}s {}ms", elapsed.as_secs() / 60, elapsed.as_secs() % 60, elapsed.subsec_millis());
 
             if let Some(output) = args.output {
-                std::fs::write(output, res).expect("Unable to write file");
+                if let Err(error) = std::fs::write(&output, res) {
+                    eprintln!("{}: cannot write {}: {}", "error".red().bold(), output, error);
+                    return ExitCode::from(EXIT_IO);
+                }
 
                 println!("{} in {}", "Finished".green().bold(), elapsed);
             } else {
                 println!("{}", res);
                 println!("{} in {}", "Finished".green().bold(), elapsed);
             }
+
+            ExitCode::SUCCESS
         },
-        Err(e) => println!("{}: {:#?}", "Compilation failed".red().bold(), e),
+        Err(diagnostics) => {
+            print_diagnostics(&diagnostics, &mephisto, args.message_format);
+
+            if args.message_format == MessageFormat::Human {
+                eprintln!("{}: {} error(s)", "Compilation failed".red().bold(), diagnostics.len());
+            }
+
+            ExitCode::from(EXIT_COMPILATION_FAILED)
+        }
+    }
+}
+
+fn emit_stage<T: FileLoader>(mut mephisto: Mephisto<T>, input: &str, emit: Emit, args: &Args) -> ExitCode {
+    let stage = match emit {
+        Emit::Tokens => Stage::Tokens,
+        Emit::Ast => Stage::Ast,
+        Emit::Symbols => Stage::Symbols,
+        Emit::Ir => Stage::Ir,
+    };
+
+    let result = mephisto.emit(input, stage);
+
+    print_diagnostics(mephisto.warnings(), &mephisto, args.message_format);
+
+    let output = match result {
+        Ok(output) => output,
+        Err(diagnostics) => {
+            print_diagnostics(&diagnostics, &mephisto, args.message_format);
+            return ExitCode::from(EXIT_COMPILATION_FAILED);
+        }
+    };
+
+    match &args.output {
+        Some(file) => {
+            if let Err(error) = std::fs::write(file, output) {
+                eprintln!("{}: cannot write {}: {}", "error".red().bold(), file, error);
+                return ExitCode::from(EXIT_IO);
+            }
+        }
+        None => print!("{}", output),
+    }
+
+    ExitCode::SUCCESS
+}
+
+fn fmt(files: &[PathBuf], check: bool, message_format: MessageFormat) -> ExitCode {
+    let mut exit_code = ExitCode::SUCCESS;
+
+    for file in files {
+        let source = match std::fs::read_to_string(file) {
+            Ok(source) => source,
+            Err(error) => {
+                eprintln!("{}: cannot read {}: {}", "error".red().bold(), file.display(), error);
+                exit_code = ExitCode::from(EXIT_IO);
+                continue;
+            }
+        };
+
+        let formatted = match formatter::format(&source) {
+            Ok(formatted) => formatted,
+            Err(diagnostics) => {
+                for diagnostic in diagnostics {
+                    let diagnostic = diagnostic.with_file(&file.display().to_string());
+
+                    match message_format {
+                        MessageFormat::Human => eprintln!("{}", diagnostic.render(Some(&source))),
+                        MessageFormat::Json => eprintln!("{}", diagnostic.to_json(Some(&source))),
+                    }
+                }
+
+                exit_code = ExitCode::from(EXIT_COMPILATION_FAILED);
+                continue;
+            }
+        };
+
+        if formatted == source {
+            continue;
+        }
+
+        if check {
+            println!("{} is not formatted", file.display());
+            exit_code = ExitCode::from(EXIT_COMPILATION_FAILED);
+        } else if let Err(error) = std::fs::write(file, formatted) {
+            eprintln!("{}: cannot write {}: {}", "error".red().bold(), file.display(), error);
+            exit_code = ExitCode::from(EXIT_IO);
+        }
+    }
+
+    exit_code
+}
+
+fn print_diagnostics<T: FileLoader>(diagnostics: &[Diagnostic], mephisto: &Mephisto<T>, message_format: MessageFormat) {
+    for diagnostic in diagnostics.iter() {
+        let source = diagnostic.file.as_deref().and_then(|file| mephisto.source(file));
+
+        match message_format {
+            MessageFormat::Human => eprintln!("{}", diagnostic.render(source)),
+            MessageFormat::Json => eprintln!("{}", diagnostic.to_json(source)),
+        }
     }
 }
@@ -2,17 +2,18 @@ pub mod codegen_js;
 pub mod codegen_wat;
 pub mod context;
 
+use crate::diagnostic::Diagnostic;
 use crate::ir::IRResult;
 
 pub trait CodeGenerator {
-    fn generate(&self, ir: IRResult) -> Result<String, Vec<String>>;
+    fn generate(&self, ir: IRResult) -> Result<String, Vec<Diagnostic>>;
     fn get_stdlib_symbol(&self, name: &str) -> String;
 }
 
 pub struct StubCodeGenerator;
 
 impl CodeGenerator for StubCodeGenerator {
-    fn generate(&self, ir: IRResult) -> Result<String, Vec<String>> {
+    fn generate(&self, ir: IRResult) -> Result<String, Vec<Diagnostic>> {
         Ok(format!("[STUB] IR: {:?}", ir))
     }
     
@@ -1,10 +1,11 @@
 use crate::codegen::CodeGenerator;
-use crate::parser::ast::{ASTTraverseStage, Node, Operator, traverse_ast, VariableSpecifier};
+use crate::diagnostic::{Diagnostic, INTERNAL_ERROR};
+use crate::parser::ast::{operator_symbol, Operator};
 
 use handlebars::Handlebars;
 use std::collections::HashMap;
-use crate::codegen::context::{CodegenContext, CodeSection};
-use crate::ir::IRResult;
+use crate::codegen::context::{CodegenContext, identifier};
+use crate::ir::{Declaration, Destination, Expression, IRResult, Reference, Slot, Statement};
 
 pub struct JSCodeGenerator {
     handlebars: Handlebars<'static>,
@@ -78,59 +79,66 @@ impl JSCodeGenerator {
 
 impl CodeGenerator for JSCodeGenerator {
 
-    fn generate(&self, ir: IRResult) -> Result<String, Vec<String>> {
-        let mut context = CodegenContext {
-            code: String::new(),
-            code_map: HashMap::new(),
-            current_block: CodeSection::Glob.as_string(),
+    fn generate(&self, ir: IRResult) -> Result<String, Vec<Diagnostic>> {
+        let program = ir.program;
 
-            parameter_declarations: Vec::new(),
-            parameter_setters: Vec::new(),
+        let mut context = CodegenContext::new(self.stdlib.clone());
 
-            skip_identifiers: false,
-            skip_identifier_once: false,
+        let mut glob_code = String::new();
 
-            is_setter: false, // TODO: Not needed for JS
-
-            errors: Vec::new(),
+        for declaration in &program.declarations {
+            glob_code.push_str(&declaration_code(declaration, &mut context));
+        }
 
-            stdlib: self.stdlib.clone(),
+        let block_code = match &program.block {
+            Some(block) => format!("{{\n{}}}\n\n", statements_code(block, &context)),
+            None => String::new(),
         };
 
-        context.code_map.insert(CodeSection::Glob.as_string(), "".to_string());
-        context.code_map.insert(CodeSection::Block.as_string(), "".to_string());
-        context.code_map.insert(CodeSection::Process.as_string(), "".to_string());
-        context.code_map.insert(CodeSection::Connect.as_string().to_string(), "".to_string());
-        context.code_map.insert(CodeSection::ImplicitConnect.as_string(), "".to_string());
+        let process_code = statements_code(&program.process, &context);
+
+        let mut connect_code = String::new();
+        let mut implicit_connect_code = String::new();
 
-        let mut ast = ir.ast;
+        for connection in &program.connections {
+            match (&connection.source, &connection.destination) {
+                // Only outputs connected to inputs can be rewired by the host at runtime
+                (Reference::Output(output), Destination::Reference(Reference::Input(input))) => {
+                    connect_code.push_str(&format!("[{}, {}],\n", output, input));
+                }
+                (source, Destination::Outputs) => {
+                    let source = reference_code(source, &context);
+
+                    implicit_connect_code.push_str(&format!("leftOutput[i] = {};\n", source));
+                    implicit_connect_code.push_str(&format!("rightOutput && (rightOutput[i] = {});\n", source));
+                }
+                (source, Destination::Channel(channel)) => {
+                    implicit_connect_code.push_str(&format!("output[{}][i] = {};\n", channel, reference_code(source, &context)));
+                }
+                (source, Destination::Reference(destination)) => {
+                    implicit_connect_code.push_str(&format!("{} = {};\n", reference_code(destination, &context), reference_code(source, &context)));
+                }
+            }
+        }
 
-        traverse_ast(&mut ast.root, &mut ast_to_code, &mut context);
+        if !program.connections.is_empty() {
+            connect_code.push_str("\n\n");
+        }
 
-        if context.errors.len() > 0 {
+        if !context.errors.is_empty() {
             return Err(context.errors);
         }
 
         let mut data = HashMap::new();
 
-        let code_map = context.code_map.clone();
-        let glob_code = code_map.get(&CodeSection::Glob.as_string()).unwrap();
-        let block_code = code_map.get(&CodeSection::Block.as_string()).unwrap();
-        let process_code = code_map.get(&CodeSection::Process.as_string()).unwrap();
-        let connect_code = code_map.get(&CodeSection::Connect.as_string()).unwrap();
-        let implicit_connect_code = code_map.get(&CodeSection::ImplicitConnect.as_string()).unwrap();
-
         let parameters = context.parameter_declarations.join(", ");
-        let parameters = &parameters;
-
         let parameter_setters = context.parameter_setters.join("\n");
-        let parameter_setters = &parameter_setters;
 
-        let inputs_length = ir.input_names.len().to_string();
-        let outputs_length = ir.output_names.len().to_string();
+        let inputs_length = program.inputs.len().to_string();
+        let outputs_length = program.outputs.len().to_string();
 
-        let input_names = ir.input_names.iter().map(|name| format!("\"{}\"", name)).collect::<Vec<_>>().join(", ");
-        let output_names = ir.output_names.iter().map(|name| format!("\"{}\"", name)).collect::<Vec<_>>().join(", ");
+        let input_names = program.inputs.iter().map(|name| format!("\"{}\"", name)).collect::<Vec<_>>().join(", ");
+        let output_names = program.outputs.iter().map(|name| format!("\"{}\"", name)).collect::<Vec<_>>().join(", ");
 
         // TODO: Need to make an enum here
         data.insert("INPUT_NAMES", &input_names);
@@ -156,837 +164,480 @@ impl CodeGenerator for JSCodeGenerator {
     }
 }
 
-fn ast_to_code(enter_exit: ASTTraverseStage, node: &mut Node, context: &mut CodegenContext) -> bool {
-    match node {
-        Node::ProgramNode { .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {}
-                ASTTraverseStage::Exit => {}
-            }
-        }
-        Node::ProcessSection { .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    context.set_current_block(CodeSection::Process);
-                }
-                ASTTraverseStage::Exit => {
-                    context.set_current_block(CodeSection::Glob);
-                }
-            }
+fn declaration_code(declaration: &Declaration, context: &mut CodegenContext) -> String {
+    match declaration {
+        Declaration::Variable { name, constant, initializer } => {
+            format!("{} {} = {};\n", if *constant { "const" } else { "let" }, identifier(name), expression_code(initializer, context))
         }
-        Node::BlockSection { .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    context.set_current_block(CodeSection::Block);
-                    context.push_code("{\n");
-                }
-                ASTTraverseStage::Exit => {
-                    context.push_code("}\n\n");
-                    context.set_current_block(CodeSection::Glob);
-                }
-            }
-        }
-        Node::ConnectSection { children, .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    
-                    context.set_current_block(CodeSection::Connect);
-                    
-                    for child in children {
-                        match child {
-                            Node::ConnectStmt { lhs, rhs, .. } => {
-                                // lhs is guaranteed to be Node::Identifier, so we can unwrap
-                                let output_name =  match lhs.as_ref() {
-                                    Node::Identifier { name, .. } => {
-
-                                        if name.starts_with("##INPUT_") {
-                                            // The string is "##INPUT_[number]". We want to extract the number
-                                            let number = name.trim_start_matches("##INPUT_[").trim_end_matches("]");
-                                            number.to_string()
-                                        } else if name.starts_with("##OUTPUT_") {
-                                            let number = name.trim_start_matches("##OUTPUT_[").trim_end_matches("]");
-                                            number.to_string()
-                                        } else if name.contains("#") {
-                                            // replace # with __, and prepend with __
-                                            let name = name.replace("#", "__");
-                                            format!("##__{}", name) // Should go to implicit connections
-                                        } else {
-                                            format!("##{}", name.to_string()) // Should go to implicit connections
-                                        }
-                                    },
-                                    _ => {
-                                        context.errors.push("ConnectNode child not expected in the IR".to_string());
-                                        return true;
-                                    }
-                                };
-
-                                let input_name = match rhs.as_ref() {
-                                    Node::Identifier { name, .. } => {
-                                        if name.starts_with("##INPUT_") {
-                                            // The string is "##INPUT_[number]". We want to extract the number
-                                            let number = name.trim_start_matches("##INPUT_[").trim_end_matches("]");
-                                            number.to_string()
-                                        } else if name.starts_with("##OUTPUT_") {
-                                            let number = name.trim_start_matches("##OUTPUT_[").trim_end_matches("]");
-                                            number.to_string()
-                                        } else if name.contains("#") {
-                                            // replace # with __, and prepend with __
-                                            let name = name.replace("#", "__");
-                                            format!("##__{}", name) // Should go to implicit connections
-                                        } else {
-                                            format!("##{}", name.to_string()) // Should go to implicit connections
-                                        }
-                                    },
-                                    Node::OutputsStmt { .. } => "#OUTPUTS".to_string(),
-                                    Node::OutputsNumberedStmt { value, .. } => {
-                                        format!("##output[{}][i]", value)
-                                    }
-                                    _ => {
-                                        context.errors.push("ConnectNode child not expected in the IR".to_string());
-                                        return true;
-                                    }
-                                };
-
-                                if input_name == "#OUTPUTS" {
-
-                                    if output_name.parse::<i32>().is_ok() {
-                                        context.push_implicit_connect(&format!("leftOutput[i] = __m_outputs[{}];\n", output_name));
-                                        context.push_implicit_connect(&format!("rightOutput && (rightOutput[i] = __m_outputs[{}]);\n", output_name));
-                                    } else {
-                                        context.push_implicit_connect(&format!("leftOutput[i] = {};\n", output_name));
-                                        context.push_implicit_connect(&format!("rightOutput && (rightOutput[i] = {});\n", output_name));
-                                    }
-
-
-                                } else if input_name.starts_with("##") || output_name.starts_with("##") {
-                                    let input_name = input_name.trim_start_matches("##");
-                                    let output_name = output_name.trim_start_matches("##");
-
-                                    // Check if input_name is a number
-                                    if input_name.parse::<i32>().is_ok() {
-                                        context.push_implicit_connect(&format!("__m_inputs[{}] =", input_name));
-                                    } else {
-                                        context.push_implicit_connect(&format!("{} =", input_name));
-                                    }
-
-                                    // Check if output_name is a number
-                                    if output_name.parse::<i32>().is_ok() {
-                                        context.push_implicit_connect(&format!(" __m_outputs[{}];\n", output_name));
-                                    } else {
-                                        context.push_implicit_connect(&format!(" {};\n", output_name));
-                                    }
-                                }
-                                else {
-                                    context.push_code(&format!("[{}, {}],\n", output_name, input_name));
-                                }
-                            }
-                            _ => {
-                                context.errors.push("ConnectNode child not expected in the IR".to_string());
-                                return true;
-                            }
-                        };
+        Declaration::Input { slot, initializer } => format!("__m_inputs[{}] = {};\n", slot, expression_code(initializer, context)),
+        Declaration::Output { slot, initializer } => format!("__m_outputs[{}] = {};\n", slot, expression_code(initializer, context)),
+        Declaration::Parameter { name, fields } => {
+            let name = identifier(name);
+
+            let mut parameter_declaration = format!("{{name:'{}'", name);
+            let mut initial_value = "0".to_string();
+
+            for (field, specifier) in fields {
+                let specifier = match context.parameter_specifier_code(specifier) {
+                    Some(specifier) => specifier,
+                    None => {
+                        context.errors.push(Diagnostic::error(INTERNAL_ERROR, format!("Internal compiler error: {} not expected in the field \"{}\" of parameter \"{}\"", specifier, field, name)));
+                        continue;
                     }
+                };
 
-                    return true;
-                }
-                ASTTraverseStage::Exit => {
-                    context.push_code("\n\n");
-                    context.set_current_block(CodeSection::Glob);
+                if field == "initial" {
+                    initial_value = specifier.clone();
                 }
+
+                parameter_declaration.push_str(&format!(",{}:{}", field, specifier));
             }
-        }
-        Node::FunctionDeclarationStmt { id, params, .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    context.skip_identifiers = true;
-
-                    context.push_code("function ");
-
-                    match id.as_ref() {
-                        Node::Identifier { name, .. } => {
-                            if name.contains("#") {
-                                // replace # with __, and prepend with __
-                                let name = name.replace("#", "__");
-                                context.push_code(&format!("__{}", name));
-                            } else {
-                                context.push_code(name);
-                            }
-                        }
-                        _ => {}
-                    }
 
-                    context.push_code("(");
-
-                    let mut params_str = String::new();
-                    for param in params {
-                        match param {
-                            Node::FunctionParameter { id, .. } => {
-                                match id.as_ref() {
-                                    Node::Identifier { name, .. } => {
-                                        if name.contains("#") {
-                                            // replace # with __, and prepend with __
-                                            let name = name.replace("#", "__");
-                                            params_str.push_str(&format!("__{}", name));
-                                        } else {
-                                            params_str.push_str(&name);
-                                        }
-
-                                        params_str.push_str(", ");
-                                    }
-                                    _ => {}
-                                }
-                            }
-                            _ => {}
-                        }
-                    }
+            parameter_declaration.push('}');
 
-                    if params_str.len() > 0 {
-                        // Remove last comma and space
-                        params_str.pop();
-                        params_str.pop();
-                    }
+            context.parameter_declarations.push(parameter_declaration);
+            context.parameter_setters.push(format!("case '{}': {} = this.scheduledParameterSetters[i].value; break;", name, name));
 
-                    context.push_code(&params_str);
-                    context.push_code(")");
-                }
-                ASTTraverseStage::Exit => {
-                    context.push_code("\n");
-                }
-            }
+            format!("let {} = {};\n", name, initial_value)
         }
-        Node::FunctionParameter { .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {}
-                ASTTraverseStage::Exit => {}
+        Declaration::Buffer { name, size, initializer } => {
+            let name = identifier(name);
+
+            let mut code = format!("let {} = new Ringbuffer({});\n", name, expression_code(size, context));
+
+            if let Some(initializer) = initializer {
+                code.push_str(&format!("{}.setAll((i) => {{{}\n}});\n", name, statements_code(initializer, context)));
             }
+
+            code
         }
-        Node::FunctionBody { .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    context.skip_identifiers = false;
-                    context.push_code(" {\n");
-                }
-                ASTTraverseStage::Exit => {
-                    context.push_code("}\n");
-                }
-            }
+        Declaration::Function { name, parameters, body, .. } => {
+            let parameters = parameters.iter().map(identifier).collect::<Vec<_>>().join(", ");
+
+            format!("function {}({}) {{\n{}}}\n\n", identifier(name), parameters, statements_code(body, context))
         }
-        Node::Identifier { name, .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    if context.skip_identifiers {
-                        return false;
-                    }
+        Declaration::Statement(statement) => statements_code(std::slice::from_ref(statement), context),
+    }
+}
 
-                    if context.skip_identifier_once {
-                        context.skip_identifiers = false;
-                        context.skip_identifier_once = false;
-                        return false;
-                    }
+// Every statement of a list is terminated with ";\n", like the statement it was parsed from
+fn statements_code(statements: &[Statement], context: &CodegenContext) -> String {
+    let mut code = String::new();
 
-                    // TODO: This mess should be cleaned up
-                    if name.starts_with("##STD_") {
-                        let stdlib_name = name.trim_start_matches("##STD_");
-                        context.push_code(&format!("{}", context.get_stdlib_symbol(stdlib_name)));
-                    } else if name.starts_with("##INPUT_") {
-                        let input_name = name.trim_start_matches("##INPUT_");
-                        context.push_code(&format!("__m_inputs{}", input_name))
-                    } else if name.starts_with("##OUTPUT_") {
-                        let output_name = name.trim_start_matches("##OUTPUT_");
-                        context.push_code(&format!("__m_outputs{}", output_name))
-                    } else if name.starts_with("##INPUTINDEX") {
-                        let input_index = name.trim_start_matches("##INPUTINDEX[");
-                        let input_index = input_index.trim_end_matches("]");
-                        context.push_code(&format!("connectedInputs, {}", input_index));
-                    } else if name.starts_with("##OUTPUTINDEX") {
-                        let output_index = name.trim_start_matches("##OUTPUTINDEX[");
-                        let output_index = output_index.trim_end_matches("]");
-                        context.push_code(&format!("connectedOutputs, {}", output_index));
-                    } else if name.contains("#") {
-                        let name = name.replace("#", "__");
-                        context.push_code(&format!("__{}", name));
-                    } else {
-                        context.push_code(name);
-                    }
-                }
-                ASTTraverseStage::Exit => {}
-            }
-        }
-        Node::ExpressionStmt { child, .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    traverse_ast(child, &mut ast_to_code, context);
-                }
-                ASTTraverseStage::Exit => {
-
-                    // If two last characters are ";\n", remove them
-                    let code = context.code_map.get_mut(&context.current_block).unwrap();
-                    let len = code.len();
-                    if len > 2 {
-                        if &code[len - 2..] == ";\n" {
-                            code.pop();
-                            code.pop();
-                        }
-                    }
+    for statement in statements {
+        let statement = statement_code(statement, context);
 
-                    context.push_code(";\n");
-                }
-            }
+        code.push_str(statement.strip_suffix(";\n").unwrap_or(&statement));
+        code.push_str(";\n");
+    }
 
-            return true;
-        }
-        Node::AssignmentExpr { lhs, rhs, .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    traverse_ast(lhs, &mut ast_to_code, context);
-                    context.push_code(" = ");
-                    traverse_ast(rhs, &mut ast_to_code, context);
-                }
-                ASTTraverseStage::Exit => {
-                    context.push_code(";\n");
-                }
-            }
+    code
+}
 
-            return true;
+fn statement_code(statement: &Statement, context: &CodegenContext) -> String {
+    match statement {
+        Statement::Let { name, constant, initializer } => {
+            format!("{} {} = {};\n", if *constant { "const" } else { "let" }, identifier(name), expression_code(initializer, context))
         }
-        Node::ConnectStmt { lhs, rhs, .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    context.push_code("console.trace('FIX ME, connect statement'); //");
-                    traverse_ast(lhs, &mut ast_to_code, context);
-                    context.push_code(" -> ");
-                    traverse_ast(rhs, &mut ast_to_code, context);
-                    return true;
-                }
-                ASTTraverseStage::Exit => {
-                    context.push_code(";\n");
-                }
+        Statement::Assign { target, value } => format!("{} = {};\n", reference_code(target, context), expression_code(value, context)),
+        Statement::Expression(expression) => expression_code(expression, context),
+        Statement::Return(expression) => format!("return {};\n", expression_code(expression, context)),
+        Statement::If { test, consequent, alternate } => {
+            let mut code = format!("if ({}) {}", expression_code(test, context), statement_code(consequent, context));
+
+            if let Some(alternate) = alternate {
+                code.push_str(&format!(" else {}", statement_code(alternate, context)));
             }
-        }
-        Node::ReturnStmt { .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    context.push_code("return ");
-                }
-                ASTTraverseStage::Exit => {
-                    context.push_code(";\n");
-                }
-            }
-        }
-        Node::VariableDeclarationStmt { id, specifier, .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    match specifier {
-                        VariableSpecifier::Let => {
-                            context.push_code("let ");
-                        }
-                        VariableSpecifier::Const => {
-                            context.push_code("const ");
-                        }
-                        VariableSpecifier::Input => {
-                            context.push_code("");
-                        }
-                        VariableSpecifier::Output => {
-                            context.push_code("");
-                        }
-                        VariableSpecifier::Buffer => {
-                            context.push_code("let ");
-                        }
-                    }
 
-                    match id.as_ref() {
-                        Node::Identifier { name, .. } => {
-                            if name.starts_with("##INPUT_") {
-                                let input_name = name.trim_start_matches("##INPUT_");
-                                context.push_code(&format!("__m_inputs{}", input_name))
-                            } else if name.starts_with("##OUTPUT_") {
-                                let output_name = name.trim_start_matches("##OUTPUT_");
-                                context.push_code(&format!("__m_outputs{}", output_name))
-                            } else if name.contains("#") {
-                                // replace # with __, and prepend with __
-                                let name = name.replace("#", "__");
-                                context.push_code(&format!("__{}", name));
-                            } else {
-                                context.push_code(name);
-                            }
-                        }
-                        _ => {}
-                    }
+            code.push('\n');
+            code
+        }
+        Statement::For { variable, from, to, body } => {
+            let variable = identifier(variable);
 
-                    context.push_code(" = ");
+            format!("for (let {} = {}; {} < {}; {}++) {}\n", variable, expression_code(from, context), variable, expression_code(to, context), variable, statement_code(body, context))
+        }
+        Statement::Block(statements) => format!("{{\n{}}} ", statements_code(statements, context)),
+    }
+}
 
-                    context.skip_identifier_once = true;
-                }
-                ASTTraverseStage::Exit => {
-                    context.push_code(";\n");
-                }
+fn expression_code(expression: &Expression, context: &CodegenContext) -> String {
+    match expression {
+        Expression::Number(value) => value.to_string(),
+        Expression::Reference(reference) => reference_code(reference, context),
+        Expression::Unary { op, operand } => {
+            let operand = expression_code(operand, context);
+
+            match op {
+                // The operand is parenthesized, so "-(-x)" never becomes the "--" operator
+                Operator::Plus => format!("+({})", operand),
+                Operator::Minus => format!("-({})", operand),
+                // Any nonzero value is true, the result is normalized to 0/1
+                Operator::Not => format!("(({}) == 0 ? 1 : 0)", operand),
+                _ => operand,
             }
         }
-        Node::MemberExpr { .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    context.errors.push("MemberExpr not expected in the IR".to_string());
-                    return false;
-                }
-                ASTTraverseStage::Exit => {}
+        Expression::Binary { op, lhs, rhs } => {
+            let lhs = expression_code(lhs, context);
+            let rhs = expression_code(rhs, context);
+
+            match op {
+                // Modulo is floored, so the result has the sign of the divisor
+                Operator::Mod => format!("Std.mod({}, {})", lhs, rhs),
+                Operator::Pow => format!("Math.pow({}, {})", lhs, rhs),
+                // Any nonzero value is true, the result is normalized to 0/1
+                Operator::And => format!("(({}) != 0 && ({}) != 0 ? 1 : 0)", lhs, rhs),
+                Operator::Or => format!("(({}) != 0 || ({}) != 0 ? 1 : 0)", lhs, rhs),
+                Operator::Eq | Operator::Gt | Operator::Lt | Operator::Ge | Operator::Le | Operator::Ne => {
+                    format!("({} {} {} ? 1 : 0)", lhs, operator_symbol(op), rhs)
+                }
+                _ => format!("({} {} {})", lhs, operator_symbol(op), rhs),
             }
         }
-        Node::ExportDeclarationStmt { .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {}
-                ASTTraverseStage::Exit => {}
-            }
+        // Any nonzero value is true, only the chosen branch is evaluated
+        Expression::Conditional { test, consequent, alternate } => {
+            format!("(({}) != 0 ? ({}) : ({}))", expression_code(test, context), expression_code(consequent, context), expression_code(alternate, context))
         }
-        Node::ParameterDeclarationStmt { id, fields, .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-
-                    let mut parameter_declaration = String::new();
-                    let mut parameter_setter = String::new();
-
-                    context.push_code("let ");
-                    parameter_declaration.push_str("{name:");
-                    parameter_setter.push_str("case ");
-
-                    match id.as_ref() {
-                        Node::Identifier { name, .. } => {
-
-                            let name = if name.contains("#") {
-                                // replace # with __, and prepend with __
-                                let name = name.replace("#", "__");
-                                format!("__{}", name)
-                            } else {
-                                name.to_string()
-                            };
-
-                            context.push_code(&name);
-                            parameter_declaration.push_str(&format!("'{}'", name));
-                            parameter_setter.push_str(&format!("'{}': {} = this.scheduledParameterSetters[i].value; break;", name, name));
-                        }
-                        _ => {}
-                    }
+        Expression::Call { callee, args } => {
+            let args = args.iter().map(|arg| expression_code(arg, context)).collect::<Vec<_>>().join(", ");
 
-                    context.push_code(" = ");
-
-                    let initial_value = fields.iter().find(|field| {
-                        match field {
-                            Node::ParameterDeclarationField { id, .. } => {
-                                match id.as_ref() {
-                                    Node::Identifier { name, .. } => {
-                                        name == "initial"
-                                    }
-                                    _ => false
-                                }
-                            }
-                            _ => false
-                        }
-                    });
-
-                    if let Some(initial_value) = initial_value {
-                        match initial_value {
-                            Node::ParameterDeclarationField { specifier, .. } => {
-                                let specifier = match specifier.as_ref() {
-                                    Node::Number { value, .. } => {
-                                        value.to_string()
-                                    }
-                                    Node::Identifier {name, .. } => {
-                                        name.to_string()
-                                    }
-                                    Node::UnaryExpr { op, child, .. } => {
-                                        match op {
-                                            Operator::Minus => {
-                                                let mut code = "-".to_string();
-
-                                                // If child is Node::Number, we can unwrap
-                                                match child.as_ref() {
-                                                    Node::Number { value, .. } => {
-                                                        code.push_str(&value.to_string());
-                                                    }
-                                                    _ => {
-                                                        context.errors.push("ParameterDeclarationField not expected in the IR".to_string());
-                                                        return true;
-                                                    }
-                                                };
-
-                                                code
-                                            }
-                                            _ => {
-                                                context.errors.push("ParameterDeclarationField not expected in the IR".to_string());
-                                                return true;
-                                            }
-                                        }
-                                    }
-                                    _ => {
-                                        context.errors.push("ParameterDeclarationField not expected in the IR".to_string());
-                                        return true;
-                                    }
-                                };
-
-                                context.push_code(&specifier.to_string());
-                            }
-                            _ => {}
-                        }
-                    } else {
-                        context.push_code("0");
-                    }
+            format!("{}({})", reference_code(callee, context), args)
+        }
+        Expression::Connected(Slot::Input(slot)) => format!("Std.connected(connectedInputs, {})", slot),
+        Expression::Connected(Slot::Output(slot)) => format!("Std.connected(connectedOutputs, {})", slot),
+    }
+}
 
-                    fields.iter().for_each(|field| {
-                        match field {
-                            Node::ParameterDeclarationField { id, specifier, .. } => {
-                                match id.as_ref() {
-                                    Node::Identifier { name, .. } => {
-
-                                        let specifier = match specifier.as_ref() {
-                                            Node::Number { value, .. } => {
-                                                value.to_string()
-                                            }
-                                            Node::Identifier {name, .. } => {
-                                                if name.starts_with("##STD_") {
-                                                    let stdlib_name = name.trim_start_matches("##STD_");
-                                                    context.get_stdlib_symbol(stdlib_name)
-                                                } else {
-                                                    name.to_string()
-                                                }
-                                            }
-                                            Node::UnaryExpr { op, child, .. } => {
-                                                match op {
-                                                    Operator::Minus => {
-                                                        let mut code = "-".to_string();
-
-                                                        // If child is Node::Number, we can unwrap
-                                                        match child.as_ref() {
-                                                            Node::Number { value, .. } => {
-                                                                code.push_str(&value.to_string());
-                                                            }
-                                                            _ => {
-                                                                context.errors.push("ParameterDeclarationField not expected in the IR".to_string());
-                                                            }
-                                                        };
-
-                                                        code
-                                                    }
-                                                    _ => {
-                                                        panic!("ParameterDeclarationField not expected in the IR")
-                                                    }
-                                                }
-                                            }
-                                            _ => {
-                                                panic!("ParameterDeclarationField not expected in the IR")
-                                            }
-
-                                        };
-
-                                        parameter_declaration.push_str(&format!(",{}:{}", name, specifier));
-                                    }
-                                    _ => {}
-                                }
-                            }
-                            _ => {}
-                        }
-                    });
-
-                    context.push_code(";\n");
-                    parameter_declaration.push_str("}");
-
-                    context.parameter_declarations.push(parameter_declaration);
-                    context.parameter_setters.push(parameter_setter);
-                }
-                ASTTraverseStage::Exit => {
+fn reference_code(reference: &Reference, context: &CodegenContext) -> String {
+    match reference {
+        Reference::Local(name) | Reference::State(name) | Reference::Parameter(name) | Reference::Function(name) => identifier(name),
+        Reference::Input(slot) => format!("__m_inputs[{}]", slot),
+        Reference::Output(slot) => format!("__m_outputs[{}]", slot),
+        Reference::Std(name) => context.get_stdlib_symbol(name),
+    }
+}
 
-                }
-            }
+#[cfg(test)]
+mod tests {
+    use indexmap::IndexMap;
+    use crate::ir::IR;
+    use crate::lexer::Lexer;
+    use crate::module_data::ModuleData;
+    use crate::parser::Parser;
+    use crate::symbol_table::SymbolTable;
+    use super::*;
 
-            return true;
-        }
+    // Ok, to be honest, this is not really a test. It's more of a "does it compile" test.
+    // I'm not sure how to test the generated code. Let me think about it.
+    #[test]
+    fn test_js_codegen() {
+        let mut handlebars = Handlebars::new();
+        handlebars.register_template_string("js", include_str!("templates/js.hbs")).unwrap();
 
-        Node::ParameterDeclarationField{..} => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    context.errors.push("ParameterDeclarationField not expected in the IR".to_string());
-                    return false;
-                }
-                ASTTraverseStage::Exit => {}
+        let code_generator = JSCodeGenerator::new();
+
+        let code = "
+            let foo = 42;
+
+            bar(a, b) {
+                return a + b;
             }
-        }
+            ".to_string();
 
-        Node::FnCallExpr { callee, args, .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    traverse_ast(callee, &mut ast_to_code, context);
-                    context.push_code("(");
+        let lexer = Lexer::new();
+        let tokens = lexer.tokenize(code);
 
-                    let mut len = 0;
+        let mut parser = Parser::new();
+        let mut ast = parser.parse(tokens);
 
-                    for arg in args {
-                        traverse_ast(arg, &mut ast_to_code, context);
-                        context.push_code(", ");
-                        len += 1;
-                    }
+        let symbol_table = SymbolTable::from_ast(&mut ast).unwrap();
 
-                    if len > 0 {
-                        context.remove_last_char();
-                        context.remove_last_char();
-                    }
+        let module_data = ModuleData {
+            ast,
+            symbol_table,
+            errors: vec![],
+        };
 
-                    context.push_code(")");
-                }
-                ASTTraverseStage::Exit => {}
-            }
+        let mut modules = IndexMap::new();
+        modules.insert("main".to_string(), module_data);
 
-            return true;
-        }
-        Node::Number { value, .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    context.push_code(&value.to_string());
-                }
-                ASTTraverseStage::Exit => {}
-            }
-        }
-        Node::UnaryExpr { op, .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    match op {
-                        Operator::Plus => {
-                            context.push_code("+");
-                        }
-                        Operator::Minus => {
-                            context.push_code("-");
-                        }
-                        _ => {}
-                    }
-                }
-                ASTTraverseStage::Exit => {}
-            }
-        }
-        Node::BinaryExpr { op, lhs, rhs, .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    context.push_code("(");
-                    traverse_ast(lhs, &mut ast_to_code, context);
-                    match op {
-                        Operator::Plus => {
-                            context.push_code(" + ");
-                        }
-                        Operator::Minus => {
-                            context.push_code(" - ");
-                        }
-                        Operator::Mul => {
-                            context.push_code(" * ");
-                        }
-                        Operator::Div => {
-                            context.push_code(" / ");
-                        }
-                        Operator::Eq => {
-                            context.push_code(" == ");
-                        }
-                        Operator::Gt => {
-                            context.push_code(" > ");
-                        }
-                        Operator::Lt => {
-                            context.push_code(" < ");
-                        }
-                        Operator::Ge => {
-                            context.push_code(" >= ");
-                        }
-                        Operator::Le => {
-                            context.push_code(" <= ");
-                        }
-                        Operator::Ne => {
-                            context.push_code(" != ");
-                        }
-                    }
-                    traverse_ast(rhs, &mut ast_to_code, context);
+        let mut ir = IR::new();
+        let result = ir.create(&mut modules, "main".to_string());
 
-                    match op {
-                        Operator::Eq | Operator::Gt | Operator::Lt | Operator::Ge | Operator::Le | Operator::Ne => {
-                            context.push_code(" ? 1 : 0");
-                        }
-                        _ => {}
-                    }
+        let result = code_generator.generate(result.unwrap());
 
-                    context.push_code(")");
+        assert!(result.is_ok());
+    }
 
-                    context.skip_identifiers = true;
-                }
-                ASTTraverseStage::Exit => {
-                    context.skip_identifiers = false;
-                }
-            }
+    #[test]
+    fn test_js_logical_operators() {
+        let code_generator = JSCodeGenerator::new();
 
-            return true;
-        }
-        Node::OutputsStmt { .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    context.push_code("outputs[0]");
-                }
-                ASTTraverseStage::Exit => {}
-            }
-        }
-        Node::OutputsNumberedStmt {value, .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    context.push_code("outputs[");
-                    context.push_code(&value.to_string());
-                    context.push_code("]");
-                }
-                ASTTraverseStage::Exit => {}
-            }
-        }
-        Node::BufferDeclarationStmt {id, size, initializer, .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    // context.push_code("");
-                    context.push_code("let ");
-                    traverse_ast(id, &mut ast_to_code, context);
-
-                    let id = match id.as_ref() {
-                        Node::Identifier { name, .. } => {
-                            if name.contains("#") {
-                                // replace # with __, and prepend with __
-                                let name = name.replace("#", "__");
-                                format!("__{}", name)
-                            } else {
-                                name.to_string()
-                            }
-                        }
-                        _ => {
-                            context.errors.push("BufferDeclarationStmt not expected in the IR".to_string());
-                            return true;
-                        }
-                    };
-
-                    // context.push_code("[");
-                    // traverse_ast(size, &mut ast_to_code, context);
-                    // context.push_code("] = ");
-                    context.push_code(" = ");
-                    context.push_code("new Ringbuffer(");
-                    traverse_ast(size, &mut ast_to_code, context);
-                    context.push_code(");\n");
-
-
-                    // If initializer is Node::Number, we can unwrap
-                    let init = match initializer.as_ref() {
-                        Node::Number { value, .. } => {
-                            value
-                        }
-                        _ => {
-                            &(-1f64)
-                        }
-                    };
-
-                    if *init != -1f64 {
-                        // context.push_code("");
-                    } else {
-                        context.push_code(format!("{}.setAll((i) => {{", id).as_str());
-                        traverse_ast(initializer, &mut ast_to_code, context);
-                        context.push_code("\n});\n");
-                    }
+        let code = "
+            let a = 0.5;
+            let b = 0;
+            let c = 0;
 
-                }
-                ASTTraverseStage::Exit => {}
+            process {
+                c = !a || a && b;
             }
+            ".to_string();
 
-            return true;
-        }
-        Node::BufferInitializer { .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    context.push_code("");
-                }
-                ASTTraverseStage::Exit => {
-                    context.push_code("");
-                }
+        let lexer = Lexer::new();
+        let tokens = lexer.tokenize(code);
+
+        let mut parser = Parser::new();
+        let mut ast = parser.parse(tokens);
+
+        let symbol_table = SymbolTable::from_ast(&mut ast).unwrap();
+
+        let module_data = ModuleData {
+            ast,
+            symbol_table,
+            errors: vec![],
+        };
+
+        let mut modules = IndexMap::new();
+        modules.insert("main".to_string(), module_data);
+
+        let mut ir = IR::new();
+        let result = ir.create(&mut modules, "main".to_string());
+
+        let result = code_generator.generate(result.unwrap()).unwrap();
+
+        assert!(result.contains("c = ((((a) == 0 ? 1 : 0)) != 0 || (((a) != 0 && (b) != 0 ? 1 : 0)) != 0 ? 1 : 0);"));
+    }
+
+    #[test]
+    fn test_js_conditional_expr() {
+        let code_generator = JSCodeGenerator::new();
+
+        let code = "
+            let a = 0.5;
+            let b = 0;
+
+            process {
+                b = a > 0.25 ? a : -a;
             }
-        }
-        Node::ImportStatement { .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    context.errors.push("ImportStatement not expected in the IR".to_string());
-                    return false;
-                }
-                ASTTraverseStage::Exit => {
+            ".to_string();
+
+        let lexer = Lexer::new();
+        let tokens = lexer.tokenize(code);
+
+        let mut parser = Parser::new();
+        let mut ast = parser.parse(tokens);
+
+        let symbol_table = SymbolTable::from_ast(&mut ast).unwrap();
+
+        let module_data = ModuleData {
+            ast,
+            symbol_table,
+            errors: vec![],
+        };
+
+        let mut modules = IndexMap::new();
+        modules.insert("main".to_string(), module_data);
+
+        let mut ir = IR::new();
+        let result = ir.create(&mut modules, "main".to_string());
+
+        let result = code_generator.generate(result.unwrap()).unwrap();
 
+        assert!(result.contains("b = (((a > 0.25 ? 1 : 0)) != 0 ? (a) : (-(a)));"));
+    }
+
+    #[test]
+    fn test_js_for_stmt() {
+        let code_generator = JSCodeGenerator::new();
+
+        let code = "
+            const N = 3;
+            let acc = 0;
+
+            process {
+                acc = 0;
+                for k in 0..N {
+                    let v = k * 2;
+                    acc = acc + v;
                 }
             }
-        }
-        Node::IfStmt { test, consequent, alternate, .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    context.push_code("if (");
-                    traverse_ast(test, &mut ast_to_code, context);
-                    context.push_code(") ");
-                    traverse_ast(consequent, &mut ast_to_code, context);
-                    if let Some(alternate) = alternate {
-                        context.push_code(" else ");
-                        traverse_ast(alternate, &mut ast_to_code, context);
-                        context.push_code("");
-                    }
+            ".to_string();
 
-                    context.push_code("\n");
-                }
-                ASTTraverseStage::Exit => {}
+        let lexer = Lexer::new();
+        let tokens = lexer.tokenize(code);
+
+        let mut parser = Parser::new();
+        let mut ast = parser.parse(tokens);
+
+        let symbol_table = SymbolTable::from_ast(&mut ast).unwrap();
+
+        let module_data = ModuleData {
+            ast,
+            symbol_table,
+            errors: vec![],
+        };
+
+        let mut modules = IndexMap::new();
+        modules.insert("main".to_string(), module_data);
+
+        let mut ir = IR::new();
+        let result = ir.create(&mut modules, "main".to_string());
+
+        let result = code_generator.generate(result.unwrap()).unwrap();
+
+        assert!(result.contains("for (let k = 0; k < N; k++) {"));
+    }
+
+    #[test]
+    fn test_js_nested_negation() {
+        let code_generator = JSCodeGenerator::new();
+
+        let code = "
+            let a = 0.5;
+            let b = 0;
+
+            fn neg(v) {
+                return -v;
             }
 
-            return true;
-        }
-        Node::BlockStmt { children, .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    context.push_code("{\n");
-                    for child in children {
-                        traverse_ast(child, &mut ast_to_code, context);
-                    }
-                    context.push_code("} ");
-                }
-                ASTTraverseStage::Exit => {}
+            process {
+                b = neg(-a) + 1;
             }
+            ".to_string();
 
-            return true;
-        }
+        let lexer = Lexer::new();
+        let tokens = lexer.tokenize(code);
 
-        Node::ConnectedExpr {test, ..} => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    context.push_code("Std.connected(");
-                    traverse_ast(test, &mut ast_to_code, context);
-                    context.push_code(")");
-                }
-                ASTTraverseStage::Exit => {}
-            }
+        let mut parser = Parser::new();
+        let mut ast = parser.parse(tokens);
 
-            return true;
-        }
+        let symbol_table = SymbolTable::from_ast(&mut ast).unwrap();
+
+        let module_data = ModuleData {
+            ast,
+            symbol_table,
+            errors: vec![],
+        };
+
+        let mut modules = IndexMap::new();
+        modules.insert("main".to_string(), module_data);
+
+        let mut ir = IR::new();
+        let mut result = ir.create(&mut modules, "main".to_string()).unwrap();
+
+        crate::ir::inline::inline_functions(&mut result.program);
+
+        let result = code_generator.generate(result).unwrap();
+
+        // "--a" would decrement a
+        assert!(result.contains("b = (-(-(a)) + 1);"));
     }
 
-    false
-}
+    #[test]
+    fn test_js_mod_pow() {
+        let code_generator = JSCodeGenerator::new();
 
-#[cfg(test)]
-mod tests {
-    use indexmap::IndexMap;
-    use crate::ir::IR;
-    use crate::lexer::Lexer;
-    use crate::module_data::ModuleData;
-    use crate::parser::Parser;
-    use crate::symbol_table::SymbolTable;
-    use super::*;
+        let code = "
+            let phase = 0;
+            let gain = 0;
+
+            process {
+                phase = (phase - 0.25) % 1;
+                gain = phase ^ 2;
+            }
+            ".to_string();
+
+        let lexer = Lexer::new();
+        let tokens = lexer.tokenize(code);
+
+        let mut parser = Parser::new();
+        let mut ast = parser.parse(tokens);
+
+        let symbol_table = SymbolTable::from_ast(&mut ast).unwrap();
+
+        let module_data = ModuleData {
+            ast,
+            symbol_table,
+            errors: vec![],
+        };
+
+        let mut modules = IndexMap::new();
+        modules.insert("main".to_string(), module_data);
+
+        let mut ir = IR::new();
+        let result = ir.create(&mut modules, "main".to_string());
+
+        let result = code_generator.generate(result.unwrap()).unwrap();
+
+        assert!(result.contains("phase = Std.mod((phase - 0.25), 1);"));
+        assert!(result.contains("gain = Math.pow(phase, 2);"));
+    }
 
-    // Ok, to be honest, this is not really a test. It's more of a "does it compile" test.
-    // I'm not sure how to test the generated code. Let me think about it.
     #[test]
-    fn test_js_codegen() {
-        let mut handlebars = Handlebars::new();
-        handlebars.register_template_string("js", include_str!("templates/js.hbs")).unwrap();
+    fn test_js_unit_literals() {
+        let code_generator = JSCodeGenerator::new();
+
+        let code = "
+            param delay {
+                min: 1ms;
+                max: 2s;
+                initial: 10ms;
+            };
+
+            let time = 0;
+            let increment = 0;
+            let gain = 0;
+
+            process {
+                time = 10ms + 64smp;
+                increment = 440hz;
+                gain = -6db * 2;
+            }
+            ".to_string();
+
+        let lexer = Lexer::new();
+        let tokens = lexer.tokenize(code);
+
+        let mut parser = Parser::new();
+        let mut ast = parser.parse(tokens);
+
+        assert_eq!(ast.errors.len(), 0);
+
+        let symbol_table = SymbolTable::from_ast(&mut ast).unwrap();
+
+        let module_data = ModuleData {
+            ast,
+            symbol_table,
+            errors: vec![],
+        };
 
+        let mut modules = IndexMap::new();
+        modules.insert("main".to_string(), module_data);
+
+        let mut ir = IR::new();
+        let result = ir.create(&mut modules, "main".to_string());
+
+        let result = code_generator.generate(result.unwrap()).unwrap();
+
+        assert!(result.contains("time = ((0.01 * sampleRate) + 64);"));
+        assert!(result.contains("increment = (440 / sampleRate);"));
+        assert!(result.contains("gain = (0.5011872336272722 * 2);"));
+        assert!(result.contains("let delay = (0.01 * sampleRate);"));
+        assert!(result.contains("{name:'delay',min:(0.001 * sampleRate),max:(2 * sampleRate),initial:(0.01 * sampleRate)}"));
+    }
+
+    // The sign belongs to the left operand of %, so negative phases wrap through Std.mod
+    #[test]
+    fn test_js_floored_modulo() {
         let code_generator = JSCodeGenerator::new();
 
         let code = "
-            let foo = 42;
+            let phase = 0;
+            let wrapped = 0;
 
-            bar(a, b) {
-                return a + b;
+            process {
+                phase = -phase % 1;
+                wrapped = -7 % 3;
             }
             ".to_string();
 
@@ -1010,8 +661,25 @@ mod tests {
         let mut ir = IR::new();
         let result = ir.create(&mut modules, "main".to_string());
 
-        let result = code_generator.generate(result.unwrap());
+        let result = code_generator.generate(result.unwrap()).unwrap();
 
-        assert!(result.is_ok());
+        assert!(result.contains("phase = Std.mod(-(phase), 1);"), "{}", result);
+        assert!(result.contains("wrapped = Std.mod(-(7), 3);"), "{}", result);
+        assert!(result.contains("mod: function (a, b) {\n        return a - b * Math.floor(a / b);\n    }"));
     }
-}
\ No newline at end of file
+
+    // Runs the Std.mod helper from the template
+    #[test]
+    #[ignore = "requires node"]
+    fn test_js_floored_modulo_in_node() {
+        let template = include_str!("templates/js.hbs");
+        let start = template.find("const Std = {").unwrap();
+        let end = start + template[start..].find("};").unwrap() + 2;
+
+        let script = format!("{}\nconsole.log([[-1, 4], [1, -4], [-7, 3], [7.5, 2], [-0.25, 1]].map(([a, b]) => Std.mod(a, b)).join(' '));", &template[start..end]);
+
+        let output = std::process::Command::new("node").arg("-e").arg(script).output().expect("node is not available");
+
+        assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "3 -3 2 1.5 0.75");
+    }
+}
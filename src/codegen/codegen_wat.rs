@@ -1,10 +1,11 @@
 use crate::codegen::CodeGenerator;
-use crate::parser::ast::{ASTTraverseStage, Node, Operator, traverse_ast, VariableSpecifier};
+use crate::diagnostic::{Diagnostic, INTERNAL_ERROR};
+use crate::parser::ast::Operator;
 
 use handlebars::Handlebars;
 use std::collections::HashMap;
-use crate::codegen::context::{CodegenContext, CodeSection};
-use crate::ir::IRResult;
+use crate::codegen::context::{CodegenContext, identifier};
+use crate::ir::{Declaration, Expression, IRResult, Reference, Slot, Statement};
 
 pub struct WATCodeGenerator {
     handlebars: Handlebars<'static>,
@@ -79,72 +80,34 @@ impl WATCodeGenerator {
 
 impl CodeGenerator for WATCodeGenerator {
 
-    fn generate(&self, ir: IRResult) -> Result<String, Vec<String>> {
-        let mut context = CodegenContext {
-            code: String::new(),
-            code_map: HashMap::new(),
-            current_block: CodeSection::Glob.as_string(),
+    fn generate(&self, ir: IRResult) -> Result<String, Vec<Diagnostic>> {
+        let program = ir.program;
 
-            parameter_declarations: Vec::new(),
-            parameter_setters: Vec::new(),
+        let mut context = CodegenContext::new(self.stdlib.clone());
 
-            skip_identifiers: false,
-            skip_identifier_once: false,
+        let mut glob_code = String::new();
 
-            is_setter: false,
-
-            errors: Vec::new(),
+        for declaration in &program.declarations {
+            glob_code.push_str(&declaration_code(declaration, &mut context));
+        }
 
-            stdlib: self.stdlib.clone(),
+        let block_code = match &program.block {
+            Some(block) => format!("{{\n{}}}\n\n", statements_code(block, &context)),
+            None => String::new(),
         };
 
-        context.code_map.insert(CodeSection::Glob.as_string(), "".to_string());
-        context.code_map.insert(CodeSection::Block.as_string(), "".to_string());
-        context.code_map.insert(CodeSection::Process.as_string(), "".to_string());
-        context.code_map.insert(CodeSection::Connect.as_string().to_string(), "".to_string());
-        context.code_map.insert(CodeSection::ImplicitConnect.as_string(), "".to_string());
-
-        let mut ast = ir.ast;
-
-        traverse_ast(&mut ast.root, &mut ast_to_code, &mut context);
+        let process_code = statements_code(&program.process, &context);
 
-        if context.errors.len() > 0 {
+        if !context.errors.is_empty() {
             return Err(context.errors);
         }
 
+        // Connections are not supported by the WAT template yet
         let mut data = HashMap::new();
 
-        let code_map = context.code_map.clone();
-        let glob_code = code_map.get(&CodeSection::Glob.as_string()).unwrap();
-        let block_code = code_map.get(&CodeSection::Block.as_string()).unwrap();
-        let process_code = code_map.get(&CodeSection::Process.as_string()).unwrap();
-        let connect_code = code_map.get(&CodeSection::Connect.as_string()).unwrap();
-        let implicit_connect_code = code_map.get(&CodeSection::ImplicitConnect.as_string()).unwrap();
-
-        let parameters = context.parameter_declarations.join(", ");
-        let parameters = &parameters;
-
-        let parameter_setters = context.parameter_setters.join("\n");
-        let parameter_setters = &parameter_setters;
-
-        let inputs_length = ir.input_names.len().to_string();
-        let outputs_length = ir.output_names.len().to_string();
-
-        let input_names = ir.input_names.iter().map(|name| format!("\"{}\"", name)).collect::<Vec<_>>().join(", ");
-        let output_names = ir.output_names.iter().map(|name| format!("\"{}\"", name)).collect::<Vec<_>>().join(", ");
-
-        // TODO: Need to make an enum here
-        data.insert("INPUT_NAMES", &input_names);
-        data.insert("OUTPUT_NAMES", &output_names);
-        data.insert("INPUTS_LENGTH", &inputs_length);
-        data.insert("OUTPUTS_LENGTH", &outputs_length);
         data.insert("GLOB", &glob_code);
-        data.insert("PARAMETERS", &parameters);
-        data.insert("PARAMETER_SETTERS", &parameter_setters);
         data.insert("BLOCK", &block_code);
         data.insert("PROCESS", &process_code);
-        data.insert("CONNECTIONS", &connect_code);
-        data.insert("IMPLICIT_CONNECTIONS", &implicit_connect_code);
 
         let rendered = self.handlebars.render("wat", &data).unwrap();
 
@@ -157,828 +120,190 @@ impl CodeGenerator for WATCodeGenerator {
     }
 }
 
-fn ast_to_code(enter_exit: ASTTraverseStage, node: &mut Node, context: &mut CodegenContext) -> bool {
-    match node {
-        Node::ProgramNode { .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {}
-                ASTTraverseStage::Exit => {}
-            }
-        }
-        Node::ProcessSection { .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    context.set_current_block(CodeSection::Process);
-                }
-                ASTTraverseStage::Exit => {
-                    context.set_current_block(CodeSection::Glob);
-                }
-            }
-        }
-        Node::BlockSection { .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    context.set_current_block(CodeSection::Block);
-                    context.push_code("{\n");
-                }
-                ASTTraverseStage::Exit => {
-                    context.push_code("}\n\n");
-                    context.set_current_block(CodeSection::Glob);
-                }
-            }
-        }
-        Node::ConnectSection { children, .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    
-                    context.set_current_block(CodeSection::Connect);
-                    
-                    for child in children {
-                        match child {
-                            Node::ConnectStmt { lhs, rhs, .. } => {
-                                // lhs is guaranteed to be Node::Identifier, so we can unwrap
-                                let output_name =  match lhs.as_ref() {
-                                    Node::Identifier { name, .. } => {
-
-                                        if name.starts_with("##INPUT_") {
-                                            // The string is "##INPUT_[number]". We want to extract the number
-                                            let number = name.trim_start_matches("##INPUT_[").trim_end_matches("]");
-                                            number.to_string()
-                                        } else if name.starts_with("##OUTPUT_") {
-                                            let number = name.trim_start_matches("##OUTPUT_[").trim_end_matches("]");
-                                            number.to_string()
-                                        } else if name.contains("#") {
-                                            // replace # with __, and prepend with __
-                                            let name = name.replace("#", "__");
-                                            format!("##__{}", name) // Should go to implicit connections
-                                        } else {
-                                            format!("##{}", name.to_string()) // Should go to implicit connections
-                                        }
-                                    },
-                                    _ => {
-                                        context.errors.push("ConnectNode child not expected in the IR".to_string());
-                                        return true;
-                                    }
-                                };
-
-                                let input_name = match rhs.as_ref() {
-                                    Node::Identifier { name, .. } => {
-                                        if name.starts_with("##INPUT_") {
-                                            // The string is "##INPUT_[number]". We want to extract the number
-                                            let number = name.trim_start_matches("##INPUT_[").trim_end_matches("]");
-                                            number.to_string()
-                                        } else if name.starts_with("##OUTPUT_") {
-                                            let number = name.trim_start_matches("##OUTPUT_[").trim_end_matches("]");
-                                            number.to_string()
-                                        } else if name.contains("#") {
-                                            // replace # with __, and prepend with __
-                                            let name = name.replace("#", "__");
-                                            format!("##__{}", name) // Should go to implicit connections
-                                        } else {
-                                            format!("##{}", name.to_string()) // Should go to implicit connections
-                                        }
-                                    },
-                                    Node::OutputsStmt { .. } => "#OUTPUTS".to_string(),
-                                    Node::OutputsNumberedStmt { value, .. } => {
-                                        format!("##output[{}][i]", value)
-                                    }
-                                    _ => {
-                                        context.errors.push("ConnectNode child not expected in the IR".to_string());
-                                        return true;
-                                    }
-                                };
-
-                                if input_name == "#OUTPUTS" {
-
-                                    if output_name.parse::<i32>().is_ok() {
-                                        context.push_implicit_connect(&format!("leftOutput[i] = __m_outputs[{}];\n", output_name));
-                                        context.push_implicit_connect(&format!("rightOutput && (rightOutput[i] = __m_outputs[{}]);\n", output_name));
-                                    } else {
-                                        context.push_implicit_connect(&format!("leftOutput[i] = {};\n", output_name));
-                                        context.push_implicit_connect(&format!("rightOutput && (rightOutput[i] = {});\n", output_name));
-                                    }
-
-
-                                } else if input_name.starts_with("##") || output_name.starts_with("##") {
-                                    let input_name = input_name.trim_start_matches("##");
-                                    let output_name = output_name.trim_start_matches("##");
-
-                                    // Check if input_name is a number
-                                    if input_name.parse::<i32>().is_ok() {
-                                        context.push_implicit_connect(&format!("__m_inputs[{}] =", input_name));
-                                    } else {
-                                        context.push_implicit_connect(&format!("{} =", input_name));
-                                    }
-
-                                    // Check if output_name is a number
-                                    if output_name.parse::<i32>().is_ok() {
-                                        context.push_implicit_connect(&format!(" __m_outputs[{}];\n", output_name));
-                                    } else {
-                                        context.push_implicit_connect(&format!(" {};\n", output_name));
-                                    }
-                                }
-                                else {
-                                    context.push_code(&format!("[{}, {}],\n", output_name, input_name));
-                                }
-                            }
-                            _ => {
-                                context.errors.push("ConnectNode child not expected in the IR".to_string());
-                                return true;
-                            }
-                        };
-                    }
-
-                    return true;
-                }
-                ASTTraverseStage::Exit => {
-                    context.push_code("\n\n");
-                    context.set_current_block(CodeSection::Glob);
-                }
-            }
-        }
-        Node::FunctionDeclarationStmt { id, params, .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    context.skip_identifiers = true;
-
-                    context.push_code("function ");
-
-                    match id.as_ref() {
-                        Node::Identifier { name, .. } => {
-                            if name.contains("#") {
-                                // replace # with __, and prepend with __
-                                let name = name.replace("#", "__");
-                                context.push_code(&format!("__{}", name));
-                            } else {
-                                context.push_code(name);
-                            }
-                        }
-                        _ => {}
-                    }
-
-                    context.push_code("(");
-
-                    let mut params_str = String::new();
-                    for param in params {
-                        match param {
-                            Node::FunctionParameter { id, .. } => {
-                                match id.as_ref() {
-                                    Node::Identifier { name, .. } => {
-                                        if name.contains("#") {
-                                            // replace # with __, and prepend with __
-                                            let name = name.replace("#", "__");
-                                            params_str.push_str(&format!("__{}", name));
-                                        } else {
-                                            params_str.push_str(&name);
-                                        }
-
-                                        params_str.push_str(", ");
-                                    }
-                                    _ => {}
-                                }
-                            }
-                            _ => {}
-                        }
-                    }
-
-                    if params_str.len() > 0 {
-                        // Remove last comma and space
-                        params_str.pop();
-                        params_str.pop();
-                    }
-
-                    context.push_code(&params_str);
-                    context.push_code(")");
-                }
-                ASTTraverseStage::Exit => {
-                    context.push_code("\n");
-                }
-            }
-        }
-        Node::FunctionParameter { .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {}
-                ASTTraverseStage::Exit => {}
-            }
+fn declaration_code(declaration: &Declaration, context: &mut CodegenContext) -> String {
+    match declaration {
+        Declaration::Variable { name, initializer, .. } => {
+            format!("(global ${} (mut f64) {})\n", identifier(name), expression_code(initializer, context))
         }
-        Node::FunctionBody { .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    context.skip_identifiers = false;
-                    context.push_code(" {\n");
-                }
-                ASTTraverseStage::Exit => {
-                    context.push_code("}\n");
-                }
-            }
-        }
-        Node::Identifier { name, .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    if context.skip_identifiers {
-                        return false;
+        // TODO: Inputs and outputs should live in memory, not in globals
+        Declaration::Input { slot, initializer } => format!("(global $__inputs_{} (mut f64) {})\n", slot, expression_code(initializer, context)),
+        Declaration::Output { slot, initializer } => format!("(global $__outputs_{} (mut f64) {})\n", slot, expression_code(initializer, context)),
+        Declaration::Parameter { name, fields } => {
+            let name = identifier(name);
+
+            let mut parameter_declaration = format!("{{name:'{}'", name);
+            let mut initial_value = "0".to_string();
+
+            for (field, specifier) in fields {
+                let specifier = match context.parameter_specifier_code(specifier) {
+                    Some(specifier) => specifier,
+                    None => {
+                        context.errors.push(Diagnostic::error(INTERNAL_ERROR, format!("Internal compiler error: {} not expected in the field \"{}\" of parameter \"{}\"", specifier, field, name)));
+                        continue;
                     }
+                };
 
-                    if context.skip_identifier_once {
-                        context.skip_identifiers = false;
-                        context.skip_identifier_once = false;
-                        return false;
-                    }
-
-                    // TODO: This mess should be cleaned up
-                    if name.starts_with("##STD_") {
-                        let stdlib_name = name.trim_start_matches("##STD_");
-                        context.push_code(&format!("{}", context.get_stdlib_symbol(stdlib_name)));
-                    } else if name.starts_with("##INPUT_") {
-                        let input_name = name.trim_start_matches("##INPUT_[");
-                        let input_name = input_name.trim_end_matches("]");
-                        // TODO: This should look into memory, not global
-                        if context.is_setter {
-                            context.push_code(&format!("$__inputs_{}", input_name))
-                        } else {
-                            context.push_code(&format!("(global.get $__inputs_{})", input_name))
-                        }
-                    } else if name.starts_with("##OUTPUT_") {
-                        let output_name = name.trim_start_matches("##OUTPUT_[");
-                        let output_name = output_name.trim_end_matches("]");
-                        // TODO: This should look into memory, not global
-
-                        if context.is_setter {
-                            context.push_code(&format!("$__outputs_{}", output_name))
-                        } else {
-                            context.push_code(&format!("(global.get $__outputs_{})", output_name))
-                        }
-                    } else if name.starts_with("##INPUTINDEX") {
-                        let input_index = name.trim_start_matches("##INPUTINDEX[");
-                        let input_index = input_index.trim_end_matches("]");
-                        context.push_code(&format!("connectedInputs, {}", input_index));
-                    } else if name.starts_with("##OUTPUTINDEX") {
-                        let output_index = name.trim_start_matches("##OUTPUTINDEX[");
-                        let output_index = output_index.trim_end_matches("]");
-                        context.push_code(&format!("connectedOutputs, {}", output_index));
-                    } else if name.contains("#") {
-                        let name = name.replace("#", "__");
-                        context.push_code(&format!("__{}", name));
-                    } else {
-                        context.push_code(&format!("(global.get ${})", name));
-                    }
+                if field == "initial" {
+                    initial_value = specifier.clone();
                 }
-                ASTTraverseStage::Exit => {}
-            }
-        }
-        Node::ExpressionStmt { child, .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    traverse_ast(child, &mut ast_to_code, context);
-                }
-                ASTTraverseStage::Exit => {
-
-                    // If two last characters are ";\n", remove them
-                    let code = context.code_map.get_mut(&context.current_block).unwrap();
-                    let len = code.len();
-                    if len > 2 {
-                        if &code[len - 2..] == ";\n" {
-                            code.pop();
-                            code.pop();
-                        }
-                    }
-
-                    // context.push_code(";\n");
-                }
-            }
-
-            return true;
-        }
-        Node::AssignmentExpr { lhs, rhs, .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    context.push_code("(global.set ");
-                    context.is_setter = true;
-                    traverse_ast(lhs, &mut ast_to_code, context);
-                    context.is_setter = false;
-                    traverse_ast(rhs, &mut ast_to_code, context);
-                    context.push_code(")");
-                }
-                ASTTraverseStage::Exit => {
-                }
-            }
 
-            return true;
-        }
-        Node::ConnectStmt { lhs, rhs, .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    context.push_code("console.trace('FIX ME, connect statement'); //");
-                    traverse_ast(lhs, &mut ast_to_code, context);
-                    context.push_code(" -> ");
-                    traverse_ast(rhs, &mut ast_to_code, context);
-                    return true;
-                }
-                ASTTraverseStage::Exit => {
-                    context.push_code(";\n");
-                }
+                parameter_declaration.push_str(&format!(",{}:{}", field, specifier));
             }
-        }
-        Node::ReturnStmt { .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    context.push_code("return ");
-                }
-                ASTTraverseStage::Exit => {
-                    context.push_code(";\n");
-                }
-            }
-        }
-        Node::VariableDeclarationStmt { id, specifier, .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    match specifier {
-                        | VariableSpecifier::Let
-                        | VariableSpecifier::Const => {
-                            context.push_code("(global $");
-                        }
-                        VariableSpecifier::Input => {
-                            context.push_code("(global $");
-                        }
-                        VariableSpecifier::Output => {
-                            context.push_code("(global $");
-                        }
-                        VariableSpecifier::Buffer => {
-                            context.push_code("let ");
-                        }
-                    }
 
-                    match id.as_ref() {
-                        Node::Identifier { name, .. } => {
-                            if name.starts_with("##INPUT_") {
-                                let input_name = name.trim_start_matches("##INPUT_[");
-                                let input_name = input_name.trim_end_matches("]");
-                                context.push_code(&format!("__inputs_{}", input_name))
-                            } else if name.starts_with("##OUTPUT_[") {
-                                let output_name = name.trim_start_matches("##OUTPUT_[");
-                                let output_name = output_name.trim_end_matches("]");
-                                context.push_code(&format!("__outputs_{}", output_name))
-                            } else if name.contains("#") {
-                                // replace # with __, and prepend with __
-                                let name = name.replace("#", "__");
-                                context.push_code(&format!("__{}", name));
-                            } else {
-                                context.push_code(name);
-                            }
-                        }
-                        _ => {}
-                    }
+            parameter_declaration.push('}');
 
-                    context.push_code(" (mut f64) ");
+            context.parameter_declarations.push(parameter_declaration);
+            context.parameter_setters.push(format!("case '{}': {} = this.scheduledParameterSetters[i].value; break;", name, name));
 
-                    context.skip_identifier_once = true;
-                }
-                ASTTraverseStage::Exit => {
-                    context.push_code(")\n");
-                }
-            }
-        }
-        Node::MemberExpr { .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    context.errors.push("MemberExpr not expected in the IR".to_string());
-                    return false;
-                }
-                ASTTraverseStage::Exit => {}
-            }
-        }
-        Node::ExportDeclarationStmt { .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {}
-                ASTTraverseStage::Exit => {}
-            }
+            format!("let {} = {};\n", name, initial_value)
         }
-        Node::ParameterDeclarationStmt { id, fields, .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-
-                    let mut parameter_declaration = String::new();
-                    let mut parameter_setter = String::new();
-
-                    context.push_code("let ");
-                    parameter_declaration.push_str("{name:");
-                    parameter_setter.push_str("case ");
-
-                    match id.as_ref() {
-                        Node::Identifier { name, .. } => {
-
-                            let name = if name.contains("#") {
-                                // replace # with __, and prepend with __
-                                let name = name.replace("#", "__");
-                                format!("__{}", name)
-                            } else {
-                                name.to_string()
-                            };
-
-                            context.push_code(&name);
-                            parameter_declaration.push_str(&format!("'{}'", name));
-                            parameter_setter.push_str(&format!("'{}': {} = this.scheduledParameterSetters[i].value; break;", name, name));
-                        }
-                        _ => {}
-                    }
-
-                    context.push_code(" = ");
-
-                    let initial_value = fields.iter().find(|field| {
-                        match field {
-                            Node::ParameterDeclarationField { id, .. } => {
-                                match id.as_ref() {
-                                    Node::Identifier { name, .. } => {
-                                        name == "initial"
-                                    }
-                                    _ => false
-                                }
-                            }
-                            _ => false
-                        }
-                    });
-
-                    if let Some(initial_value) = initial_value {
-                        match initial_value {
-                            Node::ParameterDeclarationField { specifier, .. } => {
-                                let specifier = match specifier.as_ref() {
-                                    Node::Number { value, .. } => {
-                                        value.to_string()
-                                    }
-                                    Node::Identifier {name, .. } => {
-                                        name.to_string()
-                                    }
-                                    Node::UnaryExpr { op, child, .. } => {
-                                        match op {
-                                            Operator::Minus => {
-                                                let mut code = "-".to_string();
-
-                                                // If child is Node::Number, we can unwrap
-                                                match child.as_ref() {
-                                                    Node::Number { value, .. } => {
-                                                        code.push_str(&value.to_string());
-                                                    }
-                                                    _ => {
-                                                        context.errors.push("ParameterDeclarationField not expected in the IR".to_string());
-                                                        return true;
-                                                    }
-                                                };
-
-                                                code
-                                            }
-                                            _ => {
-                                                context.errors.push("ParameterDeclarationField not expected in the IR".to_string());
-                                                return true;
-                                            }
-                                        }
-                                    }
-                                    _ => {
-                                        context.errors.push("ParameterDeclarationField not expected in the IR".to_string());
-                                        return true;
-                                    }
-                                };
-
-                                context.push_code(&specifier.to_string());
-                            }
-                            _ => {}
-                        }
-                    } else {
-                        context.push_code("0");
-                    }
+        Declaration::Buffer { name, size, initializer } => {
+            let name = identifier(name);
 
-                    fields.iter().for_each(|field| {
-                        match field {
-                            Node::ParameterDeclarationField { id, specifier, .. } => {
-                                match id.as_ref() {
-                                    Node::Identifier { name, .. } => {
-
-                                        let specifier = match specifier.as_ref() {
-                                            Node::Number { value, .. } => {
-                                                value.to_string()
-                                            }
-                                            Node::Identifier {name, .. } => {
-                                                if name.starts_with("##STD_") {
-                                                    let stdlib_name = name.trim_start_matches("##STD_");
-                                                    context.get_stdlib_symbol(stdlib_name)
-                                                } else {
-                                                    name.to_string()
-                                                }
-                                            }
-                                            Node::UnaryExpr { op, child, .. } => {
-                                                match op {
-                                                    Operator::Minus => {
-                                                        let mut code = "-".to_string();
-
-                                                        // If child is Node::Number, we can unwrap
-                                                        match child.as_ref() {
-                                                            Node::Number { value, .. } => {
-                                                                code.push_str(&value.to_string());
-                                                            }
-                                                            _ => {
-                                                                context.errors.push("ParameterDeclarationField not expected in the IR".to_string());
-                                                            }
-                                                        };
-
-                                                        code
-                                                    }
-                                                    _ => {
-                                                        panic!("ParameterDeclarationField not expected in the IR")
-                                                    }
-                                                }
-                                            }
-                                            _ => {
-                                                panic!("ParameterDeclarationField not expected in the IR")
-                                            }
-
-                                        };
-
-                                        parameter_declaration.push_str(&format!(",{}:{}", name, specifier));
-                                    }
-                                    _ => {}
-                                }
-                            }
-                            _ => {}
-                        }
-                    });
-
-                    context.push_code(";\n");
-                    parameter_declaration.push_str("}");
-
-                    context.parameter_declarations.push(parameter_declaration);
-                    context.parameter_setters.push(parameter_setter);
-                }
-                ASTTraverseStage::Exit => {
+            let mut code = format!("let {} = new Ringbuffer({});\n", name, expression_code(size, context));
 
-                }
+            if let Some(initializer) = initializer {
+                code.push_str(&format!("{}.setAll((i) => {{{}\n}});\n", name, statements_code(initializer, context)));
             }
 
-            return true;
+            code
         }
+        Declaration::Function { name, parameters, body, .. } => {
+            let parameters = parameters.iter().map(identifier).collect::<Vec<_>>().join(", ");
 
-        Node::ParameterDeclarationField{..} => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    context.errors.push("ParameterDeclarationField not expected in the IR".to_string());
-                    return false;
-                }
-                ASTTraverseStage::Exit => {}
-            }
+            format!("function {}({}) {{\n{}}}\n\n", identifier(name), parameters, statements_code(body, context))
         }
+        Declaration::Statement(statement) => statements_code(std::slice::from_ref(statement), context),
+    }
+}
 
-        Node::FnCallExpr { callee, args, .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    context.push_code("(");
-                    traverse_ast(callee, &mut ast_to_code, context);
-
-                    let mut len = 0;
+fn statements_code(statements: &[Statement], context: &CodegenContext) -> String {
+    let mut code = String::new();
 
-                    for arg in args {
-                        traverse_ast(arg, &mut ast_to_code, context);
-                        context.push_code(", ");
-                        len += 1;
-                    }
+    for statement in statements {
+        let statement = statement_code(statement, context);
 
-                    if len > 0 {
-                        context.remove_last_char();
-                        context.remove_last_char();
-                    }
+        code.push_str(statement.strip_suffix(";\n").unwrap_or(&statement));
+    }
 
-                    context.push_code(")");
-                }
-                ASTTraverseStage::Exit => {}
-            }
+    code
+}
 
-            return true;
-        }
-        Node::Number { value, .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    let number = value.to_string();
-                    context.push_code(&format!("(f64.const {})", number).as_str());
-                }
-                ASTTraverseStage::Exit => {}
-            }
+fn statement_code(statement: &Statement, context: &CodegenContext) -> String {
+    match statement {
+        Statement::Let { name, initializer, .. } => {
+            format!("(global ${} (mut f64) {})\n", identifier(name), expression_code(initializer, context))
         }
-        Node::UnaryExpr { op, .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    match op {
-                        Operator::Plus => {
-                            context.push_code("+");
-                        }
-                        Operator::Minus => {
-                            context.push_code("-");
-                        }
-                        _ => {}
-                    }
-                }
-                ASTTraverseStage::Exit => {}
+        Statement::Assign { target, value } => format!("(global.set {}{})", target_code(target, context), expression_code(value, context)),
+        Statement::Expression(expression) => expression_code(expression, context),
+        Statement::Return(expression) => format!("return {};\n", expression_code(expression, context)),
+        Statement::If { test, consequent, alternate } => {
+            let mut code = format!("if ({}) {}", expression_code(test, context), statement_code(consequent, context));
+
+            if let Some(alternate) = alternate {
+                code.push_str(&format!(" else {}", statement_code(alternate, context)));
             }
-        }
-        Node::BinaryExpr { op, lhs, rhs, .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    context.push_code("(");
-                    match op {
-                        Operator::Plus => {
-                            context.push_code("f64.add");
-                        }
-                        Operator::Minus => {
-                            context.push_code("f64.sub");
-                        }
-                        Operator::Mul => {
-                            context.push_code("f64.mul");
-                        }
-                        Operator::Div => {
-                            context.push_code("f64.div");
-                        }
-                        Operator::Eq => {
-                            context.push_code("f64.eq");
-                        }
-                        Operator::Gt => {
-                            context.push_code("f64.gt");
-                        }
-                        Operator::Lt => {
-                            context.push_code("f64.lt");
-                        }
-                        Operator::Ge => {
-                            context.push_code("f64.ge");
-                        }
-                        Operator::Le => {
-                            context.push_code("f64.le");
-                        }
-                        Operator::Ne => {
-                            context.push_code("f64.ne");
-                        }
-                    }
-
-                    traverse_ast(lhs, &mut ast_to_code, context);
-                    traverse_ast(rhs, &mut ast_to_code, context);
-
-                    match op {
-                        Operator::Eq | Operator::Gt | Operator::Lt | Operator::Ge | Operator::Le | Operator::Ne => {
-                            context.push_code(" ? 1 : 0");
-                        }
-                        _ => {}
-                    }
 
-                    context.push_code(")");
-
-                    context.skip_identifiers = true;
-                }
-                ASTTraverseStage::Exit => {
-                    context.skip_identifiers = false;
-                }
-            }
-
-            return true;
+            code.push('\n');
+            code
         }
-        Node::OutputsStmt { .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    context.push_code("outputs[0]");
-                }
-                ASTTraverseStage::Exit => {}
-            }
-        }
-        Node::OutputsNumberedStmt {value, .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    context.push_code("outputs[");
-                    context.push_code(&value.to_string());
-                    context.push_code("]");
-                }
-                ASTTraverseStage::Exit => {}
-            }
+        Statement::For { variable, from, to, body } => {
+            let variable = identifier(variable);
+
+            // The loop variable is declared like the other locals, it starts over whenever the loop runs
+            let mut code = format!("(global ${} (mut f64) (f64.const 0))\n", variable);
+            code.push_str(&format!("(global.set ${} {})\n", variable, expression_code(from, context)));
+            code.push_str(&format!("(block (loop (br_if 1 (f64.ge (global.get ${}) {}))\n", variable, expression_code(to, context)));
+            code.push_str(&statement_code(body, context));
+            code.push_str(&format!("\n(global.set ${} (f64.add (global.get ${}) (f64.const 1)))\n(br 0)))\n", variable, variable));
+            code
         }
-        Node::BufferDeclarationStmt {id, size, initializer, .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    // context.push_code("");
-                    context.push_code("let ");
-                    traverse_ast(id, &mut ast_to_code, context);
-
-                    let id = match id.as_ref() {
-                        Node::Identifier { name, .. } => {
-                            if name.contains("#") {
-                                // replace # with __, and prepend with __
-                                let name = name.replace("#", "__");
-                                format!("__{}", name)
-                            } else {
-                                name.to_string()
-                            }
-                        }
-                        _ => {
-                            context.errors.push("BufferDeclarationStmt not expected in the IR".to_string());
-                            return true;
-                        }
-                    };
-
-                    // context.push_code("[");
-                    // traverse_ast(size, &mut ast_to_code, context);
-                    // context.push_code("] = ");
-                    context.push_code(" = ");
-                    context.push_code("new Ringbuffer(");
-                    traverse_ast(size, &mut ast_to_code, context);
-                    context.push_code(");\n");
-
-
-                    // If initializer is Node::Number, we can unwrap
-                    let init = match initializer.as_ref() {
-                        Node::Number { value, .. } => {
-                            value
-                        }
-                        _ => {
-                            &(-1f64)
-                        }
-                    };
-
-                    if *init != -1f64 {
-                        // context.push_code("");
-                    } else {
-                        context.push_code(format!("{}.setAll((i) => {{", id).as_str());
-                        traverse_ast(initializer, &mut ast_to_code, context);
-                        context.push_code("\n});\n");
-                    }
+        Statement::Block(statements) => format!("{{\n{}}} ", statements_code(statements, context)),
+    }
+}
 
-                }
-                ASTTraverseStage::Exit => {}
+fn expression_code(expression: &Expression, context: &CodegenContext) -> String {
+    match expression {
+        Expression::Number(value) => format!("(f64.const {})", value),
+        Expression::Reference(reference) => reference_code(reference, context),
+        Expression::Unary { op, operand } => {
+            let operand = expression_code(operand, context);
+
+            match op {
+                Operator::Plus => format!("+{}", operand),
+                Operator::Minus => format!("-{}", operand),
+                // Any nonzero value is true, the result is normalized to 0/1
+                Operator::Not => format!("(f64.convert_i32_u (f64.eq {} (f64.const 0)))", operand),
+                _ => operand,
             }
-
-            return true;
         }
-        Node::BufferInitializer { .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    context.push_code("");
-                }
-                ASTTraverseStage::Exit => {
-                    context.push_code("");
-                }
+        Expression::Binary { op, lhs, rhs } => {
+            let lhs = expression_code(lhs, context);
+            let rhs = expression_code(rhs, context);
+
+            let instruction = match op {
+                // Modulo is floored, so the result has the sign of the divisor
+                Operator::Mod => return format!("(call $__mod {} {})", lhs, rhs),
+                Operator::Pow => return format!("(call $__pow {} {})", lhs, rhs),
+                // Any nonzero value is true, the result is normalized to 0/1. Like in JS, the right
+                // hand side is only evaluated when the left hand side does not decide the result
+                Operator::And => return format!("(f64.convert_i32_u (if (result i32) (f64.ne {} (f64.const 0)) (then (f64.ne {} (f64.const 0))) (else (i32.const 0))))", lhs, rhs),
+                Operator::Or => return format!("(f64.convert_i32_u (if (result i32) (f64.ne {} (f64.const 0)) (then (i32.const 1)) (else (f64.ne {} (f64.const 0)))))", lhs, rhs),
+                Operator::Plus => "f64.add",
+                Operator::Minus => "f64.sub",
+                Operator::Mul => "f64.mul",
+                Operator::Div => "f64.div",
+                Operator::Eq => "f64.eq",
+                Operator::Gt => "f64.gt",
+                Operator::Lt => "f64.lt",
+                Operator::Ge => "f64.ge",
+                Operator::Le => "f64.le",
+                Operator::Ne => "f64.ne",
+                Operator::Not => "",
+            };
+
+            match op {
+                Operator::Eq | Operator::Gt | Operator::Lt | Operator::Ge | Operator::Le | Operator::Ne => {
+                    format!("({}{}{} ? 1 : 0)", instruction, lhs, rhs)
+                }
+                _ => format!("({}{}{})", instruction, lhs, rhs),
             }
         }
-        Node::ImportStatement { .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    context.errors.push("ImportStatement not expected in the IR".to_string());
-                    return false;
-                }
-                ASTTraverseStage::Exit => {
-
-                }
-            }
+        // Any nonzero value is true, only the chosen branch is evaluated
+        Expression::Conditional { test, consequent, alternate } => {
+            format!("(if (result f64) (f64.ne {} (f64.const 0)) (then {}) (else {}))", expression_code(test, context), expression_code(consequent, context), expression_code(alternate, context))
         }
-        Node::IfStmt { test, consequent, alternate, .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    context.push_code("if (");
-                    traverse_ast(test, &mut ast_to_code, context);
-                    context.push_code(") ");
-                    traverse_ast(consequent, &mut ast_to_code, context);
-                    if let Some(alternate) = alternate {
-                        context.push_code(" else ");
-                        traverse_ast(alternate, &mut ast_to_code, context);
-                        context.push_code("");
-                    }
-
-                    context.push_code("\n");
-                }
-                ASTTraverseStage::Exit => {}
-            }
+        Expression::Call { callee: Reference::Function(name), args } => {
+            let args = args.iter().map(|arg| expression_code(arg, context)).collect::<Vec<_>>().join(" ");
 
-            return true;
+            format!("(call ${} {})", identifier(name), args)
         }
-        Node::BlockStmt { children, .. } => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    context.push_code("{\n");
-                    for child in children {
-                        traverse_ast(child, &mut ast_to_code, context);
-                    }
-                    context.push_code("} ");
-                }
-                ASTTraverseStage::Exit => {}
-            }
+        Expression::Call { callee, args } => {
+            let args = args.iter().map(|arg| expression_code(arg, context)).collect::<Vec<_>>().join(", ");
 
-            return true;
+            format!("({}{})", reference_code(callee, context), args)
         }
+        Expression::Connected(Slot::Input(slot)) => format!("Std.connected(connectedInputs, {})", slot),
+        Expression::Connected(Slot::Output(slot)) => format!("Std.connected(connectedOutputs, {})", slot),
+    }
+}
 
-        Node::ConnectedExpr {test, ..} => {
-            match enter_exit {
-                ASTTraverseStage::Enter => {
-                    context.push_code("Std.connected(");
-                    traverse_ast(test, &mut ast_to_code, context);
-                    context.push_code(")");
-                }
-                ASTTraverseStage::Exit => {}
-            }
-
-            return true;
-        }
+fn reference_code(reference: &Reference, context: &CodegenContext) -> String {
+    match reference {
+        Reference::Std(name) => context.get_stdlib_symbol(name),
+        _ => format!("(global.get {})", target_code(reference, context)),
     }
+}
 
-    false
+// Name of the global a reference reads from or writes to
+fn target_code(reference: &Reference, context: &CodegenContext) -> String {
+    match reference {
+        Reference::Local(name) | Reference::State(name) | Reference::Parameter(name) | Reference::Function(name) => format!("${}", identifier(name)),
+        // TODO: Inputs and outputs should live in memory, not in globals
+        Reference::Input(slot) => format!("$__inputs_{}", slot),
+        Reference::Output(slot) => format!("$__outputs_{}", slot),
+        Reference::Std(name) => context.get_stdlib_symbol(name),
+    }
 }
 
 #[cfg(test)]
@@ -1032,4 +357,134 @@ mod tests {
 
         assert!(result.is_ok());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_wat_mod_pow() {
+        let code_generator = WATCodeGenerator::new();
+
+        let code = "
+            let phase = 0;
+            let gain = 0;
+
+            process {
+                phase = (phase - 0.25) % 1;
+                gain = phase ^ 2;
+            }
+            ".to_string();
+
+        let lexer = Lexer::new();
+        let tokens = lexer.tokenize(code);
+
+        let mut parser = Parser::new();
+        let mut ast = parser.parse(tokens);
+
+        let symbol_table = SymbolTable::from_ast(&mut ast).unwrap();
+
+        let module_data = ModuleData {
+            ast,
+            symbol_table,
+            errors: vec![],
+        };
+
+        let mut modules = IndexMap::new();
+        modules.insert("main".to_string(), module_data);
+
+        let mut ir = IR::new();
+        let result = ir.create(&mut modules, "main".to_string());
+
+        let result = code_generator.generate(result.unwrap()).unwrap();
+
+        assert!(result.contains("(call $__mod (f64.sub(global.get $phase)(f64.const 0.25)) (f64.const 1))"));
+        assert!(result.contains("(call $__pow (global.get $phase) (f64.const 2))"));
+        assert!(result.contains("(func $__mod (param $a f64) (param $b f64) (result f64)"));
+    }
+
+    #[test]
+    fn test_wat_logical_operators_short_circuit() {
+        let code_generator = WATCodeGenerator::new();
+
+        let code = "
+            let gate = 0;
+            let count = 0;
+
+            fn bump() {
+                count = count + 1;
+                return 1;
+            }
+
+            process {
+                gate = gate && bump();
+                gate = gate || bump();
+            }
+            ".to_string();
+
+        let lexer = Lexer::new();
+        let tokens = lexer.tokenize(code);
+
+        let mut parser = Parser::new();
+        let mut ast = parser.parse(tokens);
+
+        let symbol_table = SymbolTable::from_ast(&mut ast).unwrap();
+
+        let module_data = ModuleData {
+            ast,
+            symbol_table,
+            errors: vec![],
+        };
+
+        let mut modules = IndexMap::new();
+        modules.insert("main".to_string(), module_data);
+
+        let mut ir = IR::new();
+        let result = ir.create(&mut modules, "main".to_string());
+
+        let result = code_generator.generate(result.unwrap()).unwrap();
+
+        // bump() changes count, so it must only be called when gate does not decide the result
+        assert!(result.contains("(if (result i32) (f64.ne (global.get $gate) (f64.const 0)) (then (f64.ne (call $bump ) (f64.const 0))) (else (i32.const 0)))"), "{}", result);
+        assert!(result.contains("(if (result i32) (f64.ne (global.get $gate) (f64.const 0)) (then (i32.const 1)) (else (f64.ne (call $bump ) (f64.const 0))))"), "{}", result);
+        assert!(!result.contains("i32.and") && !result.contains("i32.or"));
+    }
+
+    #[test]
+    fn test_wat_for_stmt() {
+        let code_generator = WATCodeGenerator::new();
+
+        let code = "
+            let acc = 0;
+
+            process {
+                acc = 0;
+                for k in 0..3 {
+                    acc = acc + k;
+                }
+            }
+            ".to_string();
+
+        let lexer = Lexer::new();
+        let tokens = lexer.tokenize(code);
+
+        let mut parser = Parser::new();
+        let mut ast = parser.parse(tokens);
+
+        let symbol_table = SymbolTable::from_ast(&mut ast).unwrap();
+
+        let module_data = ModuleData {
+            ast,
+            symbol_table,
+            errors: vec![],
+        };
+
+        let mut modules = IndexMap::new();
+        modules.insert("main".to_string(), module_data);
+
+        let mut ir = IR::new();
+        let result = ir.create(&mut modules, "main".to_string());
+
+        let result = code_generator.generate(result.unwrap()).unwrap();
+
+        assert!(result.contains("(global $k (mut f64) (f64.const 0))\n(global.set $k (f64.const 0))\n"), "{}", result);
+        assert!(result.contains("(block (loop (br_if 1 (f64.ge (global.get $k) (f64.const 3)))"), "{}", result);
+        assert!(result.contains("(global.set $k (f64.add (global.get $k) (f64.const 1)))"), "{}", result);
+    }
+}
@@ -1,63 +1,79 @@
 use std::collections::HashMap;
 
-pub struct CodegenContext {
-    pub code: String,
-
-    pub code_map: HashMap<String, String>,
-    pub current_block: String,
+use crate::diagnostic::Diagnostic;
+use crate::ir::{Expression, Name, Reference};
+use crate::parser::ast::Operator;
 
+pub struct CodegenContext {
     pub parameter_declarations: Vec<String>,
     pub parameter_setters: Vec<String>,
 
-    pub skip_identifiers: bool,
-    pub skip_identifier_once: bool,
-
-    pub is_setter: bool,
-
-    pub errors: Vec<String>,
+    pub errors: Vec<Diagnostic>,
 
     pub stdlib: HashMap<String, String>,
 }
 
 impl CodegenContext {
-    pub fn push_code(&mut self, code: &str) {
-        self.code_map.get_mut(&self.current_block).unwrap().push_str(code);
+    pub fn new(stdlib: HashMap<String, String>) -> Self {
+        CodegenContext {
+            parameter_declarations: Vec::new(),
+            parameter_setters: Vec::new(),
+            errors: Vec::new(),
+            stdlib,
+        }
     }
 
-    pub fn push_implicit_connect(&mut self, code: &str) {
-        self.code_map.get_mut(&CodeSection::ImplicitConnect.as_string()).unwrap().push_str(code);
+    pub fn get_stdlib_symbol(&self, name: &str) -> String {
+        // Name is guaranteed to be in the stdlib, so we can unwrap
+        self.stdlib.get(name).unwrap().to_string()
     }
 
-    pub fn remove_last_char(&mut self) {
-        self.code_map.get_mut(&self.current_block).unwrap().pop();
-    }
+    // Parameter fields end up in the parameter descriptors, outside of the generated process code,
+    // so only numbers, identifiers and arithmetic on them (e.g. lowered unit literals) are supported
+    pub fn parameter_specifier_code(&self, specifier: &Expression) -> Option<String> {
+        match specifier {
+            Expression::Number(value) => Some(value.to_string()),
+            Expression::Reference(Reference::Std(name)) => Some(self.get_stdlib_symbol(name)),
+            Expression::Reference(Reference::Local(name) | Reference::State(name) | Reference::Parameter(name)) => {
+                Some(identifier(name))
+            }
+            Expression::Unary { op: Operator::Minus, operand } => {
+                Some(format!("-{}", self.parameter_specifier_code(operand)?))
+            }
+            Expression::Binary { op, lhs, rhs } => {
+                let op = match op {
+                    Operator::Plus => "+",
+                    Operator::Minus => "-",
+                    Operator::Mul => "*",
+                    Operator::Div => "/",
+                    _ => return None,
+                };
 
-    pub fn set_current_block(&mut self, block: CodeSection) {
-        self.current_block = block.as_string();
+                Some(format!("({} {} {})", self.parameter_specifier_code(lhs)?, op, self.parameter_specifier_code(rhs)?))
+            }
+            _ => None,
+        }
     }
+}
 
-    pub fn get_stdlib_symbol(&self, name: &str) -> String {
-        // Name is guaranteed to be in the stdlib, so we can unwrap
-        self.stdlib.get(name).unwrap().to_string()
+// Identifier of a declaration in the generated code. Names merged from imported modules
+// and renamed ones are prefixed with "__", so they cannot clash with names of the main module
+pub fn identifier(name: &Name) -> String {
+    if name.modules.is_empty() && name.version.is_none() {
+        return name.name.clone();
     }
-}
 
-pub enum CodeSection {
-    Glob,
-    Block,
-    Process,
-    Connect,
-    ImplicitConnect,
-}
+    let mut identifier = "__".to_string();
 
-impl CodeSection {
-    pub fn as_string(&self) -> String {
-        match self {
-            CodeSection::Glob => "glob".to_string(),
-            CodeSection::Block => "block".to_string(),
-            CodeSection::Process => "process".to_string(),
-            CodeSection::Connect => "connect".to_string(),
-            CodeSection::ImplicitConnect => "implicit_connect".to_string(),
-        }
+    for module in &name.modules {
+        identifier.push_str(module);
+        identifier.push_str("__");
+    }
+
+    match name.version {
+        Some(version) => identifier.push_str(&format!("__{}_{}", name.name, version)),
+        None => identifier.push_str(&name.name),
     }
-}
\ No newline at end of file
+
+    identifier
+}
@@ -0,0 +1,336 @@
+use std::fmt;
+
+use serde::Serialize;
+use crate::lexer::token::Position;
+use crate::parser::ast::Node;
+
+// Error codes are part of the public interface (tooling matches on them), so existing codes
+// must never be renumbered or reused for a different kind of error
+pub const SYNTAX_ERROR: &str = "E0001";
+pub const MALFORMED_NUMBER: &str = "E0002";
+
+pub const MODULE_NOT_FOUND: &str = "E0101";
+pub const IMPORT_CYCLE: &str = "E0102";
+
+pub const DUPLICATE_DECLARATION: &str = "E0201";
+
+pub const UNKNOWN_NAME: &str = "E0301";
+pub const PRIVATE_SYMBOL: &str = "E0302";
+pub const ASSIGNMENT_TO_CONSTANT: &str = "E0303";
+pub const FUNCTION_AS_VALUE: &str = "E0304";
+pub const NOT_A_FUNCTION: &str = "E0305";
+pub const ARGUMENT_COUNT: &str = "E0306";
+pub const MISSING_RETURN_VALUE: &str = "E0307";
+pub const DUPLICATE_SECTION: &str = "E0308";
+pub const INVALID_CONNECTION: &str = "E0309";
+pub const NON_CONSTANT_LOOP_BOUNDS: &str = "E0310";
+pub const INVALID_NAMED_IMPORT: &str = "E0311";
+pub const NAMED_IMPORT_CLASH: &str = "E0312";
+
+pub const INTERNAL_ERROR: &str = "E9999";
+
+// Warnings from the lint pass, each can be silenced with an allow attribute on the declaration
+pub const UNUSED_VARIABLE: &str = "W0001";
+pub const UNCONNECTED_MODULE: &str = "W0002";
+pub const UNUSED_INPUT: &str = "W0003";
+pub const UNUSED_OUTPUT: &str = "W0004";
+pub const UNUSED_PARAM: &str = "W0005";
+pub const DEAD_STORE: &str = "W0006";
+pub const UNKNOWN_LINT: &str = "W0007";
+
+// Reported for nodes an earlier stage should have ruled out, instead of panicking,
+// so malformed input never takes down the application embedding the compiler
+pub fn internal_error(node: &Node, expected: &str) -> Diagnostic {
+    Diagnostic::error(INTERNAL_ERROR, format!("Internal compiler error: expected {}, found {}", expected, node.kind()))
+        .with_span(*node.position())
+}
+
+// Building the IR only fails on nodes the passes before it should have removed or rewritten
+pub fn unexpected_in_ir(node: &Node, what: &str) -> Diagnostic {
+    Diagnostic::error(INTERNAL_ERROR, format!("Internal compiler error: {} not expected in the IR", what)).at_node(node)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: &'static str,
+    pub message: String,
+
+    // Key of the module the diagnostic belongs to, set once the module is known
+    pub file: Option<String>,
+    pub span: Option<Position>,
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn error(code: &'static str, message: String) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            code,
+            message,
+            file: None,
+            span: None,
+            notes: vec![],
+        }
+    }
+
+    pub fn warning(code: &'static str, message: String) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Warning,
+            ..Diagnostic::error(code, message)
+        }
+    }
+
+    pub fn with_span(mut self, span: Position) -> Diagnostic {
+        self.span = Some(span);
+        self
+    }
+
+    // Does not override the file set closer to where the diagnostic was created
+    pub fn with_file(mut self, file: &str) -> Diagnostic {
+        if self.file.is_none() {
+            self.file = Some(file.to_string());
+        }
+        self
+    }
+
+    pub fn with_note(mut self, note: String) -> Diagnostic {
+        self.notes.push(note);
+        self
+    }
+
+    // For errors raised by helpers that don't know which node the caller is visiting
+    pub fn at_node(self, node: &Node) -> Diagnostic {
+        self.with_span(*node.position()).with_note(format!("while visiting {}", node.kind()))
+    }
+
+    // Line and column (both 1-based) of the first character the span points at.
+    // Token spans include the whitespace in front of the token, so it is skipped
+    pub fn location(&self, source: &str) -> Option<(usize, usize)> {
+        let offset = self.start_offset(source)?;
+        let line_start = source[..offset].rfind('\n').map_or(0, |index| index + 1);
+
+        let line = source[..offset].matches('\n').count() + 1;
+        let column = source[line_start..offset].chars().count() + 1;
+
+        Some((line, column))
+    }
+
+    fn start_offset(&self, source: &str) -> Option<usize> {
+        let span = self.span?;
+        let start = (span.start as usize).min(source.len());
+        let end = (span.end as usize).clamp(start, source.len());
+
+        if !source.is_char_boundary(start) || !source.is_char_boundary(end) {
+            return None;
+        }
+
+        let leading_whitespace = source[start..end].len() - source[start..end].trim_start().len();
+
+        if start + leading_whitespace == end && end > start {
+            return Some(start);
+        }
+
+        Some(start + leading_whitespace)
+    }
+
+    // Renders the diagnostic the way rustc does, with the offending line and a caret underline
+    // when the source of the module is available
+    pub fn render(&self, source: Option<&str>) -> String {
+        let mut output = format!("{}[{}]: {}\n", self.severity, self.code, self.message);
+
+        let location = source.and_then(|source| self.location(source).map(|location| (source, location)));
+
+        match (&self.file, location) {
+            (file, Some((source, (line, column)))) => {
+                let offset = self.start_offset(source).unwrap();
+                let span = self.span.unwrap();
+
+                let line_start = source[..offset].rfind('\n').map_or(0, |index| index + 1);
+                let line_end = source[offset..].find('\n').map_or(source.len(), |index| offset + index);
+                let line_text = source[line_start..line_end].trim_end();
+
+                let underline_end = (span.end as usize).clamp(offset, line_end);
+                let underline_length = source[offset..underline_end].trim_end().chars().count().max(1);
+
+                let gutter = " ".repeat(line.to_string().len());
+                let padding: String = source[line_start..offset].chars().map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+
+                output.push_str(&format!("{}--> {}:{}:{}\n", gutter, file.as_deref().unwrap_or("<unknown>"), line, column));
+                output.push_str(&format!("{} |\n", gutter));
+                output.push_str(&format!("{} | {}\n", line, line_text));
+                output.push_str(&format!("{} | {}{}\n", gutter, padding, "^".repeat(underline_length)));
+
+                for note in self.notes.iter() {
+                    output.push_str(&format!("{} = note: {}\n", gutter, note));
+                }
+            }
+            (file, None) => {
+                match (file, self.span) {
+                    (Some(file), Some(span)) => output.push_str(&format!(" --> {}:{}:{}\n", file, span.line, span.column)),
+                    (Some(file), None) => output.push_str(&format!(" --> {}\n", file)),
+                    _ => {}
+                }
+
+                for note in self.notes.iter() {
+                    output.push_str(&format!("  = note: {}\n", note));
+                }
+            }
+        }
+
+        output
+    }
+}
+
+// Shape of a diagnostic in the machine-readable output. Line and column are 1-based,
+// the byte range is relative to the start of the file and excludes leading whitespace
+#[derive(Serialize)]
+struct JsonDiagnostic<'a> {
+    file: Option<&'a str>,
+    line: Option<usize>,
+    column: Option<usize>,
+    byte_start: Option<usize>,
+    byte_end: Option<usize>,
+    severity: Severity,
+    code: &'a str,
+    message: &'a str,
+    notes: &'a [String],
+}
+
+impl Diagnostic {
+    // A single line of JSON, so a stream of diagnostics can be read line by line
+    pub fn to_json(&self, source: Option<&str>) -> String {
+        let location = source.and_then(|source| self.location(source));
+        let byte_start = source.and_then(|source| self.start_offset(source));
+
+        let (line, column) = match (location, self.span) {
+            (Some((line, column)), _) => (Some(line), Some(column)),
+            (None, Some(span)) => (Some(span.line as usize), Some(span.column as usize)),
+            (None, None) => (None, None),
+        };
+
+        let byte_start = byte_start.or(self.span.map(|span| span.start as usize));
+        let byte_end = self.span.map(|span| (span.end as usize).max(byte_start.unwrap_or(0)));
+
+        let json = JsonDiagnostic {
+            file: self.file.as_deref(),
+            line,
+            column,
+            byte_start,
+            byte_end,
+            severity: self.severity,
+            code: self.code,
+            message: &self.message,
+            notes: &self.notes,
+        };
+
+        serde_json::to_string(&json).unwrap()
+    }
+}
+
+// One line form: "file:line:column: error[E0301]: message"
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}:", file)?;
+
+            if let Some(span) = self.span {
+                write!(f, "{}:{}:", span.line, span.column)?;
+            }
+
+            write!(f, " ")?;
+        }
+
+        write!(f, "{}[{}]: {}", self.severity, self.code, self.message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lexer::token::Position;
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let source = "let foo = 42;\n\nprocess {\n    out = bar + 1;\n}\n";
+        let start = source.find(" bar").unwrap() as u32;
+
+        let diagnostic = Diagnostic::error(UNKNOWN_NAME, "Cannot find name \"bar\"".to_string())
+            .with_span(Position { start, end: start + 4, line: 4, column: 10 })
+            .with_file("main.mephisto")
+            .with_note("names must be declared before they are used".to_string());
+
+        assert_eq!(diagnostic.location(source), Some((4, 11)));
+        assert_eq!(diagnostic.render(Some(source)), concat!(
+            "error[E0301]: Cannot find name \"bar\"\n",
+            " --> main.mephisto:4:11\n",
+            "  |\n",
+            "4 |     out = bar + 1;\n",
+            "  |           ^^^\n",
+            "  = note: names must be declared before they are used\n",
+        ));
+    }
+
+    #[test]
+    fn test_render_without_source() {
+        let diagnostic = Diagnostic::error(MODULE_NOT_FOUND, "Cannot find module \"std:lib\"".to_string())
+            .with_file("main.mephisto");
+
+        assert_eq!(diagnostic.render(None), "error[E0101]: Cannot find module \"std:lib\"\n --> main.mephisto\n");
+        assert_eq!(diagnostic.to_string(), "main.mephisto: error[E0101]: Cannot find module \"std:lib\"");
+    }
+
+    #[test]
+    fn test_to_json() {
+        let source = "process {\n    out = bar;\n}";
+        let start = source.find(" bar").unwrap() as u32;
+
+        let diagnostic = Diagnostic::error(UNKNOWN_NAME, "Cannot find name \"bar\"".to_string())
+            .with_span(Position { start, end: start + 4, line: 2, column: 10 })
+            .with_file("main.mephisto");
+
+        assert_eq!(
+            diagnostic.to_json(Some(source)),
+            r#"{"file":"main.mephisto","line":2,"column":11,"byte_start":20,"byte_end":23,"severity":"error","code":"E0301","message":"Cannot find name \"bar\"","notes":[]}"#,
+        );
+
+        let diagnostic = Diagnostic::error(IMPORT_CYCLE, "Import cycle detected: a -> a".to_string());
+
+        assert_eq!(
+            diagnostic.to_json(None),
+            r#"{"file":null,"line":null,"column":null,"byte_start":null,"byte_end":null,"severity":"error","code":"E0102","message":"Import cycle detected: a -> a","notes":[]}"#,
+        );
+    }
+
+    #[test]
+    fn test_multiline_span_is_underlined_to_the_end_of_the_line() {
+        let source = "connect {\n    a -> b;\n}";
+
+        let diagnostic = Diagnostic::error(INVALID_CONNECTION, "Cannot connect".to_string())
+            .with_span(Position { start: 0, end: source.len() as u32, line: 1, column: 1 });
+
+        assert_eq!(diagnostic.render(Some(source)), concat!(
+            "error[E0309]: Cannot connect\n",
+            " --> <unknown>:1:1\n",
+            "  |\n",
+            "1 | connect {\n",
+            "  | ^^^^^^^^^\n",
+        ));
+    }
+}
@@ -0,0 +1,124 @@
+use crate::lexer::token::Token;
+use crate::parser::ast::VariableSpecifier;
+use crate::symbol_table::{SymbolInfo, SymbolOrigin, SymbolTable, SymbolVisibility};
+
+// Intermediate stages of the compilation that `mephisto --emit` can print. The formats are
+// meant to be diffed, so they list things in a fixed order and leave out generated ids
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stage {
+    Tokens,
+    Ast,
+    Symbols,
+    Ir,
+}
+
+// One token per line: byte range, type and literal
+pub fn tokens_to_string(tokens: &[Token]) -> String {
+    let mut output = String::new();
+
+    for token in tokens {
+        let location = format!("{}..{}", token.position.start, token.position.end);
+        let token_type = format!("{:?}", token.token_type);
+
+        output.push_str(&format!("{:<8} {:<12} {:?}\n", location, token_type, token.literal));
+    }
+
+    output
+}
+
+// Scopes in the order they were created, with their symbols sorted by name. Symbols of the
+// standard library are the same in every module and are left out
+pub fn symbol_table_to_string(symbol_table: &SymbolTable, display_key: &dyn Fn(&str) -> String) -> String {
+    let mut output = String::new();
+
+    for (index, scope) in symbol_table.scopes().iter().enumerate() {
+        match scope.parent() {
+            Some(parent) => output.push_str(&format!("scope {} (parent {})\n", index, parent)),
+            None => output.push_str(&format!("scope {}\n", index)),
+        }
+
+        let mut symbols: Vec<_> = scope.symbols().iter()
+            .filter(|(_, info)| !matches!(origin(info), Some(SymbolOrigin::StandardLibrary)))
+            .collect();
+
+        symbols.sort_by_key(|(name, _)| *name);
+
+        for (name, info) in symbols {
+            let position = *info.clone().position();
+
+            let mut description = vec![describe(info, display_key)];
+
+            if let SymbolInfo::Variable { visibility, .. } | SymbolInfo::Buffer { visibility, .. } | SymbolInfo::Function { visibility, .. } = info {
+                if *visibility == SymbolVisibility::Public {
+                    description.push("public".to_string());
+                }
+            }
+
+            if let Some(SymbolOrigin::ImportedModule { module }) = origin(info) {
+                description.push(format!("from \"{}\"", display_key(module)));
+            }
+
+            output.push_str(&format!("    {} {}..{} {}\n", name, position.start, position.end, description.join(", ")));
+        }
+    }
+
+    output
+}
+
+fn describe(info: &SymbolInfo, display_key: &dyn Fn(&str) -> String) -> String {
+    match info {
+        SymbolInfo::Variable { specifier, .. } => match specifier {
+            VariableSpecifier::Let => "let".to_string(),
+            VariableSpecifier::Const => "const".to_string(),
+            VariableSpecifier::Input => "input".to_string(),
+            VariableSpecifier::Output => "output".to_string(),
+            VariableSpecifier::Buffer => "buffer index".to_string(),
+        },
+        SymbolInfo::Buffer { .. } => "buffer".to_string(),
+        SymbolInfo::Parameter { .. } => "param".to_string(),
+        SymbolInfo::Function { parameters, returns_value, .. } => match returns_value {
+            true => format!("fn({})", parameters.join(", ")),
+            false => format!("fn({}), no return value", parameters.join(", ")),
+        },
+        SymbolInfo::FunctionArgument { .. } => "argument".to_string(),
+        SymbolInfo::ImportedModule { path, .. } => format!("module \"{}\"", display_key(path)),
+    }
+}
+
+fn origin(info: &SymbolInfo) -> Option<&SymbolOrigin> {
+    match info {
+        SymbolInfo::Variable { origin, .. }
+        | SymbolInfo::Buffer { origin, .. }
+        | SymbolInfo::Parameter { origin, .. }
+        | SymbolInfo::Function { origin, .. }
+        | SymbolInfo::FunctionArgument { origin, .. } => Some(origin),
+        SymbolInfo::ImportedModule { .. } => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::emit::{symbol_table_to_string, tokens_to_string};
+    use crate::Mephisto;
+
+    #[test]
+    fn test_tokens_to_string() {
+        let tokens = Mephisto::tokenize("let a = 1;".to_string());
+
+        assert_eq!(tokens_to_string(&tokens), "0..3     LET          \"let\"\n4..5     ID           \"a\"\n6..7     DEF          \"=\"\n8..9     NUMBER       \"1\"\n9..10    SEMI         \";\"\n10..10   EOF          \"\"\n");
+    }
+
+    #[test]
+    fn test_symbol_table_to_string() {
+        let mut ast = Mephisto::parse(Mephisto::tokenize("input x = 0;\nexport fn f(a) {\n    let b = a;\n    return b;\n}\n".to_string()));
+        let symbol_table = Mephisto::create_symbol_table(&mut ast).unwrap();
+
+        assert_eq!(symbol_table_to_string(&symbol_table, &|key| key.to_string()), "scope 0
+    f 23..24 fn(a), public
+    x 6..7 input, public
+scope 1 (parent 0)
+    a 25..26 argument
+    b 38..39 let, public
+");
+    }
+}
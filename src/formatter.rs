@@ -0,0 +1,285 @@
+use crate::diagnostic::{Diagnostic, INTERNAL_ERROR};
+use crate::lexer::Lexer;
+use crate::parser::Parser;
+
+const INDENT: &str = "    ";
+
+// Formats a module in the layout of `mephisto fmt`. The AST printer puts every statement and
+// comment on its own line, the lines are then indented and the connections aligned.
+// Source with syntax errors is not formatted
+pub fn format(source: &str) -> Result<String, Vec<Diagnostic>> {
+    let lexer = Lexer::new();
+    let mut parser = Parser::new();
+
+    let mut ast = parser.parse_lossless(lexer.tokenize_lossless(source.to_string()));
+
+    if !ast.errors.is_empty() {
+        return Err(ast.errors);
+    }
+
+    let formatted = layout(&ast.to_canonical_code_string());
+
+    // The fully parenthesized printout shows whether the formatted code still means the same
+    let before = parser.parse(lexer.tokenize(source.to_string())).to_code_string();
+    let after = parser.parse(lexer.tokenize(formatted.clone()));
+
+    if !after.errors.is_empty() || after.clone().to_code_string() != before {
+        return Err(vec![Diagnostic::error(INTERNAL_ERROR, "Internal compiler error: formatting changed the meaning of the code".to_string())]);
+    }
+
+    Ok(formatted)
+}
+
+pub(crate) fn layout(code: &str) -> String {
+    let mut lines: Vec<String> = vec![];
+
+    let mut depth: usize = 0;
+    let mut in_block_comment = false;
+
+    // Depth of the connect section being laid out and the connection lines in it
+    let mut connect_section: Option<usize> = None;
+    let mut connections: Vec<usize> = vec![];
+
+    for line in code.lines() {
+        // The inside of a block comment is kept as it was written
+        if in_block_comment {
+            in_block_comment = !line.contains("*/");
+            lines.push(line.trim_end().to_string());
+            continue;
+        }
+
+        let line = line.trim();
+
+        if line.is_empty() {
+            let follows_blank_or_opening = lines.last().is_none_or(|last| last.is_empty() || last.ends_with('{'));
+
+            if !follows_blank_or_opening {
+                lines.push(String::new());
+            }
+
+            continue;
+        }
+
+        let braces = count_braces(line);
+        in_block_comment = braces.opens_block_comment;
+
+        if line.starts_with('}') && lines.last().is_some_and(|last| last.is_empty()) {
+            lines.pop();
+        }
+
+        let line_depth = depth.saturating_sub(braces.leading_closing);
+        lines.push(format!("{}{}", INDENT.repeat(line_depth), line));
+
+        if connect_section.is_some_and(|section| line_depth > section) && line.contains(" -> ") && !line.starts_with("//") && !line.starts_with("/*") {
+            connections.push(lines.len() - 1);
+        }
+
+        if line == "connect {" {
+            connect_section = Some(line_depth);
+        }
+
+        depth = (depth as i32 + braces.change).max(0) as usize;
+
+        if connect_section.is_some_and(|section| depth <= section) {
+            connect_section = None;
+            align_connections(&mut lines, &connections);
+            connections.clear();
+        }
+    }
+
+    while lines.last().is_some_and(|last| last.is_empty()) {
+        lines.pop();
+    }
+
+    let mut formatted = lines.join("\n");
+    formatted.push('\n');
+
+    formatted
+}
+
+// Pads the sources of the connections so that all the "->" line up
+fn align_connections(lines: &mut [String], connections: &[usize]) {
+    let width = connections.iter()
+        .filter_map(|&index| lines[index].find(" -> "))
+        .max()
+        .unwrap_or(0);
+
+    for &index in connections {
+        if let Some(arrow) = lines[index].find(" -> ") {
+            let padding = " ".repeat(width - arrow);
+            lines[index].insert_str(arrow, &padding);
+        }
+    }
+}
+
+struct Braces {
+    // "}" and ")" the line starts with, they close blocks opened on earlier lines
+    leading_closing: usize,
+    change: i32,
+    opens_block_comment: bool,
+}
+
+fn count_braces(line: &str) -> Braces {
+    let mut braces = Braces { leading_closing: 0, change: 0, opens_block_comment: false };
+
+    let mut in_string = false;
+    let mut at_start = true;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if in_string {
+            match c {
+                '\\' => { chars.next(); }
+                '"' => in_string = false,
+                _ => {}
+            }
+
+            continue;
+        }
+
+        match c {
+            '"' => in_string = true,
+            '/' if chars.peek() == Some(&'/') => break,
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+
+                let mut closed = false;
+                let mut previous = ' ';
+
+                for c in chars.by_ref() {
+                    if previous == '*' && c == '/' {
+                        closed = true;
+                        break;
+                    }
+
+                    previous = c;
+                }
+
+                braces.opens_block_comment = !closed;
+            }
+            // Only a comment splits the parentheses over lines, the lines in them are indented like a block
+            '{' | '(' => braces.change += 1,
+            '}' | ')' => {
+                braces.change -= 1;
+
+                if at_start {
+                    braces.leading_closing += 1;
+                }
+            }
+            _ => {}
+        }
+
+        at_start = at_start && (c == '}' || c == ')');
+    }
+
+    braces
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::formatter::format;
+
+    #[test]
+    fn test_format() {
+        let source = "import Lib from \"std:lib\";
+param   cutoff { initial: 1000; min: 20.0;
+  max: 20000; };
+
+let a = 1;   // gain
+buffer b[4];
+buffer c[2] = |i| { return i * 2; };
+
+
+process {
+  // stage one
+  let x = (a + 2) * (3 + 4) - -1e-3;
+        if (x > 1) { x = 1; }
+        else { x = Lib.clamp(x, 0, 1); }
+    a = x + -x ^ 2;
+}
+
+connect {
+    a -> OUTPUTS;
+  Lib.value -> OUTPUTS[1];
+}
+";
+
+        let expected = "import Lib from \"std:lib\";
+param cutoff {
+    initial: 1000;
+    min: 20.0;
+    max: 20000;
+};
+
+let a = 1; // gain
+buffer b[4];
+buffer c[2] = |i| {
+    return i * 2;
+};
+
+process {
+    // stage one
+    let x = (a + 2) * (3 + 4) - -1e-3;
+    if (x > 1) {
+        x = 1;
+    } else {
+        x = Lib.clamp(x, 0, 1);
+    }
+    a = x + -(x ^ 2);
+}
+
+connect {
+    a         -> OUTPUTS;
+    Lib.value -> OUTPUTS[1];
+}
+";
+
+        assert_eq!(format(source).unwrap(), expected);
+        assert_eq!(format(expected).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_format_keeps_grouping_parentheses() {
+        let source = "let a = (1 - (2 - 3)) * -(b + c);\nlet d = (e ? 1 : 2) + (2 ^ 3) ^ 4;\nlet f = (-g) + h;\nlet i = (-g) ^ 2 + -(g % 2);\n";
+
+        // A sign only takes the power after it, so only "(-g) ^ 2" and "-(g % 2)" need the parentheses
+        assert_eq!(format(source).unwrap(), "let a = (1 - (2 - 3)) * -(b + c);\nlet d = (e ? 1 : 2) + (2 ^ 3) ^ 4;\nlet f = -g + h;\nlet i = (-g) ^ 2 + -(g % 2);\n");
+    }
+
+    #[test]
+    fn test_format_keeps_parameter_field_comments() {
+        let source = "param cutoff {\n  // about min\n  min: 0;   // zero\n  /* top */ max: 1;\n};\n";
+        let expected = "param cutoff {\n    // about min\n    min: 0; // zero\n    /* top */ max: 1;\n};\n";
+
+        assert_eq!(format(source).unwrap(), expected);
+        assert_eq!(format(expected).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_format_keeps_function_parameter_comments() {
+        let source = "fn mix(\n// first\na, b /* second */) {\nreturn a + b;\n}\n";
+        let expected = "fn mix(\n    // first\n    a, b /* second */) {\n    return a + b;\n}\n";
+
+        assert_eq!(format(source).unwrap(), expected);
+        assert_eq!(format(expected).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_format_keeps_expression_comments() {
+        let source = "let a = b /* inner */ + /* rhs */ c * 2;\nlet d = f(1, /* arg */ 2);\n";
+
+        assert_eq!(format(source).unwrap(), source);
+    }
+
+    #[test]
+    fn test_format_keeps_compound_assignments() {
+        let source = "process {\n    out += i;\n    out *= 2 + i;\n    out -= 1;\n    out /= 4;\n}\n";
+
+        assert_eq!(format(source).unwrap(), source);
+    }
+
+    #[test]
+    fn test_format_rejects_syntax_errors() {
+        assert!(format("let a = ;").is_err());
+    }
+}
@@ -1,11 +1,17 @@
+pub mod fold;
+pub mod inline;
+
 use std::collections::{HashMap, HashSet};
+use std::fmt;
 
 use indexmap::IndexMap;
+use uuid::Uuid;
 
+use crate::diagnostic::{internal_error, unexpected_in_ir, Diagnostic, INTERNAL_ERROR};
 use crate::lexer::token::Position;
 use crate::module_data::ModuleData;
-use crate::parser::ast::{AST, ASTTraverseStage, Node, traverse_ast, VariableSpecifier};
-use crate::symbol_table::{SymbolInfo, SymbolTable};
+use crate::parser::ast::{AST, ASTTraverseStage, Node, Operator, operator_symbol, traverse_ast, Unit, VariableSpecifier};
+use crate::symbol_table::{SymbolInfo, SymbolOrigin, SymbolTable};
 
 /*
 TODO: There are a few things that need to be done here:
@@ -14,16 +20,109 @@ TODO: There are a few things that need to be done here:
  */
 
 pub struct IR {
-    pub errors: Vec<String>,
+    pub errors: Vec<Diagnostic>,
 }
 
 #[derive(Debug)]
 pub struct IRResult {
+    // The merged and hoisted module the program was lowered from
     pub ast: AST,
     pub symbol_table: SymbolTable,
-    pub input_names: Vec<String>,
-    pub output_names: Vec<String>,
-    pub errors: Vec<String>,
+    pub program: Program,
+    pub errors: Vec<Diagnostic>,
+}
+
+// What the code generators consume. Every identifier of the source is resolved here, so the
+// backends never have to work out what a name refers to
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    // Names the host uses for the input and output slots, in slot order
+    pub inputs: Vec<String>,
+    pub outputs: Vec<String>,
+    pub declarations: Vec<Declaration>,
+    pub block: Option<Vec<Statement>>,
+    pub process: Vec<Statement>,
+    pub connections: Vec<Connection>,
+}
+
+// A declared name. Declarations merged from an imported module carry the path of import names
+// they came through, and hoisted declarations that had to be renamed carry a version
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Name {
+    pub modules: Vec<String>,
+    pub name: String,
+    pub version: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Declaration {
+    Variable { name: Name, constant: bool, initializer: Expression },
+    Input { slot: usize, initializer: Expression },
+    Output { slot: usize, initializer: Expression },
+    Parameter { name: Name, fields: Vec<(String, Expression)> },
+    // Buffers without an initializer are filled with zeros
+    Buffer { name: Name, size: Expression, initializer: Option<Vec<Statement>> },
+    // Calls of @noinline functions are never replaced with their bodies
+    Function { name: Name, parameters: Vec<Name>, body: Vec<Statement>, noinline: bool },
+    // Statements outside of the sections run once, when the program is loaded
+    Statement(Statement),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Statement {
+    Let { name: Name, constant: bool, initializer: Expression },
+    Assign { target: Reference, value: Expression },
+    Expression(Expression),
+    Return(Expression),
+    If { test: Expression, consequent: Box<Statement>, alternate: Option<Box<Statement>> },
+    For { variable: Name, from: Expression, to: Expression, body: Box<Statement> },
+    Block(Vec<Statement>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expression {
+    Number(f64),
+    Reference(Reference),
+    Unary { op: Operator, operand: Box<Expression> },
+    Binary { op: Operator, lhs: Box<Expression>, rhs: Box<Expression> },
+    Conditional { test: Box<Expression>, consequent: Box<Expression>, alternate: Box<Expression> },
+    Call { callee: Reference, args: Vec<Expression> },
+    // Whether something is connected to the slot at runtime
+    Connected(Slot),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reference {
+    // Function arguments, variables declared inside functions, loop variables and the index of a buffer initializer
+    Local(Name),
+    // Top level variables and buffers, including the hoisted ones
+    State(Name),
+    Parameter(Name),
+    Function(Name),
+    Input(usize),
+    Output(usize),
+    Std(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Slot {
+    Input(usize),
+    Output(usize),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Connection {
+    pub source: Reference,
+    pub destination: Destination,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Destination {
+    Reference(Reference),
+    // OUTPUTS, every channel of the host's output
+    Outputs,
+    // OUTPUTS[n]
+    Channel(usize),
 }
 
 struct HoistingContext {
@@ -32,6 +131,7 @@ struct HoistingContext {
     process_scope_index: Option<usize>,
 
     rename_symbols: bool,
+    errors: Vec<Diagnostic>,
 }
 
 impl HoistingContext {
@@ -54,282 +154,55 @@ impl IR {
     }
 
     // TODO: Rewrite so modules are not mutated
-    pub fn create(&mut self, modules: &mut IndexMap<String, ModuleData>, main_module: String) -> Result<IRResult, Vec<String>> {
+    pub fn create(&mut self, modules: &mut IndexMap<String, ModuleData>, main_module: String) -> Result<IRResult, Vec<Diagnostic>> {
         // First pass should go through all modules and hoist all declarations from block and process nodes
         // Hoisting means that all declarations are moved to the top of the module and initialized with 0
         // Second pass should merge all modules into one
-        // Third pass should inline all functions (skip this for now)
-        // Fourth pass should rename all inputs, outputs, and params to array accesses
+        // Third pass should resolve every identifier and build the program the backends consume
+        // Inlining and constant folding work on that program, see inline.rs and fold.rs
+        // Unit literals are lowered before everything else, so SR is resolved like any other stdlib symbol
 
+        Self::lower_unit_literals(modules);
         Self::hoist(modules);
 
         let mut processed_modules = HashSet::new();
 
+        // Each pass relies on the previous one having succeeded, so the first failing pass stops the pipeline
         let merged_module = Self::merge_modules(modules, &main_module, &mut processed_modules);
-        let mut with_replaced_module_calls = Self::replace_module_calls(&merged_module.ast.root, &merged_module.symbol_table);
-        let mut with_replaced_stdlib_calls = Self::replace_stdlib_calls(&with_replaced_module_calls.ast.root, &mut with_replaced_module_calls.symbol_table);
-        let (with_replaced_connects, input_names, output_names) = Self::replace_connects(&with_replaced_stdlib_calls.ast, &mut with_replaced_stdlib_calls.symbol_table);
-
-        let main_module_data = with_replaced_connects;
-
-        Ok(IRResult {
-            ast: main_module_data.ast.clone(),
-            symbol_table: main_module_data.symbol_table.clone(),
-            input_names,
-            output_names,
-            errors: vec![],
-        })
-    }
-
-    fn replace_connects(ast: &AST, symbol_table: &mut SymbolTable) -> (ModuleData, Vec<String>, Vec<String>) {
-        let mut result = ast.clone();
-        symbol_table.reset_scopes_indexes();
-
-        // 1st pass — collect all input and output symbols
-        // 2nd pass — rename all input and output symbols
-
-        let mut context = ();
-
-        let mut input_symbols: Vec<(String, SymbolInfo)> = vec![];
-        let mut output_symbols: Vec<(String, SymbolInfo)> = vec![];
-
-        let skip_renaming_identifiers = false;
-        let mut skip_renaming_once = false;
-        let mut rename_as_connectindex = false;
-
-        let input_names = result.inputs();
-        let output_names = result.outputs();
-
-        for name in input_names {
-            let symbol = symbol_table.lookup(&name);
-            if symbol.is_some() {
-                let symbol = symbol.unwrap();
-
-                if symbol.is_input() {
-                    input_symbols.push((name.clone(), symbol.clone()));
-                }
-            }
+        if !merged_module.errors.is_empty() {
+            return Err(merged_module.errors);
         }
 
-        for name in output_names {
-            let symbol = symbol_table.lookup(&name);
-            if symbol.is_some() {
-                let symbol = symbol.unwrap();
-
-                if symbol.is_output() && !symbol.is_parameter() {
-                    output_symbols.push((name.clone(), symbol.clone()));
-                }
-            }
+        let with_replaced_module_calls = Self::replace_module_calls(&merged_module.ast.root, &merged_module.symbol_table);
+        if !with_replaced_module_calls.errors.is_empty() {
+            return Err(with_replaced_module_calls.errors);
         }
 
-        symbol_table.reset_scopes_indexes();
-
-        traverse_ast(&mut result.root, &mut |stage, node, _context: &mut ()| {
-            match node {
-                | Node::BlockSection { .. }
-                | Node::BufferInitializer { .. }
-                | Node::FunctionBody { .. }
-                | Node::ProcessSection { .. }
-                | Node::BlockStmt { .. }
-                => {
-                    match stage {
-                        ASTTraverseStage::Enter => {
-                            symbol_table.enter_next_scope();
-                        }
-                        ASTTraverseStage::Exit => {
-                            symbol_table.exit_scope();
-                        }
-                    }
-                }
-
-                Node::ParameterDeclarationField {
-                    ..
-                } => {
-                    match stage {
-                        ASTTraverseStage::Enter => {
-                            skip_renaming_once = true;
-                        }
-                        ASTTraverseStage::Exit => {
-                            skip_renaming_once = false;
-                        }
-                    }
-                }
-
-                Node::ConnectedExpr {
-                    ..
-                } => {
-                    match stage {
-                        ASTTraverseStage::Enter => {
-                            rename_as_connectindex = true;
-                        }
-                        ASTTraverseStage::Exit => {
-                            rename_as_connectindex = false;
-                        }
-                    }
-                }
-
-                Node::Identifier { name, .. } => {
-                    if skip_renaming_identifiers {
-                        return false;
-                    }
-
-                    if skip_renaming_once {
-                        skip_renaming_once = false;
-                        return false;
-                    }
-                    match stage {
-                        ASTTraverseStage::Enter => {
-                            let symbol = symbol_table.lookup(name);
-
-                            if symbol.is_some() {
-                                let symbol = symbol.unwrap();
-
-                                input_symbols.iter().enumerate().for_each(|(i, (_, si))| {
-                                    if si.id() == symbol.id() {
-                                        if rename_as_connectindex {
-                                            *name = format!("##INPUTINDEX[{}]", i);
-                                        } else {
-                                            *name = format!("##INPUT_[{}]", i);
-                                        }
-                                    }
-                                });
-
-                                output_symbols.iter().enumerate().for_each(|(i, (_, si))| {
-                                    if si.id() == symbol.id() {
-                                        if rename_as_connectindex {
-                                            *name = format!("##OUTPUTINDEX[{}]", i);
-                                        } else {
-                                            *name = format!("##OUTPUT_[{}]", i);
-                                        }
-                                    }
-                                });
-                            }
-                        }
-                        _ => {}
-                    }
-                }
-                _ => {}
-            }
-            false
-        }, &mut context);
-
-        let input_symbol_names: Vec<String> = input_symbols.iter().map(|(name, _)| name.clone()).collect();
-        let output_symbol_names: Vec<String> = output_symbols.iter().map(|(name, _)| name.clone()).collect();
-
-        (
-            ModuleData {
-                ast: AST::new(result.root.clone(), vec![]),
-                symbol_table: SymbolTable::from_ast(&mut AST::new(result.root.clone(), vec![])).unwrap(), // TODO: This is cheating, make it better
-                errors: vec![],
-            },
-            input_symbol_names,
-            output_symbol_names,
-        )
-    }
-
-    fn replace_stdlib_calls(ast: &Node, symbol_table: &mut SymbolTable) -> ModuleData {
-        let mut result = ast.clone();
-        let skip_renaming_identifiers = false;
-        let mut skip_renaming_once = false;
-
-        let symbols_to_rename = Self::collect_stdlib_symbols(&result, symbol_table);
-        symbol_table.reset_scopes_indexes();
-
-        let mut context = ();
-
-        traverse_ast(&mut result, &mut |stage, node, _context: &mut ()| {
-            match node {
-                | Node::BlockSection { .. }
-                | Node::BufferInitializer { .. }
-                | Node::FunctionBody { .. }
-                | Node::ProcessSection { .. }
-                | Node::BlockStmt { .. }
-                => {
-                    match stage {
-                        ASTTraverseStage::Enter => {
-                            symbol_table.enter_next_scope();
-                        }
-                        ASTTraverseStage::Exit => {
-                            symbol_table.exit_scope();
-                        }
-                    }
-                }
-
-                Node::ParameterDeclarationField {
-                    ..
-                } => {
-                    match stage {
-                        ASTTraverseStage::Enter => {
-                            skip_renaming_once = true;
-                        }
-                        ASTTraverseStage::Exit => {
-                            skip_renaming_once = false;
-                        }
-                    }
-                }
-
-                Node::Identifier { name, .. } => {
-                    if skip_renaming_identifiers {
-                        return false;
-                    }
-
-                    if skip_renaming_once {
-                        skip_renaming_once = false;
-                        return false;
-                    }
-
-                    match stage {
-                        ASTTraverseStage::Enter => {
-                            let symbol = symbol_table.lookup(name);
-
-                            // println!("Renaming symbol {}", name);
-
-                            if symbol.is_some() {
-                                let symbol = symbol.unwrap();
-
-                                symbols_to_rename.iter().for_each(|(new_name, si)| {
-                                    if si.id() == symbol.id() {
-                                        *name = format!("##STD_{}", new_name);
-                                    }
-                                });
-                            }
-                        }
-                        _ => {}
-                    }
-                }
-                _ => {}
-            }
-            false
-        }, &mut context);
+        let program = ProgramBuilder::build(&with_replaced_module_calls.ast, with_replaced_module_calls.symbol_table.clone())?;
 
-        ModuleData {
-            ast: AST::new(result.clone(), vec![]),
-            symbol_table: SymbolTable::from_ast(&mut AST::new(result.clone(), vec![])).unwrap(), // TODO: This is cheating, make it better
+        Ok(IRResult {
+            ast: with_replaced_module_calls.ast,
+            symbol_table: with_replaced_module_calls.symbol_table,
+            program,
             errors: vec![],
-        }
-    }
-
-    fn collect_stdlib_symbols(_ast: &Node, symbol_table: &SymbolTable) -> Vec<(String, SymbolInfo)> {
-        symbol_table.get_stdlib_symbols()
+        })
     }
 
     fn replace_module_calls(ast: &Node, _symbol_table: &SymbolTable) -> ModuleData {
         let mut result = ast.clone();
-        let mut context = ();
+        let mut errors: Vec<Diagnostic> = vec![];
 
-        traverse_ast(&mut result, &mut |stage, node, _| {
+        traverse_ast(&mut result, &mut |stage, node, errors: &mut Vec<Diagnostic>| {
             match node {
                 Node::MemberExpr { object, property, .. } => {
                     match stage {
                         ASTTraverseStage::Enter => {
-                            let object_name = match object.as_ref() {
-                                Node::Identifier { name, .. } => name,
-                                _ => panic!("Expected identifier"),
-                            };
-
-                            let property_name = match property.as_ref() {
-                                Node::Identifier { name, .. } => name,
-                                _ => panic!("Expected identifier"),
+                            let (object_name, property_name) = match (object.as_ref(), property.as_ref()) {
+                                (Node::Identifier { name: object_name, .. }, Node::Identifier { name: property_name, .. }) => (object_name, property_name),
+                                (Node::Identifier { .. }, node) | (node, _) => {
+                                    errors.push(internal_error(node, "an identifier"));
+                                    return false;
+                                }
                             };
 
                             // Replace the module call with the module's symbol
@@ -344,12 +217,14 @@ impl IR {
                 _ => {}
             }
             false
-        }, &mut context);
+        }, &mut errors);
+
+        let symbol_table = rebuild_symbol_table(&result, &mut errors); // TODO: This is cheating, make it better
 
         ModuleData {
             ast: AST::new(result.clone(), vec![]),
-            symbol_table: SymbolTable::from_ast(&mut AST::new(result.clone(), vec![])).unwrap(), // TODO: This is cheating, make it better
-            errors: vec![],
+            symbol_table,
+            errors,
         }
     }
 
@@ -366,29 +241,51 @@ impl IR {
 
         // If the module has already been processed, just return it
         if processed_modules.contains(module_name) {
-            return modules.get(module_name).expect("Processed module not found").clone();
+            if let Some(module) = modules.get(module_name) {
+                return module.clone();
+            }
         }
 
-        let module = modules.remove(module_name).expect("Module not found");
+        let module = match modules.remove(module_name) {
+            Some(module) => module,
+            None => {
+                result.errors.push(Diagnostic::error(INTERNAL_ERROR, format!("Internal compiler error: module \"{}\" is not loaded", module_name)));
+                return result;
+            }
+        };
+
         result.symbol_table = module.symbol_table.clone();
+        result.errors.extend(module.errors.iter().cloned());
 
-        if let Node::ProgramNode { children, .. } = &module.ast.root {
+        // Calls to functions imported by name are renamed the same way as the merged declarations
+        let (root, referenced_named_imports, rename_errors) = Self::rename_named_imports(&module.ast.root);
+        result.errors.extend(rename_errors);
+        let mut merged_named_imports: HashSet<String> = HashSet::new();
+
+        if let Node::ProgramNode { children, .. } = &root {
             if let Node::ProgramNode { children: result_children, .. } = &mut result.ast.root {
                 for node in children.iter() {
                     match node {
                         Node::ImportStatement { id, path, .. } => {
                             // Recursively merge the imported module
+                            let id = match id.as_ref() {
+                                Node::Identifier { name, .. } => name,
+                                node => {
+                                    result.errors.push(internal_error(node, "an identifier"));
+                                    continue;
+                                }
+                            };
+
                             let imported_module = Self::merge_modules(modules, &path, processed_modules);
                             let mut module = Self::replace_module_calls(&imported_module.ast.root, &imported_module.symbol_table);
 
                             // println!("{}", module.ast.to_code_string());
 
-                            let id = match id.as_ref() {
-                                Node::Identifier { name, .. } => name,
-                                _ => panic!("Expected identifier"),
-                            };
+                            let (renamed_node, errors) = Self::rename_symbols(&module.ast.root, id, &mut module.symbol_table);
 
-                            let renamed_node = Self::rename_symbols(&module.ast.root, id, &mut module.symbol_table);
+                            result.errors.extend(imported_module.errors);
+                            result.errors.extend(module.errors);
+                            result.errors.extend(errors);
 
                             if let Node::ProgramNode { children: renamed_children, .. } = &renamed_node {
                                 for renamed_node in renamed_children.iter() {
@@ -410,6 +307,33 @@ impl IR {
                             }
                         }
 
+                        Node::NamedImportStatement { path, .. } => {
+                            let imported_module = Self::merge_modules(modules, path, processed_modules);
+                            let mut module = Self::replace_module_calls(&imported_module.ast.root, &imported_module.symbol_table);
+
+                            let module_id = named_import_module_id(path);
+                            let (renamed_node, errors) = Self::rename_symbols(&module.ast.root, &module_id, &mut module.symbol_table);
+
+                            result.errors.extend(imported_module.errors);
+                            result.errors.extend(module.errors);
+                            result.errors.extend(errors);
+
+                            // Only the functions that are actually called are merged, together with the declarations they depend on.
+                            // Process, block and connect sections of the imported module are not
+                            let roots: Vec<String> = referenced_named_imports.iter()
+                                .filter(|(_, module_path)| module_path == path)
+                                .map(|(name, _)| format!("{}#{}", module_id, name))
+                                .collect();
+
+                            if let Node::ProgramNode { children: renamed_children, .. } = &renamed_node {
+                                for (name, declaration) in select_declarations(renamed_children, roots) {
+                                    if merged_named_imports.insert(name) {
+                                        result_children.push(declaration);
+                                    }
+                                }
+                            }
+                        }
+
                         _ => {
                             result_children.push(node.clone());
                         }
@@ -453,7 +377,7 @@ impl IR {
         // Re-insert the merged module into the modules map
         modules.insert(module_name.to_string(), result.clone());
 
-        result.symbol_table = SymbolTable::from_ast(&mut result.ast).unwrap(); // TODO: This is cheating, make it better
+        result.symbol_table = rebuild_symbol_table(&result.ast.root, &mut result.errors); // TODO: This is cheating, make it better
 
         result
 
@@ -463,7 +387,61 @@ impl IR {
         // module
     }
 
-    fn rename_symbols(node: &Node, module_id: &str, symbol_table: &mut SymbolTable) -> Node {
+    // Returns the renamed node and the (name, module path) pairs of the named imports that are referenced
+    fn rename_named_imports(node: &Node) -> (Node, HashSet<(String, String)>, Vec<Diagnostic>) {
+        let mut result = node.clone();
+        let mut referenced = HashSet::new();
+        let mut errors = vec![];
+
+        let mut symbol_table = rebuild_symbol_table(&result, &mut errors); // TODO: This is cheating, make it better
+        symbol_table.reset_scopes_indexes();
+
+        traverse_ast(&mut result, &mut |stage, node, referenced: &mut HashSet<(String, String)>| {
+            match node {
+                | Node::BlockSection { .. }
+                | Node::BufferInitializer { .. }
+                | Node::ForStmt { .. }
+                | Node::FunctionBody { .. }
+                | Node::ProcessSection { .. }
+                | Node::BlockStmt { .. }
+                => {
+                    match stage {
+                        ASTTraverseStage::Enter => {
+                            if let Err(error) = symbol_table.enter_next_scope() {
+                                errors.push(error.at_node(node));
+                            }
+                        }
+                        ASTTraverseStage::Exit => {
+                            if let Err(error) = symbol_table.exit_scope() {
+                                errors.push(error.at_node(node));
+                            }
+                        }
+                    }
+                }
+
+                // Parameters may shadow imported names, and the import itself is not a reference
+                Node::FunctionParameter { .. } | Node::NamedImportStatement { .. } => {
+                    return true;
+                }
+
+                Node::Identifier { name, .. } => {
+                    if let ASTTraverseStage::Enter = stage {
+                        if let Some(SymbolInfo::Function { origin: SymbolOrigin::ImportedModule { module }, .. }) = symbol_table.lookup(name) {
+                            referenced.insert((name.clone(), module.clone()));
+                            *name = format!("{}#{}", named_import_module_id(module), name);
+                        }
+                    }
+                }
+                _ => {}
+            }
+
+            false
+        }, &mut referenced);
+
+        (result, referenced, errors)
+    }
+
+    fn rename_symbols(node: &Node, module_id: &str, symbol_table: &mut SymbolTable) -> (Node, Vec<Diagnostic>) {
         let mut renamed_node = node.clone();
         let mut context = HoistingContext {
             name_counts: HashMap::new(),
@@ -471,6 +449,7 @@ impl IR {
             process_scope_index: None,
 
             rename_symbols: false,
+            errors: vec![],
         };
 
         let symbols_to_rename = collect_symbols_for_rename(&mut renamed_node, &mut context);
@@ -486,16 +465,21 @@ impl IR {
             match node {
                 | Node::BlockSection { .. }
                 | Node::BufferInitializer { .. }
+                | Node::ForStmt { .. }
                 | Node::FunctionBody { .. }
                 | Node::ProcessSection { .. }
                 | Node::BlockStmt { .. }
                 => {
                     match stage {
                         ASTTraverseStage::Enter => {
-                            context.symbol_table.enter_next_scope();
+                            if let Err(error) = context.symbol_table.enter_next_scope() {
+                                context.errors.push(error.at_node(node));
+                            }
                         }
                         ASTTraverseStage::Exit => {
-                            context.symbol_table.exit_scope();
+                            if let Err(error) = context.symbol_table.exit_scope() {
+                                context.errors.push(error.at_node(node));
+                            }
                         }
                     }
                 }
@@ -563,7 +547,19 @@ impl IR {
             false
         }, &mut context);
 
-        renamed_node
+        (renamed_node, context.errors)
+    }
+
+    fn lower_unit_literals(modules: &mut IndexMap<String, ModuleData>) {
+        modules.iter_mut().for_each(|(_, module)| {
+            traverse_ast(&mut module.ast.root, &mut |stage, node, _context: &mut ()| {
+                if let (ASTTraverseStage::Enter, Node::UnitNumber { value, unit, position }) = (stage, &*node) {
+                    *node = lower_unit_literal(*value, unit, *position);
+                }
+
+                false
+            }, &mut ());
+        });
     }
 
     fn hoist(modules: &mut IndexMap<String, ModuleData>) {
@@ -574,9 +570,10 @@ impl IR {
                 process_scope_index: None,
 
                 rename_symbols: false,
+                errors: vec![],
             };
 
-            context.process_scope_index = find_process_scope(&module.ast.root, &mut module.symbol_table);
+            context.process_scope_index = find_process_scope(&module.ast.root, &mut module.symbol_table, &mut context.errors);
             if context.process_scope_index.is_none() {
                 return;
             }
@@ -650,6 +647,7 @@ impl IR {
 
             module.symbol_table = context.symbol_table;
             module.symbol_table.move_variables_to_global_scope(context.process_scope_index.unwrap());
+            module.errors.extend(context.errors);
 
             module.ast.root = Node::ProgramNode {
                 children: hoisted_nodes,
@@ -659,10 +657,108 @@ impl IR {
     }
 }
 
-fn find_process_scope(ast: &Node, symbol_table: &mut SymbolTable) -> Option<usize> {
-    symbol_table.reset_scopes_indexes();
+// Named imports have no module name to prefix the merged symbols with, so one is derived from the path:
+// the file name keeps the generated code readable and a hash of the whole path keeps it unique
+fn named_import_module_id(path: &str) -> String {
+    let file_name = std::path::Path::new(path).file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default();
+    let file_name: String = file_name.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
 
-    let mut context: (&mut SymbolTable, Option<usize>) = (
+    // FNV-1a, stable across runs and platforms
+    let hash = path.bytes().fold(0x811c9dc5u32, |hash, byte| (hash ^ byte as u32).wrapping_mul(0x01000193));
+
+    format!("{}_{:08x}", file_name, hash)
+}
+
+// Picks the top level declarations named in "roots" and, transitively, every top level declaration they reference.
+// Export wrappers are dropped, so the declarations are private to the importing module
+fn select_declarations(children: &[Node], roots: Vec<String>) -> Vec<(String, Node)> {
+    let declarations: Vec<(String, &Node)> = children.iter().filter_map(|node| {
+        let declaration = match node {
+            Node::ExportDeclarationStmt { declaration, .. } => declaration.as_ref(),
+            _ => node,
+        };
+
+        declaration_name(declaration).map(|name| (name, declaration))
+    }).collect();
+
+    let mut selected: HashSet<String> = HashSet::new();
+    let mut pending = roots;
+
+    while let Some(name) = pending.pop() {
+        if selected.contains(&name) {
+            continue;
+        }
+
+        let declaration = match declarations.iter().find(|(declaration_name, _)| *declaration_name == name) {
+            Some((_, declaration)) => declaration,
+            None => continue,
+        };
+
+        selected.insert(name);
+
+        traverse_ast(&mut (*declaration).clone(), &mut |stage, node, pending: &mut Vec<String>| {
+            if let (ASTTraverseStage::Enter, Node::Identifier { name, .. }) = (stage, node) {
+                pending.push(name.clone());
+            }
+
+            false
+        }, &mut pending);
+    }
+
+    declarations.into_iter()
+        .filter(|(name, _)| selected.contains(name))
+        .map(|(name, declaration)| (name, declaration.clone()))
+        .collect()
+}
+
+fn declaration_name(node: &Node) -> Option<String> {
+    match node {
+        | Node::FunctionDeclarationStmt { id, .. }
+        | Node::VariableDeclarationStmt { id, .. }
+        | Node::BufferDeclarationStmt { id, .. }
+        => {
+            match id.as_ref() {
+                Node::Identifier { name, .. } => Some(name.clone()),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+// Time and frequency literals become expressions over SR (10ms is 0.01 * SR samples, 440hz is 440 / SR
+// cycles per sample), decibels become a linear gain
+fn lower_unit_literal(value: f64, unit: &Unit, position: Position) -> Node {
+    let sample_rate = Node::Identifier { name: "SR".to_string(), position };
+
+    let (op, value) = match unit {
+        Unit::Milliseconds => (Operator::Mul, value / 1000.0),
+        Unit::Seconds => (Operator::Mul, value),
+        Unit::Hertz => (Operator::Div, value),
+        Unit::Decibels => return Node::Number { value: 10f64.powf(value / 20.0), position },
+        Unit::Samples => return Node::Number { value, position },
+    };
+
+    Node::BinaryExpr {
+        op,
+        lhs: Box::new(Node::Number { value, position }),
+        rhs: Box::new(sample_rate),
+        position,
+    }
+}
+
+// The passes rebuild the symbol table after rewriting the tree, failing to do so is a compiler bug
+fn rebuild_symbol_table(root: &Node, errors: &mut Vec<Diagnostic>) -> SymbolTable {
+    SymbolTable::from_ast(&mut AST::new(root.clone(), vec![])).unwrap_or_else(|rebuild_errors| {
+        errors.extend(rebuild_errors);
+        SymbolTable::new()
+    })
+}
+
+fn find_process_scope(ast: &Node, symbol_table: &mut SymbolTable, errors: &mut Vec<Diagnostic>) -> Option<usize> {
+    symbol_table.reset_scopes_indexes();
+
+    let mut context: (&mut SymbolTable, Option<usize>) = (
         symbol_table,
         None
     );
@@ -673,15 +769,20 @@ fn find_process_scope(ast: &Node, symbol_table: &mut SymbolTable) -> Option<usiz
         match node {
             | Node::BlockSection { .. }
             | Node::BufferInitializer { .. }
+            | Node::ForStmt { .. }
             | Node::FunctionBody { .. }
             | Node::BlockStmt { .. }
             => {
                 match stage {
                     ASTTraverseStage::Enter => {
-                        context.0.enter_next_scope();
+                        if let Err(error) = context.0.enter_next_scope() {
+                            errors.push(error.at_node(node));
+                        }
                     }
                     ASTTraverseStage::Exit => {
-                        context.0.exit_scope();
+                        if let Err(error) = context.0.exit_scope() {
+                            errors.push(error.at_node(node));
+                        }
                     }
                 }
             }
@@ -689,11 +790,15 @@ fn find_process_scope(ast: &Node, symbol_table: &mut SymbolTable) -> Option<usiz
             Node::ProcessSection { .. } => {
                 match stage {
                     ASTTraverseStage::Enter => {
-                        context.0.enter_next_scope();
+                        if let Err(error) = context.0.enter_next_scope() {
+                            errors.push(error.at_node(node));
+                        }
                         context.1 = Some(context.0.current_scope_index());
                     }
                     ASTTraverseStage::Exit => {
-                        context.0.exit_scope();
+                        if let Err(error) = context.0.exit_scope() {
+                            errors.push(error.at_node(node));
+                        }
                     }
                 }
             }
@@ -733,182 +838,779 @@ fn collect_symbols_for_hoisting(ast: &mut Node, context: &mut HoistingContext) -
                             Node::VariableDeclarationStmt { id, .. } => {
                                 let name = match id.as_ref() {
                                     Node::Identifier { name, .. } => name,
-                                    _ => panic!("Expected identifier"),
+                                    node => {
+                                        context.errors.push(internal_error(node, "an identifier"));
+                                        continue;
+                                    }
                                 };
 
-                                let process_scope_index = context.process_scope_index.unwrap();
+                                let process_scope_index = context.process_scope_index.unwrap();
+
+                                // println!("Looking up {} in scope {}", name, process_scope_index);
+                                // println!("Symbol table: {:#?}", context.symbol_table);
+
+                                let symbol = context.symbol_table.lookup_in_scope(name, process_scope_index).unwrap();
+                                symbols_to_hoist.push((context.get_unique_name(name), symbol.clone()));
+                            }
+                            _ => ()
+                        }
+                    }
+
+                    _ => ()
+                }
+            }
+
+            symbols_to_hoist
+        }
+        _ => vec![],
+    }
+}
+
+fn collect_symbols_for_rename(ast: &mut Node, context: &mut HoistingContext) -> Vec<(String, SymbolInfo)> {
+    let mut symbols_to_rename: Vec<(String, SymbolInfo)> = Vec::new();
+
+    context.symbol_table.reset_scopes_indexes();
+
+    // We want to collect all variable declarations in all scopes here
+    traverse_ast(ast, &mut |stage, node, context: &mut HoistingContext| {
+        match node {
+            | Node::BlockSection { .. }
+            | Node::BufferInitializer { .. }
+            | Node::ForStmt { .. }
+            | Node::FunctionBody { .. }
+            | Node::ProcessSection { .. }
+            | Node::BlockStmt { .. }
+            => {
+                match stage {
+                    ASTTraverseStage::Enter => {
+                        if let Err(error) = context.symbol_table.enter_next_scope() {
+                            context.errors.push(error.at_node(node));
+                        }
+                    }
+                    ASTTraverseStage::Exit => {
+                        if let Err(error) = context.symbol_table.exit_scope() {
+                            context.errors.push(error.at_node(node));
+                        }
+                    }
+                }
+            }
+
+            Node::VariableDeclarationStmt { id, .. }
+            |
+            Node::FunctionDeclarationStmt { id, .. }
+            |
+            Node::BufferDeclarationStmt { id, .. }
+            |
+            Node::ParameterDeclarationStmt { id, .. } => {
+                match stage {
+                    ASTTraverseStage::Enter => {
+                        let name = match id.as_ref() {
+                            Node::Identifier { name, .. } => name,
+                            node => {
+                                context.errors.push(internal_error(node, "an identifier"));
+                                return false;
+                            }
+                        };
+
+                        let symbol = context.symbol_table.lookup_in_scope(name, context.symbol_table.current_scope_index());
+
+                        if symbol.is_some() {
+                            let symbol = symbol.unwrap();
+                            symbols_to_rename.push((context.get_unique_name(name), symbol.clone()));
+                        } else {
+                            println!("Symbol {} not found", name.to_string());
+                        }
+                    }
+                    _ => ()
+                }
+            }
+
+            _ => ()
+        }
+
+        false
+    }, context);
+
+    symbols_to_rename
+}
+
+fn hoist_process_block(ast: &mut Node, context: &mut HoistingContext) -> Vec<Node> {
+    match ast {
+        Node::ProgramNode { children, .. } => {
+            let mut new_nodes = Vec::new();
+
+            for node in children {
+                match node {
+                    Node::ProcessSection { .. } => {
+                        new_nodes.append(&mut hoist_process_block(node, context));
+                    }
+                    _ => new_nodes.push(node.clone()),
+                }
+            }
+
+            new_nodes
+        }
+
+        Node::ProcessSection { children, .. } => {
+            let mut hoisted_declarations = Vec::new();
+            let mut new_nodes = Vec::new();
+
+            for node in children {
+                match node {
+                    Node::ExpressionStmt { child, .. } => {
+                        match child.as_ref() {
+                            Node::VariableDeclarationStmt { id, specifier, initializer, attributes, .. } => {
+                                // Hoisted variable declarations are converted to assignments, so we need to change the specifier to let
+                                let specifier = match specifier {
+                                    VariableSpecifier::Const => VariableSpecifier::Let,
+                                    _ => specifier.clone(),
+                                };
+
+                                hoisted_declarations.push(Node::VariableDeclarationStmt {
+                                    id: id.clone(),
+                                    specifier,
+                                    initializer: Box::new(Node::Number {
+                                        value: 0.0,
+                                        position: Position::new(),
+                                    }),
+                                    attributes: attributes.clone(),
+                                    position: Position::new(),
+                                });
+
+                                new_nodes.push(Node::AssignmentExpr {
+                                    lhs: id.clone(),
+                                    op: None,
+                                    rhs: initializer.clone(),
+                                    position: Position::new(),
+                                });
+                            }
+                            _ => new_nodes.push(node.clone()),
+                        }
+                    }
+                    Node::VariableDeclarationStmt { id, specifier, initializer, attributes, .. } => {
+                        // Hoist the declaration with a default value
+                        let specifier = match specifier {
+                            VariableSpecifier::Const => VariableSpecifier::Let,
+                            _ => specifier.clone(),
+                        };
+
+                        hoisted_declarations.push(Node::VariableDeclarationStmt {
+                            id: id.clone(),
+                            specifier,
+                            initializer: Box::new(Node::Number {
+                                value: 0.0,
+                                position: Position::new(),
+                            }),
+                            attributes: attributes.clone(),
+                            position: Position::new(),
+                        });
+
+                        new_nodes.push(Node::AssignmentExpr {
+                            lhs: id.clone(),
+                            op: None,
+                            rhs: initializer.clone(),
+                            position: Position::new(),
+                        });
+                    }
+                    _ => new_nodes.push(node.clone()),
+                }
+            }
+
+            // Return the hoisted declarations followed by the transformed ProcessNode
+            let mut result = hoisted_declarations;
+            result.push(Node::ProcessSection {
+                children: new_nodes,
+                position: ast.position().clone(),
+            });
+
+            result
+        }
+        _ => vec![ast.clone()],
+    }
+}
+
+// Builds the program out of the merged module. Identifiers are resolved through the symbol table, entering
+// the scopes in the order they were created, like the passes above do
+struct ProgramBuilder {
+    symbol_table: SymbolTable,
+    global_symbols: HashSet<Uuid>,
+    inputs: Vec<Uuid>,
+    outputs: Vec<Uuid>,
+    // Functions declared in the process section end up next to the other declarations
+    lifted_declarations: Vec<Declaration>,
+    errors: Vec<Diagnostic>,
+}
+
+impl ProgramBuilder {
+    fn build(ast: &AST, mut symbol_table: SymbolTable) -> Result<Program, Vec<Diagnostic>> {
+        symbol_table.reset_scopes_indexes();
+
+        // Every input and output gets a slot, in the order they are declared
+        let inputs: Vec<(String, Uuid)> = ast.inputs().into_iter()
+            .filter_map(|name| match symbol_table.lookup(&name) {
+                Some(symbol) if symbol.is_input() => Some((name, *symbol.id())),
+                _ => None,
+            })
+            .collect();
+
+        let outputs: Vec<(String, Uuid)> = ast.outputs().into_iter()
+            .filter_map(|name| match symbol_table.lookup(&name) {
+                Some(symbol) if symbol.is_output() && !symbol.is_parameter() => Some((name, *symbol.id())),
+                _ => None,
+            })
+            .collect();
+
+        let global_symbols = symbol_table.scopes()[0].symbols().values().map(|symbol| *symbol.id()).collect();
+
+        let mut builder = ProgramBuilder {
+            symbol_table,
+            global_symbols,
+            inputs: inputs.iter().map(|(_, id)| *id).collect(),
+            outputs: outputs.iter().map(|(_, id)| *id).collect(),
+            lifted_declarations: vec![],
+            errors: vec![],
+        };
+
+        let mut program = Program {
+            inputs: inputs.into_iter().map(|(name, _)| name).collect(),
+            outputs: outputs.into_iter().map(|(name, _)| name).collect(),
+            declarations: vec![],
+            block: None,
+            process: vec![],
+            connections: vec![],
+        };
+
+        let children = match &ast.root {
+            Node::ProgramNode { children, .. } => children,
+            node => return Err(vec![internal_error(node, "a program")]),
+        };
+
+        for node in children {
+            match node {
+                Node::BlockSection { children, .. } => {
+                    let statements = builder.scoped(node, |builder| builder.statements(children));
+                    program.block.get_or_insert_with(Vec::new).extend(statements);
+                }
+                Node::ProcessSection { children, .. } => {
+                    let statements = builder.scoped(node, |builder| builder.statements(children));
+                    program.process.extend(statements);
+                }
+                Node::ConnectSection { children, .. } => {
+                    for child in children {
+                        if let Some(connection) = builder.connection(child) {
+                            program.connections.push(connection);
+                        }
+                    }
+                }
+                _ => {
+                    if let Some(declaration) = builder.declaration(node) {
+                        program.declarations.push(declaration);
+                    }
+                }
+            }
+        }
+
+        program.declarations.append(&mut builder.lifted_declarations);
+
+        if !builder.errors.is_empty() {
+            return Err(builder.errors);
+        }
+
+        Ok(program)
+    }
+
+    // Runs "lower" inside the scope the node opens. The scope is left even if lowering fails,
+    // so the scopes after it still line up
+    fn scoped<T>(&mut self, node: &Node, lower: impl FnOnce(&mut Self) -> T) -> T {
+        if let Err(error) = self.symbol_table.enter_next_scope() {
+            self.errors.push(error.at_node(node));
+        }
+
+        let result = lower(self);
+
+        if let Err(error) = self.symbol_table.exit_scope() {
+            self.errors.push(error.at_node(node));
+        }
+
+        result
+    }
+
+    fn declaration(&mut self, node: &Node) -> Option<Declaration> {
+        match node {
+            Node::ExportDeclarationStmt { declaration, .. } => self.declaration(declaration),
+            Node::ExpressionStmt { child, .. } => self.declaration(child),
+            Node::VariableDeclarationStmt { id, initializer, specifier, .. } => {
+                let initializer = self.expression(initializer)?;
+
+                match specifier {
+                    VariableSpecifier::Input => Some(Declaration::Input { slot: self.slot(id, true)?, initializer }),
+                    VariableSpecifier::Output => Some(Declaration::Output { slot: self.slot(id, false)?, initializer }),
+                    _ => Some(Declaration::Variable {
+                        name: self.name(id)?,
+                        constant: *specifier == VariableSpecifier::Const,
+                        initializer,
+                    }),
+                }
+            }
+            Node::ParameterDeclarationStmt { id, fields, .. } => {
+                let name = self.name(id)?;
+
+                let fields = fields.iter().map(|field| match field {
+                    Node::ParameterDeclarationField { id, specifier, .. } => {
+                        Some((self.name(id)?.name, self.expression(specifier)?))
+                    }
+                    node => {
+                        self.errors.push(internal_error(node, "a parameter field"));
+                        None
+                    }
+                }).collect::<Option<Vec<_>>>()?;
+
+                Some(Declaration::Parameter { name, fields })
+            }
+            Node::BufferDeclarationStmt { id, size, initializer, .. } => {
+                let name = self.name(id)?;
+                let size = self.expression(size)?;
+
+                let initializer = match initializer.as_ref() {
+                    Node::BufferInitializer { children, .. } => {
+                        Some(self.scoped(initializer, |builder| builder.statements(children)))
+                    }
+                    _ => None,
+                };
+
+                Some(Declaration::Buffer { name, size, initializer })
+            }
+            Node::FunctionDeclarationStmt { id, params, body, .. } => {
+                let name = self.name(id);
+                let noinline = node.has_attribute("noinline");
+
+                let parameters = params.iter().map(|param| match param {
+                    Node::FunctionParameter { id, .. } => self.name(id),
+                    node => {
+                        self.errors.push(internal_error(node, "a function parameter"));
+                        None
+                    }
+                }).collect::<Option<Vec<_>>>();
+
+                let body = match body.as_ref() {
+                    Node::FunctionBody { children, .. } => self.scoped(body, |builder| builder.statements(children)),
+                    node => {
+                        self.errors.push(internal_error(node, "a function body"));
+                        return None;
+                    }
+                };
+
+                Some(Declaration::Function { name: name?, parameters: parameters?, body, noinline })
+            }
+            _ => Some(Declaration::Statement(self.statement(node)?)),
+        }
+    }
+
+    fn statements(&mut self, nodes: &[Node]) -> Vec<Statement> {
+        nodes.iter().filter_map(|node| self.statement(node)).collect()
+    }
+
+    fn statement(&mut self, node: &Node) -> Option<Statement> {
+        match node {
+            Node::ExpressionStmt { child, .. } => self.statement(child),
+            Node::VariableDeclarationStmt { id, initializer, specifier, .. } => {
+                Some(Statement::Let {
+                    name: self.name(id)?,
+                    constant: *specifier == VariableSpecifier::Const,
+                    initializer: self.expression(initializer)?,
+                })
+            }
+            Node::AssignmentExpr { lhs, op, rhs, .. } => {
+                let target = self.reference(lhs)?;
+                let value = self.expression(rhs)?;
+
+                // "a += b" assigns "a + b"
+                let value = match op {
+                    Some(op) => Expression::Binary { op: op.clone(), lhs: Box::new(Expression::Reference(target.clone())), rhs: Box::new(value) },
+                    None => value,
+                };
+
+                Some(Statement::Assign { target, value })
+            }
+            Node::ReturnStmt { child, .. } => Some(Statement::Return(self.expression(child)?)),
+            Node::IfStmt { test, consequent, alternate, .. } => {
+                let test = self.expression(test);
+                let consequent = self.statement(consequent);
+                let alternate = match alternate {
+                    Some(alternate) => Some(Box::new(self.statement(alternate)?)),
+                    None => None,
+                };
+
+                Some(Statement::If { test: test?, consequent: Box::new(consequent?), alternate })
+            }
+            Node::ForStmt { id, from, to, body, .. } => {
+                self.scoped(node, |builder| {
+                    let variable = builder.name(id);
+                    let from = builder.expression(from);
+                    let to = builder.expression(to);
+                    let body = builder.statement(body);
+
+                    Some(Statement::For { variable: variable?, from: from?, to: to?, body: Box::new(body?) })
+                })
+            }
+            Node::BlockStmt { children, .. } => {
+                Some(Statement::Block(self.scoped(node, |builder| builder.statements(children))))
+            }
+            Node::FunctionDeclarationStmt { .. } => {
+                let declaration = self.declaration(node)?;
+                self.lifted_declarations.push(declaration);
+                None
+            }
+            _ => Some(Statement::Expression(self.expression(node)?)),
+        }
+    }
+
+    fn expression(&mut self, node: &Node) -> Option<Expression> {
+        match node {
+            Node::Number { value, .. } => Some(Expression::Number(*value)),
+            Node::Identifier { .. } => Some(Expression::Reference(self.reference(node)?)),
+            Node::UnaryExpr { op, child, .. } => {
+                Some(Expression::Unary { op: op.clone(), operand: Box::new(self.expression(child)?) })
+            }
+            Node::BinaryExpr { op, lhs, rhs, .. } => {
+                let lhs = self.expression(lhs);
+                let rhs = self.expression(rhs);
+
+                Some(Expression::Binary { op: op.clone(), lhs: Box::new(lhs?), rhs: Box::new(rhs?) })
+            }
+            Node::ConditionalExpr { test, consequent, alternate, .. } => {
+                let test = self.expression(test);
+                let consequent = self.expression(consequent);
+                let alternate = self.expression(alternate);
+
+                Some(Expression::Conditional {
+                    test: Box::new(test?),
+                    consequent: Box::new(consequent?),
+                    alternate: Box::new(alternate?),
+                })
+            }
+            Node::FnCallExpr { callee, args, .. } => {
+                let callee = self.reference(callee);
+                let args = args.iter().map(|arg| self.expression(arg)).collect::<Vec<_>>();
+
+                Some(Expression::Call { callee: callee?, args: args.into_iter().collect::<Option<Vec<_>>>()? })
+            }
+            Node::ConnectedExpr { test, .. } => {
+                match self.reference(test)? {
+                    Reference::Input(slot) => Some(Expression::Connected(Slot::Input(slot))),
+                    Reference::Output(slot) => Some(Expression::Connected(Slot::Output(slot))),
+                    _ => {
+                        self.errors.push(internal_error(test, "an input or output"));
+                        None
+                    }
+                }
+            }
+            node => {
+                self.errors.push(unexpected_in_ir(node, node.kind()));
+                None
+            }
+        }
+    }
+
+    fn connection(&mut self, node: &Node) -> Option<Connection> {
+        let (lhs, rhs) = match node {
+            Node::ConnectStmt { lhs, rhs, .. } => (lhs, rhs),
+            node => {
+                self.errors.push(internal_error(node, "a connection"));
+                return None;
+            }
+        };
+
+        let source = self.reference(lhs);
+
+        let destination = match rhs.as_ref() {
+            Node::OutputsStmt { .. } => Destination::Outputs,
+            Node::OutputsNumberedStmt { value, .. } => Destination::Channel(*value as usize),
+            _ => Destination::Reference(self.reference(rhs)?),
+        };
+
+        Some(Connection { source: source?, destination })
+    }
+
+    fn reference(&mut self, node: &Node) -> Option<Reference> {
+        let name = match node {
+            Node::Identifier { name, .. } => name,
+            node => {
+                self.errors.push(internal_error(node, "an identifier"));
+                return None;
+            }
+        };
+
+        // Unknown names are reported by the semantic analysis. Like the passes above, the IR leaves them as they are
+        let symbol = match self.symbol_table.lookup(name) {
+            Some(symbol) => symbol.clone(),
+            None => return Some(Reference::State(decode_name(name))),
+        };
+
+        let reference = match symbol {
+            SymbolInfo::Variable { origin: SymbolOrigin::StandardLibrary, .. }
+            | SymbolInfo::Function { origin: SymbolOrigin::StandardLibrary, .. } => Reference::Std(name.clone()),
+            SymbolInfo::Variable { specifier: VariableSpecifier::Input, id, .. } => Reference::Input(self.slot_of(&id, true, node)?),
+            SymbolInfo::Variable { specifier: VariableSpecifier::Output, id, .. } => Reference::Output(self.slot_of(&id, false, node)?),
+            SymbolInfo::Parameter { .. } => Reference::Parameter(decode_name(name)),
+            SymbolInfo::Function { .. } => Reference::Function(decode_name(name)),
+            SymbolInfo::Variable { id, .. } | SymbolInfo::Buffer { id, .. } if self.global_symbols.contains(&id) => {
+                Reference::State(decode_name(name))
+            }
+            SymbolInfo::Variable { .. } | SymbolInfo::Buffer { .. } | SymbolInfo::FunctionArgument { .. } => {
+                Reference::Local(decode_name(name))
+            }
+            SymbolInfo::ImportedModule { .. } => {
+                self.errors.push(internal_error(node, "a variable or function"));
+                return None;
+            }
+        };
+
+        Some(reference)
+    }
+
+    // Slot of the input or output declared with "id"
+    fn slot(&mut self, id: &Node, input: bool) -> Option<usize> {
+        let symbol_id = match id {
+            Node::Identifier { name, .. } => self.symbol_table.lookup(name).map(|symbol| *symbol.id()),
+            _ => None,
+        };
+
+        match symbol_id {
+            Some(symbol_id) => self.slot_of(&symbol_id, input, id),
+            None => {
+                self.errors.push(internal_error(id, "a declared identifier"));
+                None
+            }
+        }
+    }
+
+    fn slot_of(&mut self, symbol_id: &Uuid, input: bool, node: &Node) -> Option<usize> {
+        let slots = if input { &self.inputs } else { &self.outputs };
+
+        match slots.iter().position(|id| id == symbol_id) {
+            Some(slot) => Some(slot),
+            None => {
+                self.errors.push(internal_error(node, if input { "an input" } else { "an output" }));
+                None
+            }
+        }
+    }
+
+    fn name(&mut self, node: &Node) -> Option<Name> {
+        match node {
+            Node::Identifier { name, .. } => Some(decode_name(name)),
+            node => {
+                self.errors.push(internal_error(node, "an identifier"));
+                None
+            }
+        }
+    }
+}
 
-                                // println!("Looking up {} in scope {}", name, process_scope_index);
-                                // println!("Symbol table: {:#?}", context.symbol_table);
+// The passes above keep names unique by rewriting them: "Osc#phase" for a declaration merged from the module
+// imported as Osc, "#phase_2" for a hoisted declaration that had to be renamed. They are taken apart here, so
+// nothing after the IR has to know about it
+fn decode_name(encoded: &str) -> Name {
+    let mut modules = vec![];
+    let mut rest = encoded;
 
-                                let symbol = context.symbol_table.lookup_in_scope(name, process_scope_index).unwrap();
-                                symbols_to_hoist.push((context.get_unique_name(name), symbol.clone()));
-                            }
-                            _ => ()
-                        }
-                    }
+    while let Some((module, tail)) = rest.split_once('#') {
+        if module.is_empty() {
+            let renamed = tail.trim_start_matches('#');
 
-                    _ => ()
+            if let Some((name, version)) = renamed.rsplit_once('_') {
+                if let Ok(version) = version.parse() {
+                    return Name { modules, name: name.to_string(), version: Some(version) };
                 }
             }
 
-            symbols_to_hoist
+            rest = renamed;
+            break;
         }
-        _ => vec![],
+
+        modules.push(module.to_string());
+        rest = tail;
     }
+
+    Name { modules, name: rest.to_string(), version: None }
 }
 
-fn collect_symbols_for_rename(ast: &mut Node, context: &mut HoistingContext) -> Vec<(String, SymbolInfo)> {
-    let mut symbols_to_rename: Vec<(String, SymbolInfo)> = Vec::new();
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for module in &self.modules {
+            write!(f, "{}.", module)?;
+        }
 
-    context.symbol_table.reset_scopes_indexes();
+        write!(f, "{}", self.name)?;
 
-    // We want to collect all variable declarations in all scopes here
-    traverse_ast(ast, &mut |stage, node, context: &mut HoistingContext| {
-        match node {
-            | Node::BlockSection { .. }
-            | Node::BufferInitializer { .. }
-            | Node::FunctionBody { .. }
-            | Node::ProcessSection { .. }
-            | Node::BlockStmt { .. }
-            => {
-                match stage {
-                    ASTTraverseStage::Enter => {
-                        context.symbol_table.enter_next_scope();
-                    }
-                    ASTTraverseStage::Exit => {
-                        context.symbol_table.exit_scope();
-                    }
-                }
+        match self.version {
+            Some(version) => write!(f, "'{}", version),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for Reference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reference::Local(name) | Reference::State(name) | Reference::Parameter(name) | Reference::Function(name) => write!(f, "{}", name),
+            Reference::Input(slot) => write!(f, "input[{}]", slot),
+            Reference::Output(slot) => write!(f, "output[{}]", slot),
+            Reference::Std(name) => write!(f, "std.{}", name),
+        }
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expression::Number(value) => write!(f, "{}", value),
+            Expression::Reference(reference) => write!(f, "{}", reference),
+            Expression::Unary { op, operand } => write!(f, "{}({})", operator_symbol(op), operand),
+            Expression::Binary { op, lhs, rhs } => write!(f, "({} {} {})", lhs, operator_symbol(op), rhs),
+            Expression::Conditional { test, consequent, alternate } => write!(f, "({} ? {} : {})", test, consequent, alternate),
+            Expression::Call { callee, args } => {
+                let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+                write!(f, "{}({})", callee, args.join(", "))
             }
+            Expression::Connected(Slot::Input(slot)) => write!(f, "connected(input[{}])", slot),
+            Expression::Connected(Slot::Output(slot)) => write!(f, "connected(output[{}])", slot),
+        }
+    }
+}
 
-            Node::VariableDeclarationStmt { id, .. }
-            |
-            Node::FunctionDeclarationStmt { id, .. }
-            |
-            Node::BufferDeclarationStmt { id, .. }
-            |
-            Node::ParameterDeclarationStmt { id, .. } => {
-                match stage {
-                    ASTTraverseStage::Enter => {
-                        let name = match id.as_ref() {
-                            Node::Identifier { name, .. } => name,
-                            _ => panic!("Expected identifier"),
-                        };
+impl Program {
+    // Prints the program in a syntax close to the source, for --emit ir and the tests. Everything the
+    // backends see is spelled out: slots, stdlib references and the module path of merged names
+    pub fn to_code_string(&self) -> String {
+        let mut code = String::new();
 
-                        let symbol = context.symbol_table.lookup_in_scope(name, context.symbol_table.current_scope_index());
+        code.push_str(&format!("// inputs: {}\n", self.inputs.join(", ")));
+        code.push_str(&format!("// outputs: {}\n", self.outputs.join(", ")));
 
-                        if symbol.is_some() {
-                            let symbol = symbol.unwrap();
-                            symbols_to_rename.push((context.get_unique_name(name), symbol.clone()));
-                        } else {
-                            println!("Symbol {} not found", name.to_string());
+        for declaration in &self.declarations {
+            match declaration {
+                Declaration::Variable { name, constant, initializer } => {
+                    code.push_str(&format!("{} {} = {};\n", if *constant { "const" } else { "let" }, name, initializer));
+                }
+                Declaration::Input { slot, initializer } => code.push_str(&format!("input[{}] = {};\n", slot, initializer)),
+                Declaration::Output { slot, initializer } => code.push_str(&format!("output[{}] = {};\n", slot, initializer)),
+                Declaration::Parameter { name, fields } => {
+                    let fields: Vec<String> = fields.iter().map(|(field, value)| format!("{}: {};", field, value)).collect();
+                    code.push_str(&format!("param {} {{ {} }}\n", name, fields.join(" ")));
+                }
+                Declaration::Buffer { name, size, initializer } => {
+                    code.push_str(&format!("buffer {}[{}]", name, size));
+
+                    match initializer {
+                        Some(initializer) => {
+                            code.push_str(" = |i| ");
+                            push_block(&mut code, initializer, 0);
+                            code.push('\n');
                         }
+                        None => code.push_str(";\n"),
                     }
-                    _ => ()
+                }
+                Declaration::Function { name, parameters, body, noinline } => {
+                    let parameters: Vec<String> = parameters.iter().map(|parameter| parameter.to_string()).collect();
+
+                    if *noinline {
+                        code.push_str("@noinline ");
+                    }
+
+                    code.push_str(&format!("fn {}({}) ", name, parameters.join(", ")));
+                    push_block(&mut code, body, 0);
+                    code.push('\n');
+                }
+                Declaration::Statement(statement) => {
+                    push_statement(&mut code, statement, 0);
+                    code.push('\n');
                 }
             }
+        }
 
-            _ => ()
+        if let Some(block) = &self.block {
+            code.push_str("\nblock ");
+            push_block(&mut code, block, 0);
+            code.push('\n');
         }
 
-        false
-    }, context);
+        code.push_str("\nprocess ");
+        push_block(&mut code, &self.process, 0);
+        code.push('\n');
 
-    symbols_to_rename
-}
+        if !self.connections.is_empty() {
+            code.push_str("\nconnect {\n");
 
-fn hoist_process_block(ast: &mut Node, context: &mut HoistingContext) -> Vec<Node> {
-    match ast {
-        Node::ProgramNode { children, .. } => {
-            let mut new_nodes = Vec::new();
+            for connection in &self.connections {
+                let destination = match &connection.destination {
+                    Destination::Reference(reference) => reference.to_string(),
+                    Destination::Outputs => "OUTPUTS".to_string(),
+                    Destination::Channel(channel) => format!("OUTPUTS[{}]", channel),
+                };
 
-            for node in children {
-                match node {
-                    Node::ProcessSection { .. } => {
-                        new_nodes.append(&mut hoist_process_block(node, context));
-                    }
-                    _ => new_nodes.push(node.clone()),
-                }
+                code.push_str(&format!("    {} -> {};\n", connection.source, destination));
             }
 
-            new_nodes
+            code.push_str("}\n");
         }
 
-        Node::ProcessSection { children, .. } => {
-            let mut hoisted_declarations = Vec::new();
-            let mut new_nodes = Vec::new();
-
-            for node in children {
-                match node {
-                    Node::ExpressionStmt { child, .. } => {
-                        match child.as_ref() {
-                            Node::VariableDeclarationStmt { id, specifier, initializer, .. } => {
-                                // Hoisted variable declarations are converted to assignments, so we need to change the specifier to let
-                                let specifier = match specifier {
-                                    VariableSpecifier::Const => VariableSpecifier::Let,
-                                    _ => specifier.clone(),
-                                };
+        code
+    }
+}
 
-                                hoisted_declarations.push(Node::VariableDeclarationStmt {
-                                    id: id.clone(),
-                                    specifier,
-                                    initializer: Box::new(Node::Number {
-                                        value: 0.0,
-                                        position: Position::new(),
-                                    }),
-                                    position: Position::new(),
-                                });
+fn push_block(code: &mut String, statements: &[Statement], depth: usize) {
+    code.push_str("{\n");
 
-                                new_nodes.push(Node::AssignmentExpr {
-                                    lhs: id.clone(),
-                                    rhs: initializer.clone(),
-                                    position: Position::new(),
-                                });
-                            }
-                            _ => new_nodes.push(node.clone()),
-                        }
-                    }
-                    Node::VariableDeclarationStmt { id, specifier, initializer, .. } => {
-                        // Hoist the declaration with a default value
-                        let specifier = match specifier {
-                            VariableSpecifier::Const => VariableSpecifier::Let,
-                            _ => specifier.clone(),
-                        };
+    for statement in statements {
+        code.push_str(&"    ".repeat(depth + 1));
+        push_statement(code, statement, depth + 1);
+        code.push('\n');
+    }
 
-                        hoisted_declarations.push(Node::VariableDeclarationStmt {
-                            id: id.clone(),
-                            specifier,
-                            initializer: Box::new(Node::Number {
-                                value: 0.0,
-                                position: Position::new(),
-                            }),
-                            position: Position::new(),
-                        });
+    code.push_str(&"    ".repeat(depth));
+    code.push('}');
+}
 
-                        new_nodes.push(Node::AssignmentExpr {
-                            lhs: id.clone(),
-                            rhs: initializer.clone(),
-                            position: Position::new(),
-                        });
-                    }
-                    _ => new_nodes.push(node.clone()),
-                }
+fn push_statement(code: &mut String, statement: &Statement, depth: usize) {
+    match statement {
+        Statement::Let { name, constant, initializer } => {
+            code.push_str(&format!("{} {} = {};", if *constant { "const" } else { "let" }, name, initializer));
+        }
+        Statement::Assign { target, value } => code.push_str(&format!("{} = {};", target, value)),
+        Statement::Expression(expression) => code.push_str(&format!("{};", expression)),
+        Statement::Return(expression) => code.push_str(&format!("return {};", expression)),
+        Statement::If { test, consequent, alternate } => {
+            code.push_str(&format!("if {} ", test));
+            push_statement(code, consequent, depth);
+
+            if let Some(alternate) = alternate {
+                code.push_str(" else ");
+                push_statement(code, alternate, depth);
             }
-
-            // Return the hoisted declarations followed by the transformed ProcessNode
-            let mut result = hoisted_declarations;
-            result.push(Node::ProcessSection {
-                children: new_nodes,
-                position: ast.position().clone(),
-            });
-
-            result
         }
-        _ => vec![ast.clone()],
+        Statement::For { variable, from, to, body } => {
+            code.push_str(&format!("for {} in {}..{} ", variable, from, to));
+            push_statement(code, body, depth);
+        }
+        Statement::Block(statements) => push_block(code, statements, depth),
     }
 }
 
+// Lowers a single module named "main", for the tests of the passes that work on the IR
+#[cfg(test)]
+pub(crate) fn lower(code: &str) -> Program {
+    let tokens = crate::lexer::Lexer::new().tokenize(code.to_string());
+    let mut ast = crate::parser::Parser::new().parse(tokens);
+    let symbol_table = SymbolTable::from_ast(&mut ast).unwrap();
+
+    let mut modules = IndexMap::new();
+    modules.insert("main".to_string(), ModuleData { ast, symbol_table, errors: vec![] });
+
+    IR::new().create(&mut modules, "main".to_string()).unwrap().program
+}
+
 #[cfg(test)]
 mod tests {
     use crate::lexer::Lexer;
@@ -1699,4 +2401,146 @@ process {
 
         assert!(ir_result.symbol_table.lookup("Mod#Lib#M_E").is_some());
     }
+
+    #[test]
+    fn test_named_imports() {
+        let main_code = "
+            import { clamp, lerp } from \"./lib.meph\";
+
+            output a = 0;
+
+            process {
+                a = clamp(a + 0.1, 0, 1);
+            }
+            ".to_string();
+
+        let module_code = "
+            const LIMIT = 1;
+            let unused = 0;
+
+            fn min2(a, b) {
+                return a < b ? a : b;
+            }
+
+            export fn clamp(x, lo, hi) {
+                return min2(x > lo ? x : lo, hi * LIMIT);
+            }
+
+            export fn lerp(a, b, t) {
+                return a + (b - a) * t;
+            }
+
+            process {
+                unused = unused + 1;
+            }
+        ".to_string();
+
+        let lexer = Lexer::new();
+        let main_tokens = lexer.tokenize(main_code);
+        let module_tokens = lexer.tokenize(module_code);
+
+        let mut parser = Parser::new();
+        let mut main_ast = parser.parse(main_tokens);
+        let mut module_ast = parser.parse(module_tokens);
+
+        let main_symbol_table = SymbolTable::from_ast(&mut main_ast).unwrap();
+        let module_symbol_table = SymbolTable::from_ast(&mut module_ast).unwrap();
+
+        let mut modules = IndexMap::new();
+        modules.insert("main".to_string(), ModuleData {
+            ast: main_ast,
+            symbol_table: main_symbol_table,
+            errors: vec![],
+        });
+        modules.insert("./lib.meph".to_string(), ModuleData {
+            ast: module_ast,
+            symbol_table: module_symbol_table,
+            errors: vec![],
+        });
+
+        let mut ir = IR::new();
+        let mut ir_result = ir.create(&mut modules, "main".to_string()).unwrap();
+
+        ir_result.symbol_table.reset_scopes_indexes();
+
+        assert!(ir_result.symbol_table.lookup("lib_6cfc7cc7#clamp").is_some());
+        assert!(ir_result.symbol_table.lookup("lib_6cfc7cc7#min2").is_some());
+        assert!(ir_result.symbol_table.lookup("lib_6cfc7cc7#LIMIT").is_some());
+
+        // Neither uncalled functions nor the module state are merged
+        assert!(ir_result.symbol_table.lookup("lib_6cfc7cc7#lerp").is_none());
+        assert!(ir_result.symbol_table.lookup("lib_6cfc7cc7#unused").is_none());
+
+        assert!(ir_result.program.to_code_string().contains("output[0] = lib_6cfc7cc7.clamp((output[0] + 0.1), 0, 1);"));
+    }
+
+    #[test]
+    fn test_program_references() {
+        let code = "
+            input freq = 0;
+            output out = 0;
+            let phase = 0;
+
+            process {
+                let step = freq / SR;
+                phase += step;
+                out = connected(freq) ? sin(phase) : 0;
+            }
+
+            connect {
+                out -> OUTPUTS;
+            }
+            ".to_string();
+
+        let lexer = Lexer::new();
+        let tokens = lexer.tokenize(code);
+
+        let mut parser = Parser::new();
+        let mut ast = parser.parse(tokens);
+
+        let symbol_table = SymbolTable::from_ast(&mut ast).unwrap();
+
+        let mut modules = IndexMap::new();
+        modules.insert("main".to_string(), ModuleData { ast, symbol_table, errors: vec![] });
+
+        let mut ir = IR::new();
+        let program = ir.create(&mut modules, "main".to_string()).unwrap().program;
+
+        let name = |name: &str| Name { modules: vec![], name: name.to_string(), version: None };
+
+        assert_eq!(program.inputs, vec!["freq".to_string()]);
+        assert_eq!(program.outputs, vec!["out".to_string()]);
+
+        // Declarations of the process section are hoisted, so "step" is state as well
+        assert_eq!(program.process[1], Statement::Assign {
+            target: Reference::State(name("phase")),
+            value: Expression::Binary {
+                op: Operator::Plus,
+                lhs: Box::new(Expression::Reference(Reference::State(name("phase")))),
+                rhs: Box::new(Expression::Reference(Reference::State(name("step")))),
+            },
+        });
+
+        assert_eq!(program.process[2], Statement::Assign {
+            target: Reference::Output(0),
+            value: Expression::Conditional {
+                test: Box::new(Expression::Connected(Slot::Input(0))),
+                consequent: Box::new(Expression::Call {
+                    callee: Reference::Std("sin".to_string()),
+                    args: vec![Expression::Reference(Reference::State(name("phase")))],
+                }),
+                alternate: Box::new(Expression::Number(0.0)),
+            },
+        });
+
+        assert_eq!(program.connections, vec![Connection { source: Reference::Output(0), destination: Destination::Outputs }]);
+    }
+
+    #[test]
+    fn test_decode_name() {
+        assert_eq!(decode_name("phase"), Name { modules: vec![], name: "phase".to_string(), version: None });
+        assert_eq!(decode_name("Osc#Lib#phase"), Name { modules: vec!["Osc".to_string(), "Lib".to_string()], name: "phase".to_string(), version: None });
+        assert_eq!(decode_name("#my_phase_2"), Name { modules: vec![], name: "my_phase".to_string(), version: Some(2) });
+        assert_eq!(decode_name("Osc##phase_1"), Name { modules: vec!["Osc".to_string()], name: "phase".to_string(), version: Some(1) });
+    }
 }
@@ -0,0 +1,397 @@
+use std::f64::consts;
+
+use crate::ir::{Declaration, Expression, Program, Reference, Statement};
+use crate::parser::ast::Operator;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FoldOptions {
+    // Keeps "x * 0" as it is. It is NaN, not 0, when x is NaN or infinite
+    pub preserve_nan: bool,
+}
+
+// Folds constant subexpressions, arithmetic identities, branches on constant conditions and
+// stdlib calls on constant arguments. Returns how many nodes were folded
+pub fn fold_constants(program: &mut Program, options: FoldOptions) -> usize {
+    let mut folder = Folder { options, folded: 0 };
+
+    for declaration in program.declarations.iter_mut() {
+        folder.declaration(declaration);
+    }
+
+    if let Some(block) = program.block.as_mut() {
+        folder.statements(block);
+    }
+
+    folder.statements(&mut program.process);
+
+    folder.folded
+}
+
+struct Folder {
+    options: FoldOptions,
+    folded: usize,
+}
+
+impl Folder {
+    fn declaration(&mut self, declaration: &mut Declaration) {
+        match declaration {
+            Declaration::Variable { initializer, .. }
+            | Declaration::Input { initializer, .. }
+            | Declaration::Output { initializer, .. } => self.expression(initializer),
+            Declaration::Parameter { fields, .. } => {
+                for (_, specifier) in fields.iter_mut() {
+                    self.expression(specifier);
+                }
+            }
+            Declaration::Buffer { size, initializer, .. } => {
+                self.expression(size);
+
+                if let Some(initializer) = initializer {
+                    self.statements(initializer);
+                }
+            }
+            Declaration::Function { body, .. } => self.statements(body),
+            Declaration::Statement(statement) => self.statement(statement),
+        }
+    }
+
+    fn statements(&mut self, statements: &mut Vec<Statement>) {
+        for statement in statements.iter_mut() {
+            self.statement(statement);
+        }
+
+        // Left behind by conditions that are always false
+        statements.retain(|statement| !matches!(statement, Statement::Block(children) if children.is_empty()));
+    }
+
+    fn statement(&mut self, statement: &mut Statement) {
+        match statement {
+            Statement::Let { initializer: expression, .. }
+            | Statement::Assign { value: expression, .. }
+            | Statement::Expression(expression)
+            | Statement::Return(expression) => self.expression(expression),
+            Statement::If { test, consequent, alternate } => {
+                self.expression(test);
+                self.statement(consequent);
+
+                if let Some(alternate) = alternate {
+                    self.statement(alternate);
+                }
+
+                if let Expression::Number(value) = test {
+                    // The chosen branch keeps its block, so its declarations stay in their scope
+                    *statement = match (*value != 0.0, alternate.take()) {
+                        (true, _) => *consequent.clone(),
+                        (false, Some(alternate)) => *alternate,
+                        (false, None) => Statement::Block(vec![]),
+                    };
+
+                    self.folded += 1;
+                }
+            }
+            Statement::For { from, to, body, .. } => {
+                self.expression(from);
+                self.expression(to);
+                self.statement(body);
+            }
+            Statement::Block(statements) => self.statements(statements),
+        }
+    }
+
+    // Folds the operands first, so constants propagate up the tree
+    fn expression(&mut self, expression: &mut Expression) {
+        match expression {
+            Expression::Unary { operand, .. } => self.expression(operand),
+            Expression::Binary { lhs, rhs, .. } => {
+                self.expression(lhs);
+                self.expression(rhs);
+            }
+            Expression::Conditional { test, consequent, alternate } => {
+                self.expression(test);
+                self.expression(consequent);
+                self.expression(alternate);
+            }
+            Expression::Call { args, .. } => {
+                for arg in args.iter_mut() {
+                    self.expression(arg);
+                }
+            }
+            Expression::Number(_) | Expression::Reference(_) | Expression::Connected(_) => {}
+        }
+
+        if let Some(folded) = self.fold(expression) {
+            *expression = folded;
+            self.folded += 1;
+        }
+    }
+
+    fn fold(&self, expression: &Expression) -> Option<Expression> {
+        match expression {
+            Expression::Reference(Reference::Std(name)) => stdlib_constant(name).map(Expression::Number),
+            Expression::Unary { op, operand } => match operand.as_ref() {
+                Expression::Number(value) => number(unary(op, *value)?),
+                _ => None,
+            },
+            Expression::Binary { op, lhs, rhs } => match (lhs.as_ref(), rhs.as_ref()) {
+                (Expression::Number(lhs), Expression::Number(rhs)) => number(binary(op, *lhs, *rhs)?),
+                (Expression::Number(constant), operand) | (operand, Expression::Number(constant)) => {
+                    self.identity(op, *constant, operand, matches!(lhs.as_ref(), Expression::Number(_)))
+                }
+                _ => None,
+            },
+            // Only the chosen branch is evaluated, so dropping the other one is always safe
+            Expression::Conditional { test, consequent, alternate } => match test.as_ref() {
+                Expression::Number(value) if *value != 0.0 => Some(*consequent.clone()),
+                Expression::Number(_) => Some(*alternate.clone()),
+                _ => None,
+            },
+            Expression::Call { callee: Reference::Std(name), args } => {
+                let args = args.iter()
+                    .map(|arg| match arg {
+                        Expression::Number(value) => Some(*value),
+                        _ => None,
+                    })
+                    .collect::<Option<Vec<_>>>()?;
+
+                number(stdlib_call(name, &args)?)
+            }
+            _ => None,
+        }
+    }
+
+    // "x * 1", "x / 1", "x + 0", "x - 0" and "x * 0" with "constant" on either side
+    fn identity(&self, op: &Operator, constant: f64, operand: &Expression, constant_is_lhs: bool) -> Option<Expression> {
+        match op {
+            Operator::Mul if constant == 1.0 => Some(operand.clone()),
+            Operator::Div if constant == 1.0 && !constant_is_lhs => Some(operand.clone()),
+            Operator::Plus if constant == 0.0 => Some(operand.clone()),
+            Operator::Minus if constant == 0.0 && !constant_is_lhs => Some(operand.clone()),
+            // The operand is dropped, so it must not have side effects
+            Operator::Mul if constant == 0.0 && !self.options.preserve_nan && is_pure(operand) => Some(Expression::Number(0.0)),
+            _ => None,
+        }
+    }
+}
+
+// Only finite results are folded, the backends have no literal for infinities or NaN
+fn number(value: f64) -> Option<Expression> {
+    value.is_finite().then_some(Expression::Number(value))
+}
+
+// Any nonzero value is true, the result is normalized to 0/1
+fn boolean(value: bool) -> f64 {
+    if value { 1.0 } else { 0.0 }
+}
+
+fn unary(op: &Operator, value: f64) -> Option<f64> {
+    match op {
+        Operator::Plus => Some(value),
+        Operator::Minus => Some(-value),
+        Operator::Not => Some(boolean(value == 0.0)),
+        _ => None,
+    }
+}
+
+fn binary(op: &Operator, lhs: f64, rhs: f64) -> Option<f64> {
+    match op {
+        Operator::Plus => Some(lhs + rhs),
+        Operator::Minus => Some(lhs - rhs),
+        Operator::Mul => Some(lhs * rhs),
+        Operator::Div => Some(lhs / rhs),
+        // Modulo is floored, so the result has the sign of the divisor
+        Operator::Mod => Some(lhs - rhs * (lhs / rhs).floor()),
+        Operator::Pow => Some(lhs.powf(rhs)),
+        Operator::Eq => Some(boolean(lhs == rhs)),
+        Operator::Ne => Some(boolean(lhs != rhs)),
+        Operator::Gt => Some(boolean(lhs > rhs)),
+        Operator::Lt => Some(boolean(lhs < rhs)),
+        Operator::Ge => Some(boolean(lhs >= rhs)),
+        Operator::Le => Some(boolean(lhs <= rhs)),
+        Operator::And => Some(boolean(lhs != 0.0 && rhs != 0.0)),
+        Operator::Or => Some(boolean(lhs != 0.0 || rhs != 0.0)),
+        Operator::Not => None,
+    }
+}
+
+// SR is only known by the host, so it is never folded
+fn stdlib_constant(name: &str) -> Option<f64> {
+    match name {
+        "PI" => Some(consts::PI),
+        "E" => Some(consts::E),
+        "C_TRIGGER" => Some(0.0),
+        "C_SLIDER" => Some(1.0),
+        "C_TOGGLE" => Some(2.0),
+        _ => None,
+    }
+}
+
+// Pure stdlib functions, computed the way the generated code computes them
+fn stdlib_call(name: &str, args: &[f64]) -> Option<f64> {
+    match (name, args) {
+        ("abs", [x]) => Some(x.abs()),
+        ("sqrt", [x]) => Some(x.sqrt()),
+        ("pow", [x, y]) => Some(x.powf(*y)),
+        ("exp", [x]) => Some(x.exp()),
+        ("min", [x, y]) => Some(x.min(*y)),
+        ("max", [x, y]) => Some(x.max(*y)),
+        // Unlike the % operator, the stdlib function truncates
+        ("mod", [x, y]) => Some(x % y),
+        ("sin", [x]) => Some(x.sin()),
+        ("cos", [x]) => Some(x.cos()),
+        ("tan", [x]) => Some(x.tan()),
+        ("asin", [x]) => Some(x.asin()),
+        ("acos", [x]) => Some(x.acos()),
+        ("atan", [x]) => Some(x.atan()),
+        ("atan2", [y, x]) => Some(y.atan2(*x)),
+        ("log", [x]) => Some(x.ln()),
+        ("log10", [x]) => Some(x.log10()),
+        ("floor", [x]) => Some(x.floor()),
+        ("ceil", [x]) => Some(x.ceil()),
+        // Halves are rounded up, like Math.round does
+        ("round", [x]) => {
+            let floor = x.floor();
+            Some(if x - floor >= 0.5 { floor + 1.0 } else { floor })
+        }
+        _ => None,
+    }
+}
+
+// Whether evaluating the expression only computes a value. Calls of user functions can assign
+// state, and rand and the buffer functions change something on every call
+pub(crate) fn is_pure(expression: &Expression) -> bool {
+    match expression {
+        Expression::Number(_) | Expression::Reference(_) | Expression::Connected(_) => true,
+        Expression::Unary { operand, .. } => is_pure(operand),
+        Expression::Binary { lhs, rhs, .. } => is_pure(lhs) && is_pure(rhs),
+        Expression::Conditional { test, consequent, alternate } => is_pure(test) && is_pure(consequent) && is_pure(alternate),
+        Expression::Call { callee: Reference::Std(name), args } => {
+            name != "rand" && !name.starts_with("buf_") && args.iter().all(is_pure)
+        }
+        Expression::Call { .. } => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ir::fold::{fold_constants, FoldOptions};
+    use crate::ir::lower;
+
+    #[test]
+    fn test_fold_constants() {
+        let mut program = lower("
+            param cutoff {
+                min: 20;
+                max: 20000;
+                initial: 1000;
+            };
+
+            let omega = 0;
+            let gain = 0;
+            let x = 0;
+
+            process {
+                omega = 2 * PI * cutoff;
+                gain = sqrt(4) + 10 % 3 * 1;
+                x = x * 1 + 0;
+                x = x * 0;
+                x = rand() * 0;
+                x = 1 > 2 ? sin(x) : -x;
+            }
+            ");
+
+        let folded = fold_constants(&mut program, FoldOptions::default());
+
+        let code = program.to_code_string();
+
+        assert!(code.contains("omega = (6.283185307179586 * cutoff);"));
+        assert!(code.contains("gain = 3;"));
+        assert!(code.contains("x = x;"));
+        assert!(code.contains("x = 0;"));
+        assert!(code.contains("x = (std.rand() * 0);"));
+        assert!(code.contains("x = -(x);"));
+        assert_eq!(folded, 11);
+    }
+
+    #[test]
+    fn test_fold_floored_modulo_of_negative_operands() {
+        let mut program = lower("
+            let a = 0;
+            let b = 0;
+            let c = 0;
+            let phase = 0;
+
+            process {
+                a = -7 % 3;
+                b = -0.25 % 1;
+                c = 7 % -4;
+                phase = -phase % 1;
+            }
+            ");
+
+        fold_constants(&mut program, FoldOptions::default());
+
+        let code = program.to_code_string();
+
+        // The sign belongs to the left operand, the result has the sign of the divisor
+        assert!(code.contains("a = 2;"), "{}", code);
+        assert!(code.contains("b = 0.75;"), "{}", code);
+        assert!(code.contains("c = -1;"), "{}", code);
+        assert!(code.contains("phase = (-(phase) % 1);"), "{}", code);
+    }
+
+    #[test]
+    fn test_fold_preserve_nan() {
+        let mut program = lower("
+            let x = 0;
+
+            process {
+                x = x * 0;
+            }
+            ");
+
+        fold_constants(&mut program, FoldOptions { preserve_nan: true });
+
+        assert!(program.to_code_string().contains("x = (x * 0);"));
+    }
+
+    #[test]
+    fn test_fold_constant_if() {
+        let mut program = lower("
+            let x = 0;
+
+            process {
+                if (1 < 0) {
+                    x = 1;
+                }
+
+                if (2 == 2) {
+                    x = 2;
+                } else {
+                    x = 3;
+                }
+            }
+            ");
+
+        fold_constants(&mut program, FoldOptions::default());
+
+        let code = program.to_code_string();
+
+        assert!(!code.contains("x = 1;"));
+        assert!(!code.contains("x = 3;"));
+        assert!(!code.contains("if"));
+        assert!(code.contains("x = 2;"));
+    }
+
+    #[test]
+    fn test_fold_keeps_division_by_zero() {
+        let mut program = lower("
+            let x = 0;
+
+            process {
+                x = 1 / 0;
+            }
+            ");
+
+        assert_eq!(fold_constants(&mut program, FoldOptions::default()), 0);
+    }
+}
@@ -0,0 +1,804 @@
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+
+use crate::ir::fold::is_pure;
+use crate::ir::{Declaration, Expression, Name, Program, Reference, Statement};
+
+// Functions with more nodes than this are always called. A call is cheap next to a big body,
+// and every inlined copy makes the generated code bigger
+pub const MAX_INLINE_SIZE: usize = 32;
+
+// Replaces calls of small, non-recursive functions with their bodies. Functions marked
+// @noinline are left alone, and the ones that are no longer called are removed.
+// Returns how many calls were inlined
+pub fn inline_functions(program: &mut Program) -> usize {
+    let mut inliner = Inliner {
+        callees: HashMap::new(),
+        versions: HashMap::new(),
+        inlined: 0,
+        inlined_functions: HashSet::new(),
+    };
+
+    inliner.collect_names(program);
+
+    let recursive = recursive_functions(program);
+
+    // Callees are inlined into their own bodies first, so a call never has to be inlined twice
+    for index in callee_order(program) {
+        let body = match &mut program.declarations[index] {
+            Declaration::Function { body, .. } => body,
+            _ => continue,
+        };
+
+        inliner.statements(body);
+
+        if let Declaration::Function { name, parameters, body, noinline: false } = &program.declarations[index] {
+            if !recursive.contains(name) && statements_size(body) <= MAX_INLINE_SIZE {
+                inliner.callees.insert(name.clone(), Callee { parameters: parameters.clone(), body: body.clone() });
+            }
+        }
+    }
+
+    let declarations = std::mem::take(&mut program.declarations);
+
+    for mut declaration in declarations {
+        if let Declaration::Statement(statement) = declaration {
+            program.declarations.extend(inliner.statement(statement).into_iter().map(Declaration::Statement));
+            continue;
+        }
+
+        match &mut declaration {
+            Declaration::Variable { initializer, .. }
+            | Declaration::Input { initializer, .. }
+            | Declaration::Output { initializer, .. } => inliner.expression(initializer),
+            Declaration::Parameter { fields, .. } => {
+                for (_, specifier) in fields.iter_mut() {
+                    inliner.expression(specifier);
+                }
+            }
+            Declaration::Buffer { size, initializer, .. } => {
+                inliner.expression(size);
+
+                if let Some(initializer) = initializer {
+                    inliner.statements(initializer);
+                }
+            }
+            Declaration::Function { .. } | Declaration::Statement(_) => {}
+        }
+
+        program.declarations.push(declaration);
+    }
+
+    if let Some(block) = program.block.as_mut() {
+        inliner.statements(block);
+    }
+
+    inliner.statements(&mut program.process);
+
+    // Functions that were never inlined stay, even if nothing calls them
+    let called = called_functions(program);
+
+    program.declarations.retain(|declaration| match declaration {
+        Declaration::Function { name, .. } => !inliner.inlined_functions.contains(name) || called.contains(name),
+        _ => true,
+    });
+
+    inliner.inlined
+}
+
+struct Callee {
+    parameters: Vec<Name>,
+    body: Vec<Statement>,
+}
+
+struct Inliner {
+    callees: HashMap<Name, Callee>,
+    // The highest version of every name in the program, see Inliner::fresh
+    versions: HashMap<(Vec<String>, String), usize>,
+    inlined: usize,
+    inlined_functions: HashSet<Name>,
+}
+
+impl Inliner {
+    // Every name of the program, so the locals of the inlined bodies can be renamed to unused ones
+    fn collect_names(&mut self, program: &Program) {
+        let mut names = vec![];
+
+        for declaration in &program.declarations {
+            match declaration {
+                Declaration::Variable { name, initializer, .. } => {
+                    names.push(name.clone());
+                    expression_names(initializer, &mut names);
+                }
+                Declaration::Input { initializer, .. } | Declaration::Output { initializer, .. } => {
+                    expression_names(initializer, &mut names);
+                }
+                Declaration::Parameter { name, fields } => {
+                    names.push(name.clone());
+
+                    for (_, specifier) in fields {
+                        expression_names(specifier, &mut names);
+                    }
+                }
+                Declaration::Buffer { name, size, initializer } => {
+                    names.push(name.clone());
+                    expression_names(size, &mut names);
+
+                    for statement in initializer.iter().flatten() {
+                        statement_names(statement, &mut names);
+                    }
+                }
+                Declaration::Function { name, parameters, body, .. } => {
+                    names.push(name.clone());
+                    names.extend(parameters.iter().cloned());
+
+                    for statement in body {
+                        statement_names(statement, &mut names);
+                    }
+                }
+                Declaration::Statement(statement) => statement_names(statement, &mut names),
+            }
+        }
+
+        for statement in program.block.iter().flatten().chain(program.process.iter()) {
+            statement_names(statement, &mut names);
+        }
+
+        for name in names {
+            let version = self.versions.entry((name.modules, name.name)).or_insert(1);
+            *version = (*version).max(name.version.unwrap_or(1));
+        }
+    }
+
+    // Versions the name like the hoisting does, after the last version that is already taken
+    fn fresh(&mut self, name: &Name) -> Name {
+        let version = self.versions.entry((name.modules.clone(), name.name.clone())).or_insert(1);
+        *version += 1;
+
+        Name { modules: name.modules.clone(), name: name.name.clone(), version: Some(*version) }
+    }
+
+    fn statements(&mut self, statements: &mut Vec<Statement>) {
+        let mut result = Vec::with_capacity(statements.len());
+
+        for statement in statements.drain(..) {
+            result.extend(self.statement(statement));
+        }
+
+        *statements = result;
+    }
+
+    // Nested statements are single statements, several ones are put into a block
+    fn nested(&mut self, statement: Statement) -> Statement {
+        let mut statements = self.statement(statement);
+
+        match statements.len() {
+            1 => statements.remove(0),
+            _ => Statement::Block(statements),
+        }
+    }
+
+    fn statement(&mut self, mut statement: Statement) -> Vec<Statement> {
+        match &mut statement {
+            Statement::Let { initializer: expression, .. }
+            | Statement::Assign { value: expression, .. }
+            | Statement::Expression(expression)
+            | Statement::Return(expression) => {
+                self.expression(expression);
+
+                // A call that is the whole expression can be replaced with its body followed by the
+                // statement itself, using the returned value
+                let (name, args) = match expression {
+                    Expression::Call { callee: Reference::Function(name), args } => (name.clone(), args.clone()),
+                    _ => return vec![statement],
+                };
+
+                let discards_value = matches!(statement, Statement::Expression(_));
+
+                if let Some((mut statements, value)) = self.inline_body(&name, &args, discards_value) {
+                    match (statement, value) {
+                        (Statement::Expression(_), Some(value)) if !is_pure(&value) => statements.push(Statement::Expression(value)),
+                        (Statement::Expression(_), _) => {}
+                        (Statement::Let { name, constant, .. }, Some(value)) => {
+                            statements.push(Statement::Let { name, constant, initializer: value });
+                        }
+                        (Statement::Assign { target, .. }, Some(value)) => statements.push(Statement::Assign { target, value }),
+                        (Statement::Return(_), Some(value)) => statements.push(Statement::Return(value)),
+                        _ => unreachable!("only calls whose value is discarded are inlined without a return value"),
+                    }
+
+                    return statements;
+                }
+            }
+            Statement::If { test, consequent, alternate } => {
+                self.expression(test);
+
+                **consequent = self.nested(*consequent.clone());
+
+                if let Some(alternate) = alternate {
+                    **alternate = self.nested(*alternate.clone());
+                }
+            }
+            Statement::For { from, to, body, .. } => {
+                self.expression(from);
+                self.expression(to);
+
+                **body = self.nested(*body.clone());
+            }
+            Statement::Block(statements) => self.statements(statements),
+        }
+
+        vec![statement]
+    }
+
+    // Inlines the calls in the arguments first, so the ones left are the calls that cannot be inlined
+    fn expression(&mut self, expression: &mut Expression) {
+        match expression {
+            Expression::Unary { operand, .. } => self.expression(operand),
+            Expression::Binary { lhs, rhs, .. } => {
+                self.expression(lhs);
+                self.expression(rhs);
+            }
+            Expression::Conditional { test, consequent, alternate } => {
+                self.expression(test);
+                self.expression(consequent);
+                self.expression(alternate);
+            }
+            Expression::Call { args, .. } => {
+                for arg in args.iter_mut() {
+                    self.expression(arg);
+                }
+            }
+            Expression::Number(_) | Expression::Reference(_) | Expression::Connected(_) => {}
+        }
+
+        if let Expression::Call { callee: Reference::Function(name), args } = expression {
+            if let Some(inlined) = self.inline_expression(name, args) {
+                self.inlined += 1;
+                self.inlined_functions.insert(name.clone());
+                *expression = inlined;
+            }
+        }
+    }
+
+    // A call anywhere in an expression can only be replaced with the returned expression. The arguments
+    // are substituted for the parameters, so neither they nor the body may have side effects, which could
+    // then happen in a different order. Arguments that are used more than once must be cheap to repeat
+    fn inline_expression(&self, name: &Name, args: &[Expression]) -> Option<Expression> {
+        let callee = self.callees.get(name)?;
+
+        let mut value = match callee.body.as_slice() {
+            [Statement::Return(value)] if is_pure(value) => value.clone(),
+            _ => return None,
+        };
+
+        if callee.parameters.len() != args.len() || !args.iter().all(is_pure) {
+            return None;
+        }
+
+        let mut substitutions = HashMap::new();
+
+        for (parameter, arg) in callee.parameters.iter().zip(args) {
+            let repeatable = matches!(arg, Expression::Number(_) | Expression::Reference(_));
+
+            if !repeatable && uses(&value, parameter) > 1 {
+                return None;
+            }
+
+            substitutions.insert(parameter.clone(), arg.clone());
+        }
+
+        substitute(&mut value, &substitutions);
+
+        Some(value)
+    }
+
+    // The body runs where the call was, after the arguments are stored in fresh variables. Returns
+    // the statements to run before the one with the call, and the value the call is replaced with
+    fn inline_body(&mut self, name: &Name, args: &[Expression], discards_value: bool) -> Option<(Vec<Statement>, Option<Expression>)> {
+        let callee = self.callees.get(name)?;
+
+        if callee.parameters.len() != args.len() {
+            return None;
+        }
+
+        // Only a return at the very end can be turned into a value
+        let (body, value) = match callee.body.split_last() {
+            Some((Statement::Return(value), body)) => (body, Some(value)),
+            _ if discards_value => (callee.body.as_slice(), None),
+            _ => return None,
+        };
+
+        if body.iter().any(contains_return) {
+            return None;
+        }
+
+        let mut body = body.to_vec();
+        let mut value = value.cloned();
+        let parameters = callee.parameters.clone();
+
+        let mut assigned = HashSet::new();
+        let mut declared = vec![];
+
+        for statement in &body {
+            local_declarations(statement, &mut assigned, &mut declared);
+        }
+
+        let mut statements = vec![];
+        let mut substitutions = HashMap::new();
+
+        for (parameter, arg) in parameters.iter().zip(args) {
+            // Values nothing in the body can change are used as they are
+            let unchanging = matches!(arg, Expression::Number(_) | Expression::Reference(Reference::Local(_) | Reference::Parameter(_) | Reference::Std(_)));
+
+            if unchanging && !assigned.contains(parameter) {
+                substitutions.insert(parameter.clone(), arg.clone());
+                continue;
+            }
+
+            let fresh = self.fresh(parameter);
+            statements.push(Statement::Let { name: fresh.clone(), constant: false, initializer: arg.clone() });
+            substitutions.insert(parameter.clone(), Expression::Reference(Reference::Local(fresh)));
+        }
+
+        for local in declared {
+            if let Entry::Vacant(entry) = substitutions.entry(local) {
+                let fresh = self.fresh(entry.key());
+                entry.insert(Expression::Reference(Reference::Local(fresh)));
+            }
+        }
+
+        for statement in body.iter_mut() {
+            substitute_statement(statement, &substitutions);
+        }
+
+        if let Some(value) = value.as_mut() {
+            substitute(value, &substitutions);
+        }
+
+        statements.extend(body);
+
+        self.inlined += 1;
+        self.inlined_functions.insert(name.clone());
+
+        Some((statements, value))
+    }
+}
+
+// Replaces the locals in "substitutions". Locals that are declared or assigned in the body are
+// only ever replaced with other locals
+fn substitute_statement(statement: &mut Statement, substitutions: &HashMap<Name, Expression>) {
+    let renamed = |name: &mut Name| {
+        if let Some(Expression::Reference(Reference::Local(local))) = substitutions.get(name) {
+            *name = local.clone();
+        }
+    };
+
+    match statement {
+        Statement::Let { name, initializer, .. } => {
+            renamed(name);
+            substitute(initializer, substitutions);
+        }
+        Statement::Assign { target, value } => {
+            if let Reference::Local(name) = target {
+                renamed(name);
+            }
+
+            substitute(value, substitutions);
+        }
+        Statement::Expression(expression) | Statement::Return(expression) => substitute(expression, substitutions),
+        Statement::If { test, consequent, alternate } => {
+            substitute(test, substitutions);
+            substitute_statement(consequent, substitutions);
+
+            if let Some(alternate) = alternate {
+                substitute_statement(alternate, substitutions);
+            }
+        }
+        Statement::For { variable, from, to, body } => {
+            renamed(variable);
+            substitute(from, substitutions);
+            substitute(to, substitutions);
+            substitute_statement(body, substitutions);
+        }
+        Statement::Block(statements) => {
+            for statement in statements.iter_mut() {
+                substitute_statement(statement, substitutions);
+            }
+        }
+    }
+}
+
+fn substitute(expression: &mut Expression, substitutions: &HashMap<Name, Expression>) {
+    match expression {
+        Expression::Reference(Reference::Local(name)) => {
+            if let Some(substitution) = substitutions.get(name) {
+                *expression = substitution.clone();
+            }
+        }
+        Expression::Unary { operand, .. } => substitute(operand, substitutions),
+        Expression::Binary { lhs, rhs, .. } => {
+            substitute(lhs, substitutions);
+            substitute(rhs, substitutions);
+        }
+        Expression::Conditional { test, consequent, alternate } => {
+            substitute(test, substitutions);
+            substitute(consequent, substitutions);
+            substitute(alternate, substitutions);
+        }
+        Expression::Call { args, .. } => {
+            for arg in args.iter_mut() {
+                substitute(arg, substitutions);
+            }
+        }
+        Expression::Number(_) | Expression::Reference(_) | Expression::Connected(_) => {}
+    }
+}
+
+fn uses(expression: &Expression, local: &Name) -> usize {
+    match expression {
+        Expression::Reference(Reference::Local(name)) => (name == local) as usize,
+        Expression::Unary { operand, .. } => uses(operand, local),
+        Expression::Binary { lhs, rhs, .. } => uses(lhs, local) + uses(rhs, local),
+        Expression::Conditional { test, consequent, alternate } => {
+            uses(test, local) + uses(consequent, local) + uses(alternate, local)
+        }
+        Expression::Call { args, .. } => args.iter().map(|arg| uses(arg, local)).sum(),
+        Expression::Number(_) | Expression::Reference(_) | Expression::Connected(_) => 0,
+    }
+}
+
+fn contains_return(statement: &Statement) -> bool {
+    match statement {
+        Statement::Return(_) => true,
+        Statement::If { consequent, alternate, .. } => {
+            contains_return(consequent) || alternate.as_deref().is_some_and(contains_return)
+        }
+        Statement::For { body, .. } => contains_return(body),
+        Statement::Block(statements) => statements.iter().any(contains_return),
+        Statement::Let { .. } | Statement::Assign { .. } | Statement::Expression(_) => false,
+    }
+}
+
+// The locals a body assigns and the ones it declares, loop variables included
+fn local_declarations(statement: &Statement, assigned: &mut HashSet<Name>, declared: &mut Vec<Name>) {
+    match statement {
+        Statement::Let { name, .. } => declared.push(name.clone()),
+        Statement::Assign { target: Reference::Local(name), .. } => {
+            assigned.insert(name.clone());
+        }
+        Statement::If { consequent, alternate, .. } => {
+            local_declarations(consequent, assigned, declared);
+
+            if let Some(alternate) = alternate {
+                local_declarations(alternate, assigned, declared);
+            }
+        }
+        Statement::For { variable, body, .. } => {
+            declared.push(variable.clone());
+            local_declarations(body, assigned, declared);
+        }
+        Statement::Block(statements) => {
+            for statement in statements {
+                local_declarations(statement, assigned, declared);
+            }
+        }
+        Statement::Assign { .. } | Statement::Expression(_) | Statement::Return(_) => {}
+    }
+}
+
+fn statements_size(statements: &[Statement]) -> usize {
+    statements.iter().map(statement_size).sum()
+}
+
+fn statement_size(statement: &Statement) -> usize {
+    1 + match statement {
+        Statement::Let { initializer: expression, .. }
+        | Statement::Assign { value: expression, .. }
+        | Statement::Expression(expression)
+        | Statement::Return(expression) => expression_size(expression),
+        Statement::If { test, consequent, alternate } => {
+            expression_size(test) + statement_size(consequent) + alternate.as_deref().map_or(0, statement_size)
+        }
+        Statement::For { from, to, body, .. } => expression_size(from) + expression_size(to) + statement_size(body),
+        Statement::Block(statements) => statements_size(statements),
+    }
+}
+
+fn expression_size(expression: &Expression) -> usize {
+    1 + match expression {
+        Expression::Unary { operand, .. } => expression_size(operand),
+        Expression::Binary { lhs, rhs, .. } => expression_size(lhs) + expression_size(rhs),
+        Expression::Conditional { test, consequent, alternate } => {
+            expression_size(test) + expression_size(consequent) + expression_size(alternate)
+        }
+        Expression::Call { args, .. } => args.iter().map(expression_size).sum(),
+        Expression::Number(_) | Expression::Reference(_) | Expression::Connected(_) => 0,
+    }
+}
+
+fn statement_names(statement: &Statement, names: &mut Vec<Name>) {
+    match statement {
+        Statement::Let { name, initializer, .. } => {
+            names.push(name.clone());
+            expression_names(initializer, names);
+        }
+        Statement::Assign { target, value } => {
+            reference_name(target, names);
+            expression_names(value, names);
+        }
+        Statement::Expression(expression) | Statement::Return(expression) => expression_names(expression, names),
+        Statement::If { test, consequent, alternate } => {
+            expression_names(test, names);
+            statement_names(consequent, names);
+
+            if let Some(alternate) = alternate {
+                statement_names(alternate, names);
+            }
+        }
+        Statement::For { variable, from, to, body } => {
+            names.push(variable.clone());
+            expression_names(from, names);
+            expression_names(to, names);
+            statement_names(body, names);
+        }
+        Statement::Block(statements) => {
+            for statement in statements {
+                statement_names(statement, names);
+            }
+        }
+    }
+}
+
+fn expression_names(expression: &Expression, names: &mut Vec<Name>) {
+    match expression {
+        Expression::Reference(reference) => reference_name(reference, names),
+        Expression::Unary { operand, .. } => expression_names(operand, names),
+        Expression::Binary { lhs, rhs, .. } => {
+            expression_names(lhs, names);
+            expression_names(rhs, names);
+        }
+        Expression::Conditional { test, consequent, alternate } => {
+            expression_names(test, names);
+            expression_names(consequent, names);
+            expression_names(alternate, names);
+        }
+        Expression::Call { callee, args } => {
+            reference_name(callee, names);
+
+            for arg in args {
+                expression_names(arg, names);
+            }
+        }
+        Expression::Number(_) | Expression::Connected(_) => {}
+    }
+}
+
+fn reference_name(reference: &Reference, names: &mut Vec<Name>) {
+    match reference {
+        Reference::Local(name) | Reference::State(name) | Reference::Parameter(name) | Reference::Function(name) => {
+            names.push(name.clone());
+        }
+        Reference::Input(_) | Reference::Output(_) | Reference::Std(_) => {}
+    }
+}
+
+// Functions called anywhere in the program, including the bodies of other functions
+fn called_functions(program: &Program) -> HashSet<Name> {
+    let mut names = vec![];
+
+    for declaration in &program.declarations {
+        match declaration {
+            Declaration::Variable { initializer, .. }
+            | Declaration::Input { initializer, .. }
+            | Declaration::Output { initializer, .. } => expression_names(initializer, &mut names),
+            Declaration::Parameter { fields, .. } => {
+                for (_, specifier) in fields {
+                    expression_names(specifier, &mut names);
+                }
+            }
+            Declaration::Buffer { size, initializer, .. } => {
+                expression_names(size, &mut names);
+
+                for statement in initializer.iter().flatten() {
+                    statement_names(statement, &mut names);
+                }
+            }
+            Declaration::Function { body: statements, .. } => {
+                for statement in statements {
+                    statement_names(statement, &mut names);
+                }
+            }
+            Declaration::Statement(statement) => statement_names(statement, &mut names),
+        }
+    }
+
+    for statement in program.block.iter().flatten().chain(program.process.iter()) {
+        statement_names(statement, &mut names);
+    }
+
+    names.into_iter().collect()
+}
+
+// Functions by index, each with the functions its body calls
+fn call_graph(program: &Program) -> Vec<(usize, Name, Vec<Name>)> {
+    program.declarations.iter().enumerate().filter_map(|(index, declaration)| match declaration {
+        Declaration::Function { name, body, .. } => {
+            let mut names = vec![];
+
+            for statement in body {
+                statement_names(statement, &mut names);
+            }
+
+            Some((index, name.clone(), names))
+        }
+        _ => None,
+    }).collect()
+}
+
+// Functions that call themselves, directly or through other functions
+fn recursive_functions(program: &Program) -> HashSet<Name> {
+    let graph = call_graph(program);
+
+    graph.iter().filter(|(_, function, _)| {
+        let mut visited = HashSet::new();
+        let mut pending: Vec<&Name> = vec![function];
+
+        while let Some(name) = pending.pop() {
+            for (_, _, calls) in graph.iter().filter(|(_, caller, _)| caller == name) {
+                for call in calls {
+                    if call == function {
+                        return true;
+                    }
+
+                    if visited.insert(call) {
+                        pending.push(call);
+                    }
+                }
+            }
+        }
+
+        false
+    }).map(|(_, function, _)| function.clone()).collect()
+}
+
+// Indexes of the function declarations, every function after the ones it calls
+fn callee_order(program: &Program) -> Vec<usize> {
+    fn visit(index: usize, graph: &[(usize, Name, Vec<Name>)], visited: &mut HashSet<usize>, order: &mut Vec<usize>) {
+        if !visited.insert(index) {
+            return;
+        }
+
+        let (_, _, calls) = graph.iter().find(|(function, _, _)| *function == index).unwrap();
+
+        for (callee, _, _) in graph.iter().filter(|(_, name, _)| calls.contains(name)) {
+            visit(*callee, graph, visited, order);
+        }
+
+        order.push(index);
+    }
+
+    let graph = call_graph(program);
+    let mut visited = HashSet::new();
+    let mut order = vec![];
+
+    for (index, _, _) in &graph {
+        visit(*index, &graph, &mut visited, &mut order);
+    }
+
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ir::inline::inline_functions;
+    use crate::ir::lower;
+
+    #[test]
+    fn test_inline_expression() {
+        let mut program = lower("
+            fn clamp(x, a, b) {
+                return min(max(x, a), b);
+            }
+
+            fn square(x) {
+                return x * x;
+            }
+
+            let y = 0;
+
+            process {
+                y = clamp(y * 2, 0, 1);
+                y = square(y) + square(y + 1);
+            }
+            ");
+
+        assert_eq!(inline_functions(&mut program), 2);
+
+        let code = program.to_code_string();
+
+        assert!(code.contains("y = std.min(std.max((y * 2), 0), 1);"));
+        // "y + 1" would be computed twice
+        assert!(code.contains("y = ((y * y) + square((y + 1)));"));
+        assert!(!code.contains("fn clamp"));
+        assert!(code.contains("fn square"));
+    }
+
+    #[test]
+    fn test_inline_body() {
+        let mut program = lower("
+            let state = 0;
+
+            fn smooth(signal) {
+                let next = 0.5 * (state - signal) + signal;
+                state = next;
+                return next;
+            }
+
+            let next = 0;
+
+            process {
+                next = smooth(rand());
+                next = smooth(next);
+            }
+            ");
+
+        assert_eq!(inline_functions(&mut program), 2);
+
+        let code = program.to_code_string();
+
+        // The locals of the inlined bodies must not clash with each other or with the state
+        assert!(code.contains("let signal'2 = std.rand();\n    let next'2 = ((0.5 * (state - signal'2)) + signal'2);\n    state = next'2;\n    next = next'2;"));
+        assert!(code.contains("let signal'3 = next;\n    let next'3 = ((0.5 * (state - signal'3)) + signal'3);\n    state = next'3;\n    next = next'3;"));
+        assert!(!code.contains("fn smooth"));
+    }
+
+    #[test]
+    fn test_inline_negated_argument() {
+        let mut program = lower("
+            fn neg(v) {
+                return -v;
+            }
+
+            let y = 0;
+
+            process {
+                y = neg(-y) + neg(-1);
+            }
+            ");
+
+        assert_eq!(inline_functions(&mut program), 2);
+
+        assert!(program.to_code_string().contains("y = (-(-(y)) + -(-(1)));"));
+    }
+
+    #[test]
+    fn test_inline_skips_recursion_noinline_and_big_functions() {
+        let mut program = lower("
+            fn countdown(n) {
+                return n > 0 ? countdown(n - 1) : 0;
+            }
+
+            @noinline fn half(x) {
+                return x / 2;
+            }
+
+            fn big(x) {
+                return x + x + x + x + x + x + x + x + x + x + x + x + x + x + x + x + x + x;
+            }
+
+            let y = 0;
+
+            process {
+                y = countdown(y) + half(y) + big(y);
+            }
+            ");
+
+        assert_eq!(inline_functions(&mut program), 0);
+
+        let code = program.to_code_string();
+
+        assert!(code.contains("@noinline fn half(x)"));
+        assert!(code.contains("y = ((countdown(y) + half(y)) + big(y));"));
+    }
+}
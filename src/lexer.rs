@@ -36,12 +36,19 @@ impl Lexer {
                 |chars: &str, current: u32| full_pattern_t(TokenType::BUFFER, Regex::new(r"^buffer\b").unwrap(), chars, current),
                 |chars: &str, current: u32| full_pattern_t(TokenType::IF, Regex::new(r"^if\b").unwrap(), chars, current),
                 |chars: &str, current: u32| full_pattern_t(TokenType::ELSE, Regex::new(r"^else\b").unwrap(), chars, current),
+                |chars: &str, current: u32| full_pattern_t(TokenType::FOR, Regex::new(r"^for\b").unwrap(), chars, current),
                 |chars: &str, current: u32| match_word_t(TokenType::BUFI, "|i|".to_string(), chars, current),
                 |chars: &str, current: u32| match_word_t(TokenType::EQ, "==".to_string(), chars, current),
                 |chars: &str, current: u32| match_word_t(TokenType::NE, "!=".to_string(), chars, current),
                 |chars: &str, current: u32| match_word_t(TokenType::GE, ">=".to_string(), chars, current),
                 |chars: &str, current: u32| match_word_t(TokenType::LE, "<=".to_string(), chars, current),
+                |chars: &str, current: u32| match_word_t(TokenType::AND, "&&".to_string(), chars, current),
+                |chars: &str, current: u32| match_word_t(TokenType::OR, "||".to_string(), chars, current),
                 |chars: &str, current: u32| match_word_t(TokenType::CABLE, "->".to_string(), chars, current),
+                |chars: &str, current: u32| match_word_t(TokenType::PLUSDEF, "+=".to_string(), chars, current),
+                |chars: &str, current: u32| match_word_t(TokenType::MINUSDEF, "-=".to_string(), chars, current),
+                |chars: &str, current: u32| match_word_t(TokenType::MULDEF, "*=".to_string(), chars, current),
+                |chars: &str, current: u32| match_word_t(TokenType::DIVDEF, "/=".to_string(), chars, current),
                 |chars: &str, current: u32| match_word_t(TokenType::LCURLY, "{".to_string(), chars, current),
                 |chars: &str, current: u32| match_word_t(TokenType::RCURLY, "}".to_string(), chars, current),
                 |chars: &str, current: u32| match_word_t(TokenType::LPAREN, "(".to_string(), chars, current),
@@ -50,16 +57,22 @@ impl Lexer {
                 |chars: &str, current: u32| match_word_t(TokenType::RSQUARE, "]".to_string(), chars, current),
                 |chars: &str, current: u32| match_word_t(TokenType::SEMI, ";".to_string(), chars, current),
                 |chars: &str, current: u32| match_word_t(TokenType::COLON, ":".to_string(), chars, current),
+                |chars: &str, current: u32| match_word_t(TokenType::QUESTION, "?".to_string(), chars, current),
+                |chars: &str, current: u32| match_word_t(TokenType::RANGE, "..".to_string(), chars, current),
                 |chars: &str, current: u32| match_word_t(TokenType::DOT, ".".to_string(), chars, current),
                 |chars: &str, current: u32| match_word_t(TokenType::COMMA, ",".to_string(), chars, current),
+                |chars: &str, current: u32| match_word_t(TokenType::AT, "@".to_string(), chars, current),
                 |chars: &str, current: u32| match_word_t(TokenType::DEF, "=".to_string(), chars, current),
                 |chars: &str, current: u32| match_word_t(TokenType::DIV, "/".to_string(), chars, current),
                 |chars: &str, current: u32| match_word_t(TokenType::MUL, "*".to_string(), chars, current),
+                |chars: &str, current: u32| match_word_t(TokenType::MOD, "%".to_string(), chars, current),
+                |chars: &str, current: u32| match_word_t(TokenType::POW, "^".to_string(), chars, current),
                 |chars: &str, current: u32| match_word_t(TokenType::MINUS, "-".to_string(), chars, current),
                 |chars: &str, current: u32| match_word_t(TokenType::PLUS, "+".to_string(), chars, current),
                 |chars: &str, current: u32| match_word_t(TokenType::GT, ">".to_string(), chars, current),
                 |chars: &str, current: u32| match_word_t(TokenType::LT, "<".to_string(), chars, current),
-                |chars: &str, current: u32| full_pattern_t(TokenType::NUMBER, Regex::new(r"^[+-]?([0-9]*[.])?[0-9]+").unwrap(), chars, current),
+                |chars: &str, current: u32| match_word_t(TokenType::NOT, "!".to_string(), chars, current),
+                |chars: &str, current: u32| full_pattern_t(TokenType::NUMBER, Regex::new(r"^[+-]?(0[xX][0-9a-zA-Z_]*|[0-9][0-9_]*([.][0-9][0-9_]*)?([eE][+-]?[0-9_]*)?([a-zA-Z][a-zA-Z0-9_]*)?)").unwrap(), chars, current),
                 |chars: &str, current: u32| full_pattern_t(TokenType::STRING, Regex::new(r#"^"([^"\\]|\\.)*""#).unwrap(), chars, current),
                 |chars: &str, current: u32| full_pattern_t(TokenType::ID, Regex::new(r"^[_$]*[_$a-zA-Z][$_a-zA-Z0-9]*").unwrap(), chars, current),
             ]
@@ -67,6 +80,16 @@ impl Lexer {
     }
 
     pub fn tokenize(&self, input: String) -> Vec<Token> {
+        let mut tokens = self.tokenize_lossless(input);
+
+        tokens.retain(|t| !t.is_trivia());
+
+        tokens
+    }
+
+    // Keeps whitespace and comments as WS and COMMENT tokens, so concatenating the literals
+    // of the returned tokens gives back the input up to the first unknown character
+    pub fn tokenize_lossless(&self, input: String) -> Vec<Token> {
         let mut tokens: Vec<Token> = Vec::new();
         let mut position = Position { line: 1, column: 1, start: 0, end: 0 };
 
@@ -78,6 +101,14 @@ impl Lexer {
             for tokenizer in &self.tokenizers {
                 let (token, consumed, skipped_lines, skipped_columns) = tokenizer(&orig_str, position.start);
 
+                // Whitespace is consumed without a token, it is kept as a WS token spanning what was skipped
+                if token.is_none() && consumed > 0 {
+                    let literal = orig_str.get(position.start as usize..(position.start + consumed) as usize).unwrap_or_default();
+                    let end = position.start + consumed;
+
+                    tokens.push(Token::new(TokenType::WS, literal.to_string(), Position { end, ..position }));
+                }
+
                 // TODO: Some bullshit here
                 if consumed > 0 {
                     tokenized = true;
@@ -104,16 +135,12 @@ impl Lexer {
             if !tokenized {
                 let mut t = Token::new(TokenType::UNKNOWN, "".to_string(), position.clone());
 
-                let char = input.chars().nth(position.start as usize);
-
-                if char.is_none() {
-                    println!("{:#?}", position);
-                    panic!("Unexpected who knows what happened")
-                }
+                // Positions are byte offsets, so the character is looked up by slicing, not with chars().nth()
+                let char = input.get(position.start as usize..).and_then(|rest| rest.chars().next());
 
-                t.literal = input.chars().nth(position.start as usize).unwrap().to_string();
+                t.literal = char.map(|char| char.to_string()).unwrap_or_default();
                 t.position.start = position.start;
-                t.position.end = position.start + 1;
+                t.position.end = position.start + char.map_or(1, |char| char.len_utf8() as u32);
                 t.position.column += 1;
                 position.start += 1;
 
@@ -124,14 +151,6 @@ impl Lexer {
 
         tokens.push(Token::new(TokenType::EOF, "".to_string(), position));
 
-        for token in &tokens {
-            if token.token_type == TokenType::COMMENT {
-                continue;
-            }
-        }
-
-        tokens.retain(|t| t.token_type != TokenType::COMMENT);
-
         tokens
     }
 }
@@ -210,4 +229,69 @@ mod tests {
         assert_eq!(tokens[16].token_type, super::token_type::TokenType::RCURLY);
         assert_eq!(tokens[17].token_type, super::token_type::TokenType::EOF);
     }
+
+    #[test]
+    fn test_logical_operators() {
+        let lexer = super::Lexer::new();
+        let tokens = lexer.tokenize("!a && b || c != d".to_string());
+
+        assert_eq!(tokens.len(), 9);
+        assert_eq!(tokens[0].token_type, super::token_type::TokenType::NOT);
+        assert_eq!(tokens[1].token_type, super::token_type::TokenType::ID);
+        assert_eq!(tokens[2].token_type, super::token_type::TokenType::AND);
+        assert_eq!(tokens[3].token_type, super::token_type::TokenType::ID);
+        assert_eq!(tokens[4].token_type, super::token_type::TokenType::OR);
+        assert_eq!(tokens[5].token_type, super::token_type::TokenType::ID);
+        assert_eq!(tokens[6].token_type, super::token_type::TokenType::NE);
+        assert_eq!(tokens[7].token_type, super::token_type::TokenType::ID);
+        assert_eq!(tokens[8].token_type, super::token_type::TokenType::EOF);
+    }
+
+    #[test]
+    fn test_for_range() {
+        let lexer = super::Lexer::new();
+        let tokens = lexer.tokenize("for i in 0..N".to_string());
+
+        assert_eq!(tokens.len(), 7);
+        assert_eq!(tokens[0].token_type, super::token_type::TokenType::FOR);
+        assert_eq!(tokens[1].token_type, super::token_type::TokenType::ID);
+        assert_eq!(tokens[2].token_type, super::token_type::TokenType::ID);
+        assert_eq!(tokens[3].token_type, super::token_type::TokenType::NUMBER);
+        assert_eq!(tokens[4].token_type, super::token_type::TokenType::RANGE);
+        assert_eq!(tokens[5].token_type, super::token_type::TokenType::ID);
+        assert_eq!(tokens[6].token_type, super::token_type::TokenType::EOF);
+    }
+
+    #[test]
+    fn test_compound_assignment() {
+        let lexer = super::Lexer::new();
+        let tokens = lexer.tokenize("a += 1; a -= 1; a *= 2; a /= 2;".to_string());
+
+        assert_eq!(tokens.len(), 17);
+        assert_eq!(tokens[1].token_type, super::token_type::TokenType::PLUSDEF);
+        assert_eq!(tokens[2].token_type, super::token_type::TokenType::NUMBER);
+        assert_eq!(tokens[5].token_type, super::token_type::TokenType::MINUSDEF);
+        assert_eq!(tokens[6].token_type, super::token_type::TokenType::NUMBER);
+        assert_eq!(tokens[9].token_type, super::token_type::TokenType::MULDEF);
+        assert_eq!(tokens[13].token_type, super::token_type::TokenType::DIVDEF);
+    }
+
+    #[test]
+    fn test_tokenize_lossless() {
+        let lexer = super::Lexer::new();
+        let input = "let s = 0.999; // feedback\n\n/* block\ncomment */ s = 1;";
+        let tokens = lexer.tokenize_lossless(input.to_string());
+
+        let source: String = tokens.iter().map(|token| token.literal.as_str()).collect();
+        assert_eq!(source, input);
+
+        assert_eq!(tokens[1].token_type, super::token_type::TokenType::WS);
+        assert_eq!(tokens[9].token_type, super::token_type::TokenType::COMMENT);
+        assert_eq!(tokens[9].literal, "// feedback");
+        assert_eq!(tokens[11].literal, "/* block\ncomment */");
+        assert_eq!((tokens[11].position.start, tokens[11].position.end), (28, 47));
+
+        let significant: Vec<_> = tokens.into_iter().filter(|token| !token.is_trivia()).collect();
+        assert_eq!(significant, lexer.tokenize(input.to_string()));
+    }
 }
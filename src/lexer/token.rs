@@ -36,6 +36,11 @@ impl Token {
         }
     }
 
+    // Whitespace and comments, only present in the output of Lexer::tokenize_lossless
+    pub fn is_trivia(&self) -> bool {
+        matches!(self.token_type, TokenType::WS | TokenType::COMMENT)
+    }
+
     pub fn to_string(&self) -> String {
         format!("<{:?}:{} [{:?}]>", self.token_type, self.literal, self.position)
     }
@@ -8,22 +8,34 @@ pub enum TokenType {
     RSQUARE,
     SEMI,
     COLON,
+    QUESTION,
     DOT,
+    RANGE,
     COMMA,
+    AT,
     NUMBER,
     STRING,
 
     DEF,
+    PLUSDEF,
+    MINUSDEF,
+    MULDEF,
+    DIVDEF,
     DIV,
     MINUS,
     PLUS,
     MUL,
+    MOD,
+    POW,
     GT,
     LT,
     GE,
     LE,
     EQ,
     NE,
+    AND,
+    OR,
+    NOT,
 
     PROCESS,
     RETURN,
@@ -60,5 +72,7 @@ pub enum TokenType {
     IF,
     ELSE,
 
+    FOR,
+
     CONNECTED,
 }
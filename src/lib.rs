@@ -1,10 +1,15 @@
+use std::collections::HashMap;
 use std::error::Error;
 use std::path::Path;
 use indexmap::IndexMap;
 use crate::codegen::{CodeGenerator};
+use crate::diagnostic::{Diagnostic, IMPORT_CYCLE, MODULE_NOT_FOUND};
+use crate::emit::Stage;
 use crate::ir::{IR, IRResult};
+use crate::ir::fold::{self, FoldOptions};
+use crate::ir::inline;
 
-use crate::lexer::{Lexer, token::Token};
+use crate::lexer::{Lexer, token::{Position, Token}};
 use crate::module_data::ModuleData;
 use crate::module_loader::{FileLoader, StubFileLoader};
 use crate::parser::ast::{AST};
@@ -14,10 +19,15 @@ use crate::symbol_table::SymbolTable;
 
 use colored::Colorize;
 
+pub mod diagnostic;
 pub mod lexer;
 pub mod parser;
 pub mod symbol_table;
 pub mod semantic;
+pub mod lint;
+pub mod formatter;
+pub mod lsp;
+pub mod emit;
 
 pub mod module_data;
 
@@ -28,12 +38,33 @@ pub mod codegen;
 
 pub struct Mephisto<FL: FileLoader> {
     loader: FL,
+
+    // Sources of the loaded modules by module key, used to render diagnostics
+    sources: HashMap<String, String>,
+
+    // Warnings of the last compilation, reported whether it succeeded or not
+    warnings: Vec<Diagnostic>,
+
+    fold_options: FoldOptions,
+
+    // Print what the optimizing passes did along with the progress
+    verbose: bool,
+}
+
+pub struct CheckResult {
+    // Key of the main module, None when loading it failed
+    pub main_module: Option<String>,
+    pub modules: IndexMap<String, ModuleData>,
+    pub diagnostics: Vec<Diagnostic>,
 }
 
 #[derive(Debug)]
 struct Context {
     loaded_modules: Box<Vec<String>>,
     modules: Box<IndexMap<String, ModuleData>>,
+
+    // Modules that are being processed, from the main module down to the current one
+    import_chain: Vec<String>,
 }
 
 impl Mephisto<StubFileLoader> {
@@ -47,11 +78,11 @@ impl Mephisto<StubFileLoader> {
         parser.parse(tokens)
     }
 
-    pub fn create_symbol_table(ast: &mut AST) -> Result<SymbolTable, Vec<String>> {
+    pub fn create_symbol_table(ast: &mut AST) -> Result<SymbolTable, Vec<Diagnostic>> {
         SymbolTable::from_ast(ast)
     }
 
-    pub fn create_ir(modules: &mut Box<IndexMap<String, ModuleData>>, main_module: String) -> Result<ir::IRResult, Vec<String>> {
+    pub fn create_ir(modules: &mut Box<IndexMap<String, ModuleData>>, main_module: String) -> Result<ir::IRResult, Vec<Diagnostic>> {
         let mut ir = IR::new();
         ir.create(&mut *modules, main_module)
     }
@@ -61,139 +92,307 @@ impl<T: FileLoader> Mephisto<T> {
     pub fn new(loader: T) -> Self {
         Mephisto {
             loader,
+            sources: HashMap::new(),
+            warnings: Vec::new(),
+            fold_options: FoldOptions::default(),
+            verbose: false,
         }
     }
 
-    pub fn validate_semantics(&self, modules: &mut IndexMap<String, ModuleData>) -> Result<String, Vec<String>> {
+    pub fn set_fold_options(&mut self, options: FoldOptions) {
+        self.fold_options = options;
+    }
+
+    pub fn set_verbose(&mut self, verbose: bool) {
+        self.verbose = verbose;
+    }
+
+    pub fn source(&self, module: &str) -> Option<&str> {
+        self.sources.get(module).map(|source| source.as_str())
+    }
+
+    pub fn warnings(&self) -> &[Diagnostic] {
+        &self.warnings
+    }
+
+    pub fn validate_semantics(&self, modules: &mut IndexMap<String, ModuleData>) -> Result<String, Vec<Diagnostic>> {
         let mut semantic = SemanticAnalyzer::new();
         semantic.validate_semantics(modules)
     }
 
-    pub fn compile(&mut self, main_module_path: &str, codegen: Box<dyn CodeGenerator>) -> Result<String, Vec<String>> {
-        let modules: IndexMap<String, ModuleData> = IndexMap::new();
+    // Runs the checks of a compilation without generating code or printing progress, for tools
+    // that only need the diagnostics and the analyzed modules
+    pub fn check(&mut self, main_module_path: &str) -> CheckResult {
+        self.warnings.clear();
 
-        let mut context = Context {
-            loaded_modules: Box::new(Vec::new()),
-            modules: Box::new(modules),
+        let (main_module, mut modules) = self.load_modules(main_module_path);
+
+        let mut diagnostics = module_errors(&modules);
+
+        match main_module {
+            Ok(_) if diagnostics.is_empty() => {
+                match self.validate_semantics(&mut modules) {
+                    Ok(_) => self.warnings = lint::lint(&modules),
+                    Err(errors) => diagnostics.extend(errors),
+                }
+            }
+            Ok(_) => {}
+            Err(ref errors) => diagnostics.extend(errors.iter().cloned()),
+        }
+
+        CheckResult {
+            main_module: main_module.ok(),
+            modules,
+            diagnostics,
+        }
+    }
+
+    pub fn compile(&mut self, main_module_path: &str, codegen: Box<dyn CodeGenerator>) -> Result<String, Vec<Diagnostic>> {
+        let ir_result = self.lower(main_module_path, true)?;
+
+        println!("{}", "Generating code...".blue());
+
+        let code = self.generate_code(ir_result, codegen);
+
+        code
+    }
+
+    // Dumps an intermediate stage of the compilation. Module keys are shown relative to the
+    // directory of the main module, so the output does not depend on where the project lives
+    pub fn emit(&mut self, main_module_path: &str, stage: Stage) -> Result<String, Vec<Diagnostic>> {
+        if stage == Stage::Ir {
+            let ir_result = self.lower(main_module_path, false)?;
+
+            return Ok(ir_result.program.to_code_string());
+        }
+
+        self.warnings.clear();
+
+        let (main_module_path, modules) = self.load_modules(main_module_path);
+        let main_module_path = main_module_path?;
+
+        let errors = module_errors(&modules);
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        let base = Path::new(&main_module_path).parent().unwrap_or(Path::new(""));
+
+        let display_key = |key: &str| match Path::new(key).strip_prefix(base) {
+            Ok(relative) if !base.as_os_str().is_empty() => relative.display().to_string(),
+            _ => key.to_string(),
         };
 
-        let p: &Path = Path::new(main_module_path);
+        let output = match stage {
+            Stage::Tokens => modules.keys()
+                .map(|key| {
+                    let tokens = Mephisto::tokenize(self.sources.get(key).cloned().unwrap_or_default());
+                    format!("// {}\n{}", display_key(key), emit::tokens_to_string(&tokens))
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+            Stage::Ast => {
+                let modules: serde_json::Map<String, serde_json::Value> = modules.iter()
+                    .map(|(key, module)| (display_key(key), serde_json::to_value(&module.ast).unwrap()))
+                    .collect();
+
+                serde_json::to_string_pretty(&modules).unwrap() + "\n"
+            }
+            Stage::Symbols => modules.iter()
+                .map(|(key, module)| format!("// {}\n{}", display_key(key), emit::symbol_table_to_string(&module.symbol_table, &display_key)))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            Stage::Ir => unreachable!("the IR is emitted before the modules are loaded"),
+        };
 
-        // We want just to take the directory of the main module
-        let current_dir = p.parent().unwrap_or(Path::new("."));
-        let main_module_path = p.file_name().unwrap().to_str().unwrap();
+        Ok(output)
+    }
 
-        self.process_module(main_module_path, &mut context, Some(current_dir), p)?; // Recursively process all modules
+    // Runs the compilation up to the optimized IR, printing the progress if asked to
+    fn lower(&mut self, main_module_path: &str, report_progress: bool) -> Result<IRResult, Vec<Diagnostic>> {
+        self.warnings.clear();
+
+        let (main_module_path, modules) = self.load_modules(main_module_path);
+        let main_module_path = &main_module_path?;
 
         // println!("Modules: {:#?}", context);
 
         // For each module, check for errors
-        let mut errors = Vec::new();
-        for (path, module) in context.modules.iter() {
-            if module.errors.len() > 0 {
-                errors.extend(module.errors.iter().map(|e| format!("{}: {}", path, e)));
-            }
-        }
+        let mut errors = module_errors(&modules);
 
         if errors.len() > 0 {
             return Err(errors);
         }
 
-        let mut modules = context.modules;
+        let mut modules = modules;
 
         let main_module = modules.get(main_module_path);
 
         if main_module.is_none() {
-            return Err(vec![format!("Main module {} not found", main_module_path)]);
+            return Err(vec![Diagnostic::error(MODULE_NOT_FOUND, format!("Main module {} not found", main_module_path))]);
         }
 
-        println!("{}", "Validating semantics...".blue());
+        if report_progress {
+            println!("{}", "Validating semantics...".blue());
+        }
 
         self.validate_semantics(&mut modules)?;
 
+        self.warnings = lint::lint(&modules);
+
         for (path, module) in modules.iter() {
             if module.errors.len() > 0 {
-                errors.extend(module.errors.iter().map(|e| format!("{}: {}", path, e)));
+                errors.extend(module.errors.iter().map(|e| e.clone().with_file(path)));
             }
         }
 
-        let main_module = modules.get_mut(main_module_path).unwrap();
-
-        let mut code = main_module.ast.to_code_string();
-
-        code.push_str("\n\n");
-
-        println!("{}", "Creating IR...".blue());
+        if report_progress {
+            println!("{}", "Creating IR...".blue());
+        }
 
-        // println!("Code: {}", code);
         let mut ir = IR::new();
-        let ir_result = ir.create(&mut modules, main_module_path.to_string())?;
+        let mut ir_result = ir.create(&mut modules, main_module_path.to_string())?;
 
-        // println!("IR: {:#?}", ir_result);
+        // Inlining first, so the arguments of the inlined calls are folded into the bodies
+        let inlined = inline::inline_functions(&mut ir_result.program);
+        let folded = fold::fold_constants(&mut ir_result.program, self.fold_options);
 
-        // println!("Code: {}", ir_result.ast.to_code_string());
+        if report_progress && self.verbose {
+            println!("{}", format!("Inlined {} function call(s)", inlined).blue());
+            println!("{}", format!("Folded {} constant node(s)", folded).blue());
+        }
 
         if ir_result.errors.len() > 0 {
-            errors.extend(ir_result.errors.iter().map(|e| format!("{}: {}", main_module_path, e)));
+            errors.extend(ir_result.errors.iter().map(|e| e.clone().with_file(main_module_path)));
         }
 
         if errors.len() > 0 {
             return Err(errors);
         }
 
-        println!("{}", "Generating code...".blue());
+        Ok(ir_result)
+    }
 
-        let code = self.generate_code(ir_result, codegen);
+    // Loads the main module and, recursively, all the modules it imports. The modules loaded so
+    // far are returned even when loading stops early
+    fn load_modules(&mut self, main_module_path: &str) -> (Result<String, Vec<Diagnostic>>, IndexMap<String, ModuleData>) {
+        let mut context = Context {
+            loaded_modules: Box::new(Vec::new()),
+            modules: Box::new(IndexMap::new()),
+            import_chain: Vec::new(),
+        };
 
-        code
+        let p: &Path = Path::new(main_module_path);
+
+        // We want just to take the directory of the main module
+        let current_dir = p.parent().unwrap_or(Path::new("."));
+        // Paths like ".." name a directory, not a module
+        let Some(main_module_path) = p.file_name().and_then(|name| name.to_str()) else {
+            let error = Diagnostic::error(MODULE_NOT_FOUND, format!("Cannot find module \"{}\", the path does not name a file", main_module_path));
+            return (Err(vec![error]), *context.modules);
+        };
+
+        let main_module_path = self.process_module(main_module_path, None, &mut context, Some(current_dir), p); // Recursively process all modules
+
+        (main_module_path, *context.modules)
     }
 
-    fn process_module(&mut self, path: &str, context: &mut Context, base_path: Option<&Path>, current_path: &Path) -> Result<(), Vec<String>> {
-        if context.loaded_modules.contains(&path.to_string()) {
-            return Ok(());
+    // Modules are stored under the key returned by the loader, so a file imported via different
+    // relative paths is parsed once. Import statements are rewritten to point to that key.
+    // Each default import still gets its own instance of the module in the IR.
+    // `import` is the position of the import statement in the importing module, if there is one
+    fn process_module(&mut self, path: &str, import: Option<Position>, context: &mut Context, base_path: Option<&Path>, current_path: &Path) -> Result<String, Vec<Diagnostic>> {
+        let mut module = ModuleData::new();
+
+        // A module that cannot be found is reported at the import statement that asks for it
+        let importer = context.import_chain.last().cloned();
+        let not_found = |message: String| match (import, &importer) {
+            (Some(position), Some(importer)) => Diagnostic::error(MODULE_NOT_FOUND, message).with_span(position).with_file(importer),
+            _ => Diagnostic::error(MODULE_NOT_FOUND, message),
+        };
+
+        let key = match self.loader.resolve(path, base_path) {
+            Ok(key) => key,
+            Err(error) => {
+                // Every import of the missing module gets its own diagnostic
+                context.modules.entry(path.to_string()).or_insert_with(ModuleData::new).errors.push(not_found(error.to_string()));
+
+                return Ok(path.to_string());
+            }
+        };
+
+        if let Some(index) = context.import_chain.iter().position(|module_key| *module_key == key) {
+            let mut chain = context.import_chain[index..].to_vec();
+            chain.push(key);
+
+            let importer = context.import_chain.last().unwrap();
+            let mut diagnostic = Diagnostic::error(IMPORT_CYCLE, format!("Import cycle detected: {}", chain.join(" -> ")));
+
+            // The cycle is reported at the import statement that closes it
+            if let Some(position) = import {
+                diagnostic = diagnostic.with_span(position);
+            }
+
+            return Err(vec![diagnostic.with_file(importer)]);
         }
 
-        let mut module = ModuleData::new();
+        if context.loaded_modules.contains(&key) {
+            return Ok(key);
+        }
 
         let input = self.load_module(path, base_path, current_path);
 
         if input.is_err() {
-            module.errors.push(input.err().unwrap().to_string());
-            context.modules.insert(path.to_string(), module);
+            context.modules.entry(key.clone()).or_insert_with(ModuleData::new).errors.push(not_found(input.err().unwrap().to_string()));
 
-            return Ok(());
+            return Ok(key);
         }
 
         let input = input.unwrap();
 
-        context.loaded_modules.push(path.to_string());
+        context.loaded_modules.push(key.clone());
+        context.import_chain.push(key.clone());
+
+        self.sources.insert(key.clone(), input.clone());
 
         let tokens = Mephisto::tokenize(input);
         let mut ast = Mephisto::parse(tokens);
 
-        let import_paths: Vec<_> = ast.imports();
+        let import_paths = ast.import_statements();
+
+        // Imports are relative to the file the module was found at, which is not necessarily
+        // next to the importing file when it comes from a search path
+        let current_dir = Path::new(&key).parent().unwrap_or(Path::new("."));
 
-        let current_dir = base_path.unwrap_or(Path::new("."));
-        // We want to join base path with the current path
-        let cp = current_dir.join(path);
-        let current_dir = cp.parent().unwrap_or(Path::new("."));
+        let mut resolved_paths = HashMap::new();
 
-        for path in import_paths {
-            self.process_module(&path, context, Some(&current_dir), current_path)?;
+        for (path, position) in import_paths {
+            let imported_key = self.process_module(&path, Some(position), context, Some(&current_dir), current_path)?;
+            resolved_paths.insert(path, imported_key);
         }
 
-        let symbol_table = Mephisto::create_symbol_table(&mut ast)?;
+        context.import_chain.pop();
+
+        ast.resolve_imports(&resolved_paths);
 
         if ast.errors.len() > 0 {
             module.errors = ast.errors.to_owned();
         }
 
+        // Like the syntax errors, these are reported with the module's file and the loading goes on,
+        // so the other modules get their diagnostics too
+        let symbol_table = Mephisto::create_symbol_table(&mut ast).unwrap_or_else(|errors| {
+            module.errors.extend(errors);
+            SymbolTable::new()
+        });
+
         module.ast = ast;
         module.symbol_table = symbol_table;
 
-        context.modules.insert(path.to_string(), module);
+        context.modules.insert(key.clone(), module);
 
-        Ok(())
+        Ok(key)
     }
 
     fn load_module(&self, path: &str, base_path: Option<&Path>, current_path: &Path) -> Result<String, Box<dyn Error>> {
@@ -201,7 +400,302 @@ impl<T: FileLoader> Mephisto<T> {
         result
     }
 
-    pub fn generate_code(&self, ir: IRResult, code_generator: Box<dyn CodeGenerator>) -> Result<String, Vec<String>> {
+    pub fn generate_code(&self, ir: IRResult, code_generator: Box<dyn CodeGenerator>) -> Result<String, Vec<Diagnostic>> {
         code_generator.generate(ir)
     }
 }
+
+// Errors the modules collected while loading, parsing and building their symbol tables
+fn module_errors(modules: &IndexMap<String, ModuleData>) -> Vec<Diagnostic> {
+    let mut errors = Vec::new();
+
+    for (path, module) in modules.iter() {
+        if module.errors.len() > 0 {
+            errors.extend(module.errors.iter().map(|e| e.clone().with_file(path)));
+        }
+    }
+
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::path::Path;
+    use indexmap::IndexMap;
+    use crate::{Context, Mephisto};
+    use crate::codegen::codegen_js::JSCodeGenerator;
+    use crate::diagnostic::{ARGUMENT_COUNT, Diagnostic, DUPLICATE_DECLARATION, IMPORT_CYCLE, MODULE_NOT_FOUND, UNKNOWN_NAME};
+    use crate::emit::Stage;
+    use crate::lexer::token::Position;
+    use crate::module_loader::{BuiltinFileLoader, StubFileLoader, VirtualFileLoader, BUNDLED_MODULES};
+
+    fn synth_files() -> HashMap<String, String> {
+        let mut files = HashMap::new();
+
+        files.insert("examples/synth.mephisto".to_string(), "
+            import Osc from \"./osc.mephisto\";
+            import Osc2 from \"../examples/osc.mephisto\";
+            import { clamp } from \"./lib.mephisto\";
+
+            output out = 0;
+
+            process {
+                out = clamp(Osc.out + Osc2.out, 0, 1);
+            }
+
+            connect {
+                out -> OUTPUTS;
+            }
+        ".to_string());
+
+        files.insert("examples/osc.mephisto".to_string(), "
+            import { clamp } from \"../examples/lib.mephisto\";
+
+            output out = 0;
+            let phase = 0;
+
+            process {
+                phase = clamp(phase + 0.01, 0, 1);
+                out = phase;
+            }
+        ".to_string());
+
+        files.insert("examples/lib.mephisto".to_string(), "
+            export fn clamp(x, lo, hi) {
+                return x < lo ? lo : (x > hi ? hi : x);
+            }
+        ".to_string());
+
+        files
+    }
+
+    #[test]
+    fn test_shared_imports_are_loaded_once() {
+        let mut mephisto = Mephisto::new(StubFileLoader::new(synth_files()));
+
+        let mut context = Context {
+            loaded_modules: Box::new(Vec::new()),
+            modules: Box::new(IndexMap::new()),
+            import_chain: Vec::new(),
+        };
+
+        let path = Path::new("examples/synth.mephisto");
+        let key = mephisto.process_module("synth.mephisto", None, &mut context, Some(Path::new("examples")), path).unwrap();
+
+        assert_eq!(key, "examples/synth.mephisto");
+        assert_eq!(context.modules.keys().collect::<Vec<_>>(), vec!["examples/lib.mephisto", "examples/osc.mephisto", "examples/synth.mephisto"]);
+        assert_eq!(context.modules.get(&key).unwrap().ast.imports(), vec!["examples/osc.mephisto", "examples/osc.mephisto", "examples/lib.mephisto"]);
+    }
+
+    #[test]
+    fn test_shared_module_instances_keep_their_own_state() {
+        let mut mephisto = Mephisto::new(StubFileLoader::new(synth_files()));
+
+        let code = mephisto.compile("examples/synth.mephisto", Box::new(JSCodeGenerator::new())).unwrap();
+
+        assert!(code.contains("let __Osc__phase = 0;"));
+        assert!(code.contains("let __Osc2__phase = 0;"));
+    }
+
+    #[test]
+    fn test_import_cycle() {
+        let mut files = HashMap::new();
+
+        files.insert("a.mephisto".to_string(), "import B from \"./b.mephisto\";".to_string());
+        files.insert("b.mephisto".to_string(), "import C from \"./c.mephisto\";".to_string());
+        files.insert("c.mephisto".to_string(), "import B from \"./b.mephisto\";".to_string());
+
+        let mut mephisto = Mephisto::new(StubFileLoader::new(files));
+
+        let result = mephisto.compile("a.mephisto", Box::new(JSCodeGenerator::new()));
+
+        assert_eq!(result.unwrap_err(), vec![
+            Diagnostic::error(IMPORT_CYCLE, "Import cycle detected: b.mephisto -> c.mephisto -> b.mephisto".to_string())
+                .with_span(Position { start: 0, end: 29, line: 1, column: 1 })
+                .with_file("c.mephisto"),
+        ]);
+    }
+
+    #[test]
+    fn test_bundled_modules_compile() {
+        for (name, _) in BUNDLED_MODULES.iter() {
+            let mut files = HashMap::new();
+
+            files.insert("main.mephisto".to_string(), format!("
+                import Module from \"std:{}\";
+                import {{ clamp }} from \"std:lib\";
+
+                output out = 0;
+
+                process {{
+                    out = clamp(0.5, 0, 1);
+                }}
+            ", name));
+
+            let mut mephisto = Mephisto::new(BuiltinFileLoader::new(StubFileLoader::new(files)));
+
+            let result = mephisto.compile("main.mephisto", Box::new(JSCodeGenerator::new()));
+
+            assert!(result.is_ok(), "std:{} failed to compile: {:?}", name, result.unwrap_err());
+        }
+    }
+
+    #[test]
+    fn test_nested_relative_imports() {
+        let mut loader = VirtualFileLoader::default();
+
+        loader.add_file("project/src/main.mephisto", "
+            import Voice from \"./voices/voice.mephisto\";
+            import { clamp } from \"../shared/lib.mephisto\";
+
+            output out = 0;
+
+            process {
+                out = clamp(Voice.out, 0, 1);
+            }
+        ");
+        loader.add_file("project/src/voices/voice.mephisto", "
+            import { clamp } from \"../../shared/lib.mephisto\";
+
+            output out = 0;
+
+            process {
+                out = clamp(2, 0, 1);
+            }
+        ");
+        loader.add_file("project/shared/lib.mephisto", "
+            export fn clamp(x, lo, hi) {
+                return x < lo ? lo : (x > hi ? hi : x);
+            }
+        ");
+
+        let mut mephisto = Mephisto::new(loader);
+
+        let mut context = Context {
+            loaded_modules: Box::new(Vec::new()),
+            modules: Box::new(IndexMap::new()),
+            import_chain: Vec::new(),
+        };
+
+        let path = Path::new("project/src/main.mephisto");
+        mephisto.process_module("project/src/main.mephisto", None, &mut context, None, path).unwrap();
+
+        assert_eq!(context.modules.keys().collect::<Vec<_>>(), vec!["project/shared/lib.mephisto", "project/src/voices/voice.mephisto", "project/src/main.mephisto"]);
+        assert!(context.modules.values().all(|module| module.errors.is_empty()));
+
+        assert!(mephisto.compile("project/src/main.mephisto", Box::new(JSCodeGenerator::new())).is_ok());
+    }
+
+    #[test]
+    fn test_diagnostics_point_at_the_module_source() {
+        let mut loader = VirtualFileLoader::default();
+
+        loader.add_file("main.mephisto", "import Lib from \"./lib.mephisto\";\n\noutput out = 0;\n\nprocess {\n    out = Lib.clamp(1);\n}\n");
+        loader.add_file("lib.mephisto", "export fn clamp(x, lo, hi) {\n    return x < lo ? lo : (x > hi ? hi : x);\n}\n");
+
+        let mut mephisto = Mephisto::new(loader);
+
+        let diagnostics = mephisto.compile("main.mephisto", Box::new(JSCodeGenerator::new())).unwrap_err();
+
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].code, ARGUMENT_COUNT);
+        assert_eq!(diagnostics[0].file.as_deref(), Some("main.mephisto"));
+
+        let source = mephisto.source("main.mephisto");
+
+        assert_eq!(diagnostics[0].location(source.unwrap()), Some((6, 11)));
+        assert!(diagnostics[0].render(source).contains("6 |     out = Lib.clamp(1);\n"));
+    }
+
+    #[test]
+    fn test_diagnostics_underline_only_the_offending_name() {
+        let mut loader = VirtualFileLoader::default();
+
+        loader.add_file("main.mephisto", "output out = 0;\n\nprocess {\n    out = zz + 0.1;\n}\n");
+
+        let mut mephisto = Mephisto::new(loader);
+
+        let diagnostics = mephisto.compile("main.mephisto", Box::new(JSCodeGenerator::new())).unwrap_err();
+
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].code, UNKNOWN_NAME);
+
+        let source = mephisto.source("main.mephisto");
+
+        assert_eq!(diagnostics[0].render(source), concat!(
+            "error[E0301]: Cannot find name \"zz\"\n",
+            " --> main.mephisto:4:11\n",
+            "  |\n",
+            "4 |     out = zz + 0.1;\n",
+            "  |           ^^\n",
+        ));
+        assert!(diagnostics[0].to_json(source).contains("\"byte_start\":37,\"byte_end\":39"));
+    }
+
+    #[test]
+    fn test_duplicate_declaration_points_at_the_module_source() {
+        let mut loader = VirtualFileLoader::default();
+
+        loader.add_file("main.mephisto", "import Lib from \"./lib.mephisto\";\n\nlet x = 0;\nlet x = 1;\n");
+        loader.add_file("lib.mephisto", "let y = 0;\nlet y = 1;\n");
+
+        let mut mephisto = Mephisto::new(loader);
+
+        let diagnostics = mephisto.compile("main.mephisto", Box::new(JSCodeGenerator::new())).unwrap_err();
+
+        assert_eq!(diagnostics.iter().map(|diagnostic| (diagnostic.code, diagnostic.file.as_deref())).collect::<Vec<_>>(), vec![
+            (DUPLICATE_DECLARATION, Some("lib.mephisto")),
+            (DUPLICATE_DECLARATION, Some("main.mephisto")),
+        ]);
+
+        let source = mephisto.source("main.mephisto");
+
+        assert_eq!(diagnostics[1].location(source.unwrap()), Some((4, 5)));
+        assert!(diagnostics[1].render(source).contains("4 | let x = 1;\n"));
+        assert!(diagnostics[1].to_json(source).contains("\"file\":\"main.mephisto\",\"line\":4,\"column\":5"));
+    }
+
+    #[test]
+    fn test_missing_module_points_at_the_import() {
+        let mut loader = VirtualFileLoader::default();
+
+        loader.add_file("main.mephisto", "let a = 0;\nimport Lib from \"./missing.mephisto\";\n");
+
+        let mut mephisto = Mephisto::new(loader);
+
+        let diagnostics = mephisto.compile("main.mephisto", Box::new(JSCodeGenerator::new())).unwrap_err();
+
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].code, MODULE_NOT_FOUND);
+        assert_eq!(diagnostics[0].file.as_deref(), Some("main.mephisto"));
+
+        let source = mephisto.source("main.mephisto");
+
+        assert_eq!(diagnostics[0].location(source.unwrap()), Some((2, 1)));
+        assert!(diagnostics[0].render(source).contains("2 | import Lib from \"./missing.mephisto\";\n"));
+    }
+
+    #[test]
+    fn test_emit_symbols_with_relative_module_keys() {
+        let mut loader = VirtualFileLoader::default();
+
+        loader.add_file("project/main.mephisto", "import Lib from \"./shared/lib.mephisto\";\n");
+        loader.add_file("project/shared/lib.mephisto", "export fn clamp(x, lo, hi) {\n    return x;\n}\n");
+
+        let mut mephisto = Mephisto::new(loader);
+
+        assert_eq!(mephisto.emit("project/main.mephisto", Stage::Symbols).unwrap(), "// shared/lib.mephisto
+scope 0
+    clamp 10..15 fn(x, lo, hi), public
+scope 1 (parent 0)
+    hi 23..25 argument
+    lo 19..21 argument
+    x 16..17 argument
+
+// main.mephisto
+scope 0
+    Lib 7..10 module \"shared/lib.mephisto\"
+");
+    }
+}
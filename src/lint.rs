@@ -0,0 +1,575 @@
+use std::collections::{HashMap, HashSet};
+
+use indexmap::IndexMap;
+use uuid::Uuid;
+
+use crate::diagnostic::{DEAD_STORE, Diagnostic, UNCONNECTED_MODULE, UNKNOWN_LINT, UNUSED_INPUT, UNUSED_OUTPUT, UNUSED_PARAM, UNUSED_VARIABLE};
+use crate::lexer::token::Position;
+use crate::module_data::ModuleData;
+use crate::parser::ast::{ASTTraverseStage, Attribute, Node, traverse_ast, VariableSpecifier};
+use crate::symbol_table::{SymbolInfo, SymbolOrigin, SymbolTable};
+
+// Lint names used in allow attributes, e.g. @allow(unused_variable), and the codes they report
+pub const LINTS: [(&str, &str); 6] = [
+    ("unused_variable", UNUSED_VARIABLE),
+    ("unconnected_module", UNCONNECTED_MODULE),
+    ("unused_input", UNUSED_INPUT),
+    ("unused_output", UNUSED_OUTPUT),
+    ("unused_param", UNUSED_PARAM),
+    ("dead_store", DEAD_STORE),
+];
+
+enum DeclarationKind {
+    Variable(VariableSpecifier),
+    Parameter,
+    Module { path: String },
+}
+
+struct Declaration {
+    name: String,
+    kind: DeclarationKind,
+    position: Position,
+    allowed: Vec<String>,
+    exported: bool,
+}
+
+struct Context {
+    symbol_table: SymbolTable,
+    errors: Vec<Diagnostic>,
+
+    declarations: IndexMap<Uuid, Declaration>,
+    read: HashSet<Uuid>,
+    written: HashSet<Uuid>,
+    // Modules whose outputs are connected or read
+    used_modules: HashSet<Uuid>,
+
+    // Assignments that were not read yet, with the statement list they were made in.
+    // Only an assignment in the same statement list can overwrite them for sure
+    pending_stores: HashMap<Uuid, (Position, usize)>,
+    dead_stores: Vec<(Uuid, Position)>,
+    statement_lists: Vec<usize>,
+    statement_list_count: usize,
+
+    exported: bool,
+    skip_identifier_once: bool,
+}
+
+// Warns about declarations that have no effect on the output of the program. Runs on modules
+// that passed semantic analysis, so names are expected to resolve
+pub fn lint(modules: &IndexMap<String, ModuleData>) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+
+    for (module_name, module) in modules.iter() {
+        diagnostics.extend(lint_module(module, modules).into_iter().map(|diagnostic| diagnostic.with_file(module_name)));
+    }
+
+    diagnostics
+}
+
+fn lint_module(module: &ModuleData, modules: &IndexMap<String, ModuleData>) -> Vec<Diagnostic> {
+    let mut symbol_table = module.symbol_table.clone();
+    symbol_table.reset_scopes_indexes();
+
+    let mut context = Context {
+        symbol_table,
+        errors: Vec::new(),
+
+        declarations: IndexMap::new(),
+        read: HashSet::new(),
+        written: HashSet::new(),
+        used_modules: HashSet::new(),
+
+        pending_stores: HashMap::new(),
+        dead_stores: Vec::new(),
+        statement_lists: Vec::new(),
+        statement_list_count: 0,
+
+        exported: false,
+        skip_identifier_once: false,
+    };
+
+    let mut ast = module.ast.clone();
+
+    traverse_ast(&mut ast.root, &mut |stage, node, context: &mut Context| {
+        match node {
+            | Node::ProcessSection { .. }
+            | Node::BlockSection { .. }
+            | Node::FunctionBody { .. }
+            | Node::BlockStmt { .. }
+            => {
+                match stage {
+                    ASTTraverseStage::Enter => {
+                        if let Err(error) = context.symbol_table.enter_next_scope() {
+                            context.errors.push(error.at_node(node));
+                        }
+
+                        context.statement_list_count += 1;
+                        context.statement_lists.push(context.statement_list_count);
+                    }
+                    ASTTraverseStage::Exit => {
+                        if let Err(error) = context.symbol_table.exit_scope() {
+                            context.errors.push(error.at_node(node));
+                        }
+
+                        // Whatever follows the list may read the values stored in it
+                        if let Some(list) = context.statement_lists.pop() {
+                            context.pending_stores.retain(|_, (_, store_list)| *store_list != list);
+                        }
+                    }
+                }
+            }
+
+            Node::BufferInitializer { .. } => {
+                match stage {
+                    ASTTraverseStage::Enter => {
+                        if let Err(error) = context.symbol_table.enter_next_scope() {
+                            context.errors.push(error.at_node(node));
+                        }
+                    }
+                    ASTTraverseStage::Exit => {
+                        if let Err(error) = context.symbol_table.exit_scope() {
+                            context.errors.push(error.at_node(node));
+                        }
+                    }
+                }
+            }
+
+            // The loop variable is the first child and is not a read
+            Node::ForStmt { .. } => {
+                match stage {
+                    ASTTraverseStage::Enter => {
+                        if let Err(error) = context.symbol_table.enter_next_scope() {
+                            context.errors.push(error.at_node(node));
+                        }
+
+                        context.skip_identifier_once = true;
+                    }
+                    ASTTraverseStage::Exit => {
+                        if let Err(error) = context.symbol_table.exit_scope() {
+                            context.errors.push(error.at_node(node));
+                        }
+                    }
+                }
+            }
+
+            // Exported declarations are used by the importing modules
+            Node::ExportDeclarationStmt { .. } => {
+                match stage {
+                    ASTTraverseStage::Enter => context.exported = true,
+                    ASTTraverseStage::Exit => context.exported = false,
+                }
+            }
+
+            Node::VariableDeclarationStmt { id, specifier, attributes, .. } => {
+                if let (ASTTraverseStage::Enter, Node::Identifier { name, position }) = (&stage, id.as_ref()) {
+                    if *specifier != VariableSpecifier::Buffer {
+                        declare(context, name, DeclarationKind::Variable(specifier.clone()), *position, attributes);
+                    }
+
+                    context.skip_identifier_once = true;
+                }
+            }
+
+            Node::ParameterDeclarationStmt { id, attributes, .. } => {
+                if let (ASTTraverseStage::Enter, Node::Identifier { name, position }) = (&stage, id.as_ref()) {
+                    declare(context, name, DeclarationKind::Parameter, *position, attributes);
+                }
+
+                return true;
+            }
+
+            Node::ImportStatement { id, path, attributes, .. } => {
+                if let (ASTTraverseStage::Enter, Node::Identifier { name, position }) = (&stage, id.as_ref()) {
+                    declare(context, name, DeclarationKind::Module { path: path.clone() }, *position, attributes);
+                }
+
+                return true;
+            }
+
+            Node::NamedImportStatement { .. } | Node::FunctionParameter { .. } => {
+                return true;
+            }
+
+            Node::FunctionDeclarationStmt { attributes, .. } => {
+                if let ASTTraverseStage::Enter = stage {
+                    check_lint_names(context, attributes);
+                    context.skip_identifier_once = true;
+                }
+            }
+
+            Node::BufferDeclarationStmt { .. } => {
+                if let ASTTraverseStage::Enter = stage {
+                    context.skip_identifier_once = true;
+                }
+            }
+
+            // The left hand side is always an identifier and is visited first
+            Node::AssignmentExpr { lhs, op, position, .. } => {
+                match stage {
+                    ASTTraverseStage::Enter => {
+                        context.skip_identifier_once = true;
+                    }
+                    ASTTraverseStage::Exit => {
+                        if let Node::Identifier { name, .. } = lhs.as_ref() {
+                            if let Some(id) = context.symbol_table.lookup(name).map(|symbol| *symbol.id()) {
+                                // A compound assignment reads the value it replaces
+                                if op.is_some() {
+                                    context.read.insert(id);
+                                    context.pending_stores.remove(&id);
+                                }
+
+                                store(context, id, *position);
+                            }
+                        }
+                    }
+                }
+            }
+
+            Node::ConnectStmt { lhs, rhs, .. } => {
+                if let ASTTraverseStage::Enter = stage {
+                    match lhs.as_ref() {
+                        Node::Identifier { name, .. } => {
+                            if let Some(symbol) = context.symbol_table.lookup(name) {
+                                context.read.insert(*symbol.id());
+                            }
+                        }
+                        Node::MemberExpr { object, .. } => {
+                            if let Some(id) = module_id(&context.symbol_table, object) {
+                                context.used_modules.insert(id);
+                            }
+                        }
+                        _ => {}
+                    }
+
+                    if let Node::Identifier { name, .. } = rhs.as_ref() {
+                        if let Some(symbol) = context.symbol_table.lookup(name) {
+                            context.written.insert(*symbol.id());
+                        }
+                    }
+                }
+
+                return true;
+            }
+
+            // Outputs of imported modules can be read directly. Properties belong to the imported
+            // module, not to this one
+            Node::MemberExpr { object, .. } => {
+                if let (ASTTraverseStage::Enter, Some(id)) = (&stage, module_id(&context.symbol_table, object)) {
+                    context.used_modules.insert(id);
+                }
+
+                return true;
+            }
+
+            Node::FnCallExpr { callee, .. } => {
+                if let ASTTraverseStage::Exit = stage {
+                    let is_stdlib_call = match callee.as_ref() {
+                        Node::Identifier { name, .. } => matches!(
+                            context.symbol_table.lookup(name),
+                            Some(SymbolInfo::Function { origin: SymbolOrigin::StandardLibrary, .. })
+                        ),
+                        _ => false,
+                    };
+
+                    // Other functions may read any of the variables in scope
+                    if !is_stdlib_call {
+                        context.pending_stores.clear();
+                    }
+                }
+            }
+
+            Node::Identifier { name, .. } => {
+                if let ASTTraverseStage::Enter = stage {
+                    if context.skip_identifier_once {
+                        context.skip_identifier_once = false;
+                        return false;
+                    }
+
+                    if let Some(id) = context.symbol_table.lookup(name).map(|symbol| *symbol.id()) {
+                        context.read.insert(id);
+                        context.pending_stores.remove(&id);
+                    }
+                }
+            }
+
+            _ => {}
+        }
+
+        false
+    }, &mut context);
+
+    let mut warnings = context.errors;
+
+    for (id, declaration) in context.declarations.iter() {
+        let warning = match &declaration.kind {
+            DeclarationKind::Variable(VariableSpecifier::Let | VariableSpecifier::Const) if !declaration.exported && !context.read.contains(id) => {
+                Some((UNUSED_VARIABLE, format!("Variable \"{}\" is never read", declaration.name)))
+            }
+            DeclarationKind::Variable(VariableSpecifier::Input) if !context.read.contains(id) => {
+                Some((UNUSED_INPUT, format!("Input \"{}\" is never read", declaration.name)))
+            }
+            DeclarationKind::Variable(VariableSpecifier::Output) if !context.written.contains(id) => {
+                Some((UNUSED_OUTPUT, format!("Output \"{}\" is never written", declaration.name)))
+            }
+            DeclarationKind::Parameter if !context.read.contains(id) => {
+                Some((UNUSED_PARAM, format!("Parameter \"{}\" is never used", declaration.name)))
+            }
+            DeclarationKind::Module { path } if !context.used_modules.contains(id) && has_outputs(path, modules) => {
+                Some((UNCONNECTED_MODULE, format!("Outputs of module \"{}\" are never connected or read", declaration.name)))
+            }
+            _ => None,
+        };
+
+        if let Some((code, message)) = warning {
+            push_warning(&mut warnings, declaration, code, message, declaration.position);
+        }
+    }
+
+    for (id, position) in context.dead_stores.iter() {
+        if let Some(declaration) = context.declarations.get(id) {
+            let message = format!("Value assigned to \"{}\" is overwritten before it is read", declaration.name);
+            push_warning(&mut warnings, declaration, DEAD_STORE, message, *position);
+        }
+    }
+
+    warnings.sort_by_key(|warning| warning.span.map(|span| span.start));
+    warnings
+}
+
+fn declare(context: &mut Context, name: &str, kind: DeclarationKind, position: Position, attributes: &[Attribute]) {
+    check_lint_names(context, attributes);
+
+    let Some(id) = context.symbol_table.lookup(name).map(|symbol| *symbol.id()) else {
+        return;
+    };
+
+    let allowed = attributes.iter()
+        .filter(|attribute| attribute.name == "allow")
+        .flat_map(|attribute| attribute.args.iter().cloned())
+        .collect();
+
+    context.declarations.insert(id, Declaration {
+        name: name.to_string(),
+        kind,
+        position,
+        allowed,
+        exported: context.exported,
+    });
+}
+
+fn check_lint_names(context: &mut Context, attributes: &[Attribute]) {
+    for attribute in attributes.iter().filter(|attribute| attribute.name == "allow") {
+        for lint in attribute.args.iter() {
+            if !LINTS.iter().any(|(name, _)| name == lint) {
+                context.errors.push(Diagnostic::warning(UNKNOWN_LINT, format!("Unknown lint \"{}\"", lint)).with_span(attribute.position));
+            }
+        }
+    }
+}
+
+fn store(context: &mut Context, id: Uuid, position: Position) {
+    let Some(&list) = context.statement_lists.last() else {
+        return;
+    };
+
+    if let Some((previous, previous_list)) = context.pending_stores.insert(id, (position, list)) {
+        if previous_list == list {
+            context.dead_stores.push((id, previous));
+        }
+    }
+
+    context.written.insert(id);
+}
+
+fn module_id(symbol_table: &SymbolTable, object: &Node) -> Option<Uuid> {
+    match object {
+        Node::Identifier { name, .. } => match symbol_table.lookup(name) {
+            Some(SymbolInfo::ImportedModule { id, .. }) => Some(*id),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// Modules without outputs are only imported for their functions
+fn has_outputs(path: &str, modules: &IndexMap<String, ModuleData>) -> bool {
+    modules.get(path).is_some_and(|module| !module.ast.outputs().is_empty())
+}
+
+fn push_warning(warnings: &mut Vec<Diagnostic>, declaration: &Declaration, code: &'static str, message: String, position: Position) {
+    let Some((lint, _)) = LINTS.iter().find(|(_, lint_code)| *lint_code == code) else {
+        return;
+    };
+
+    if declaration.allowed.iter().any(|allowed| allowed == lint) {
+        return;
+    }
+
+    warnings.push(Diagnostic::warning(code, message)
+        .with_span(position)
+        .with_note(format!("silence this warning with @allow({}) on the declaration of \"{}\"", lint, declaration.name)));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::codegen::codegen_js::JSCodeGenerator;
+    use crate::Mephisto;
+    use crate::module_loader::StubFileLoader;
+
+    fn warnings(files: Vec<(&str, &str)>) -> Vec<String> {
+        let files: HashMap<String, String> = files.into_iter().map(|(path, code)| (path.to_string(), code.to_string())).collect();
+
+        let mut mephisto = Mephisto::new(StubFileLoader::new(files));
+        let result = mephisto.compile("main.mephisto", Box::new(JSCodeGenerator::new()));
+
+        assert!(result.is_ok(), "{:?}", result.unwrap_err());
+
+        mephisto.warnings().iter().map(|warning| format!("{}: {}", warning.code, warning.message)).collect()
+    }
+
+    #[test]
+    fn test_unused_declarations() {
+        let code = "
+            param unused {
+                initial: 0;
+            };
+
+            param gain {
+                initial: 0.5;
+            };
+
+            input unread = 0;
+            input signal = 0;
+            output out = 0;
+            output never_written = 0;
+
+            let unused_variable = 1;
+            const offset = 0.1;
+            export let shared = 2;
+
+            fn scale(x) {
+                let unused_local = x;
+                return x * gain;
+            }
+
+            process {
+                let temporary = 0;
+                out = scale(signal) + offset;
+            }
+        ";
+
+        assert_eq!(warnings(vec![("main.mephisto", code)]), vec![
+            "W0005: Parameter \"unused\" is never used",
+            "W0003: Input \"unread\" is never read",
+            "W0004: Output \"never_written\" is never written",
+            "W0001: Variable \"unused_variable\" is never read",
+            "W0001: Variable \"unused_local\" is never read",
+            "W0001: Variable \"temporary\" is never read",
+        ]);
+    }
+
+    #[test]
+    fn test_unconnected_modules() {
+        let osc = "
+            input frequency = 440;
+            output out = 0;
+
+            process {
+                out = frequency;
+            }
+        ";
+
+        let lib = "
+            export fn double(x) {
+                return x * 2;
+            }
+        ";
+
+        let main = "
+            import Connected from \"osc.mephisto\";
+            import Read from \"osc.mephisto\";
+            import Unconnected from \"osc.mephisto\";
+            import Lib from \"lib.mephisto\";
+
+            output out = 0;
+            output frequency = 220;
+
+            process {
+                out = Lib.double(Read.out);
+            }
+
+            connect {
+                frequency -> Unconnected.frequency;
+                Connected.out -> OUTPUTS;
+            }
+        ";
+
+        assert_eq!(warnings(vec![("main.mephisto", main), ("osc.mephisto", osc), ("lib.mephisto", lib)]), vec![
+            "W0002: Outputs of module \"Unconnected\" are never connected or read",
+            "W0004: Output \"frequency\" is never written",
+        ]);
+    }
+
+    #[test]
+    fn test_dead_stores() {
+        let code = "
+            input signal = 0;
+            output out = 0;
+
+            let state = 0;
+
+            fn read_state() {
+                return state;
+            }
+
+            process {
+                out = 1;
+                out = signal;
+
+                state = 1;
+                out = read_state();
+                state = 2;
+
+                let x = 0;
+                x = 1;
+                if (signal > 0) {
+                    x = 2;
+                }
+                out += x;
+                x = 3;
+                x = 4;
+            }
+        ";
+
+        assert_eq!(warnings(vec![("main.mephisto", code)]), vec![
+            "W0006: Value assigned to \"out\" is overwritten before it is read",
+            "W0006: Value assigned to \"x\" is overwritten before it is read",
+        ]);
+    }
+
+    #[test]
+    fn test_allow_attributes() {
+        let code = "
+            @allow(unused_param)
+            param unused {
+                initial: 0;
+            };
+
+            @allow(unused_input) input unread = 0;
+            @allow(unused_output, dead_store) output out = 0;
+            @allow(unused_variable) let unused_variable = 1;
+            @allow(no_such_lint) let used = 1;
+
+            process {
+                out = used;
+                out = used;
+            }
+        ";
+
+        assert_eq!(warnings(vec![("main.mephisto", code)]), vec![
+            "W0007: Unknown lint \"no_such_lint\"",
+        ]);
+    }
+}
@@ -0,0 +1,971 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+use indexmap::IndexMap;
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::Mephisto;
+use crate::diagnostic::{Diagnostic, Severity};
+use crate::formatter::layout;
+use crate::lexer::token::Position;
+use crate::module_data::ModuleData;
+use crate::module_loader::{FileLoader, normalize_path};
+use crate::parser::ast::{AST, ASTTraverseStage, Node, traverse_ast, VariableSpecifier};
+use crate::symbol_table::{SymbolInfo, SymbolOrigin, SymbolTable};
+
+// JSON-RPC error codes
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_REQUEST: i64 = -32600;
+
+// LSP enumerations
+const TEXT_DOCUMENT_SYNC_FULL: u32 = 1;
+const SEVERITY_ERROR: u32 = 1;
+const SEVERITY_WARNING: u32 = 2;
+const COMPLETION_FUNCTION: u32 = 3;
+const COMPLETION_PROPERTY: u32 = 10;
+
+// Reads one message framed with a Content-Length header, None at the end of the input
+pub fn read_message(reader: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut content_length = None;
+
+    loop {
+        let mut line = String::new();
+
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+
+        let line = line.trim_end();
+
+        if line.is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                content_length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+
+    let length = content_length.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length header"))?;
+
+    let mut content = vec![0; length];
+    reader.read_exact(&mut content)?;
+
+    serde_json::from_slice(&content).map(Some).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+}
+
+pub fn write_message(writer: &mut impl Write, message: &Value) -> io::Result<()> {
+    let content = message.to_string();
+
+    write!(writer, "Content-Length: {}\r\n\r\n{}", content.len(), content)?;
+    writer.flush()
+}
+
+// Serves the protocol until the client sends exit or closes the input. Returns whether the
+// client asked for a shutdown first, a server that exits without one should fail
+pub fn run<L: FileLoader>(loader: L, mut reader: impl BufRead, mut writer: impl Write) -> io::Result<bool> {
+    let mut server = LanguageServer::new(loader);
+
+    while let Some(message) = read_message(&mut reader)? {
+        for response in server.handle(&message) {
+            write_message(&mut writer, &response)?;
+        }
+
+        if server.exited {
+            break;
+        }
+    }
+
+    Ok(server.shutdown_requested)
+}
+
+struct Document {
+    uri: String,
+    text: String,
+}
+
+// A name in the source and the declaration it refers to
+struct Occurrence {
+    module: String,
+    position: Position,
+    symbol: Symbol,
+}
+
+#[derive(Clone, PartialEq)]
+struct Symbol {
+    // Module the symbol is declared in, named imports refer to the symbol of the imported module
+    module: String,
+    id: Uuid,
+}
+
+// Result of checking an open document as the main module of a program
+struct Analysis {
+    // Module key of the document, None when it could not be loaded
+    module: Option<String>,
+    modules: IndexMap<String, ModuleData>,
+    sources: HashMap<String, String>,
+    occurrences: Vec<Occurrence>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+pub struct LanguageServer<L: FileLoader> {
+    loader: L,
+
+    // Open documents by module key. They are read from the editor, not from the loader
+    documents: HashMap<String, Document>,
+    analyses: HashMap<String, Analysis>,
+
+    shutdown_requested: bool,
+    exited: bool,
+}
+
+impl<L: FileLoader> LanguageServer<L> {
+    pub fn new(loader: L) -> Self {
+        LanguageServer {
+            loader,
+            documents: HashMap::new(),
+            analyses: HashMap::new(),
+            shutdown_requested: false,
+            exited: false,
+        }
+    }
+
+    // Handles a request or a notification and returns the messages to send back, the response
+    // to a request followed by the notifications it caused
+    pub fn handle(&mut self, message: &Value) -> Vec<Value> {
+        let id = message.get("id").cloned();
+        let params = message.get("params").cloned().unwrap_or(Value::Null);
+
+        let method = match message.get("method").and_then(Value::as_str) {
+            Some(method) => method,
+            // Responses to requests of the server, it does not send any
+            None if message.get("result").is_some() || message.get("error").is_some() => return vec![],
+            None => return vec![error_response(id.unwrap_or(Value::Null), INVALID_REQUEST, "Message has no method".to_string())],
+        };
+
+        let mut notifications = vec![];
+
+        let result = match method {
+            "initialize" => Some(json!({
+                "capabilities": {
+                    "textDocumentSync": TEXT_DOCUMENT_SYNC_FULL,
+                    "definitionProvider": true,
+                    "referencesProvider": true,
+                    "hoverProvider": true,
+                    "completionProvider": { "triggerCharacters": ["."] },
+                },
+                "serverInfo": { "name": "mephisto", "version": env!("CARGO_PKG_VERSION") },
+            })),
+            "shutdown" => {
+                self.shutdown_requested = true;
+                Some(Value::Null)
+            }
+            "exit" => {
+                self.exited = true;
+                None
+            }
+            "textDocument/didOpen" => {
+                let document = &params["textDocument"];
+
+                if let (Some(uri), Some(text)) = (document["uri"].as_str(), document["text"].as_str()) {
+                    notifications = self.update_document(uri, Some(text.to_string()));
+                }
+
+                None
+            }
+            "textDocument/didChange" => {
+                // Changes carry the whole text, the last one is the current version
+                let text = params["contentChanges"].as_array()
+                    .and_then(|changes| changes.last())
+                    .and_then(|change| change["text"].as_str());
+
+                if let (Some(uri), Some(text)) = (params["textDocument"]["uri"].as_str(), text) {
+                    notifications = self.update_document(uri, Some(text.to_string()));
+                }
+
+                None
+            }
+            "textDocument/didClose" => {
+                if let Some(uri) = params["textDocument"]["uri"].as_str() {
+                    notifications = self.update_document(uri, None);
+                }
+
+                None
+            }
+            "textDocument/definition" => Some(self.definition(&params)),
+            "textDocument/references" => Some(self.references(&params)),
+            "textDocument/hover" => Some(self.hover(&params)),
+            "textDocument/completion" => Some(self.completion(&params)),
+            _ => {
+                // Unknown notifications are ignored, unknown requests must be answered
+                return match id {
+                    Some(id) => vec![error_response(id, METHOD_NOT_FOUND, format!("Unknown method {}", method))],
+                    None => vec![],
+                };
+            }
+        };
+
+        let mut messages = vec![];
+
+        if let (Some(id), Some(result)) = (id, result) {
+            messages.push(json!({ "jsonrpc": "2.0", "id": id, "result": result }));
+        }
+
+        messages.extend(notifications);
+        messages
+    }
+
+    // Opens, changes or closes (no text) a document. Other documents may import it, so all of
+    // them are checked again and get their diagnostics published
+    fn update_document(&mut self, uri: &str, text: Option<String>) -> Vec<Value> {
+        let Some(key) = self.document_key(uri) else {
+            return vec![];
+        };
+
+        let mut notifications = vec![];
+
+        match text {
+            Some(text) => {
+                self.documents.insert(key, Document { uri: uri.to_string(), text });
+            }
+            None => {
+                self.documents.remove(&key);
+                self.analyses.remove(&key);
+
+                notifications.push(publish_diagnostics(uri, vec![]));
+            }
+        }
+
+        let mut keys: Vec<String> = self.documents.keys().cloned().collect();
+        keys.sort();
+
+        for key in keys {
+            let analysis = self.analyze(&key);
+            let document = &self.documents[&key];
+
+            notifications.push(publish_diagnostics(&document.uri, document_diagnostics(&key, &document.text, &analysis)));
+
+            self.analyses.insert(key, analysis);
+        }
+
+        notifications
+    }
+
+    // Documents are stored under the key the loader gives their file, so imports of an open
+    // document find it. Files that do not exist on disk yet keep their path
+    fn document_key(&self, uri: &str) -> Option<String> {
+        let path = uri_to_path(uri)?;
+        let path = Path::new(&path);
+
+        let directory = path.parent().unwrap_or(Path::new("."));
+        let file_name = path.file_name()?.to_str()?;
+
+        Some(self.loader.resolve(file_name, Some(directory)).unwrap_or_else(|_| normalize_path(path)))
+    }
+
+    fn analyze(&self, key: &str) -> Analysis {
+        let loader = DocumentLoader { loader: &self.loader, documents: &self.documents };
+        let mut mephisto = Mephisto::new(loader);
+
+        let result = mephisto.check(key);
+
+        let mut diagnostics = result.diagnostics;
+        diagnostics.extend(mephisto.warnings().iter().cloned());
+
+        let sources = result.modules.keys()
+            .filter_map(|module| mephisto.source(module).map(|source| (module.clone(), source.to_string())))
+            .collect();
+
+        let occurrences = result.modules.keys()
+            .flat_map(|module| index_module(module, &result.modules))
+            .collect();
+
+        Analysis {
+            module: result.main_module,
+            modules: result.modules,
+            sources,
+            occurrences,
+            diagnostics,
+        }
+    }
+
+    // The analysis of the document at the requested position and the name under the cursor
+    fn occurrence_at(&self, params: &Value) -> Option<(&Analysis, &Occurrence)> {
+        let key = self.document_key(params["textDocument"]["uri"].as_str()?)?;
+        let analysis = self.analyses.get(&key)?;
+
+        let module = analysis.module.as_ref()?;
+        let offset = position_to_offset(analysis.sources.get(module)?, &params["position"])? as u32;
+
+        let occurrence = analysis.occurrences.iter()
+            .find(|occurrence| occurrence.module == *module && occurrence.position.start <= offset && offset <= occurrence.position.end)?;
+
+        Some((analysis, occurrence))
+    }
+
+    fn definition(&self, params: &Value) -> Value {
+        let definition = self.occurrence_at(params).and_then(|(analysis, occurrence)| {
+            let (_, info) = find_declaration(&analysis.modules, &occurrence.symbol)?;
+            self.location(analysis, &occurrence.symbol.module, &info.clone().position().clone())
+        });
+
+        definition.unwrap_or(Value::Null)
+    }
+
+    fn references(&self, params: &Value) -> Value {
+        let Some((analysis, occurrence)) = self.occurrence_at(params) else {
+            return Value::Null;
+        };
+
+        let include_declaration = params["context"]["includeDeclaration"].as_bool().unwrap_or(true);
+
+        let declaration = find_declaration(&analysis.modules, &occurrence.symbol)
+            .map(|(_, info)| *info.clone().position());
+
+        let locations: Vec<Value> = analysis.occurrences.iter()
+            .filter(|other| other.symbol == occurrence.symbol)
+            .filter(|other| include_declaration || other.module != occurrence.symbol.module || Some(other.position) != declaration)
+            .filter_map(|other| self.location(analysis, &other.module, &other.position))
+            .collect();
+
+        Value::Array(locations)
+    }
+
+    fn hover(&self, params: &Value) -> Value {
+        let Some((analysis, occurrence)) = self.occurrence_at(params) else {
+            return Value::Null;
+        };
+
+        let Some((name, info)) = find_declaration(&analysis.modules, &occurrence.symbol) else {
+            return Value::Null;
+        };
+
+        let declaration = match info {
+            SymbolInfo::Function { parameters, .. } => format!("fn {}({})", name, parameters.join(", ")),
+            SymbolInfo::FunctionArgument { .. } => format!("argument {}", name),
+            _ => {
+                let module = &analysis.modules[&occurrence.symbol.module];
+                let position = *info.clone().position();
+
+                match declaration_statement(&module.ast.root, &position) {
+                    Some(statement) => {
+                        let mut ast = AST::new(Node::ProgramNode { children: vec![statement.clone()], position: Position::new() }, vec![]);
+                        layout(&ast.to_canonical_code_string()).trim_end().to_string()
+                    }
+                    None => name.clone(),
+                }
+            }
+        };
+
+        let source = &analysis.sources[&occurrence.module];
+
+        json!({
+            "contents": { "kind": "markdown", "value": format!("```mephisto\n{}\n```", declaration) },
+            "range": range(source, &occurrence.position),
+        })
+    }
+
+    // Completes the ports of an imported module after "Module."
+    fn completion(&self, params: &Value) -> Value {
+        let items = (|| {
+            let key = self.document_key(params["textDocument"]["uri"].as_str()?)?;
+            let text = &self.documents.get(&key)?.text;
+            let analysis = self.analyses.get(&key)?;
+
+            let offset = position_to_offset(text, &params["position"])?;
+            let before = &text[..offset];
+
+            let prefix_start = before.rfind(|c: char| !is_identifier_char(c)).map_or(0, |index| index + 1);
+            let (before, prefix) = before.split_at(prefix_start);
+
+            let before = before.strip_suffix('.')?;
+            let object = &before[before.rfind(|c: char| !is_identifier_char(c)).map_or(0, |index| index + 1)..];
+
+            let module = analysis.modules.get(analysis.module.as_ref()?)?;
+
+            let imported = match global_scope(&module.symbol_table)?.get(object)? {
+                SymbolInfo::ImportedModule { path, .. } => analysis.modules.get(path)?,
+                _ => return None,
+            };
+
+            let mut items: Vec<(String, Value)> = global_scope(&imported.symbol_table)?.iter()
+                .filter(|(name, _)| name.starts_with(prefix))
+                .filter_map(|(name, info)| {
+                    let (kind, detail) = match info {
+                        SymbolInfo::Parameter { .. } => (COMPLETION_PROPERTY, "param".to_string()),
+                        SymbolInfo::Variable { specifier: VariableSpecifier::Input, .. } => (COMPLETION_PROPERTY, "input".to_string()),
+                        SymbolInfo::Variable { specifier: VariableSpecifier::Output, .. } => (COMPLETION_PROPERTY, "output".to_string()),
+                        SymbolInfo::Function { parameters, .. } if !info.is_private() => (COMPLETION_FUNCTION, format!("fn {}({})", name, parameters.join(", "))),
+                        _ => return None,
+                    };
+
+                    Some((name.clone(), json!({ "label": name, "kind": kind, "detail": detail })))
+                })
+                .collect();
+
+            items.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+            Some(items.into_iter().map(|(_, item)| item).collect::<Vec<_>>())
+        })();
+
+        Value::Array(items.unwrap_or_default())
+    }
+
+    fn location(&self, analysis: &Analysis, module: &str, position: &Position) -> Option<Value> {
+        let uri = match self.documents.get(module) {
+            Some(document) => document.uri.clone(),
+            // Bundled modules have no file to point to
+            None if Path::new(module).is_absolute() => path_to_uri(module),
+            None => return None,
+        };
+
+        Some(json!({ "uri": uri, "range": range(analysis.sources.get(module)?, position) }))
+    }
+}
+
+// Serves the open documents in place of their files, they may have unsaved changes or not
+// exist on disk at all
+struct DocumentLoader<'a, L: FileLoader> {
+    loader: &'a L,
+    documents: &'a HashMap<String, Document>,
+}
+
+impl<L: FileLoader> FileLoader for DocumentLoader<'_, L> {
+    fn load(&self, path: &str, base_path: Option<&Path>, current_path: &Path) -> Result<String, Box<dyn Error>> {
+        match self.documents.get(&self.resolve(path, base_path)?) {
+            Some(document) => Ok(document.text.clone()),
+            None => self.loader.load(path, base_path, current_path),
+        }
+    }
+
+    fn resolve(&self, path: &str, base_path: Option<&Path>) -> Result<String, Box<dyn Error>> {
+        self.loader.resolve(path, base_path).or_else(|error| {
+            let joined = normalize_path(&base_path.unwrap_or(Path::new("")).join(path));
+
+            match self.documents.contains_key(&joined) {
+                true => Ok(joined),
+                false => Err(error),
+            }
+        })
+    }
+}
+
+fn error_response(id: Value, code: i64, message: String) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+}
+
+fn publish_diagnostics(uri: &str, diagnostics: Vec<Value>) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/publishDiagnostics",
+        "params": { "uri": uri, "diagnostics": diagnostics },
+    })
+}
+
+// Diagnostics of other modules are shown on the import of the module, or at the top of the
+// document when the module is imported indirectly
+fn document_diagnostics(key: &str, text: &str, analysis: &Analysis) -> Vec<Value> {
+    let imports = analysis.modules.get(key).map(|module| import_positions(&module.ast.root)).unwrap_or_default();
+
+    analysis.diagnostics.iter()
+        .map(|diagnostic| {
+            let mut message = diagnostic.message.clone();
+
+            for note in diagnostic.notes.iter() {
+                message.push('\n');
+                message.push_str(note);
+            }
+
+            let position = match diagnostic.file.as_deref() {
+                None => diagnostic.span,
+                Some(file) if file == key => diagnostic.span,
+                Some(file) => {
+                    message = format!("{}: {}", file, message);
+                    imports.get(file).copied()
+                }
+            };
+
+            let severity = match diagnostic.severity {
+                Severity::Error => SEVERITY_ERROR,
+                Severity::Warning => SEVERITY_WARNING,
+            };
+
+            json!({
+                "range": range(text, &position.unwrap_or_else(Position::new)),
+                "severity": severity,
+                "code": diagnostic.code,
+                "source": "mephisto",
+                "message": message,
+            })
+        })
+        .collect()
+}
+
+// Positions of the import statements by the key of the imported module
+fn import_positions(root: &Node) -> HashMap<String, Position> {
+    let mut positions = HashMap::new();
+
+    if let Node::ProgramNode { children, .. } = root {
+        for child in children {
+            if let Node::ImportStatement { path, position, .. } | Node::NamedImportStatement { path, position, .. } = child {
+                positions.entry(path.clone()).or_insert(*position);
+            }
+        }
+    }
+
+    positions
+}
+
+struct IndexContext<'a> {
+    module: &'a str,
+    modules: &'a IndexMap<String, ModuleData>,
+    symbol_table: SymbolTable,
+
+    // Parameters of the function whose body comes next, they are declared in the scope of the body
+    parameters: Vec<Node>,
+    occurrences: Vec<Occurrence>,
+}
+
+// Finds the names in a module and the declarations they refer to, walking the scopes the same
+// way the symbol table was built
+fn index_module(module: &str, modules: &IndexMap<String, ModuleData>) -> Vec<Occurrence> {
+    let mut symbol_table = modules[module].symbol_table.clone();
+    symbol_table.reset_scopes_indexes();
+
+    let mut context = IndexContext {
+        module,
+        modules,
+        symbol_table,
+        parameters: vec![],
+        occurrences: vec![],
+    };
+
+    let mut ast = modules[module].ast.clone();
+
+    traverse_ast(&mut ast.root, &mut index_node, &mut context);
+
+    context.occurrences
+}
+
+// Scope errors are ignored, the worst outcome is a name that does not resolve
+fn index_node(stage: ASTTraverseStage, node: &mut Node, context: &mut IndexContext) -> bool {
+    match node {
+        | Node::ProcessSection { .. }
+        | Node::BlockSection { .. }
+        | Node::BlockStmt { .. }
+        | Node::BufferInitializer { .. }
+        | Node::ForStmt { .. }
+        => {
+            let _ = match stage {
+                ASTTraverseStage::Enter => context.symbol_table.enter_next_scope(),
+                ASTTraverseStage::Exit => context.symbol_table.exit_scope(),
+            };
+        }
+
+        Node::FunctionBody { .. } => {
+            match stage {
+                ASTTraverseStage::Enter => {
+                    let _ = context.symbol_table.enter_next_scope();
+
+                    for parameter in std::mem::take(&mut context.parameters) {
+                        if let Node::FunctionParameter { id, .. } = parameter {
+                            record_identifier(&id, context);
+                        }
+                    }
+                }
+                ASTTraverseStage::Exit => {
+                    let _ = context.symbol_table.exit_scope();
+                }
+            }
+        }
+
+        Node::FunctionDeclarationStmt { params, .. } => {
+            if let ASTTraverseStage::Enter = stage {
+                context.parameters = params.clone();
+            }
+        }
+
+        // Field names of a param declaration are not symbols
+        Node::FunctionParameter { .. } | Node::ParameterDeclarationField { .. } => return true,
+
+        // Properties are looked up in the module the object refers to
+        Node::MemberExpr { object, property, .. } => {
+            if let ASTTraverseStage::Enter = stage {
+                record_identifier(object, context);
+
+                let module = match object.as_ref() {
+                    Node::Identifier { name, .. } => match context.symbol_table.lookup(name) {
+                        Some(SymbolInfo::ImportedModule { path, .. }) => Some(path.clone()),
+                        _ => None,
+                    },
+                    _ => None,
+                };
+
+                match property.as_mut() {
+                    Node::FnCallExpr { callee, args, .. } => {
+                        record_member(module.as_deref(), callee, context);
+
+                        for arg in args {
+                            traverse_ast(arg, &mut index_node, context);
+                        }
+                    }
+                    property => record_member(module.as_deref(), property, context),
+                }
+            }
+
+            return true;
+        }
+
+        Node::Identifier { .. } => {
+            if let ASTTraverseStage::Enter = stage {
+                record_identifier(node, context);
+            }
+        }
+
+        _ => {}
+    }
+
+    false
+}
+
+fn record_identifier(node: &Node, context: &mut IndexContext) {
+    if let Node::Identifier { name, position } = node {
+        let symbol = context.symbol_table.lookup(name)
+            .and_then(|info| declared_symbol(context.module, context.modules, name, info));
+
+        if let Some(symbol) = symbol {
+            context.occurrences.push(Occurrence { module: context.module.to_string(), position: *position, symbol });
+        }
+    }
+}
+
+fn record_member(module: Option<&str>, node: &Node, context: &mut IndexContext) {
+    if let (Some(module), Node::Identifier { name, position }) = (module, node) {
+        if let Some(symbol) = global_symbol(context.modules, module, name) {
+            context.occurrences.push(Occurrence { module: context.module.to_string(), position: *position, symbol });
+        }
+    }
+}
+
+// The symbol a name declared in a module refers to. Named imports refer to the function of the
+// module they come from. Symbols without a place in the source, like the standard library or the
+// index of a buffer initializer, are left out
+fn declared_symbol(module: &str, modules: &IndexMap<String, ModuleData>, name: &str, info: &SymbolInfo) -> Option<Symbol> {
+    match info {
+        SymbolInfo::Function { origin: SymbolOrigin::ImportedModule { module }, .. } => global_symbol(modules, module, name),
+        _ if info.clone().position().end == 0 => None,
+        _ => Some(Symbol { module: module.to_string(), id: *info.id() }),
+    }
+}
+
+fn global_symbol(modules: &IndexMap<String, ModuleData>, module: &str, name: &str) -> Option<Symbol> {
+    let info = global_scope(&modules.get(module)?.symbol_table)?.get(name)?;
+
+    declared_symbol(module, modules, name, info)
+}
+
+fn global_scope(symbol_table: &SymbolTable) -> Option<&HashMap<String, SymbolInfo>> {
+    symbol_table.scopes().first().map(|scope| scope.symbols())
+}
+
+// Name and symbol table entry of a symbol
+fn find_declaration<'a>(modules: &'a IndexMap<String, ModuleData>, symbol: &Symbol) -> Option<(&'a String, &'a SymbolInfo)> {
+    modules.get(&symbol.module)?.symbol_table.scopes().iter()
+        .flat_map(|scope| scope.symbols().iter())
+        .find(|(_, info)| *info.id() == symbol.id)
+}
+
+// The statement that declares the identifier at the given position
+fn declaration_statement<'a>(node: &'a Node, position: &Position) -> Option<&'a Node> {
+    match node {
+        | Node::VariableDeclarationStmt { id, .. }
+        | Node::ParameterDeclarationStmt { id, .. }
+        | Node::BufferDeclarationStmt { id, .. }
+        | Node::ImportStatement { id, .. }
+        if id.position() == position => Some(node),
+
+        Node::ProgramNode { children, .. }
+        | Node::ProcessSection { children, .. }
+        | Node::BlockSection { children, .. }
+        | Node::FunctionBody { children, .. }
+        | Node::BlockStmt { children, .. } => children.iter().find_map(|child| declaration_statement(child, position)),
+
+        Node::ExportDeclarationStmt { declaration, .. } => declaration_statement(declaration, position),
+        Node::FunctionDeclarationStmt { body, .. } => declaration_statement(body, position),
+        Node::IfStmt { consequent, alternate, .. } => declaration_statement(consequent, position)
+            .or_else(|| alternate.as_ref().and_then(|alternate| declaration_statement(alternate, position))),
+        Node::ForStmt { body, .. } => declaration_statement(body, position),
+
+        _ => None,
+    }
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+// Only file URIs are supported, they are the only ones a module can be loaded from
+fn uri_to_path(uri: &str) -> Option<String> {
+    let path = uri.strip_prefix("file://")?;
+    let bytes = path.as_bytes();
+
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+
+    while index < bytes.len() {
+        let escaped = match bytes[index] {
+            b'%' => path.get(index + 1..index + 3).and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                index += 3;
+            }
+            None => {
+                decoded.push(bytes[index]);
+                index += 1;
+            }
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+fn path_to_uri(path: &str) -> String {
+    let mut uri = "file://".to_string();
+
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'/' | b'-' | b'.' | b'_' | b'~' => uri.push(byte as char),
+            _ => uri.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    uri
+}
+
+// LSP positions count lines and UTF-16 code units, positions in the source are byte offsets
+fn offset_to_position(source: &str, offset: u32) -> Value {
+    let mut offset = (offset as usize).min(source.len());
+
+    while !source.is_char_boundary(offset) {
+        offset -= 1;
+    }
+
+    let before = &source[..offset];
+    let line_start = before.rfind('\n').map_or(0, |index| index + 1);
+
+    json!({
+        "line": before.matches('\n').count(),
+        "character": before[line_start..].encode_utf16().count(),
+    })
+}
+
+fn position_to_offset(source: &str, position: &Value) -> Option<usize> {
+    let line = position["line"].as_u64()?;
+    let character = position["character"].as_u64()? as usize;
+
+    let mut line_start = 0;
+
+    for _ in 0..line {
+        line_start += source[line_start..].find('\n')? + 1;
+    }
+
+    let mut units = 0;
+
+    for (index, c) in source[line_start..].char_indices() {
+        if units >= character || c == '\n' {
+            return Some(line_start + index);
+        }
+
+        units += c.len_utf16();
+    }
+
+    Some(source.len())
+}
+
+fn range(source: &str, position: &Position) -> Value {
+    json!({
+        "start": offset_to_position(source, position.start),
+        "end": offset_to_position(source, position.end),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use serde_json::{json, Value};
+
+    use crate::lsp::{LanguageServer, read_message, run, write_message};
+    use crate::module_loader::VirtualFileLoader;
+
+    const MAIN_URI: &str = "file:///project/main.mephisto";
+
+    const MAIN: &str = "import Osc from \"./osc.mephisto\";
+import { clamp } from \"./lib.mephisto\";
+
+output out = 0;
+
+process {
+    out = clamp(Osc.out, 0, 1);
+}
+
+connect {
+    out -> Osc.sync;
+}
+";
+
+    fn server() -> LanguageServer<VirtualFileLoader> {
+        let mut loader = VirtualFileLoader::default();
+
+        loader.add_file("/project/osc.mephisto", "param frequency {\n    initial: 440;\n    min: 20;\n    max: 2000;\n};\n\ninput sync = 0;\noutput out = 0;\n\nprocess {\n    out = frequency;\n}\n");
+        loader.add_file("/project/lib.mephisto", "export fn clamp(x, lo, hi) {\n    return x < lo ? lo : (x > hi ? hi : x);\n}\n");
+
+        LanguageServer::new(loader)
+    }
+
+    fn open(server: &mut LanguageServer<VirtualFileLoader>, text: &str) -> Vec<Value> {
+        server.handle(&json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": { "textDocument": { "uri": MAIN_URI, "languageId": "mephisto", "version": 1, "text": text } },
+        }))
+    }
+
+    fn request(server: &mut LanguageServer<VirtualFileLoader>, method: &str, line: u32, character: u32) -> Value {
+        let mut messages = server.handle(&json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": method,
+            "params": {
+                "textDocument": { "uri": MAIN_URI },
+                "position": { "line": line, "character": character },
+                "context": { "includeDeclaration": true },
+            },
+        }));
+
+        messages.remove(0)["result"].take()
+    }
+
+    fn location(uri: &str, line: u32, start: u32, end: u32) -> Value {
+        json!({ "uri": uri, "range": { "start": { "line": line, "character": start }, "end": { "line": line, "character": end } } })
+    }
+
+    #[test]
+    fn test_session() {
+        let messages = [
+            json!({ "jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {} }),
+            json!({ "jsonrpc": "2.0", "method": "initialized", "params": {} }),
+            json!({ "jsonrpc": "2.0", "id": 2, "method": "workspace/symbol", "params": {} }),
+            json!({ "jsonrpc": "2.0", "id": 3, "method": "shutdown" }),
+            json!({ "jsonrpc": "2.0", "method": "exit" }),
+        ];
+
+        let mut input = vec![];
+
+        for message in messages.iter() {
+            write_message(&mut input, message).unwrap();
+        }
+
+        let mut output = vec![];
+
+        assert!(run(VirtualFileLoader::default(), Cursor::new(input), &mut output).unwrap());
+
+        let mut output = Cursor::new(output);
+        let mut responses = vec![];
+
+        while let Some(response) = read_message(&mut output).unwrap() {
+            responses.push(response);
+        }
+
+        assert_eq!(responses.len(), 3);
+        assert_eq!(responses[0]["result"]["capabilities"]["definitionProvider"], json!(true));
+        assert_eq!(responses[1]["error"]["code"], json!(-32601));
+        assert_eq!(responses[2], json!({ "jsonrpc": "2.0", "id": 3, "result": null }));
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let mut server = server();
+
+        let messages = open(&mut server, "let a = ;\n");
+
+        assert_eq!(messages[0]["method"], json!("textDocument/publishDiagnostics"));
+        assert_eq!(messages[0]["params"]["uri"], json!(MAIN_URI));
+        assert_eq!(messages[0]["params"]["diagnostics"][0]["severity"], json!(1));
+        assert_eq!(messages[0]["params"]["diagnostics"][0]["range"]["start"]["line"], json!(0));
+
+        let messages = server.handle(&json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didChange",
+            "params": { "textDocument": { "uri": MAIN_URI, "version": 2 }, "contentChanges": [{ "text": "let a = 1;\n" }] },
+        }));
+
+        let diagnostics = messages[0]["params"]["diagnostics"].as_array().unwrap();
+
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0]["severity"], json!(2));
+        assert_eq!(diagnostics[0]["range"]["start"], json!({ "line": 0, "character": 4 }));
+        assert_eq!(diagnostics[0]["range"]["end"], json!({ "line": 0, "character": 5 }));
+    }
+
+    #[test]
+    fn test_definition_and_references() {
+        let mut server = server();
+        open(&mut server, MAIN);
+
+        // Osc in Osc.out
+        assert_eq!(request(&mut server, "textDocument/definition", 6, 17), location(MAIN_URI, 0, 7, 10));
+        // out in Osc.out
+        assert_eq!(request(&mut server, "textDocument/definition", 6, 21), location("file:///project/osc.mephisto", 7, 7, 10));
+        // clamp resolves to the module it is imported from
+        assert_eq!(request(&mut server, "textDocument/definition", 6, 10), location("file:///project/lib.mephisto", 0, 10, 15));
+
+        assert_eq!(request(&mut server, "textDocument/references", 3, 8), json!([
+            location(MAIN_URI, 3, 7, 10),
+            location(MAIN_URI, 6, 4, 7),
+            location(MAIN_URI, 10, 4, 7),
+        ]));
+    }
+
+    #[test]
+    fn test_hover() {
+        let mut server = server();
+        open(&mut server, MAIN);
+
+        let hover = request(&mut server, "textDocument/hover", 6, 10);
+        assert_eq!(hover["contents"]["value"], json!("```mephisto\nfn clamp(x, lo, hi)\n```"));
+
+        let hover = request(&mut server, "textDocument/hover", 0, 8);
+        assert_eq!(hover["contents"]["value"], json!("```mephisto\nimport Osc from \"/project/osc.mephisto\";\n```"));
+    }
+
+    #[test]
+    fn test_hover_param() {
+        let mut server = server();
+        open(&mut server, "import Osc from \"./osc.mephisto\";\n\nprocess {\n    let f = Osc.frequency;\n}\n");
+
+        let hover = request(&mut server, "textDocument/hover", 3, 18);
+        assert_eq!(hover["contents"]["value"], json!("```mephisto\nparam frequency {\n    initial: 440;\n    min: 20;\n    max: 2000;\n};\n```"));
+    }
+
+    #[test]
+    fn test_completion() {
+        let mut server = server();
+        open(&mut server, MAIN);
+
+        let labels = |items: Value| items.as_array().unwrap().iter().map(|item| item["label"].clone()).collect::<Vec<_>>();
+
+        assert_eq!(labels(request(&mut server, "textDocument/completion", 10, 15)), vec![json!("frequency"), json!("out"), json!("sync")]);
+        assert_eq!(labels(request(&mut server, "textDocument/completion", 6, 21)), vec![json!("out")]);
+        assert_eq!(request(&mut server, "textDocument/completion", 3, 5), json!([]));
+    }
+}
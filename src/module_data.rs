@@ -1,3 +1,4 @@
+use crate::diagnostic::Diagnostic;
 use crate::lexer::token::Position;
 use crate::parser::ast::{AST, Node};
 use crate::symbol_table::SymbolTable;
@@ -6,7 +7,7 @@ use crate::symbol_table::SymbolTable;
 pub struct ModuleData {
     pub ast: AST,
     pub symbol_table: SymbolTable,
-    pub errors: Vec<String>,
+    pub errors: Vec<Diagnostic>,
 }
 
 impl ModuleData {
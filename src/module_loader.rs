@@ -2,31 +2,171 @@ use std::collections::HashMap;
 use std::error::Error;
 use std::fs::File;
 use std::io::Read;
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
 use colored::Colorize;
 
 pub trait FileLoader {
     fn load(&self, path: &str, base_path: Option<&Path>, current_path: &Path) -> Result<String, Box<dyn Error>>;
+
+    // Returns the key the module is stored under. The same file must resolve to the same key
+    // no matter which relative path it is imported with
+    fn resolve(&self, path: &str, base_path: Option<&Path>) -> Result<String, Box<dyn Error>>;
 }
 
-pub struct NativeFileLoader;
+// Prefix of the imports that are looked up only in the search paths, e.g. "std:lib"
+pub const STD_PREFIX: &str = "std:";
 
-impl FileLoader for NativeFileLoader {
-    fn load(&self, path: &str, base_path: Option<&Path>, current_path: &Path) -> Result<String, Box<dyn Error>> {
+const MODULE_EXTENSION: &str = "mephisto";
 
-        let resolved_path = if let Some(base) = base_path {
-            base.join(path)
-        } else {
-            Path::new(path).to_path_buf()
-        };
+pub struct NativeFileLoader {
+    // Roots that are tried in order when a module is not found next to the importing file
+    search_paths: Vec<PathBuf>,
+}
+
+impl NativeFileLoader {
+    pub fn new(search_paths: Vec<PathBuf>) -> NativeFileLoader {
+        NativeFileLoader {
+            search_paths,
+        }
+    }
+
+    // Search paths passed explicitly are tried before the ones from MEPHISTO_PATH
+    pub fn with_env_search_paths(search_paths: Vec<PathBuf>) -> NativeFileLoader {
+        let mut search_paths = search_paths;
+
+        if let Some(env_paths) = std::env::var_os("MEPHISTO_PATH") {
+            search_paths.extend(std::env::split_paths(&env_paths).filter(|path| !path.as_os_str().is_empty()));
+        }
+
+        NativeFileLoader::new(search_paths)
+    }
 
-        println!("{} Resolved path: {:?}", "[Module Loader]".blue(), resolved_path);
+    fn find(&self, path: &str, base_path: Option<&Path>) -> Result<PathBuf, Box<dyn Error>> {
+        let candidates = candidate_paths(path, base_path, &self.search_paths);
+
+        match candidates.iter().find(|candidate| candidate.is_file()) {
+            Some(found) => Ok(found.clone()),
+            None => Err(unresolved_module_error(path, &candidates)),
+        }
+    }
+}
+
+impl Default for NativeFileLoader {
+    fn default() -> Self {
+        NativeFileLoader::new(vec![])
+    }
+}
+
+impl FileLoader for NativeFileLoader {
+    fn load(&self, path: &str, base_path: Option<&Path>, _: &Path) -> Result<String, Box<dyn Error>> {
+        let resolved_path = self.find(path, base_path)?;
+
+        eprintln!("{} Resolved path: {:?}", "[Module Loader]".blue(), resolved_path);
 
         let mut file = File::open(&resolved_path)?;
         let mut contents = String::new();
         file.read_to_string(&mut contents)?;
         Ok(contents)
     }
+
+    fn resolve(&self, path: &str, base_path: Option<&Path>) -> Result<String, Box<dyn Error>> {
+        let resolved_path = self.find(path, base_path)?;
+
+        Ok(std::fs::canonicalize(resolved_path)?.to_string_lossy().to_string())
+    }
+}
+
+// Lists the locations a module can be found at, in the order they should be tried.
+// "std:" modules are looked up only in the search paths, explicitly relative ("./", "../")
+// and absolute paths only next to the importing file, the rest next to the importing file first
+pub fn candidate_paths(path: &str, base_path: Option<&Path>, search_paths: &[PathBuf]) -> Vec<PathBuf> {
+    if let Some(name) = path.strip_prefix(STD_PREFIX) {
+        let mut file = PathBuf::from(name);
+
+        if file.extension().is_none() {
+            file.set_extension(MODULE_EXTENSION);
+        }
+
+        return search_paths.iter().map(|root| root.join(&file)).collect();
+    }
+
+    let relative = match base_path {
+        Some(base) => base.join(path),
+        None => PathBuf::from(path),
+    };
+
+    if path.starts_with("./") || path.starts_with("../") || Path::new(path).is_absolute() {
+        return vec![relative];
+    }
+
+    let mut candidates = vec![relative];
+    candidates.extend(search_paths.iter().map(|root| root.join(path)));
+
+    candidates
+}
+
+fn unresolved_module_error(path: &str, candidates: &[PathBuf]) -> Box<dyn Error> {
+    let message = if candidates.is_empty() {
+        format!("Cannot find module \"{}\", no search paths are set (use --path or MEPHISTO_PATH)", path)
+    } else {
+        let tried: Vec<String> = candidates.iter().map(|candidate| format!("    {}", candidate.display())).collect();
+
+        format!("Cannot find module \"{}\", tried:\n{}", path, tried.join("\n"))
+    };
+
+    Box::new(std::io::Error::new(std::io::ErrorKind::NotFound, message))
+}
+
+// Standard modules compiled into the binary, imported as "std:<name>"
+pub const BUNDLED_MODULES: [(&str, &str); 10] = [
+    ("adsr", include_str!("../std/adsr.mephisto")),
+    ("ar", include_str!("../std/ar.mephisto")),
+    ("echo", include_str!("../std/echo.mephisto")),
+    ("freeverb", include_str!("../std/freeverb.mephisto")),
+    ("gate-sequencer", include_str!("../std/gate-sequencer.mephisto")),
+    ("lib", include_str!("../std/lib.mephisto")),
+    ("limiter", include_str!("../std/limiter.mephisto")),
+    ("lowpass", include_str!("../std/lowpass.mephisto")),
+    ("osc", include_str!("../std/osc.mephisto")),
+    ("phaser", include_str!("../std/phaser.mephisto")),
+];
+
+pub fn bundled_module(path: &str) -> Option<&'static str> {
+    let name = path.strip_prefix(STD_PREFIX)?;
+    let name = name.strip_suffix(".mephisto").unwrap_or(name);
+
+    BUNDLED_MODULES.iter().find(|(module_name, _)| *module_name == name).map(|(_, source)| *source)
+}
+
+// Serves the bundled "std:" modules and passes everything else to the wrapped loader,
+// so the bundled versions win over the ones found in the search paths
+pub struct BuiltinFileLoader<L: FileLoader> {
+    fallback: L,
+}
+
+impl<L: FileLoader> BuiltinFileLoader<L> {
+    pub fn new(fallback: L) -> BuiltinFileLoader<L> {
+        BuiltinFileLoader {
+            fallback,
+        }
+    }
+}
+
+impl<L: FileLoader> FileLoader for BuiltinFileLoader<L> {
+    fn load(&self, path: &str, base_path: Option<&Path>, current_path: &Path) -> Result<String, Box<dyn Error>> {
+        match bundled_module(path) {
+            Some(source) => Ok(source.to_string()),
+            None => self.fallback.load(path, base_path, current_path),
+        }
+    }
+
+    // Bundled modules are keyed by their "std:" name, they do not exist on disk
+    fn resolve(&self, path: &str, base_path: Option<&Path>) -> Result<String, Box<dyn Error>> {
+        match bundled_module(path) {
+            Some(_) => Ok(path.strip_suffix(".mephisto").unwrap_or(path).to_string()),
+            None => self.fallback.resolve(path, base_path),
+        }
+    }
 }
 
 pub struct StubFileLoader {
@@ -41,8 +181,9 @@ impl StubFileLoader {
     }
 }
 impl FileLoader for StubFileLoader {
-    fn load(&self, path: &str, _base_path_: Option<&Path>, _: &Path) -> Result<String, Box<dyn Error>> {
-        let contents = self.files.get(path);
+    fn load(&self, path: &str, base_path: Option<&Path>, _: &Path) -> Result<String, Box<dyn Error>> {
+        let resolved_path = self.resolve(path, base_path)?;
+        let contents = self.files.get(&resolved_path);
 
         if contents.is_none() {
             Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("File {} not found", path)))?;
@@ -50,4 +191,210 @@ impl FileLoader for StubFileLoader {
 
         Ok(contents.unwrap().to_string())
     }
+
+    // Files are stored under normalized relative paths, e.g. "examples/lib.mephisto"
+    fn resolve(&self, path: &str, base_path: Option<&Path>) -> Result<String, Box<dyn Error>> {
+        let resolved_path = if let Some(base) = base_path {
+            base.join(path)
+        } else {
+            Path::new(path).to_path_buf()
+        };
+
+        Ok(normalize_path(&resolved_path))
+    }
+}
+
+// In-memory project tree, resolves imports the same way NativeFileLoader does.
+// Files are stored under normalized paths, e.g. "project/shared/lib.mephisto"
+pub struct VirtualFileLoader {
+    files: HashMap<String, String>,
+    search_paths: Vec<PathBuf>,
+}
+
+impl VirtualFileLoader {
+    pub fn new(search_paths: Vec<PathBuf>) -> VirtualFileLoader {
+        VirtualFileLoader {
+            files: HashMap::new(),
+            search_paths,
+        }
+    }
+
+    pub fn add_file(&mut self, path: &str, contents: &str) {
+        self.files.insert(normalize_path(Path::new(path)), contents.to_string());
+    }
+
+    fn find(&self, path: &str, base_path: Option<&Path>) -> Result<String, Box<dyn Error>> {
+        let candidates = candidate_paths(path, base_path, &self.search_paths);
+
+        candidates.iter()
+            .map(|candidate| normalize_path(candidate))
+            .find(|candidate| self.files.contains_key(candidate))
+            .ok_or_else(|| unresolved_module_error(path, &candidates))
+    }
+}
+
+impl Default for VirtualFileLoader {
+    fn default() -> Self {
+        VirtualFileLoader::new(vec![])
+    }
+}
+
+impl FileLoader for VirtualFileLoader {
+    fn load(&self, path: &str, base_path: Option<&Path>, _: &Path) -> Result<String, Box<dyn Error>> {
+        let resolved_path = self.find(path, base_path)?;
+
+        Ok(self.files[&resolved_path].clone())
+    }
+
+    fn resolve(&self, path: &str, base_path: Option<&Path>) -> Result<String, Box<dyn Error>> {
+        self.find(path, base_path)
+    }
+}
+
+// Removes "." and resolves ".." components without touching the file system
+pub(crate) fn normalize_path(path: &Path) -> String {
+    let mut normalized = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                match normalized.components().next_back() {
+                    Some(Component::Normal(_)) => {
+                        normalized.pop();
+                    }
+                    _ => {
+                        normalized.push("..");
+                    }
+                }
+            }
+            _ => {
+                normalized.push(component);
+            }
+        }
+    }
+
+    normalized.to_string_lossy().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::collections::HashMap;
+    use super::{bundled_module, candidate_paths, BuiltinFileLoader, FileLoader, NativeFileLoader, StubFileLoader, VirtualFileLoader};
+
+    fn temp_root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("mephisto_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    #[test]
+    fn test_candidate_paths() {
+        let search_paths = vec![PathBuf::from("/opt/mephisto"), PathBuf::from("/home/user/modules")];
+
+        assert_eq!(candidate_paths("lib.mephisto", Some(Path::new("examples")), &search_paths), vec![
+            PathBuf::from("examples/lib.mephisto"),
+            PathBuf::from("/opt/mephisto/lib.mephisto"),
+            PathBuf::from("/home/user/modules/lib.mephisto"),
+        ]);
+
+        assert_eq!(candidate_paths("./lib.mephisto", Some(Path::new("examples")), &search_paths), vec![
+            PathBuf::from("examples/./lib.mephisto"),
+        ]);
+
+        assert_eq!(candidate_paths("std:lib", Some(Path::new("examples")), &search_paths), vec![
+            PathBuf::from("/opt/mephisto/lib.mephisto"),
+            PathBuf::from("/home/user/modules/lib.mephisto"),
+        ]);
+
+        assert_eq!(candidate_paths("std:filters/svf.mephisto", None, &search_paths), vec![
+            PathBuf::from("/opt/mephisto/filters/svf.mephisto"),
+            PathBuf::from("/home/user/modules/filters/svf.mephisto"),
+        ]);
+    }
+
+    #[test]
+    fn test_search_paths() {
+        let root = temp_root("search_paths");
+        let project = root.join("project");
+        let first = root.join("first");
+        let second = root.join("second");
+
+        for dir in [&project, &first, &second] {
+            fs::create_dir_all(dir).unwrap();
+        }
+
+        fs::write(project.join("local.mephisto"), "// project").unwrap();
+        fs::write(first.join("local.mephisto"), "// first").unwrap();
+        fs::write(second.join("lib.mephisto"), "// second").unwrap();
+
+        let loader = NativeFileLoader::new(vec![first.clone(), second.clone()]);
+
+        // The importing file's directory wins over the search paths
+        assert_eq!(loader.load("local.mephisto", Some(&project), &project).unwrap(), "// project");
+        assert_eq!(loader.load("lib.mephisto", Some(&project), &project).unwrap(), "// second");
+        assert_eq!(loader.load("std:lib", Some(&project), &project).unwrap(), "// second");
+        assert_eq!(
+            loader.resolve("std:lib", Some(&project)).unwrap(),
+            fs::canonicalize(second.join("lib.mephisto")).unwrap().to_string_lossy(),
+        );
+
+        let error = loader.resolve("std:missing", Some(&project)).unwrap_err().to_string();
+        assert_eq!(error, format!(
+            "Cannot find module \"std:missing\", tried:\n    {}\n    {}",
+            first.join("missing.mephisto").display(),
+            second.join("missing.mephisto").display(),
+        ));
+
+        let error = NativeFileLoader::default().resolve("std:lib", None).unwrap_err().to_string();
+        assert_eq!(error, "Cannot find module \"std:lib\", no search paths are set (use --path or MEPHISTO_PATH)");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_builtin_file_loader() {
+        let mut files = HashMap::new();
+
+        files.insert("std:lib".to_string(), "// shadowed".to_string());
+        files.insert("examples/lib.mephisto".to_string(), "// local".to_string());
+
+        let loader = BuiltinFileLoader::new(StubFileLoader::new(files));
+        let base = Path::new("examples");
+
+        assert_eq!(loader.resolve("std:lib", Some(base)).unwrap(), "std:lib");
+        assert_eq!(loader.resolve("std:lib.mephisto", Some(base)).unwrap(), "std:lib");
+        assert_eq!(loader.load("std:lib", Some(base), base).unwrap(), bundled_module("std:lib").unwrap());
+
+        assert_eq!(loader.resolve("./lib.mephisto", Some(base)).unwrap(), "examples/lib.mephisto");
+        assert_eq!(loader.load("./lib.mephisto", Some(base), base).unwrap(), "// local");
+
+        assert!(bundled_module("lib").is_none());
+        assert!(bundled_module("std:missing").is_none());
+    }
+
+    #[test]
+    fn test_virtual_file_loader() {
+        let mut loader = VirtualFileLoader::new(vec![PathBuf::from("vendor")]);
+
+        loader.add_file("project/src/main.mephisto", "// main");
+        loader.add_file("project/shared/lib.mephisto", "// shared");
+        loader.add_file("./vendor/filters/svf.mephisto", "// svf");
+        loader.add_file("vendor/lib.mephisto", "// vendored lib");
+
+        let base = Path::new("project/src");
+
+        assert_eq!(loader.resolve("../shared/lib.mephisto", Some(base)).unwrap(), "project/shared/lib.mephisto");
+        assert_eq!(loader.resolve("./../src/../shared/./lib.mephisto", Some(base)).unwrap(), "project/shared/lib.mephisto");
+        assert_eq!(loader.load("../shared/lib.mephisto", Some(base), base).unwrap(), "// shared");
+
+        assert_eq!(loader.resolve("filters/svf.mephisto", Some(base)).unwrap(), "vendor/filters/svf.mephisto");
+        assert_eq!(loader.resolve("std:lib", Some(base)).unwrap(), "vendor/lib.mephisto");
+
+        let error = loader.resolve("./lib.mephisto", Some(base)).unwrap_err().to_string();
+        assert_eq!(error, "Cannot find module \"./lib.mephisto\", tried:\n    project/src/./lib.mephisto");
+    }
 }
@@ -1,6 +1,9 @@
+use std::collections::BTreeMap;
+
+use crate::diagnostic::{Diagnostic, INTERNAL_ERROR, MALFORMED_NUMBER, SYNTAX_ERROR};
 use crate::lexer::token::{Position, Token};
 use crate::lexer::token_type::TokenType;
-use crate::parser::ast::{AST, Node, Operator, VariableSpecifier};
+use crate::parser::ast::{AST, Attribute, Node, NodeTrivia, Operator, Trivia, TriviaKind, Unit, VariableSpecifier};
 
 pub mod ast;
 
@@ -8,7 +11,16 @@ pub struct Parser {
     tokens: Vec<Token>,
     position: usize,
     ast: Node,
-    errors: Vec<String>,
+    errors: Vec<Diagnostic>,
+    spans: Vec<NodeSpan>,
+}
+
+// Source range of a parsed statement, parameter field, function parameter or primary expression,
+// the nodes trivia is attached to
+struct NodeSpan {
+    node_start: u32,
+    start: u32,
+    end: u32,
 }
 
 impl Parser {
@@ -18,6 +30,7 @@ impl Parser {
             position: 0,
             ast: Node::ProgramNode { children: Vec::new(), position: Position::new() },
             errors: Vec::new(),
+            spans: Vec::new(),
         }
     }
 
@@ -25,6 +38,7 @@ impl Parser {
         self.tokens = Vec::new();
         self.ast = Node::ProgramNode { children: Vec::new(), position: Position::new() };
         self.errors = Vec::new();
+        self.spans = Vec::new();
         self.position = 0;
     }
 
@@ -39,204 +53,217 @@ impl Parser {
         while self.position < self.tokens.len() {
             if let Node::ProgramNode { children, .. } = &mut ast {
                 let token = self.peek();
-
-                match token.token_type {
-                    TokenType::IMPORT => {
-                        let statement = match self.parse_import_statement() {
-                            Ok(statement) => statement,
-                            Err(e) => {
-                                self.errors.push(e);
-
-                                return AST {
-                                    root: ast.clone(),
-                                    errors: self.errors.clone(),
-                                };
-                            }
-                        };
-
-                        children.push(statement);
-                    }
-                    TokenType::PROCESS => {
-                        let process_node = match self.parse_process() {
-                            Ok(process_node) => process_node,
-                            Err(e) => {
-                                self.errors.push(e);
-
-                                return AST {
-                                    root: ast.clone(),
-                                    errors: self.errors.clone(),
-                                };
-                            }
-                        };
-
-                        children.push(process_node);
-                    }
-                    TokenType::BLOCK => {
-                        let block_node = match self.parse_block_section() {
-                            Ok(block_node) => block_node,
-                            Err(e) => {
-                                self.errors.push(e);
-
-                                return AST {
-                                    root: ast.clone(),
-                                    errors: self.errors.clone(),
-                                };
-                            }
-                        };
-
-                        children.push(block_node);
-                    }
-                    TokenType::INPUT | TokenType::OUTPUT | TokenType::LET | TokenType::CONST => {
-                        let variable_declaration_stmt = match self.parse_variable_declaration_stmt() {
-                            Ok(variable_declaration_stmt) => variable_declaration_stmt,
-                            Err(e) => {
-                                self.errors.push(e);
-
-                                return AST {
-                                    root: ast.clone(),
-                                    errors: self.errors.clone(),
-                                };
-                            }
-                        };
-
-                        children.push(variable_declaration_stmt);
-                    }
-                    TokenType::BUFFER => {
-                        let buffer_statement = match self.parse_buffer_declaration_stmt() {
-                            Ok(buffer_statement) => buffer_statement,
-                            Err(e) => {
-                                self.errors.push(e);
-
-                                return AST {
-                                    root: ast.clone(),
-                                    errors: self.errors.clone(),
-                                };
-                            }
-                        };
-
-                        children.push(buffer_statement);
-                    }
-                    TokenType::FN => {
-                        let function_declaration_stmt = match self.parse_function_declaration_stmt() {
-                            Ok(function_declaration_stmt) => function_declaration_stmt,
-                            Err(e) => {
-                                self.errors.push(e);
-
-                                return AST {
-                                    root: ast.clone(),
-                                    errors: self.errors.clone(),
-                                };
-                            }
-                        };
-                        children.push(function_declaration_stmt);
-                    }
-                    TokenType::ID => {
-                        let statement = match self.parse_expression_statement() {
-                            Ok(statement) => statement,
-                            Err(e) => {
-                                self.errors.push(e);
-
-                                return AST {
-                                    root: ast.clone(),
-                                    errors: self.errors.clone(),
-                                };
-                            }
-                        };
-
-                        children.push(statement);
-                    }
-                    TokenType::EXPORT => {
-                        let export_statement = match self.parse_export_declaration_stmt() {
-                            Ok(export_statement) => export_statement,
-                            Err(e) => {
-                                self.errors.push(e);
-
-                                return AST {
-                                    root: ast.clone(),
-                                    errors: self.errors.clone(),
-                                };
-                            }
-                        };
-
-                        children.push(export_statement);
-                    }
-                    TokenType::CONNECT => {
-                        let connect_statement = match self.parse_connect() {
-                            Ok(connect_statement) => connect_statement,
-                            Err(e) => {
-                                self.errors.push(e);
-
-                                return AST {
-                                    root: ast.clone(),
-                                    errors: self.errors.clone(),
-                                };
-                            }
-                        };
-
-                        children.push(connect_statement);
-                    }
-                    TokenType::PARAM => {
-                        let parameter_declaration_stmt = match self.parse_parameter_declaration_stmt() {
-                            Ok(parameter_declaration_stmt) => parameter_declaration_stmt,
-                            Err(e) => {
-                                self.errors.push(e);
-
-                                return AST {
-                                    root: ast.clone(),
-                                    errors: self.errors.clone(),
-                                };
-                            }
-                        };
-
-                        children.push(parameter_declaration_stmt);
-                    }
-                    TokenType::IF => {
-                        let if_statement = match self.parse_if_statement() {
-                            Ok(if_statement) => if_statement,
-                            Err(e) => {
-                                self.errors.push(e);
-
-                                return AST {
-                                    root: ast.clone(),
-                                    errors: self.errors.clone(),
-                                };
-                            }
-                        };
-
-                        children.push(if_statement);
-                    }
+                let start = self.position;
+
+                let result = match token.token_type {
+                    TokenType::IMPORT => self.parse_import_statement(),
+                    TokenType::PROCESS => self.parse_process(),
+                    TokenType::BLOCK => self.parse_block_section(),
+                    TokenType::INPUT | TokenType::OUTPUT | TokenType::LET | TokenType::CONST => self.parse_variable_declaration_stmt(),
+                    TokenType::BUFFER => self.parse_buffer_declaration_stmt(),
+                    TokenType::FN => self.parse_function_declaration_stmt(),
+                    TokenType::ID => self.parse_expression_statement(),
+                    TokenType::EXPORT => self.parse_export_declaration_stmt(),
+                    TokenType::CONNECT => self.parse_connect(),
+                    TokenType::PARAM => self.parse_parameter_declaration_stmt(),
+                    TokenType::IF => self.parse_if_statement(),
+                    TokenType::AT => self.parse_attributed_declaration(true),
                     TokenType::EOF => {
                         break;
                     }
-                    _ => {
-                        self.errors.push(format!("Unexpected token: {}", token.to_string()));
+                    _ => Err(self.generic_error(&token, "declaration or statement")),
+                };
 
-                        return AST {
-                            root: ast.clone(),
-                            errors: self.errors.clone(),
-                        };
+                match result {
+                    Ok(node) => {
+                        self.record_node(&node, start);
+                        children.push(node);
+                    }
+                    Err(e) => {
+                        self.errors.push(e);
+                        self.synchronize(start, true);
                     }
                 }
             }
         }
 
-
         // let end = self.position();
 
         // println!("End: {:?}", end);
 
         self.set_end(&mut ast);
 
-        AST {
-            root: ast,
-            errors: Vec::new(),
+        AST::new(ast, self.errors.clone())
+    }
+
+    // Parses the output of Lexer::tokenize_lossless. Comments and whitespace are attached to
+    // the statements around them in AST::trivia instead of being dropped
+    pub fn parse_lossless(&mut self, input: Vec<Token>) -> AST {
+        let gaps = trivia_gaps(&input);
+
+        let number_literals = input.iter()
+            .filter(|token| token.token_type == TokenType::NUMBER)
+            .map(|token| (token.position.start, token.literal.clone()))
+            .collect();
+
+        let mut ast = self.parse(input.into_iter().filter(|token| !token.is_trivia()).collect());
+
+        for gap in gaps {
+            self.attach_trivia(gap, &mut ast.trivia);
+        }
+
+        ast.number_literals = number_literals;
+
+        ast
+    }
+
+    fn record_node(&mut self, node: &Node, start: usize) {
+        let end = self.last_token_end();
+
+        self.spans.push(NodeSpan {
+            node_start: node.position().start,
+            start: self.token_at(start).position.start,
+            end,
+        });
+    }
+
+    // Trivia on the line of the previous token trails the node ending there, the rest leads
+    // the node starting after it. Anything left over goes to the closest node around
+    fn attach_trivia(&self, gap: TriviaGap, trivia: &mut BTreeMap<u32, NodeTrivia>) {
+        // Outer nodes are recorded after the ones nested in them, so the outermost one is the last match
+        let ending = gap.previous_end.and_then(|end| self.spans.iter().rev().find(|span| span.end == end));
+        let starting = self.spans.iter().rev().find(|span| span.start == gap.next_start);
+        let enclosing = gap.previous_end.and_then(|end| {
+            self.spans.iter()
+                .filter(|span| span.start < end && span.end > gap.next_start)
+                .max_by_key(|span| span.start)
+        });
+
+        let line_end = if gap.previous_end.is_some() {
+            gap.trivia.iter().position(|trivia| trivia.kind == TriviaKind::Whitespace && trivia.text.contains('\n')).unwrap_or(gap.trivia.len())
+        } else {
+            0
+        };
+
+        let mut same_line = gap.trivia;
+        let mut rest = same_line.split_off(line_end);
+
+        if let Some(ending) = ending {
+            trivia.entry(ending.node_start).or_default().trailing.append(&mut same_line);
+        } else {
+            same_line.append(&mut rest);
+            rest = same_line;
+        }
+
+        if rest.is_empty() {
+            return;
+        }
+
+        if let Some(starting) = starting {
+            trivia.entry(starting.node_start).or_default().leading.append(&mut rest);
+        } else if let Some(owner) = ending.or(enclosing) {
+            trivia.entry(owner.node_start).or_default().trailing.append(&mut rest);
+        }
+    }
+
+    // Parses statements up to the "}" closing the enclosing section or block. A statement that
+    // fails to parse is recorded and skipped, so the statements after it are still checked.
+    // A top-level keyword means the closing "}" is missing, it is left for the caller to report
+    fn parse_statement_list(&mut self, parse_statement: fn(&mut Parser) -> Result<Node, Diagnostic>) -> Vec<Node> {
+        let mut children = Vec::new();
+
+        loop {
+            let token_type = self.peek().token_type;
+
+            if matches!(token_type, TokenType::RCURLY | TokenType::EOF) || is_top_level_keyword(&token_type) {
+                break;
+            }
+
+            let start = self.position;
+
+            match parse_statement(self) {
+                Ok(child) => {
+                    self.record_node(&child, start);
+                    children.push(child);
+                }
+                Err(e) => {
+                    self.errors.push(e);
+                    self.synchronize(start, false);
+                }
+            }
+        }
+
+        children
+    }
+
+    // Skips the rest of a construct that failed to parse, starting at token index `start`.
+    // Parsing resumes after the ";" or "}" ending it, or at the next keyword that starts a new one.
+    // Braces opened since `start` have to be closed first, unless a top-level keyword shows up
+    fn synchronize(&mut self, start: usize, top_level: bool) {
+        let end = self.position.min(self.tokens.len());
+        let mut depth: i32 = self.tokens[start.min(end)..end].iter().map(|token| brace_depth_change(&token.token_type)).sum();
+
+        // Always make progress, otherwise the same token would fail again
+        if self.position == start && self.peek().token_type != TokenType::EOF {
+            depth += brace_depth_change(&self.consume().token_type);
+
+            if depth <= 0 && matches!(self.token_at(start).token_type, TokenType::SEMI | TokenType::RCURLY) {
+                return;
+            }
+        }
+
+        loop {
+            let token_type = self.peek().token_type;
+
+            match token_type {
+                TokenType::EOF => return,
+                _ if is_top_level_keyword(&token_type) => return,
+                _ if depth <= 0 && is_statement_keyword(&token_type) => return,
+                TokenType::SEMI => {
+                    self.consume();
+
+                    if depth <= 0 {
+                        return;
+                    }
+                }
+                TokenType::LCURLY => {
+                    self.consume();
+                    depth += 1;
+                }
+                TokenType::RCURLY => {
+                    // Belongs to the enclosing block, which is still being parsed
+                    if depth <= 0 && !top_level {
+                        return;
+                    }
+
+                    self.consume();
+                    depth -= 1;
+
+                    if depth <= 0 {
+                        // Parameter and buffer declarations end with "};"
+                        if top_level && self.peek().token_type == TokenType::SEMI {
+                            self.consume();
+                        }
+
+                        return;
+                    }
+                }
+                _ => {
+                    self.consume();
+                }
+            }
         }
     }
 
-    fn parse_import_statement(&mut self) -> Result<Node, String> {
+    fn parse_import_statement(&mut self) -> Result<Node, Diagnostic> {
         let position = self.position();
 
         self.skip(TokenType::IMPORT)?;
+
+        if self.peek().token_type == TokenType::LCURLY {
+            return self.parse_named_import_statement(position);
+        }
+
         let id = match self.parse_id() {
             Ok(id) => id,
             Err(e) => return Err(e),
@@ -254,6 +281,7 @@ impl Parser {
             let mut node = Node::ImportStatement {
                 id: Box::new(id),
                 path,
+                attributes: vec![],
                 position: position.clone(),
             };
 
@@ -267,7 +295,48 @@ impl Parser {
         }
     }
 
-    fn parse_buffer_declaration_stmt(&mut self) -> Result<Node, String> {
+    // import { a, b } from "path";
+    fn parse_named_import_statement(&mut self, position: Position) -> Result<Node, Diagnostic> {
+        self.skip(TokenType::LCURLY)?;
+
+        let mut names = vec![self.parse_id()?];
+
+        while self.peek().token_type == TokenType::COMMA {
+            self.skip(TokenType::COMMA)?;
+
+            // Trailing comma
+            if self.peek().token_type == TokenType::RCURLY {
+                break;
+            }
+
+            names.push(self.parse_id()?);
+        }
+
+        self.skip(TokenType::RCURLY)?;
+        self.skip(TokenType::FROM)?;
+
+        let path = self.consume();
+        if path.token_type != TokenType::STRING {
+            return Err(self.generic_error(&path, "Expected string literal"));
+        }
+
+        // Remove quotes
+        let path = path.literal[1..path.literal.len() - 1].to_string();
+
+        self.skip(TokenType::SEMI)?;
+
+        let mut node = Node::NamedImportStatement {
+            names,
+            path,
+            position,
+        };
+
+        self.set_end(&mut node);
+
+        Ok(node)
+    }
+
+    fn parse_buffer_declaration_stmt(&mut self) -> Result<Node, Diagnostic> {
         let position = self.position();
 
         self.skip(TokenType::BUFFER)?;
@@ -313,7 +382,7 @@ impl Parser {
         Ok(node)
     }
 
-    fn parse_buffer_initialization(&mut self) -> Result<Node, String> {
+    fn parse_buffer_initialization(&mut self) -> Result<Node, Diagnostic> {
         let mut buffer_initialization = Node::BufferInitializer {
             children: Vec::new(),
             position: self.position(),
@@ -322,7 +391,7 @@ impl Parser {
         self.skip(TokenType::BUFI)?;
         self.skip(TokenType::LCURLY)?;
 
-        while self.tokens[self.position].token_type != TokenType::RCURLY {
+        while self.token_at(self.position).token_type != TokenType::RCURLY {
             if let Node::BufferInitializer { children, position: _ } = &mut buffer_initialization {
                 let statement = self.parse_statement()?;
                 children.push(statement);
@@ -336,7 +405,7 @@ impl Parser {
         Ok(buffer_initialization)
     }
 
-    fn parse_parameter_declaration_stmt(&mut self) -> Result<Node, String> {
+    fn parse_parameter_declaration_stmt(&mut self) -> Result<Node, Diagnostic> {
         let position = self.position();
 
         self.skip(TokenType::PARAM)?;
@@ -350,11 +419,12 @@ impl Parser {
         let mut parameter_declaration_stmt = Node::ParameterDeclarationStmt {
             id: Box::new(id),
             fields: Vec::new(),
+            attributes: vec![],
             position,
         };
 
-        while self.tokens[self.position].token_type != TokenType::RCURLY {
-            if let Node::ParameterDeclarationStmt { id: _, fields, position: _ } = &mut parameter_declaration_stmt {
+        while self.token_at(self.position).token_type != TokenType::RCURLY {
+            if let Node::ParameterDeclarationStmt { fields, .. } = &mut parameter_declaration_stmt {
                 let field = match self.parse_parameter_declaration_field() {
                     Ok(field) => field,
                     Err(e) => return Err(e),
@@ -371,7 +441,8 @@ impl Parser {
         Ok(parameter_declaration_stmt)
     }
 
-    fn parse_parameter_declaration_field(&mut self) -> Result<Node, String> {
+    fn parse_parameter_declaration_field(&mut self) -> Result<Node, Diagnostic> {
+        let start = self.position;
         let position = self.position();
 
         let id = match self.parse_id() {
@@ -407,24 +478,19 @@ impl Parser {
         };
 
         self.set_end(&mut node);
+        self.record_node(&node, start);
 
         Ok(node)
     }
 
-    fn parse_connect(&mut self) -> Result<Node, String> {
+    fn parse_connect(&mut self) -> Result<Node, Diagnostic> {
         let position = self.position();
 
         self.skip(TokenType::CONNECT)?;
         self.skip(TokenType::LCURLY)?;
 
-        let mut connect = Node::ConnectSection { children: Vec::new(), position };
-
-        while self.tokens[self.position].token_type != TokenType::RCURLY {
-            if let Node::ConnectSection { children, position: _ } = &mut connect {
-                let child = self.parse_connect_statement()?;
-                children.push(child);
-            }
-        }
+        let children = self.parse_statement_list(Parser::parse_connect_statement);
+        let mut connect = Node::ConnectSection { children, position };
 
         self.skip(TokenType::RCURLY)?;
 
@@ -433,7 +499,7 @@ impl Parser {
         Ok(connect)
     }
 
-    fn parse_connect_statement(&mut self) -> Result<Node, String> {
+    fn parse_connect_statement(&mut self) -> Result<Node, Diagnostic> {
         let position = self.position();
 
         let token = self.peek();
@@ -463,7 +529,7 @@ impl Parser {
         }
     }
 
-    fn parse_right_connection_member(&mut self) -> Result<Node, String> {
+    fn parse_right_connection_member(&mut self) -> Result<Node, Diagnostic> {
         let token = self.peek();
 
         match token.token_type {
@@ -479,7 +545,7 @@ impl Parser {
         }
     }
 
-    fn parse_outputs_stmt(&mut self) -> Result<Node, String> {
+    fn parse_outputs_stmt(&mut self) -> Result<Node, Diagnostic> {
         let position = self.position();
 
         self.skip(TokenType::OUTPUTS)?;
@@ -503,12 +569,12 @@ impl Parser {
         Err(self.generic_error(&token, "number"))
     }
 
-    fn parse_connection_member(&mut self) -> Result<Node, String> {
+    fn parse_connection_member(&mut self) -> Result<Node, Diagnostic> {
         let token = self.peek();
 
         match token.token_type {
             TokenType::ID => {
-                let next_token = self.tokens[self.position + 1].clone();
+                let next_token = self.token_at(self.position + 1).clone();
 
                 if next_token.token_type != TokenType::DOT {
                     return self.parse_id();
@@ -522,7 +588,7 @@ impl Parser {
         }
     }
 
-    fn parse_export_declaration_stmt(&mut self) -> Result<Node, String> {
+    fn parse_export_declaration_stmt(&mut self) -> Result<Node, Diagnostic> {
         let position = self.position();
         self.skip(TokenType::EXPORT)?;
 
@@ -535,8 +601,14 @@ impl Parser {
             TokenType::FN => {
                 self.parse_function_declaration_stmt()?
             }
+            TokenType::AT => {
+                match self.parse_attributed_declaration(true)? {
+                    declaration @ (Node::VariableDeclarationStmt { .. } | Node::FunctionDeclarationStmt { .. }) => declaration,
+                    _ => Err(self.generic_error(&token, "variable declaration or function declaration"))?
+                }
+            }
             // TokenType::ID => {
-            //     let next_token = self.tokens[self.position + 1].clone();
+            //     let next_token = self.token_at(self.position + 1).clone();
             //
             //     match next_token.token_type {
             //         TokenType::LPAREN => {
@@ -562,7 +634,91 @@ impl Parser {
         Ok(export_declaration_stmt)
     }
 
-    fn parse_expression_statement(&mut self) -> Result<Node, String> {
+    // Attributes are attached to the declaration that follows them. Inside sections and functions
+    // only local declarations can have attributes
+    fn parse_attributed_declaration(&mut self, top_level: bool) -> Result<Node, Diagnostic> {
+        let mut attributes = Vec::new();
+
+        while self.peek().token_type == TokenType::AT {
+            attributes.push(self.parse_attribute()?);
+        }
+
+        let token = self.peek();
+
+        let mut declaration = match token.token_type {
+            TokenType::LET | TokenType::CONST => self.parse_variable_declaration_stmt()?,
+            TokenType::FN => self.parse_function_declaration_stmt()?,
+            TokenType::INPUT | TokenType::OUTPUT if top_level => self.parse_variable_declaration_stmt()?,
+            TokenType::PARAM if top_level => self.parse_parameter_declaration_stmt()?,
+            TokenType::IMPORT if top_level => self.parse_import_statement()?,
+            TokenType::EXPORT if top_level => self.parse_export_declaration_stmt()?,
+            _ => {
+                Err(self.generic_error(&token, "declaration after attribute"))?
+            }
+        };
+
+        let target = match &mut declaration {
+            Node::ExportDeclarationStmt { declaration, .. } => declaration.as_mut(),
+            declaration => declaration,
+        };
+
+        match target {
+            Node::VariableDeclarationStmt { attributes: target, .. }
+            | Node::FunctionDeclarationStmt { attributes: target, .. }
+            | Node::ParameterDeclarationStmt { attributes: target, .. }
+            | Node::ImportStatement { attributes: target, .. } => {
+                target.extend(attributes);
+            }
+            // Named imports have no single declaration to attach the attributes to
+            _ => {
+                Err(self.generic_error(&token, "declaration after attribute"))?
+            }
+        }
+
+        Ok(declaration)
+    }
+
+    fn parse_attribute(&mut self) -> Result<Attribute, Diagnostic> {
+        let position = self.position();
+
+        self.skip(TokenType::AT)?;
+
+        let name = self.consume();
+        if name.token_type != TokenType::ID {
+            return Err(self.generic_error(&name, "attribute name"));
+        }
+
+        let mut args = Vec::new();
+
+        if self.peek().token_type == TokenType::LPAREN {
+            self.skip(TokenType::LPAREN)?;
+
+            while self.peek().token_type != TokenType::RPAREN {
+                let arg = self.consume();
+                if arg.token_type != TokenType::ID {
+                    return Err(self.generic_error(&arg, "attribute argument"));
+                }
+
+                args.push(arg.literal);
+
+                if self.peek().token_type != TokenType::RPAREN {
+                    self.skip(TokenType::COMMA)?;
+                }
+            }
+
+            self.skip(TokenType::RPAREN)?;
+        }
+
+        let end = self.token_at(self.position - 1).position.end;
+
+        Ok(Attribute {
+            name: name.literal,
+            args,
+            position: Position { end, ..position },
+        })
+    }
+
+    fn parse_expression_statement(&mut self) -> Result<Node, Diagnostic> {
         let position = self.position();
         let expr = self.parse_statement()?;
 
@@ -581,12 +737,12 @@ impl Parser {
         Ok(node)
     }
 
-    fn parse_statement(&mut self) -> Result<Node, String> {
+    fn parse_statement(&mut self) -> Result<Node, Diagnostic> {
         let token = self.peek();
 
         match token.token_type {
             TokenType::ID => {
-                let next_token = self.tokens[self.position + 1].clone();
+                let next_token = self.token_at(self.position + 1).clone();
 
                 let node = match next_token.token_type {
                     TokenType::LPAREN => {
@@ -596,7 +752,15 @@ impl Parser {
 
                         n
                     }
-                    TokenType::DEF => {
+                    TokenType::DOT => {
+                        let n = self.parse_member_expr()?;
+
+                        match n {
+                            Node::FnCallExpr { .. } => Ok(n),
+                            _ => Err(self.generic_error(&self.peek(), "function call"))?
+                        }
+                    }
+                    TokenType::DEF | TokenType::PLUSDEF | TokenType::MINUSDEF | TokenType::MULDEF | TokenType::DIVDEF => {
                         self.parse_assignment_expression()
                     }
                     _ => {
@@ -609,6 +773,9 @@ impl Parser {
             TokenType::IF => {
                 self.parse_if_statement()
             }
+            TokenType::FOR => {
+                self.parse_for_statement()
+            }
             TokenType::LCURLY => {
                 self.parse_block()
             }
@@ -621,13 +788,16 @@ impl Parser {
             TokenType::RETURN => {
                 self.parse_return_stmt()
             }
+            TokenType::AT => {
+                self.parse_attributed_declaration(false)
+            }
             _ => {
                 Err(self.generic_error(&token, "statement"))?
             }
         }
     }
 
-    fn parse_return_stmt(&mut self) -> Result<Node, String> {
+    fn parse_return_stmt(&mut self) -> Result<Node, Diagnostic> {
         let position = self.position();
         self.skip(TokenType::RETURN)?;
         let expr = self.parse_expression()?;
@@ -642,7 +812,7 @@ impl Parser {
         Ok(return_stmt)
     }
 
-    fn parse_function_declaration_stmt(&mut self) -> Result<Node, String> {
+    fn parse_function_declaration_stmt(&mut self) -> Result<Node, Diagnostic> {
         let position = self.position();
 
         self.skip(TokenType::FN)?;
@@ -656,6 +826,7 @@ impl Parser {
             id: Box::new(id),
             params,
             body: Box::new(body),
+            attributes: vec![],
             position,
         };
 
@@ -664,13 +835,13 @@ impl Parser {
         Ok(node)
     }
 
-    fn parse_params(&mut self) -> Result<Vec<Node>, String> {
+    fn parse_params(&mut self) -> Result<Vec<Node>, Diagnostic> {
         let mut params = Vec::new();
 
-        while self.tokens[self.position].token_type != TokenType::RPAREN {
+        while self.token_at(self.position).token_type != TokenType::RPAREN {
             params.push(self.parse_param()?);
 
-            if self.tokens[self.position].token_type == TokenType::COMMA {
+            if self.token_at(self.position).token_type == TokenType::COMMA {
                 self.skip(TokenType::COMMA)?;
             }
         }
@@ -678,7 +849,8 @@ impl Parser {
         Ok(params)
     }
 
-    fn parse_param(&mut self) -> Result<Node, String> {
+    fn parse_param(&mut self) -> Result<Node, Diagnostic> {
+        let start = self.position;
         let position = self.position();
 
         let id = self.parse_id()?;
@@ -689,24 +861,19 @@ impl Parser {
         };
 
         self.set_end(&mut node);
+        self.record_node(&node, start);
 
         Ok(node)
     }
 
-    fn parse_process(&mut self) -> Result<Node, String> {
+    fn parse_process(&mut self) -> Result<Node, Diagnostic> {
         let position = self.position();
         // Should skip {
         self.skip(TokenType::PROCESS)?;
         self.skip(TokenType::LCURLY)?;
 
-        let mut process = Node::ProcessSection { children: Vec::new(), position };
-
-        while self.peek().token_type != TokenType::RCURLY {
-            if let Node::ProcessSection { children, .. } = &mut process {
-                let child = self.parse_expression_statement()?;
-                children.push(child);
-            }
-        }
+        let children = self.parse_statement_list(Parser::parse_expression_statement);
+        let mut process = Node::ProcessSection { children, position };
 
         self.skip(TokenType::RCURLY)?;
 
@@ -715,20 +882,14 @@ impl Parser {
         Ok(process)
     }
 
-    fn parse_block_section(&mut self) -> Result<Node, String> {
-        let mut block = Node::BlockSection {
-            children: Vec::new(),
-            position: self.position(),
-        };
+    fn parse_block_section(&mut self) -> Result<Node, Diagnostic> {
+        let position = self.position();
 
         self.skip(TokenType::BLOCK)?;
         self.skip(TokenType::LCURLY)?;
 
-        while self.tokens[self.position].token_type != TokenType::RCURLY {
-            if let Node::BlockSection { children, .. } = &mut block {
-                children.push(self.parse_expression_statement()?);
-            }
-        }
+        let children = self.parse_statement_list(Parser::parse_expression_statement);
+        let mut block = Node::BlockSection { children, position };
 
         self.skip(TokenType::RCURLY)?;
 
@@ -737,28 +898,24 @@ impl Parser {
         Ok(block)
     }
 
+    // Nodes end with the last token they consumed, their start line and column stay as they are
     fn set_end(&mut self, node: &mut Node) {
-        let position = self.position();
+        let end = self.last_token_end();
 
-        let end = position.end;
-        let column = position.column;
+        node.set_end(end);
+    }
 
-        node.set_end(end, column);
+    fn last_token_end(&self) -> u32 {
+        self.position.checked_sub(1).map_or(0, |last| self.token_at(last).position.end)
     }
 
-    fn parse_function_body(&mut self) -> Result<Node, String> {
+    fn parse_function_body(&mut self) -> Result<Node, Diagnostic> {
         let position = self.position();
         // Should skip {
         self.skip(TokenType::LCURLY)?;
 
-        let mut process = Node::FunctionBody { children: Vec::new(), position };
-
-        while self.tokens[self.position].token_type != TokenType::RCURLY {
-            if let Node::FunctionBody { children, .. } = &mut process {
-                let child = self.parse_expression_statement()?;
-                children.push(child);
-            }
-        }
+        let children = self.parse_statement_list(Parser::parse_expression_statement);
+        let mut process = Node::FunctionBody { children, position };
 
         self.skip(TokenType::RCURLY)?;
 
@@ -767,7 +924,7 @@ impl Parser {
         Ok(process)
     }
 
-    fn parse_variable_specifier(&mut self) -> Result<VariableSpecifier, String> {
+    fn parse_variable_specifier(&mut self) -> Result<VariableSpecifier, Diagnostic> {
         let token = self.consume();
 
         let result = match token.token_type {
@@ -791,7 +948,7 @@ impl Parser {
         Ok(result)
     }
 
-    fn parse_variable_declaration_stmt(&mut self) -> Result<Node, String> {
+    fn parse_variable_declaration_stmt(&mut self) -> Result<Node, Diagnostic> {
         let position = self.position();
 
         let specifier = self.parse_variable_specifier()?;
@@ -804,6 +961,7 @@ impl Parser {
             id: Box::new(id),
             initializer: Box::new(initializer),
             specifier,
+            attributes: vec![],
             position,
         };
 
@@ -812,11 +970,24 @@ impl Parser {
         Ok(node)
     }
 
-    fn parse_assignment_expression(&mut self) -> Result<Node, String> {
+    fn parse_assignment_expression(&mut self) -> Result<Node, Diagnostic> {
         let position = self.position();
 
         let id = self.parse_id()?;
-        self.skip(TokenType::DEF)?;
+
+        let token = self.consume();
+
+        // Compound assignments keep their operator, so `mephisto fmt` prints them as written
+        let op = match token.token_type {
+            TokenType::DEF => None,
+            TokenType::PLUSDEF => Some(Operator::Plus),
+            TokenType::MINUSDEF => Some(Operator::Minus),
+            TokenType::MULDEF => Some(Operator::Mul),
+            TokenType::DIVDEF => Some(Operator::Div),
+            _ => {
+                return Err(self.generic_error(&token, "assignment operator"));
+            }
+        };
 
         let expr = self.parse_expression()?;
 
@@ -824,6 +995,7 @@ impl Parser {
 
         let mut node = Node::AssignmentExpr {
             lhs: Box::new(id),
+            op,
             rhs: Box::new(expr),
             position,
         };
@@ -833,30 +1005,54 @@ impl Parser {
         Ok(node)
     }
 
-    fn parse_expression(&mut self) -> Result<Node, String> {
+    fn parse_expression(&mut self) -> Result<Node, Diagnostic> {
         /*
         Expression is defined as:
-        expr -> infix_expr | unary_expr
-        infix_expr -> expr op expr | expr LPAR params RPAR
-        unary_expr -> op expr
+        expr -> infix_expr [QUESTION expr COLON expr]
+        infix_expr -> expr op expr | expr LPAR params RPAR | unary_expr
+        unary_expr -> op power
          */
 
+        let position = self.position();
         let token = self.peek();
 
-        match token.token_type {
+        // A leading sign only applies to the first operand, so it is parsed as part of the binary expression
+        let expr = match token.token_type {
             TokenType::PLUS | TokenType::MINUS => {
-                self.parse_unary_expr()
-            }
-            TokenType::CONNECTED => {
-                self.parse_connected()
+                self.parse_binary_expr()?
             }
             _ => {
-                self.parse_infix_expr()
+                self.parse_infix_expr()?
+            }
+        };
+
+        match self.peek().token_type {
+            TokenType::QUESTION => {
+                self.parse_conditional_expr(expr, position)
             }
+            _ => Ok(expr)
         }
     }
 
-    fn parse_if_statement(&mut self) -> Result<Node, String> {
+    fn parse_conditional_expr(&mut self, test: Node, position: Position) -> Result<Node, Diagnostic> {
+        self.skip(TokenType::QUESTION)?;
+        let consequent = self.parse_expression()?;
+        self.skip(TokenType::COLON)?;
+        let alternate = self.parse_expression()?;
+
+        let mut node = Node::ConditionalExpr {
+            test: Box::new(test),
+            consequent: Box::new(consequent),
+            alternate: Box::new(alternate),
+            position,
+        };
+
+        self.set_end(&mut node);
+
+        Ok(node)
+    }
+
+    fn parse_if_statement(&mut self) -> Result<Node, Diagnostic> {
         let position = self.position();
 
         self.skip(TokenType::IF)?;
@@ -904,22 +1100,43 @@ impl Parser {
         Ok(node)
     }
 
-    fn parse_block(&mut self) -> Result<Node, String> {
+    fn parse_for_statement(&mut self) -> Result<Node, Diagnostic> {
         let position = self.position();
 
-        self.skip(TokenType::LCURLY)?;
+        self.skip(TokenType::FOR)?;
+        let id = self.parse_id()?;
 
-        let mut block = Node::BlockStmt {
-            children: Vec::new(),
+        // "in" is not reserved, so existing code can keep using it as a name
+        let token = self.consume();
+        if token.token_type != TokenType::ID || token.literal != "in" {
+            return Err(self.generic_error(&token, "in"));
+        }
+
+        let from = self.parse_expression()?;
+        self.skip(TokenType::RANGE)?;
+        let to = self.parse_expression()?;
+        let body = self.parse_block()?;
+
+        let mut node = Node::ForStmt {
+            id: Box::new(id),
+            from: Box::new(from),
+            to: Box::new(to),
+            body: Box::new(body),
             position,
         };
 
-        while self.tokens[self.position].token_type != TokenType::RCURLY {
-            if let Node::BlockStmt { children, .. } = &mut block {
-                let child = self.parse_expression_statement()?;
-                children.push(child);
-            }
-        }
+        self.set_end(&mut node);
+
+        Ok(node)
+    }
+
+    fn parse_block(&mut self) -> Result<Node, Diagnostic> {
+        let position = self.position();
+
+        self.skip(TokenType::LCURLY)?;
+
+        let children = self.parse_statement_list(Parser::parse_expression_statement);
+        let mut block = Node::BlockStmt { children, position };
 
         self.skip(TokenType::RCURLY)?;
 
@@ -928,7 +1145,11 @@ impl Parser {
         Ok(block)
     }
 
-    fn parse_unary_number(&mut self) -> Result<Node, String> {
+    fn parse_unary_number(&mut self) -> Result<Node, Diagnostic> {
+        if self.is_negative_decibel_literal() {
+            return self.parse_negative_decibel_literal();
+        }
+
         let position = self.position();
         let token = self.consume();
 
@@ -954,7 +1175,33 @@ impl Parser {
         Ok(node)
     }
 
-    fn parse_unary_expr(&mut self) -> Result<Node, String> {
+    // -6db is a gain of -6 decibels, not a negated +6db gain, so the sign belongs to the literal
+    fn is_negative_decibel_literal(&self) -> bool {
+        if self.token_at(self.position).token_type != TokenType::MINUS {
+            return false;
+        }
+
+        let next_token = self.token_at(self.position + 1);
+
+        next_token.token_type == TokenType::NUMBER && split_unit_suffix(&next_token.literal).1 == Unit::Decibels.suffix()
+    }
+
+    fn parse_negative_decibel_literal(&mut self) -> Result<Node, Diagnostic> {
+        let position = self.position();
+
+        self.skip(TokenType::MINUS)?;
+
+        let mut node = match self.parse_number()? {
+            Node::UnitNumber { value, unit, .. } => Node::UnitNumber { value: -value, unit, position },
+            node => return Err(Diagnostic::error(INTERNAL_ERROR, format!("Expected a decibel literal, got {:?}", node)).with_span(position)),
+        };
+
+        self.set_end(&mut node);
+
+        Ok(node)
+    }
+
+    fn parse_unary_expr(&mut self) -> Result<Node, Diagnostic> {
         let position = self.position();
         let token = self.consume();
 
@@ -970,7 +1217,9 @@ impl Parser {
             }
         };
 
-        let child = self.parse_expression()?;
+        // Like in math, -x ^ 2 is -(x ^ 2), but the sign binds tighter than any other binary
+        // operator, so -7 % 3 is (-7) % 3
+        let child = self.parse_power()?;
 
         let mut node = Node::UnaryExpr {
             op: operator,
@@ -983,11 +1232,30 @@ impl Parser {
         Ok(node)
     }
 
-    fn parse_infix_expr(&mut self) -> Result<Node, String> {
+    fn parse_not_expr(&mut self) -> Result<Node, Diagnostic> {
+        let position = self.position();
+
+        self.skip(TokenType::NOT)?;
+
+        // `!` binds tighter than any binary operator, so only a primitive is negated
+        let child = self.parse_primitive()?;
+
+        let mut node = Node::UnaryExpr {
+            op: Operator::Not,
+            child: Box::new(child),
+            position,
+        };
+
+        self.set_end(&mut node);
+
+        Ok(node)
+    }
+
+    fn parse_infix_expr(&mut self) -> Result<Node, Diagnostic> {
         let token = self.peek();
 
         match token.token_type {
-            TokenType::LPAREN | TokenType::NUMBER | TokenType::ID => {
+            TokenType::LPAREN | TokenType::NUMBER | TokenType::ID | TokenType::NOT | TokenType::CONNECTED => {
                 self.parse_binary_expr()
             }
             _ => {
@@ -996,7 +1264,7 @@ impl Parser {
         }
     }
 
-    fn parse_member_expr(&mut self) -> Result<Node, String> {
+    fn parse_member_expr(&mut self) -> Result<Node, Diagnostic> {
         let position = self.position();
         let id = self.parse_id()?;
         self.skip(TokenType::DOT)?;
@@ -1032,20 +1300,20 @@ impl Parser {
         Ok(node)
     }
 
-    fn parse_binary_expr(&mut self) -> Result<Node, String> {
-        self.parse_comparison()
+    fn parse_binary_expr(&mut self) -> Result<Node, Diagnostic> {
+        self.parse_logical_or()
     }
 
-    fn parse_comparison(&mut self) -> Result<Node, String> {
+    fn parse_logical_or(&mut self) -> Result<Node, Diagnostic> {
         let position = self.position();
-        let mut lhs = self.parse_add_sub()?;
+        let mut lhs = self.parse_logical_and()?;
 
         loop {
             let token = self.peek();
             match token.token_type {
-                TokenType::EQ | TokenType::GT | TokenType::LT | TokenType::GE | TokenType::LE | TokenType::NE => {
+                TokenType::OR => {
                     let op = self.parse_operator()?;
-                    let rhs = self.parse_add_sub()?;
+                    let rhs = self.parse_logical_and()?;
                     lhs = Node::BinaryExpr {
                         op,
                         lhs: Box::new(lhs),
@@ -1062,14 +1330,66 @@ impl Parser {
         Ok(lhs)
     }
 
-    fn parse_add_sub(&mut self) -> Result<Node, String> {
+    fn parse_logical_and(&mut self) -> Result<Node, Diagnostic> {
         let position = self.position();
-        let mut lhs = self.parse_mul_div()?;
+        let mut lhs = self.parse_comparison()?;
 
         loop {
             let token = self.peek();
             match token.token_type {
-                TokenType::PLUS | TokenType::MINUS => {
+                TokenType::AND => {
+                    let op = self.parse_operator()?;
+                    let rhs = self.parse_comparison()?;
+                    lhs = Node::BinaryExpr {
+                        op,
+                        lhs: Box::new(lhs),
+                        rhs: Box::new(rhs),
+                        position,
+                    };
+                }
+                _ => break,
+            }
+        }
+
+        self.set_end(&mut lhs);
+
+        Ok(lhs)
+    }
+
+    fn parse_comparison(&mut self) -> Result<Node, Diagnostic> {
+        let position = self.position();
+        let mut lhs = self.parse_add_sub()?;
+
+        loop {
+            let token = self.peek();
+            match token.token_type {
+                TokenType::EQ | TokenType::GT | TokenType::LT | TokenType::GE | TokenType::LE | TokenType::NE => {
+                    let op = self.parse_operator()?;
+                    let rhs = self.parse_add_sub()?;
+                    lhs = Node::BinaryExpr {
+                        op,
+                        lhs: Box::new(lhs),
+                        rhs: Box::new(rhs),
+                        position,
+                    };
+                }
+                _ => break,
+            }
+        }
+
+        self.set_end(&mut lhs);
+
+        Ok(lhs)
+    }
+
+    fn parse_add_sub(&mut self) -> Result<Node, Diagnostic> {
+        let position = self.position();
+        let mut lhs = self.parse_mul_div()?;
+
+        loop {
+            let token = self.peek();
+            match token.token_type {
+                TokenType::PLUS | TokenType::MINUS => {
                     let op = self.parse_operator()?;
                     let rhs = self.parse_mul_div()?;
                     lhs = Node::BinaryExpr {
@@ -1088,16 +1408,16 @@ impl Parser {
         Ok(lhs)
     }
 
-    fn parse_mul_div(&mut self) -> Result<Node, String> {
+    fn parse_mul_div(&mut self) -> Result<Node, Diagnostic> {
         let position = self.position();
-        let mut lhs = self.parse_primitive()?;
+        let mut lhs = self.parse_power()?;
 
         loop {
             let token = self.peek();
             match token.token_type {
-                TokenType::MUL | TokenType::DIV => {
+                TokenType::MUL | TokenType::DIV | TokenType::MOD => {
                     let op = self.parse_operator()?;
-                    let rhs = self.parse_primitive()?;
+                    let rhs = self.parse_power()?;
                     lhs = Node::BinaryExpr {
                         op,
                         lhs: Box::new(lhs),
@@ -1115,15 +1435,46 @@ impl Parser {
     }
 
 
-    fn parse_primitive(&mut self) -> Result<Node, String> {
+    fn parse_power(&mut self) -> Result<Node, Diagnostic> {
+        let position = self.position();
+        let mut lhs = self.parse_primitive()?;
+
+        // Power is right associative, so 2 ^ 3 ^ 2 is 2 ^ (3 ^ 2)
+        if self.peek().token_type == TokenType::POW {
+            let op = self.parse_operator()?;
+            let rhs = self.parse_power()?;
+            lhs = Node::BinaryExpr {
+                op,
+                lhs: Box::new(lhs),
+                rhs: Box::new(rhs),
+                position,
+            };
+        }
+
+        self.set_end(&mut lhs);
+
+        Ok(lhs)
+    }
+
+    fn parse_primitive(&mut self) -> Result<Node, Diagnostic> {
+        let start = self.position;
         let token = self.peek();
 
-        match token.token_type {
-            TokenType::MINUS => {
+        let node = match token.token_type {
+            TokenType::MINUS if self.is_negative_decibel_literal() => {
+                self.parse_negative_decibel_literal()
+            }
+            TokenType::PLUS | TokenType::MINUS => {
                 self.parse_unary_expr()
             }
+            TokenType::NOT => {
+                self.parse_not_expr()
+            }
+            TokenType::CONNECTED => {
+                self.parse_connected()
+            }
             TokenType::ID => {
-                let next_token = self.tokens[self.position + 1].clone();
+                let next_token = self.token_at(self.position + 1).clone();
                 match next_token.token_type {
                     TokenType::LPAREN => {
                         self.parse_fn_call()
@@ -1137,21 +1488,27 @@ impl Parser {
                 }
             }
             TokenType::LPAREN => {
+                // The nodes inside the parentheses carry their own trivia
                 self.skip(TokenType::LPAREN)?;
                 let expr = self.parse_expression()?;
                 self.skip(TokenType::RPAREN)?;
-                Ok(expr)
+                return Ok(expr);
             }
             TokenType::NUMBER => {
                 self.parse_number()
             }
             _ => {
-                Err(self.generic_error(&token, "(, -, id, number)"))
+                Err(self.generic_error(&token, "(, -, !, id, number)"))
             }
-        }
+        }?;
+
+        // Comments between the operands of an expression stay next to the operand they follow or precede
+        self.record_node(&node, start);
+
+        Ok(node)
     }
 
-    fn parse_connected(&mut self) -> Result<Node, String> {
+    fn parse_connected(&mut self) -> Result<Node, Diagnostic> {
         let position = self.position();
         self.skip(TokenType::CONNECTED)?;
         self.skip(TokenType::LPAREN)?;
@@ -1168,13 +1525,28 @@ impl Parser {
         Ok(node)
     }
 
-    fn parse_number(&mut self) -> Result<Node, String> {
+    fn parse_number(&mut self) -> Result<Node, Diagnostic> {
         let position = self.position();
         let token = self.consume();
 
         match token.token_type {
             TokenType::NUMBER => {
-                let mut node = Node::Number { value: token.literal.parse::<f64>().unwrap(), position };
+                let (literal, suffix) = split_unit_suffix(&token.literal);
+
+                let value = parse_number_literal(literal)
+                    .map_err(|reason| malformed_number_error(&token, &reason))?;
+
+                let mut node = if suffix.is_empty() {
+                    Node::Number { value, position }
+                } else {
+                    match Unit::from_suffix(suffix) {
+                        Some(unit) => Node::UnitNumber { value, unit, position },
+                        None => {
+                            return Err(malformed_number_error(&token, &format!("unknown unit suffix \"{}\"", suffix)));
+                        }
+                    }
+                };
+
                 self.set_end(&mut node);
                 Ok(node)
             }
@@ -1184,7 +1556,7 @@ impl Parser {
         }
     }
 
-    fn parse_fn_call(&mut self) -> Result<Node, String> {
+    fn parse_fn_call(&mut self) -> Result<Node, Diagnostic> {
         let position = self.position();
 
         let id = self.parse_id()?;
@@ -1203,7 +1575,7 @@ impl Parser {
         Ok(node)
     }
 
-    fn parse_arguments(&mut self) -> Result<Vec<Node>, String> {
+    fn parse_arguments(&mut self) -> Result<Vec<Node>, Diagnostic> {
         let mut args = Vec::new();
 
         loop {
@@ -1224,7 +1596,7 @@ impl Parser {
         Ok(args)
     }
 
-    fn parse_operator(&mut self) -> Result<Operator, String> {
+    fn parse_operator(&mut self) -> Result<Operator, Diagnostic> {
         let tok = self.consume();
 
         let result = match tok.token_type {
@@ -1232,12 +1604,16 @@ impl Parser {
             TokenType::MINUS => Operator::Minus,
             TokenType::MUL => Operator::Mul,
             TokenType::DIV => Operator::Div,
+            TokenType::MOD => Operator::Mod,
+            TokenType::POW => Operator::Pow,
             TokenType::EQ => Operator::Eq,
             TokenType::GT => Operator::Gt,
             TokenType::LT => Operator::Lt,
             TokenType::GE => Operator::Ge,
             TokenType::LE => Operator::Le,
             TokenType::NE => Operator::Ne,
+            TokenType::AND => Operator::And,
+            TokenType::OR => Operator::Or,
             _ => {
                 return Err(self.generic_error(&tok, "operator"));
             }
@@ -1246,7 +1622,7 @@ impl Parser {
         Ok(result)
     }
 
-    fn parse_id(&mut self) -> Result<Node, String> {
+    fn parse_id(&mut self) -> Result<Node, Diagnostic> {
         let position = self.position();
         let token = self.consume();
 
@@ -1260,12 +1636,13 @@ impl Parser {
         }
     }
 
-    fn generic_error(&self, token: &Token, expected: &str) -> String {
-        format!("Unexpected token: {}, expected {}", token.to_string(), expected)
+    fn generic_error(&self, token: &Token, expected: &str) -> Diagnostic {
+        Diagnostic::error(SYNTAX_ERROR, format!("Unexpected {}, expected {}", describe_token(token), expected))
+            .with_span(token.position)
     }
 
-    fn skip(&mut self, token_type: TokenType) -> Result<(), String> {
-        if self.tokens[self.position].token_type == token_type {
+    fn skip(&mut self, token_type: TokenType) -> Result<(), Diagnostic> {
+        if self.token_at(self.position).token_type == token_type {
             self.position += 1;
             Ok(())
         } else {
@@ -1273,31 +1650,178 @@ impl Parser {
             // println!("TOKENS: {:?}", self.tokens);
             // println!("POSITION: {:?}", self.position);
 
-            Err(format!("Unexpected token: {}, expected {:?}", self.tokens[self.position].to_string(), token_type))
+            Err(self.generic_error(self.token_at(self.position), &format!("{:?}", token_type)))
         }
     }
 
+    // Reading past the end yields the EOF token, so no lookahead can run out of bounds
+    fn token_at(&self, index: usize) -> &Token {
+        &self.tokens[index.min(self.tokens.len() - 1)]
+    }
+
     fn peek(&self) -> Token {
-        self.tokens[self.position].clone()
+        self.token_at(self.position).clone()
     }
 
     fn consume(&mut self) -> Token {
-        let token = self.tokens[self.position].clone();
+        let token = self.token_at(self.position).clone();
         self.position += 1;
         token
     }
 
     fn position(&self) -> Position {
-        self.tokens[self.position].position
+        self.token_at(self.position).position
+    }
+}
+
+// Trivia between two tokens the parser sees
+struct TriviaGap {
+    previous_end: Option<u32>,
+    next_start: u32,
+    trivia: Vec<Trivia>,
+}
+
+fn trivia_gaps(tokens: &[Token]) -> Vec<TriviaGap> {
+    let mut gaps = vec![];
+    let mut previous_end = None;
+    let mut trivia = vec![];
+
+    for token in tokens {
+        if token.is_trivia() {
+            let kind = if token.token_type == TokenType::COMMENT { TriviaKind::Comment } else { TriviaKind::Whitespace };
+            trivia.push(Trivia { kind, text: token.literal.clone(), position: token.position });
+            continue;
+        }
+
+        if !trivia.is_empty() {
+            gaps.push(TriviaGap { previous_end, next_start: token.position.start, trivia: std::mem::take(&mut trivia) });
+        }
+
+        previous_end = Some(token.position.end);
+    }
+
+    gaps
+}
+
+fn brace_depth_change(token_type: &TokenType) -> i32 {
+    match token_type {
+        TokenType::LCURLY => 1,
+        TokenType::RCURLY => -1,
+        _ => 0,
     }
 }
 
+// Keywords that only start top-level constructs
+fn is_top_level_keyword(token_type: &TokenType) -> bool {
+    matches!(token_type, TokenType::IMPORT | TokenType::PROCESS | TokenType::BLOCK | TokenType::CONNECT
+        | TokenType::PARAM | TokenType::BUFFER | TokenType::EXPORT | TokenType::INPUT | TokenType::OUTPUT)
+}
+
+fn is_statement_keyword(token_type: &TokenType) -> bool {
+    matches!(token_type, TokenType::LET | TokenType::CONST | TokenType::FN | TokenType::IF | TokenType::FOR | TokenType::RETURN | TokenType::AT)
+}
+
+fn describe_token(token: &Token) -> String {
+    match token.token_type {
+        TokenType::EOF => "end of file".to_string(),
+        _ => format!("token \"{}\"", token.literal),
+    }
+}
+
+fn malformed_number_error(token: &Token, reason: &str) -> Diagnostic {
+    Diagnostic::error(MALFORMED_NUMBER, format!("Malformed number literal \"{}\", {}", token.literal, reason))
+        .with_span(token.position)
+}
+
+// Converts a NUMBER literal into its value. Accepts decimal literals with an optional
+// fraction and exponent (1.5, 2.5E4, 1e-3), hex literals (0x7F) and "_" digit separators
+// between digits (48_000). The lexer is deliberately lenient, so malformed forms end up here.
+fn parse_number_literal(literal: &str) -> Result<f64, String> {
+    let (negative, body) = match literal.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, literal.strip_prefix('+').unwrap_or(literal)),
+    };
+
+    let value = if let Some(digits) = body.strip_prefix("0x").or_else(|| body.strip_prefix("0X")) {
+        if digits.is_empty() {
+            return Err("missing hex digits".to_string());
+        }
+
+        if let Some(c) = digits.chars().find(|c| !c.is_ascii_hexdigit() && *c != '_') {
+            return Err(format!("invalid hex digit '{}'", c));
+        }
+
+        check_digit_separators(digits)?;
+
+        let digits = digits.replace('_', "");
+        u64::from_str_radix(&digits, 16).map_err(|_| "hex literal is too large".to_string())? as f64
+    } else {
+        let (mantissa, exponent) = match body.find(['e', 'E']) {
+            Some(index) => (&body[..index], Some(&body[index + 1..])),
+            None => (body, None),
+        };
+
+        for part in mantissa.split('.') {
+            check_digit_separators(part)?;
+        }
+
+        if let Some(exponent) = exponent {
+            let digits = exponent.strip_prefix(['+', '-']).unwrap_or(exponent);
+
+            if digits.is_empty() {
+                return Err("missing exponent digits".to_string());
+            }
+
+            check_digit_separators(digits)?;
+        }
+
+        body.replace('_', "").parse::<f64>().map_err(|e| e.to_string())?
+    };
+
+    Ok(if negative { -value } else { value })
+}
+
+// Splits a unit suffix like "ms" off a NUMBER literal. An "e" followed by digits, a sign or nothing
+// starts an exponent rather than a suffix
+fn split_unit_suffix(literal: &str) -> (&str, &str) {
+    let body = literal.trim_start_matches(['+', '-']);
+
+    if body.starts_with("0x") || body.starts_with("0X") {
+        return (literal, "");
+    }
+
+    let mut end = literal.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(literal.len());
+
+    if literal[end..].starts_with(['e', 'E']) {
+        let rest = &literal[end + 1..];
+
+        if rest.is_empty() || rest.starts_with(|c: char| c.is_ascii_digit() || c == '+' || c == '-' || c == '_') {
+            end += 1 + rest.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(rest.len());
+        }
+    }
+
+    literal.split_at(end)
+}
+
+// Digit separators are only allowed between two digits
+fn check_digit_separators(digits: &str) -> Result<(), String> {
+    if digits.starts_with('_') || digits.ends_with('_') || digits.contains("__") {
+        return Err("digit separator \"_\" must be placed between digits".to_string());
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::diagnostic::{Diagnostic, MALFORMED_NUMBER, SYNTAX_ERROR};
     use crate::lexer::Lexer;
     use crate::lexer::token::{Position, Token};
     use crate::lexer::token_type::TokenType;
-    use crate::parser::{AST, Node, Parser};
+    use crate::parser::{AST, Attribute, Node, Parser, Trivia, TriviaKind, parse_number_literal};
+    use crate::symbol_table::SymbolTable;
 
     #[test]
     fn test_parser_lotion() {
@@ -1316,12 +1840,14 @@ mod tests {
                 children: vec![
                     Node::BlockSection {
                         children: vec![],
-                        position: Position { start: 0, end: 7, line: 1, column: 7 },
+                        position: Position { start: 0, end: 7, line: 1, column: 1 },
                     },
                 ],
-                position: Position { start: 0, end: 7, line: 1, column: 7 },
+                position: Position { start: 0, end: 7, line: 1, column: 1 },
             },
             errors: vec![],
+            trivia: BTreeMap::new(),
+            number_literals: BTreeMap::new(),
         });
     }
 
@@ -1403,7 +1929,8 @@ mod tests {
         assert_eq!(parser.position, 3);
 
         let result = parser.skip(TokenType::UNKNOWN); // Should be EOF, but let's test the error message
-        assert_eq!(result, Err("Unexpected token: <EOF: [Position { start: 7, end: 7, line: 1, column: 7 }]>, expected UNKNOWN".to_string()));
+        assert_eq!(result, Err(Diagnostic::error(SYNTAX_ERROR, "Unexpected end of file, expected UNKNOWN".to_string())
+            .with_span(Position { start: 7, end: 7, line: 1, column: 7 })));
         assert_eq!(parser.position, 3);
     }
 
@@ -1422,7 +1949,8 @@ mod tests {
 
         let result = parser.generic_error(&token, "Test error");
 
-        assert_eq!(result, "Unexpected token: <BLOCK:block [Position { start: 0, end: 5, line: 1, column: 1 }]>, expected Test error");
+        assert_eq!(result, Diagnostic::error(SYNTAX_ERROR, "Unexpected token \"block\", expected Test error".to_string())
+            .with_span(Position { start: 0, end: 5, line: 1, column: 1 }));
     }
 
     #[test]
@@ -1461,7 +1989,8 @@ mod tests {
 
         let result = parser.parse_id();
 
-        assert_eq!(result, Err("Unexpected token: <EOF: [Position { start: 0, end: 0, line: 0, column: 0 }]>, expected identifier".to_string()));
+        assert_eq!(result, Err(Diagnostic::error(SYNTAX_ERROR, "Unexpected end of file, expected identifier".to_string())
+            .with_span(Position { start: 0, end: 0, line: 0, column: 0 })));
     }
 
     #[test]
@@ -1615,4 +2144,440 @@ mod tests {
 
         assert_eq!(ast.errors.len(), 0);
     }
+
+    #[test]
+    fn test_logical_operators_precedence() {
+        let code = "
+            let a = !b || c && d == 1;
+            ".to_string();
+
+        let lexer = Lexer::new();
+        let tokens = lexer.tokenize(code);
+
+        let mut parser = Parser::new();
+        let mut ast = parser.parse(tokens);
+
+        assert_eq!(ast.errors.len(), 0);
+        assert_eq!(ast.to_code_string(), "let a = (!b || (c && (d == 1)));\n");
+    }
+
+    #[test]
+    fn test_not_connected() {
+        let code = "
+            process {
+                if (!connected(a) && connected(b)) {
+                    x = 1;
+                }
+            }
+            ".to_string();
+
+        let lexer = Lexer::new();
+        let tokens = lexer.tokenize(code);
+
+        let mut parser = Parser::new();
+        let ast = parser.parse(tokens);
+
+        assert_eq!(ast.errors.len(), 0);
+    }
+
+    #[test]
+    fn test_conditional_expr() {
+        let code = "
+            let a = b > 0 ? 1 : c ? -1 : 0;
+            let d = !b ? x + 1 : min(x, 2);
+            ".to_string();
+
+        let lexer = Lexer::new();
+        let tokens = lexer.tokenize(code);
+
+        let mut parser = Parser::new();
+        let mut ast = parser.parse(tokens);
+
+        assert_eq!(ast.errors.len(), 0);
+        assert_eq!(ast.to_code_string(), "let a = ((b > 0) ? 1 : (c ? -1 : 0));\nlet d = (!b ? (x + 1) : min(x, 2));\n");
+    }
+
+    #[test]
+    fn test_for_stmt() {
+        let code = "
+            process {
+                for k in 0..N * 2 {
+                    x = x + k;
+                }
+            }
+            ".to_string();
+
+        let lexer = Lexer::new();
+        let tokens = lexer.tokenize(code);
+
+        let mut parser = Parser::new();
+        let mut ast = parser.parse(tokens);
+
+        assert_eq!(ast.errors.len(), 0);
+        assert_eq!(ast.to_code_string(), "process {\nfor k in 0..(N * 2) {\nx = (x + k);\n}\n}\n\n");
+    }
+
+    #[test]
+    fn test_call_statements() {
+        let code = "
+            fn reset() {
+                buf_clear(b);
+            }
+
+            process {
+                reset();
+                Module.reset();
+            }
+            ".to_string();
+
+        let lexer = Lexer::new();
+        let tokens = lexer.tokenize(code);
+
+        let mut parser = Parser::new();
+        let ast = parser.parse(tokens);
+
+        assert_eq!(ast.errors.len(), 0);
+    }
+
+    #[test]
+    fn test_compound_assignment() {
+        let code = "
+            process {
+                a += 1;
+                a -= b * 2;
+                a *= b + 1;
+                a /= 2;
+            }
+            ".to_string();
+
+        let lexer = Lexer::new();
+        let tokens = lexer.tokenize(code);
+
+        let mut parser = Parser::new();
+        let mut ast = parser.parse(tokens);
+
+        assert_eq!(ast.errors.len(), 0);
+        assert_eq!(ast.to_code_string(), "process {\na += 1;\na -= (b * 2);\na *= (b + 1);\na /= 2;\n}\n\n");
+    }
+
+    #[test]
+    fn test_mod_pow_precedence() {
+        let code = "
+            let a = x % 4 ^ 2 ^ 3 * b + 1;
+            ".to_string();
+
+        let lexer = Lexer::new();
+        let tokens = lexer.tokenize(code);
+
+        let mut parser = Parser::new();
+        let mut ast = parser.parse(tokens);
+
+        assert_eq!(ast.errors.len(), 0);
+        assert_eq!(ast.to_code_string(), "let a = (((x % (4 ^ (2 ^ 3))) * b) + 1);\n");
+    }
+
+    #[test]
+    fn test_numeric_literals() {
+        let code = "
+            let a = 1e-3 + 2.5E4 + 0x7F + 48_000 + 1_0.2_5e+1;
+            ".to_string();
+
+        let lexer = Lexer::new();
+        let tokens = lexer.tokenize(code);
+
+        let mut parser = Parser::new();
+        let mut ast = parser.parse(tokens);
+
+        assert_eq!(ast.errors.len(), 0);
+        assert_eq!(ast.to_code_string(), "let a = ((((0.001 + 25000) + 127) + 48000) + 102.5);\n");
+    }
+
+    #[test]
+    fn test_malformed_numeric_literals() {
+        assert_eq!(parse_number_literal("1e"), Err("missing exponent digits".to_string()));
+        assert_eq!(parse_number_literal("1e+"), Err("missing exponent digits".to_string()));
+        assert_eq!(parse_number_literal("0x"), Err("missing hex digits".to_string()));
+        assert_eq!(parse_number_literal("0x7G"), Err("invalid hex digit 'G'".to_string()));
+        assert_eq!(parse_number_literal("0xFFFFFFFFFFFFFFFFF"), Err("hex literal is too large".to_string()));
+        assert_eq!(parse_number_literal("48_"), Err("digit separator \"_\" must be placed between digits".to_string()));
+        assert_eq!(parse_number_literal("1__0"), Err("digit separator \"_\" must be placed between digits".to_string()));
+        assert_eq!(parse_number_literal("1_.5"), Err("digit separator \"_\" must be placed between digits".to_string()));
+        assert_eq!(parse_number_literal("1e_3"), Err("digit separator \"_\" must be placed between digits".to_string()));
+
+        let code = "
+            let a = 1e;
+            ".to_string();
+
+        let lexer = Lexer::new();
+        let tokens = lexer.tokenize(code);
+
+        let mut parser = Parser::new();
+        let ast = parser.parse(tokens);
+
+        assert_eq!(ast.errors, vec![
+            Diagnostic::error(MALFORMED_NUMBER, "Malformed number literal \"1e\", missing exponent digits".to_string())
+                .with_span(Position { start: 21, end: 23, line: 2, column: 18 })
+        ]);
+    }
+
+    #[test]
+    fn test_unit_literals() {
+        let code = "
+            param delay {
+                min: -6db;
+                max: 2s;
+            };
+
+            let a = 10ms + 440hz * -6db - 6db + 1e3smp;
+            ".to_string();
+
+        let lexer = Lexer::new();
+        let tokens = lexer.tokenize(code);
+
+        let mut parser = Parser::new();
+        let mut ast = parser.parse(tokens);
+
+        assert_eq!(ast.errors.len(), 0);
+        assert_eq!(ast.to_code_string(), "param delay {\nmin: -6db;\nmax: 2s;\n};\nlet a = (((10ms + (440hz * -6db)) - 6db) + 1000smp);\n");
+    }
+
+    #[test]
+    fn test_unknown_unit_suffix() {
+        let code = "
+            let a = 10sec;
+            ".to_string();
+
+        let lexer = Lexer::new();
+        let tokens = lexer.tokenize(code);
+
+        let mut parser = Parser::new();
+        let ast = parser.parse(tokens);
+
+        assert_eq!(ast.errors, vec![
+            Diagnostic::error(MALFORMED_NUMBER, "Malformed number literal \"10sec\", unknown unit suffix \"sec\"".to_string())
+                .with_span(Position { start: 21, end: 26, line: 2, column: 18 })
+        ]);
+    }
+
+    #[test]
+    fn test_named_import() {
+        let code = "
+            import { clamp, lerp, } from \"./lib.mephisto\";
+            import Lib from \"./lib.mephisto\";
+            ".to_string();
+
+        let lexer = Lexer::new();
+        let tokens = lexer.tokenize(code);
+
+        let mut parser = Parser::new();
+        let mut ast = parser.parse(tokens);
+
+        assert_eq!(ast.errors.len(), 0);
+        assert_eq!(ast.imports(), vec!["./lib.mephisto".to_string(), "./lib.mephisto".to_string()]);
+        assert_eq!(ast.to_code_string(), "import { clamp, lerp } from \"./lib.mephisto\";\nimport Lib from \"./lib.mephisto\";\n");
+    }
+
+    #[test]
+    fn test_error_recovery() {
+        let code = "
+            output out = 0
+
+            let gain = 0.5;
+
+            fn amp(x) {
+                let y = x * ;
+                return y * gain;
+            }
+
+            process {
+                out = ) 2;
+                out = amp(out);
+            }
+
+            connect {
+                out -> ;
+                out -> OUTPUTS;
+            }
+            ".to_string();
+
+        let lexer = Lexer::new();
+        let tokens = lexer.tokenize(code);
+
+        let mut parser = Parser::new();
+        let mut ast = parser.parse(tokens);
+
+        let messages: Vec<String> = ast.errors.iter().map(|error| error.message.clone()).collect();
+
+        assert_eq!(messages, vec![
+            "Unexpected token \"let\", expected SEMI",
+            "Unexpected token \";\", expected (, -, !, id, number)",
+            "Unexpected token \")\", expected expression",
+            "Unexpected token \";\", expected identifier or outputs specifier",
+        ]);
+
+        assert_eq!(ast.to_code_string(), "let gain = 0.5;\nfn amp(x) {\nreturn (y * gain);\n}\n\nprocess {\nout = amp(out);\n}\n\nconnect {\nout -> OUTPUTS;\n}\n\n");
+        assert!(SymbolTable::from_ast(&mut ast).is_ok());
+    }
+
+    #[test]
+    fn test_error_recovery_missing_closing_brace() {
+        let code = "
+            process {
+                out = 1;
+
+            block {
+                out = 2;
+            }
+
+            param p {
+                initial: ;
+            };
+
+            }
+            let a = 1;
+            ".to_string();
+
+        let lexer = Lexer::new();
+        let tokens = lexer.tokenize(code);
+
+        let mut parser = Parser::new();
+        let mut ast = parser.parse(tokens);
+
+        let messages: Vec<String> = ast.errors.iter().map(|error| error.message.clone()).collect();
+
+        assert_eq!(messages, vec![
+            "Unexpected token \"block\", expected RCURLY",
+            "Unexpected token \";\", expected specifier or number",
+            "Unexpected token \"}\", expected declaration or statement",
+        ]);
+
+        assert_eq!(ast.to_code_string(), "block {\nout = 2;\n}\n\nlet a = 1;\n");
+    }
+
+    #[test]
+    fn test_attributes() {
+        let code = "
+            @allow(unused_variable, dead_store)
+            let foo = 1;
+
+            export @noinline fn bar(a) {
+                @allow(unused_variable) let x = 2;
+                return a;
+            }
+
+            @allow(unconnected_module) import Osc from \"std:osc\";
+
+            @allow(unused_param)
+            param p {
+                min: 0;
+            };
+            ".to_string();
+
+        let lexer = Lexer::new();
+        let tokens = lexer.tokenize(code);
+
+        let mut parser = Parser::new();
+        let mut ast = parser.parse(tokens);
+
+        assert_eq!(ast.errors, vec![]);
+
+        let Node::ProgramNode { children, .. } = &ast.root else { panic!("Expected a program node") };
+
+        assert_eq!(children[0].attributes(), &[Attribute {
+            name: "allow".to_string(),
+            args: vec!["unused_variable".to_string(), "dead_store".to_string()],
+            position: Position { start: 13, end: 48, line: 2, column: 13 },
+        }]);
+        assert!(children[1].has_attribute("noinline"));
+        assert!(children[2].has_attribute("allow"));
+        assert!(children[3].has_attribute("allow"));
+
+        assert_eq!(ast.to_code_string(), concat!(
+            "@allow(unused_variable, dead_store) let foo = 1;\n",
+            "export @noinline fn bar(a) {\n@allow(unused_variable) let x = 2;\nreturn a;\n}\n\n",
+            "@allow(unconnected_module) import Osc from \"std:osc\";\n",
+            "@allow(unused_param) param p {\nmin: 0;\n};\n",
+        ));
+    }
+
+    #[test]
+    fn test_attribute_errors() {
+        let code = "
+            @allow(unused_variable)
+            process {}
+
+            fn foo() {
+                @inline out = 1;
+                @allow(1) let a = 1;
+                return 1;
+            }
+            ".to_string();
+
+        let lexer = Lexer::new();
+        let tokens = lexer.tokenize(code);
+
+        let mut parser = Parser::new();
+        let ast = parser.parse(tokens);
+
+        let messages: Vec<String> = ast.errors.iter().map(|error| error.message.clone()).collect();
+
+        assert_eq!(messages, vec![
+            "Unexpected token \"process\", expected declaration after attribute",
+            "Unexpected token \"out\", expected declaration after attribute",
+            "Unexpected token \"1\", expected attribute argument",
+        ]);
+    }
+
+    #[test]
+    fn test_parse_lossless() {
+        let code = "// Header
+let a = 1; // trailing
+
+process {
+    /* leading */
+    a = 2;
+    // dangling
+}
+".to_string();
+
+        let lexer = Lexer::new();
+        let tokens = lexer.tokenize_lossless(code);
+
+        let mut parser = Parser::new();
+        let ast = parser.parse_lossless(tokens);
+
+        assert_eq!(ast.errors, vec![]);
+
+        let comments = |trivia: &Vec<Trivia>| -> Vec<String> {
+            trivia.iter().filter(|trivia| trivia.kind == TriviaKind::Comment).map(|trivia| trivia.text.clone()).collect()
+        };
+
+        let declaration = &ast.trivia[&10];
+        assert_eq!(comments(&declaration.leading), vec!["// Header"]);
+        assert_eq!(comments(&declaration.trailing), vec!["// trailing"]);
+
+        let assignment = &ast.trivia[&66];
+        assert_eq!(comments(&assignment.leading), vec!["/* leading */"]);
+        assert_eq!(comments(&assignment.trailing), vec!["// dangling"]);
+    }
+
+    #[test]
+    fn test_lossless_to_code_string_keeps_comments() {
+        let code = include_str!("../std/lowpass.mephisto");
+
+        let lexer = Lexer::new();
+        let mut parser = Parser::new();
+
+        let mut ast = parser.parse_lossless(lexer.tokenize_lossless(code.to_string()));
+        assert_eq!(ast.errors, vec![]);
+
+        let printed = ast.to_code_string();
+
+        for line in code.lines().map(|line| line.trim()).filter(|line| line.starts_with("//")) {
+            assert!(printed.contains(&format!("\n{}\n", line)), "{} is missing from:\n{}", line, printed);
+        }
+
+        let mut plain = parser.parse(lexer.tokenize(code.to_string()));
+        assert!(!plain.to_code_string().contains("//"));
+    }
 }
@@ -1,23 +1,40 @@
+use std::collections::{BTreeMap, HashMap};
+
 use serde::Serialize;
 use serde_json;
 
+use crate::diagnostic::Diagnostic;
 use crate::lexer::token::Position;
 
 #[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct AST {
     pub root: Node,
-    pub errors: Vec<String>,
+    pub errors: Vec<Diagnostic>,
+    // Comments and whitespace around statements, parameters and operands, keyed by the start of
+    // their node. Only filled by Parser::parse_lossless
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub trivia: BTreeMap<u32, NodeTrivia>,
+    // Source spelling of the number literals by their start, also only filled by Parser::parse_lossless
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub number_literals: BTreeMap<u32, String>,
 }
 
 pub struct Context {
     pub code: String,
     pub skip_identifiers: bool,
     pub skip_identifier_once: bool,
+    pub trivia: BTreeMap<u32, NodeTrivia>,
+    pub number_literals: BTreeMap<u32, String>,
+    // Trailing trivia of the statements being printed, with the start and kind of their node
+    pub trailing_trivia: Vec<(u32, &'static str, Vec<Trivia>)>,
+    // Layout used by the formatter: only the parentheses the parser needs, blank lines between
+    // statements kept from the trivia
+    pub canonical: bool,
 }
 
 impl AST {
-    pub fn new(root: Node, errors: Vec<String>) -> AST {
-        AST { root, errors }
+    pub fn new(root: Node, errors: Vec<Diagnostic>) -> AST {
+        AST { root, errors, trivia: BTreeMap::new(), number_literals: BTreeMap::new() }
     }
 
     pub fn to_json(&self) -> String {
@@ -25,10 +42,23 @@ impl AST {
     }
 
     pub fn to_code_string(&mut self) -> String {
+        self.print(false)
+    }
+
+    // Code in the layout of `mephisto fmt`, before indentation
+    pub fn to_canonical_code_string(&mut self) -> String {
+        self.print(true)
+    }
+
+    fn print(&mut self, canonical: bool) -> String {
         let mut context = Context {
             code: String::new(),
             skip_identifiers: false,
             skip_identifier_once: false,
+            trivia: self.trivia.clone(),
+            number_literals: self.number_literals.clone(),
+            trailing_trivia: vec![],
+            canonical,
         };
 
         traverse_ast(&mut self.root, &mut ast_to_code, &mut context);
@@ -38,13 +68,18 @@ impl AST {
 
 
     pub fn imports(&self) -> Vec<String> {
+        self.import_statements().into_iter().map(|(path, _)| path).collect()
+    }
+
+    // Paths of the imports with the position of their import statement
+    pub fn import_statements(&self) -> Vec<(String, Position)> {
         let mut imports = Vec::new();
-        traverse_ast(&mut self.root.clone(), &mut |enter_exit, node, context: &mut Vec<String>| {
+        traverse_ast(&mut self.root.clone(), &mut |enter_exit, node, context: &mut Vec<(String, Position)>| {
             match node {
-                Node::ImportStatement { path, .. } => {
+                Node::ImportStatement { path, position, .. } | Node::NamedImportStatement { path, position, .. } => {
                     match enter_exit {
                         ASTTraverseStage::Enter => {
-                            context.push(path.clone());
+                            context.push((path.clone(), *position));
                         }
                         ASTTraverseStage::Exit => {}
                     }
@@ -56,6 +91,22 @@ impl AST {
         }, &mut imports);
         imports
     }
+
+    // Points import statements to the keys the imported modules are stored under
+    pub fn resolve_imports(&mut self, resolved_paths: &HashMap<String, String>) {
+        traverse_ast(&mut self.root, &mut |enter_exit, node, _context: &mut ()| {
+            match node {
+                Node::ImportStatement { path, .. } | Node::NamedImportStatement { path, .. } => {
+                    if let (ASTTraverseStage::Enter, Some(resolved_path)) = (enter_exit, resolved_paths.get(path.as_str())) {
+                        *path = resolved_path.clone();
+                    }
+                }
+                _ => {}
+            }
+
+            false
+        }, &mut ());
+    }
     
     pub fn inputs(&self) -> Vec<String> {
         let mut inputs = Vec::new();
@@ -118,7 +169,185 @@ impl AST {
     }
 }
 
+// Comments are printed on their own lines before the statement they lead, and trailing ones after
+// the statement, on the same line if they were there in the source. Whitespace is only reproduced
+// as blank lines in the canonical layout
+fn print_trivia(enter_exit: &ASTTraverseStage, node: &Node, context: &mut Context) {
+    // Binary and conditional expressions start with their first operand, which owns the trivia there
+    if matches!(node, Node::ProgramNode { .. } | Node::BinaryExpr { .. } | Node::ConditionalExpr { .. }) {
+        return;
+    }
+
+    let start = node.position().start;
+
+    match enter_exit {
+        ASTTraverseStage::Enter => {
+            // Nodes nested in a statement can start at the same offset, the statement is entered first
+            let Some(trivia) = context.trivia.remove(&start) else {
+                return;
+            };
+
+            for (i, trivia_item) in trivia.leading.iter().enumerate() {
+                match trivia_item.kind {
+                    TriviaKind::Whitespace => {
+                        if context.canonical && trivia_item.text.matches('\n').count() > 1 {
+                            context.code.push('\n');
+                        }
+                    }
+                    TriviaKind::Comment => {
+                        // A comment on a line of its own inside an expression keeps its line
+                        let on_own_line = i > 0 && trivia.leading[i - 1].text.contains('\n');
+
+                        if on_own_line && !context.code.is_empty() && !context.code.ends_with('\n') {
+                            context.code.truncate(context.code.trim_end_matches(' ').len());
+                            context.code.push('\n');
+                        }
+
+                        context.code.push_str(&trivia_item.text);
+
+                        // A block comment in front of an operand stays on the operand's line
+                        let ends_line = trivia_item.text.starts_with("//")
+                            || trivia.leading.get(i + 1).is_some_and(|next| next.text.contains('\n'));
+
+                        context.code.push(if ends_line { '\n' } else { ' ' });
+                    }
+                }
+            }
+
+            context.trailing_trivia.push((start, node.kind(), trivia.trailing));
+        }
+        ASTTraverseStage::Exit => {
+            if !context.trailing_trivia.last().is_some_and(|(trivia_start, kind, _)| *trivia_start == start && *kind == node.kind()) {
+                return;
+            }
+
+            let Some((_, _, trailing)) = context.trailing_trivia.pop() else {
+                return;
+            };
+
+            let trimmed_length = context.code.trim_end_matches('\n').len();
+            let mut newlines = context.code.len() - trimmed_length;
+            context.code.truncate(trimmed_length);
+
+            let mut same_line = true;
+
+            for trivia in trailing {
+                match trivia.kind {
+                    TriviaKind::Whitespace => same_line = same_line && !trivia.text.contains('\n'),
+                    TriviaKind::Comment => {
+                        context.code.push(if same_line { ' ' } else { '\n' });
+                        context.code.push_str(&trivia.text);
+
+                        // A line comment swallows whatever follows it on the line
+                        if trivia.text.starts_with("//") {
+                            newlines = newlines.max(1);
+                        }
+                    }
+                }
+            }
+
+            context.code.push_str(&"\n".repeat(newlines));
+        }
+    }
+}
+
+// Operators of one level bind tighter than the ones before. Power is the only right associative one
+fn precedence(op: &Operator) -> u8 {
+    match op {
+        Operator::Or => 1,
+        Operator::And => 2,
+        Operator::Eq | Operator::Gt | Operator::Lt | Operator::Ge | Operator::Le | Operator::Ne => 3,
+        Operator::Plus | Operator::Minus => 4,
+        Operator::Mul | Operator::Div | Operator::Mod => 5,
+        Operator::Pow => 6,
+        Operator::Not => 7,
+    }
+}
+
+// Numbers are printed as they were written when the source spelling is known, so 1.0 stays 1.0
+fn number_to_code(node: &Node, context: &Context) -> String {
+    if let Some(literal) = context.number_literals.get(&node.position().start) {
+        return literal.clone();
+    }
+
+    match node {
+        Node::Number { value, .. } => value.to_string(),
+        Node::UnitNumber { value, unit, .. } => format!("{}{}", value, unit.suffix()),
+        _ => String::new(),
+    }
+}
+
+pub(crate) fn operator_symbol(op: &Operator) -> &'static str {
+    match op {
+        Operator::Plus => "+",
+        Operator::Minus => "-",
+        Operator::Mul => "*",
+        Operator::Div => "/",
+        Operator::Mod => "%",
+        Operator::Pow => "^",
+        Operator::Eq => "==",
+        Operator::Gt => ">",
+        Operator::Lt => "<",
+        Operator::Ge => ">=",
+        Operator::Le => "<=",
+        Operator::Ne => "!=",
+        Operator::And => "&&",
+        Operator::Or => "||",
+        Operator::Not => "!",
+    }
+}
+
+// Whether an operand of a binary expression with operator `op` needs parentheses to parse back the same
+fn needs_parentheses(op: &Operator, operand: &Node, is_lhs: bool) -> bool {
+    match operand {
+        Node::BinaryExpr { op: operand_op, .. } => {
+            let (outer, inner) = (precedence(op), precedence(operand_op));
+
+            inner < outer || (inner == outer && (is_lhs == (*op == Operator::Pow)))
+        }
+        Node::ConditionalExpr { .. } => true,
+        // A sign takes the whole power as its operand, so -x ^ 2 is -(x ^ 2)
+        Node::UnaryExpr { op: Operator::Plus | Operator::Minus, .. } => *op == Operator::Pow && is_lhs,
+        _ => false,
+    }
+}
+
+fn print_operand(node: &mut Node, parenthesized: bool, context: &mut Context) {
+    if parenthesized {
+        context.code.push('(');
+    }
+
+    traverse_ast(node, &mut ast_to_code, context);
+
+    if parenthesized {
+        context.code.push(')');
+    }
+}
+
 fn ast_to_code(enter_exit: ASTTraverseStage, node: &mut Node, context: &mut Context) -> bool {
+    if matches!(enter_exit, ASTTraverseStage::Enter) {
+        print_trivia(&enter_exit, node, context);
+    }
+
+    let is_exit = matches!(enter_exit, ASTTraverseStage::Exit);
+    let skip = node_to_code(enter_exit, node, context);
+
+    if is_exit {
+        print_trivia(&ASTTraverseStage::Exit, node, context);
+    }
+
+    skip
+}
+
+fn node_to_code(enter_exit: ASTTraverseStage, node: &mut Node, context: &mut Context) -> bool {
+    // Attributes of exported declarations end up between "export" and the declaration
+    if matches!(enter_exit, ASTTraverseStage::Enter) && !matches!(node, Node::ExportDeclarationStmt { .. }) {
+        for attribute in node.attributes() {
+            context.code.push_str(&attribute.to_code_string());
+            context.code.push(' ');
+        }
+    }
+
     match node {
         Node::ProgramNode { .. } => {
             match enter_exit {
@@ -161,6 +390,8 @@ fn ast_to_code(enter_exit: ASTTraverseStage, node: &mut Node, context: &mut Cont
                 ASTTraverseStage::Enter => {
                     context.skip_identifiers = true;
 
+                    context.code.push_str("fn ");
+
                     match id.as_ref() {
                         Node::Identifier { name, .. } => {
                             context.code.push_str(name);
@@ -170,29 +401,24 @@ fn ast_to_code(enter_exit: ASTTraverseStage, node: &mut Node, context: &mut Cont
 
                     context.code.push_str("(");
 
-                    let mut params_str = String::new();
-                    for param in params {
-                        match param {
-                            Node::FunctionParameter { id, .. } => {
-                                match id.as_ref() {
-                                    Node::Identifier { name, .. } => {
-                                        params_str.push_str(&name);
-                                        params_str.push_str(", ");
-                                    }
-                                    _ => {}
-                                }
+                    // The parameters are printed here with their comments, the identifiers are skipped
+                    // when they are traversed
+                    for (i, param) in params.iter().enumerate() {
+                        if i > 0 {
+                            context.code.push_str(", ");
+                        }
+
+                        print_trivia(&ASTTraverseStage::Enter, param, context);
+
+                        if let Node::FunctionParameter { id, .. } = param {
+                            if let Node::Identifier { name, .. } = id.as_ref() {
+                                context.code.push_str(name);
                             }
-                            _ => {}
                         }
-                    }
 
-                    if params_str.len() > 0 {
-                        // Remove last comma and space
-                        params_str.pop();
-                        params_str.pop();
+                        print_trivia(&ASTTraverseStage::Exit, param, context);
                     }
 
-                    context.code.push_str(&params_str);
                     context.code.push_str(")");
                 }
                 ASTTraverseStage::Exit => {
@@ -235,12 +461,29 @@ fn ast_to_code(enter_exit: ASTTraverseStage, node: &mut Node, context: &mut Cont
                 ASTTraverseStage::Exit => {}
             }
         }
-        Node::ExpressionStmt { .. } => {}
-        Node::AssignmentExpr { lhs, rhs, .. } => {
+        Node::ExpressionStmt { child, .. } => {
+            // Function calls are the only expressions that do not end their own line
+            let is_call = match child.as_ref() {
+                Node::FnCallExpr { .. } => true,
+                Node::MemberExpr { property, .. } => matches!(property.as_ref(), Node::FnCallExpr { .. }),
+                _ => false,
+            };
+
+            if is_call && matches!(enter_exit, ASTTraverseStage::Exit) {
+                context.code.push_str(";\n");
+            }
+        }
+        Node::AssignmentExpr { lhs, op, rhs, .. } => {
             match enter_exit {
                 ASTTraverseStage::Enter => {
                     traverse_ast(lhs, &mut ast_to_code, context);
-                    context.code.push_str(" = ");
+                    context.code.push(' ');
+
+                    if let Some(op) = op {
+                        context.code.push_str(operator_symbol(op));
+                    }
+
+                    context.code.push_str("= ");
                     traverse_ast(rhs, &mut ast_to_code, context);
                 }
                 ASTTraverseStage::Exit => {
@@ -369,8 +612,8 @@ fn ast_to_code(enter_exit: ASTTraverseStage, node: &mut Node, context: &mut Cont
                         Node::Identifier { name, .. } => {
                             name.to_string()
                         }
-                        Node::Number { value, .. } => {
-                            value.to_string()
+                        Node::Number { .. } | Node::UnitNumber { .. } => {
+                            number_to_code(specifier, context)
                         }
                         Node::UnaryExpr { op, child, .. } => {
                             match op {
@@ -379,8 +622,8 @@ fn ast_to_code(enter_exit: ASTTraverseStage, node: &mut Node, context: &mut Cont
                                         Node::Identifier { name, .. } => {
                                             format!("-{}", name)
                                         }
-                                        Node::Number { value, .. } => {
-                                            format!("-{}", value.to_string())
+                                        Node::Number { .. } | Node::UnitNumber { .. } => {
+                                            format!("-{}", number_to_code(child, context))
                                         }
                                         _ => panic!("Invalid specifier")
                                     }
@@ -406,15 +649,14 @@ fn ast_to_code(enter_exit: ASTTraverseStage, node: &mut Node, context: &mut Cont
                     traverse_ast(callee, &mut ast_to_code, context);
                     context.code.push_str("(");
 
-                    for arg in args {
+                    for (i, arg) in args.iter_mut().enumerate() {
+                        if i > 0 {
+                            context.code.push_str(", ");
+                        }
+
                         traverse_ast(arg, &mut ast_to_code, context);
-                        context.code.push_str(", ");
                     }
 
-                    // Remove last comma and space
-                    context.code.pop();
-                    context.code.pop();
-
                     context.code.push_str(")");
                 }
                 ASTTraverseStage::Exit => {}
@@ -422,14 +664,31 @@ fn ast_to_code(enter_exit: ASTTraverseStage, node: &mut Node, context: &mut Cont
 
             return true;
         }
-        Node::Number { value, .. } => {
+        Node::Number { .. } | Node::UnitNumber { .. } => {
             match enter_exit {
                 ASTTraverseStage::Enter => {
-                    context.code.push_str(&value.to_string());
+                    let number = number_to_code(node, context);
+                    context.code.push_str(&number);
                 }
                 ASTTraverseStage::Exit => {}
             }
         }
+        Node::UnaryExpr { op, child, .. } if context.canonical => {
+            if let ASTTraverseStage::Enter = enter_exit {
+                let prefix = match op {
+                    Operator::Plus => "+",
+                    Operator::Minus => "-",
+                    _ => "!",
+                };
+
+                let child_parenthesized = matches!(child.as_ref(), Node::BinaryExpr { .. } | Node::ConditionalExpr { .. } | Node::UnaryExpr { .. });
+
+                context.code.push_str(prefix);
+                print_operand(child, child_parenthesized, context);
+            }
+
+            return true;
+        }
         Node::UnaryExpr { op, .. } => {
             match enter_exit {
                 ASTTraverseStage::Enter => {
@@ -440,12 +699,26 @@ fn ast_to_code(enter_exit: ASTTraverseStage, node: &mut Node, context: &mut Cont
                         Operator::Minus => {
                             context.code.push_str("-");
                         }
+                        Operator::Not => {
+                            context.code.push('!');
+                        }
                         _ => {}
                     }
                 }
                 ASTTraverseStage::Exit => {}
             }
         }
+        Node::BinaryExpr { op, lhs, rhs, .. } if context.canonical => {
+            if let ASTTraverseStage::Enter = enter_exit {
+                print_operand(lhs, needs_parentheses(op, lhs, true), context);
+                context.code.push(' ');
+                context.code.push_str(operator_symbol(op));
+                context.code.push(' ');
+                print_operand(rhs, needs_parentheses(op, rhs, false), context);
+            }
+
+            return true;
+        }
         Node::BinaryExpr { op, lhs, rhs, .. } => {
             match enter_exit {
                 ASTTraverseStage::Enter => {
@@ -464,6 +737,12 @@ fn ast_to_code(enter_exit: ASTTraverseStage, node: &mut Node, context: &mut Cont
                         Operator::Div => {
                             context.code.push_str(" / ");
                         }
+                        Operator::Mod => {
+                            context.code.push_str(" % ");
+                        }
+                        Operator::Pow => {
+                            context.code.push_str(" ^ ");
+                        }
                         Operator::Eq => {
                             context.code.push_str(" == ");
                         }
@@ -482,6 +761,13 @@ fn ast_to_code(enter_exit: ASTTraverseStage, node: &mut Node, context: &mut Cont
                         Operator::Ne => {
                             context.code.push_str(" != ");
                         }
+                        Operator::And => {
+                            context.code.push_str(" && ");
+                        }
+                        Operator::Or => {
+                            context.code.push_str(" || ");
+                        }
+                        Operator::Not => {}
                     }
                     traverse_ast(rhs, &mut ast_to_code, context);
                     context.code.push_str(")");
@@ -520,8 +806,14 @@ fn ast_to_code(enter_exit: ASTTraverseStage, node: &mut Node, context: &mut Cont
                     traverse_ast(id, &mut ast_to_code, context);
                     context.code.push_str("[");
                     traverse_ast(size, &mut ast_to_code, context);
-                    context.code.push_str("] = ");
-                    traverse_ast(initializer, &mut ast_to_code, context);
+                    context.code.push(']');
+
+                    // Buffers declared without an initializer get a 0 one from the parser
+                    if let Node::BufferInitializer { .. } = initializer.as_ref() {
+                        context.code.push_str(" = ");
+                        traverse_ast(initializer, &mut ast_to_code, context);
+                    }
+
                     context.code.push_str(";\n");
                 }
                 ASTTraverseStage::Exit => {}
@@ -545,11 +837,33 @@ fn ast_to_code(enter_exit: ASTTraverseStage, node: &mut Node, context: &mut Cont
                     context.code.push_str("import ");
                 }
                 ASTTraverseStage::Exit => {
-                    context.code.push_str(" from ");
+                    context.code.push_str(" from \"");
                     context.code.push_str(&path);
-                    context.code.push_str(";\n");
+                    context.code.push_str("\";\n");
+                }
+            }
+        }
+        Node::NamedImportStatement { names, path, .. } => {
+            match enter_exit {
+                ASTTraverseStage::Enter => {
+                    context.code.push_str("import { ");
+
+                    for (i, name) in names.iter_mut().enumerate() {
+                        if i > 0 {
+                            context.code.push_str(", ");
+                        }
+
+                        traverse_ast(name, &mut ast_to_code, context);
+                    }
+
+                    context.code.push_str(" } from \"");
+                    context.code.push_str(path);
+                    context.code.push_str("\";\n");
                 }
+                ASTTraverseStage::Exit => {}
             }
+
+            return true;
         }
         Node::IfStmt { test, consequent, alternate, .. } => {
             match enter_exit {
@@ -560,12 +874,35 @@ fn ast_to_code(enter_exit: ASTTraverseStage, node: &mut Node, context: &mut Cont
                     traverse_ast(consequent, &mut ast_to_code, context);
 
                     if let Some(alternate) = alternate {
+                        // "else" goes on the line of the "}" closing the consequent
+                        let trimmed_length = context.code.trim_end_matches('\n').len();
+                        context.code.truncate(trimmed_length);
+
                         context.code.push_str(" else ");
                         traverse_ast(alternate, &mut ast_to_code, context);
                     }
                 }
                 ASTTraverseStage::Exit => {}
             }
+
+            return true;
+        }
+        Node::ForStmt { id, from, to, body, .. } => {
+            match enter_exit {
+                ASTTraverseStage::Enter => {
+                    context.code.push_str("for ");
+                    traverse_ast(id, &mut ast_to_code, context);
+                    context.code.push_str(" in ");
+                    traverse_ast(from, &mut ast_to_code, context);
+                    context.code.push_str("..");
+                    traverse_ast(to, &mut ast_to_code, context);
+                    context.code.push(' ');
+                    traverse_ast(body, &mut ast_to_code, context);
+                }
+                ASTTraverseStage::Exit => {}
+            }
+
+            return true;
         }
         Node::BlockStmt { .. } => {
             match enter_exit {
@@ -587,6 +924,37 @@ fn ast_to_code(enter_exit: ASTTraverseStage, node: &mut Node, context: &mut Cont
                 }
             }
         }
+        Node::ConditionalExpr { test, consequent, alternate, .. } if context.canonical => {
+            if let ASTTraverseStage::Enter = enter_exit {
+                let is_nested = |node: &Node| matches!(node, Node::ConditionalExpr { .. });
+
+                let (test_parenthesized, consequent_parenthesized, alternate_parenthesized) = (is_nested(test), is_nested(consequent), is_nested(alternate));
+
+                print_operand(test, test_parenthesized, context);
+                context.code.push_str(" ? ");
+                print_operand(consequent, consequent_parenthesized, context);
+                context.code.push_str(" : ");
+                print_operand(alternate, alternate_parenthesized, context);
+            }
+
+            return true;
+        }
+        Node::ConditionalExpr { test, consequent, alternate, .. } => {
+            match enter_exit {
+                ASTTraverseStage::Enter => {
+                    context.code.push('(');
+                    traverse_ast(test, &mut ast_to_code, context);
+                    context.code.push_str(" ? ");
+                    traverse_ast(consequent, &mut ast_to_code, context);
+                    context.code.push_str(" : ");
+                    traverse_ast(alternate, &mut ast_to_code, context);
+                    context.code.push(')');
+                }
+                ASTTraverseStage::Exit => {}
+            }
+
+            return true;
+        }
     }
 
     false
@@ -599,12 +967,91 @@ pub enum Operator {
     Minus,
     Mul,
     Div,
+    Mod,
+    Pow,
     Eq,
     Gt,
     Lt,
     Ge,
     Le,
     Ne,
+    And,
+    Or,
+    Not,
+}
+
+// Suffix of a unit literal like 10ms. The IR lowers these into plain expressions over SR
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum Unit {
+    Milliseconds,
+    Seconds,
+    Hertz,
+    Decibels,
+    Samples,
+}
+
+impl Unit {
+    pub fn from_suffix(suffix: &str) -> Option<Unit> {
+        match suffix {
+            "ms" => Some(Unit::Milliseconds),
+            "s" => Some(Unit::Seconds),
+            "hz" => Some(Unit::Hertz),
+            "db" => Some(Unit::Decibels),
+            "smp" => Some(Unit::Samples),
+            _ => None,
+        }
+    }
+
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            Unit::Milliseconds => "ms",
+            Unit::Seconds => "s",
+            Unit::Hertz => "hz",
+            Unit::Decibels => "db",
+            Unit::Samples => "smp",
+        }
+    }
+}
+
+// @name or @name(arg, ...) in front of a declaration, e.g. @allow(unused_variable)
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Attribute {
+    pub name: String,
+    pub args: Vec<String>,
+    pub position: Position,
+}
+
+impl Attribute {
+    pub fn to_code_string(&self) -> String {
+        if self.args.is_empty() {
+            format!("@{}", self.name)
+        } else {
+            format!("@{}({})", self.name, self.args.join(", "))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum TriviaKind {
+    Whitespace,
+    Comment,
+}
+
+// Source text between tokens that the parser does not see
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Trivia {
+    pub kind: TriviaKind,
+    pub text: String,
+    pub position: Position,
+}
+
+// Leading trivia runs from the end of the previous line up to the statement. Trailing trivia
+// follows the statement on its last line, plus comments that no later statement can take,
+// like the ones before a closing "}" or inside the statement itself
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct NodeTrivia {
+    pub leading: Vec<Trivia>,
+    pub trailing: Vec<Trivia>,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize)]
@@ -648,6 +1095,8 @@ pub enum Node {
     },
     AssignmentExpr {
         lhs: Box<Node>,
+        // Operator of a compound assignment like "a += b", the IR lowers it to "a = a + b"
+        op: Option<Operator>,
         rhs: Box<Node>,
         position: Position,
     },
@@ -664,12 +1113,14 @@ pub enum Node {
         id: Box<Node>,
         initializer: Box<Node>,
         specifier: VariableSpecifier,
+        attributes: Vec<Attribute>,
         position: Position,
     },
     FunctionDeclarationStmt {
         id: Box<Node>,
         params: Vec<Node>,
         body: Box<Node>,
+        attributes: Vec<Attribute>,
         position: Position,
     },
     FunctionParameter {
@@ -689,6 +1140,7 @@ pub enum Node {
     ParameterDeclarationStmt {
         id: Box<Node>,
         fields: Vec<Node>,
+        attributes: Vec<Attribute>,
         position: Position,
     },
 
@@ -708,6 +1160,11 @@ pub enum Node {
         value: f64,
         position: Position,
     },
+    UnitNumber {
+        value: f64,
+        unit: Unit,
+        position: Position,
+    },
     UnaryExpr {
         op: Operator,
         child: Box<Node>,
@@ -739,6 +1196,13 @@ pub enum Node {
     ImportStatement {
         id: Box<Node>,
         path: String,
+        attributes: Vec<Attribute>,
+        position: Position,
+    },
+    // import { a, b } from "path";
+    NamedImportStatement {
+        names: Vec<Node>,
+        path: String,
         position: Position,
     },
     IfStmt {
@@ -747,6 +1211,13 @@ pub enum Node {
         alternate: Option<Box<Node>>,
         position: Position,
     },
+    ForStmt {
+        id: Box<Node>,
+        from: Box<Node>,
+        to: Box<Node>,
+        body: Box<Node>,
+        position: Position,
+    },
     BlockStmt {
         children: Vec<Node>,
         position: Position,
@@ -755,9 +1226,31 @@ pub enum Node {
         test: Box<Node>,
         position: Position,
     },
+    ConditionalExpr {
+        test: Box<Node>,
+        consequent: Box<Node>,
+        alternate: Box<Node>,
+        position: Position,
+    },
 }
 
 impl Node {
+    // Only declarations can carry attributes
+    pub fn attributes(&self) -> &[Attribute] {
+        match self {
+            Node::VariableDeclarationStmt { attributes, .. }
+            | Node::FunctionDeclarationStmt { attributes, .. }
+            | Node::ParameterDeclarationStmt { attributes, .. }
+            | Node::ImportStatement { attributes, .. } => attributes,
+            Node::ExportDeclarationStmt { declaration, .. } => declaration.attributes(),
+            _ => &[],
+        }
+    }
+
+    pub fn has_attribute(&self, name: &str) -> bool {
+        self.attributes().iter().any(|attribute| attribute.name == name)
+    }
+
     pub fn to_json(&self) -> String {
         serde_json::to_string_pretty(&self).unwrap()
     }
@@ -786,6 +1279,7 @@ impl Node {
             Node::ParameterDeclarationField { position, .. } => position,
             Node::FnCallExpr { position, .. } => position,
             Node::Number { position, .. } => position,
+            Node::UnitNumber { position, .. } => position,
             Node::UnaryExpr { position, .. } => position,
             Node::BinaryExpr { position, .. } => position,
             Node::OutputsStmt { position, .. } => position,
@@ -793,129 +1287,154 @@ impl Node {
             Node::BufferDeclarationStmt { position, .. } => position,
             Node::BufferInitializer { position, .. } => position,
             Node::ImportStatement { position, .. } => position,
+            Node::NamedImportStatement { position, .. } => position,
             Node::IfStmt { position, .. } => position,
+            Node::ForStmt { position, .. } => position,
             Node::BlockStmt { position, .. } => position,
             Node::ConnectedExpr { position, .. } => position,
+            Node::ConditionalExpr { position, .. } => position,
         }
     }
 
-    pub fn set_end(&mut self, end: u32, column: u32) {
+    // Name of the variant, for messages about nodes that are not expected in some place
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Node::ProgramNode { .. } => "ProgramNode",
+            Node::ProcessSection { .. } => "ProcessSection",
+            Node::BlockSection { .. } => "BlockSection",
+            Node::ConnectSection { .. } => "ConnectSection",
+            Node::FunctionBody { .. } => "FunctionBody",
+            Node::Identifier { .. } => "Identifier",
+            Node::ExpressionStmt { .. } => "ExpressionStmt",
+            Node::AssignmentExpr { .. } => "AssignmentExpr",
+            Node::ConnectStmt { .. } => "ConnectStmt",
+            Node::ReturnStmt { .. } => "ReturnStmt",
+            Node::VariableDeclarationStmt { .. } => "VariableDeclarationStmt",
+            Node::FunctionDeclarationStmt { .. } => "FunctionDeclarationStmt",
+            Node::FunctionParameter { .. } => "FunctionParameter",
+            Node::MemberExpr { .. } => "MemberExpr",
+            Node::ExportDeclarationStmt { .. } => "ExportDeclarationStmt",
+            Node::ParameterDeclarationStmt { .. } => "ParameterDeclarationStmt",
+            Node::ParameterDeclarationField { .. } => "ParameterDeclarationField",
+            Node::FnCallExpr { .. } => "FnCallExpr",
+            Node::Number { .. } => "Number",
+            Node::UnitNumber { .. } => "UnitNumber",
+            Node::UnaryExpr { .. } => "UnaryExpr",
+            Node::BinaryExpr { .. } => "BinaryExpr",
+            Node::OutputsStmt { .. } => "OutputsStmt",
+            Node::OutputsNumberedStmt { .. } => "OutputsNumberedStmt",
+            Node::BufferDeclarationStmt { .. } => "BufferDeclarationStmt",
+            Node::BufferInitializer { .. } => "BufferInitializer",
+            Node::ImportStatement { .. } => "ImportStatement",
+            Node::NamedImportStatement { .. } => "NamedImportStatement",
+            Node::IfStmt { .. } => "IfStmt",
+            Node::ForStmt { .. } => "ForStmt",
+            Node::BlockStmt { .. } => "BlockStmt",
+            Node::ConnectedExpr { .. } => "ConnectedExpr",
+            Node::ConditionalExpr { .. } => "ConditionalExpr",
+        }
+    }
+
+    pub fn set_end(&mut self, end: u32) {
         match self {
             Node::ProgramNode { position, .. } => {
                 position.end = end;
-                position.column = column;
             }
             Node::ProcessSection { position, .. } => {
                 position.end = end;
-                position.column = column;
             }
             Node::BlockSection { position, .. } => {
                 position.end = end;
-                position.column = column;
             }
             Node::ConnectSection { position, .. } => {
                 position.end = end;
-                position.column = column;
             }
             Node::FunctionBody { position, .. } => {
                 position.end = end;
-                position.column = column;
             }
             Node::Identifier { position, .. } => {
                 position.end = end;
-                position.column = column;
             }
             Node::ExpressionStmt { position, .. } => {
                 position.end = end;
-                position.column = column;
             }
             Node::AssignmentExpr { position, .. } => {
                 position.end = end;
-                position.column = column;
             }
             Node::ConnectStmt { position, .. } => {
                 position.end = end;
-                position.column = column;
             }
             Node::ReturnStmt { position, .. } => {
                 position.end = end;
-                position.column = column;
             }
             Node::VariableDeclarationStmt { position, .. } => {
                 position.end = end;
-                position.column = column;
             }
             Node::FunctionDeclarationStmt { position, .. } => {
                 position.end = end;
-                position.column = column;
             }
             Node::FunctionParameter { position, .. } => {
                 position.end = end;
-                position.column = column;
             }
             Node::MemberExpr { position, .. } => {
                 position.end = end;
-                position.column = column;
             }
             Node::ExportDeclarationStmt { position, .. } => {
                 position.end = end;
-                position.column = column;
             }
             Node::ParameterDeclarationStmt { position, .. } => {
                 position.end = end;
-                position.column = column;
             }
             Node::ParameterDeclarationField { position, .. } => {
                 position.end = end;
-                position.column = column;
             }
             Node::FnCallExpr { position, .. } => {
                 position.end = end;
-                position.column = column;
             }
             Node::Number { position, .. } => {
                 position.end = end;
-                position.column = column;
+            }
+            Node::UnitNumber { position, .. } => {
+                position.end = end;
             }
             Node::UnaryExpr { position, .. } => {
                 position.end = end;
-                position.column = column;
             }
             Node::BinaryExpr { position, .. } => {
                 position.end = end;
-                position.column = column;
             }
             Node::OutputsStmt { position, .. } => {
                 position.end = end;
-                position.column = column;
             }
             Node::OutputsNumberedStmt { position, .. } => {
                 position.end = end;
-                position.column = column;
             }
             Node::BufferDeclarationStmt { position, .. } => {
                 position.end = end;
-                position.column = column;
             }
             Node::BufferInitializer { position, .. } => {
                 position.end = end;
-                position.column = column;
             }
             Node::ImportStatement { position, .. } => {
                 position.end = end;
-                position.column = column;
+            }
+            Node::NamedImportStatement { position, .. } => {
+                position.end = end;
             }
             Node::IfStmt { position, .. } => {
                 position.end = end;
-                position.column = column;
+            }
+            Node::ForStmt { position, .. } => {
+                position.end = end;
             }
             Node::BlockStmt { position, .. } => {
                 position.end = end;
-                position.column = column;
             }
             Node::ConnectedExpr { position, .. } => {
                 position.end = end;
-                position.column = column;
+            }
+            Node::ConditionalExpr { position, .. } => {
+                position.end = end;
             }
         }
     }
@@ -953,6 +1472,12 @@ pub fn traverse_ast<Context>(node: &mut Node, f: &mut dyn FnMut(ASTTraverseStage
                     traverse_ast(alternate, f, context);
                 }
             }
+            Node::ForStmt { id, from, to, body, position: _ } => {
+                traverse_ast(id, f, context);
+                traverse_ast(from, f, context);
+                traverse_ast(to, f, context);
+                traverse_ast(body, f, context);
+            }
             Node::BlockStmt { children, position: _ } => {
                 for child in children {
                     traverse_ast(child, f, context);
@@ -971,7 +1496,7 @@ pub fn traverse_ast<Context>(node: &mut Node, f: &mut dyn FnMut(ASTTraverseStage
             Node::ExpressionStmt { child, position: _ } => {
                 traverse_ast(child, f, context);
             }
-            Node::AssignmentExpr { lhs, rhs, position: _ } => {
+            Node::AssignmentExpr { lhs, rhs, .. } => {
                 traverse_ast(lhs, f, context);
                 traverse_ast(rhs, f, context);
             }
@@ -986,12 +1511,13 @@ pub fn traverse_ast<Context>(node: &mut Node, f: &mut dyn FnMut(ASTTraverseStage
                 id,
                 initializer,
                 specifier: _,
+                attributes: _,
                 position: _
             } => {
                 traverse_ast(id, f, context);
                 traverse_ast(initializer, f, context);
             }
-            Node::FunctionDeclarationStmt { id, params, body, position: _ } => {
+            Node::FunctionDeclarationStmt { id, params, body, attributes: _, position: _ } => {
                 traverse_ast(id, f, context);
                 for param in params {
                     traverse_ast(param, f, context);
@@ -1008,7 +1534,7 @@ pub fn traverse_ast<Context>(node: &mut Node, f: &mut dyn FnMut(ASTTraverseStage
             Node::ExportDeclarationStmt { declaration, position: _ } => {
                 traverse_ast(declaration, f, context);
             }
-            Node::ParameterDeclarationStmt { id, fields, position: _ } => {
+            Node::ParameterDeclarationStmt { id, fields, attributes: _, position: _ } => {
                 traverse_ast(id, f, context);
                 for field in fields {
                     traverse_ast(field, f, context);
@@ -1025,6 +1551,7 @@ pub fn traverse_ast<Context>(node: &mut Node, f: &mut dyn FnMut(ASTTraverseStage
                 }
             }
             Node::Number { value: _, position: _ } => {}
+            Node::UnitNumber { value: _, unit: _, position: _ } => {}
             Node::UnaryExpr { op: _, child, position: _ } => {
                 traverse_ast(child, f, context);
             }
@@ -1049,13 +1576,23 @@ pub fn traverse_ast<Context>(node: &mut Node, f: &mut dyn FnMut(ASTTraverseStage
                     traverse_ast(child, f, context);
                 }
             }
-            Node::ImportStatement { id, path: _, position: _ } => {
+            Node::ImportStatement { id, path: _, attributes: _, position: _ } => {
                 traverse_ast(id, f, context);
             }
+            Node::NamedImportStatement { names, path: _, position: _ } => {
+                for name in names {
+                    traverse_ast(name, f, context);
+                }
+            }
             Node::Identifier { name: _, position: _ } => {}
             Node::ConnectedExpr { test, position: _ } => {
                 traverse_ast(test, f, context);
             }
+            Node::ConditionalExpr { test, consequent, alternate, position: _ } => {
+                traverse_ast(test, f, context);
+                traverse_ast(consequent, f, context);
+                traverse_ast(alternate, f, context);
+            }
         }
     }
 
@@ -1074,6 +1611,8 @@ mod tests {
                 position: Position { line: 0, column: 0, start: 0, end: 0 },
             },
             errors: vec![],
+            trivia: BTreeMap::new(),
+            number_literals: BTreeMap::new(),
         };
 
         struct Context {
@@ -1,15 +1,19 @@
+use std::collections::HashSet;
+
 use indexmap::IndexMap;
+use uuid::Uuid;
+use crate::diagnostic::{internal_error, ARGUMENT_COUNT, ASSIGNMENT_TO_CONSTANT, Diagnostic, DUPLICATE_SECTION, FUNCTION_AS_VALUE, INVALID_CONNECTION, INVALID_NAMED_IMPORT, MISSING_RETURN_VALUE, NAMED_IMPORT_CLASH, NON_CONSTANT_LOOP_BOUNDS, NOT_A_FUNCTION, PRIVATE_SYMBOL, UNKNOWN_NAME};
 use crate::module_data::ModuleData;
-use crate::parser::ast::{ASTTraverseStage, Node, traverse_ast};
-use crate::symbol_table::{SymbolInfo, SymbolTable};
+use crate::parser::ast::{ASTTraverseStage, Node, Operator, traverse_ast, Unit, VariableSpecifier};
+use crate::symbol_table::{SymbolInfo, SymbolOrigin, SymbolTable};
 
 pub struct SemanticAnalyzer {
-    pub errors: Vec<String>,
+    pub errors: Vec<Diagnostic>,
 }
 
 pub struct ValidationResult {
     pub module_name: String,
-    pub errors: Vec<String>,
+    pub errors: Vec<Diagnostic>,
 }
 
 impl SemanticAnalyzer {
@@ -19,12 +23,12 @@ impl SemanticAnalyzer {
         }
     }
 
-    pub fn validate_semantics(&mut self, modules: &mut IndexMap<String, ModuleData>) -> Result<String, Vec<String>> {
+    pub fn validate_semantics(&mut self, modules: &mut IndexMap<String, ModuleData>) -> Result<String, Vec<Diagnostic>> {
         self.clear_errors();
 
         struct Context {
             symbol_table: SymbolTable,
-            errors: Vec<String>,
+            errors: Vec<Diagnostic>,
 
             skip_identifier_check: bool,
             skip_identifier_check_once: bool,
@@ -32,6 +36,12 @@ impl SemanticAnalyzer {
 
             has_process_node: bool,
             has_connect_node: bool,
+
+            // Constants whose initializers are known at compile time
+            constant_symbols: HashSet<Uuid>,
+
+            // Set when a function call is a statement on its own, so its result is not used
+            call_as_statement: bool,
         }
 
         // For each module, traverse the AST and check for semantic errors
@@ -52,6 +62,10 @@ impl SemanticAnalyzer {
 
                 has_process_node: false,
                 has_connect_node: false,
+
+                constant_symbols: HashSet::new(),
+
+                call_as_statement: false,
             };
 
             traverse_ast(&mut ast.root, &mut |traverse_stage, node, context: &mut Context| {
@@ -63,10 +77,14 @@ impl SemanticAnalyzer {
                     => {
                         match traverse_stage {
                             ASTTraverseStage::Enter => {
-                                context.symbol_table.enter_next_scope();
+                                if let Err(error) = context.symbol_table.enter_next_scope() {
+                                    context.errors.push(error.at_node(node));
+                                }
                             }
                             ASTTraverseStage::Exit => {
-                                context.symbol_table.exit_scope();
+                                if let Err(error) = context.symbol_table.exit_scope() {
+                                    context.errors.push(error.at_node(node));
+                                }
                             }
                         }
                     }
@@ -75,14 +93,18 @@ impl SemanticAnalyzer {
                         match traverse_stage {
                             ASTTraverseStage::Enter => {
                                 if context.has_process_node {
-                                    context.errors.push(format!("Cannot have more than one process block, {:?}", node.position()));
+                                    context.errors.push(Diagnostic::error(DUPLICATE_SECTION, "Cannot have more than one process block".to_string()).with_span(*node.position()));
                                 }
 
                                 context.has_process_node = true;
-                                context.symbol_table.enter_next_scope();
+                                if let Err(error) = context.symbol_table.enter_next_scope() {
+                                    context.errors.push(error.at_node(node));
+                                }
                             }
                             ASTTraverseStage::Exit => {
-                                context.symbol_table.exit_scope();
+                                if let Err(error) = context.symbol_table.exit_scope() {
+                                    context.errors.push(error.at_node(node));
+                                }
                             }
                         }
                     }
@@ -91,7 +113,7 @@ impl SemanticAnalyzer {
                         match traverse_stage {
                             ASTTraverseStage::Enter => {
                                 if context.has_connect_node {
-                                    context.errors.push(format!("Cannot have more than one connect block, {:?}", node.position()));
+                                    context.errors.push(Diagnostic::error(DUPLICATE_SECTION, "Cannot have more than one connect block".to_string()).with_span(*node.position()));
                                 }
 
                                 context.has_connect_node = true;
@@ -133,7 +155,7 @@ impl SemanticAnalyzer {
                                 match symbol {
                                     Some(_) => {}
                                     None => {
-                                        context.errors.push(format!("Cannot find name \"{}\", {:?}", name, position));
+                                        context.errors.push(Diagnostic::error(UNKNOWN_NAME, format!("Cannot find name \"{}\"", name)).with_span(*position));
                                     }
                                 }
                             }
@@ -152,7 +174,7 @@ impl SemanticAnalyzer {
                                         match context.symbol_table.lookup(name) {
                                             Some(symbol_info) => {
                                                 if !symbol_info.is_input() && !symbol_info.is_output() {
-                                                    context.errors.push(format!("Cannot use {} in connected statement. Use either input or output. {:?}", name, position));
+                                                    context.errors.push(Diagnostic::error(INVALID_CONNECTION, format!("Cannot use {} in connected statement. Use either input or output.", name)).with_span(*position));
                                                 }
                                             }
                                             None => {}
@@ -179,7 +201,7 @@ impl SemanticAnalyzer {
 
                                         if let Some(symbol) = symbol {
                                             if symbol.is_constant() {
-                                                context.errors.push(format!("Cannot assign to constant \"{}\", {:?}", name, position));
+                                                context.errors.push(Diagnostic::error(ASSIGNMENT_TO_CONSTANT, format!("Cannot assign to constant \"{}\"", name)).with_span(*position));
                                             }
                                         }
                                     }
@@ -191,7 +213,7 @@ impl SemanticAnalyzer {
                                         match context.symbol_table.lookup(name) {
                                             Some(symbol_info) => {
                                                 if let SymbolInfo::Function { .. } = symbol_info {
-                                                    context.errors.push(format!("Cannot assign function \"{}\" to a variable, {:?}", name, position));
+                                                    context.errors.push(Diagnostic::error(FUNCTION_AS_VALUE, format!("Cannot assign function \"{}\" to a variable", name)).with_span(*position));
                                                 }
                                             }
                                             None => {}
@@ -202,14 +224,12 @@ impl SemanticAnalyzer {
                                         property,
                                         ..
                                     } => {
-                                        let object_name = match object.as_ref() {
-                                            Node::Identifier { name, .. } => name,
-                                            _ => panic!("Expected identifier")
-                                        };
-
-                                        let property_name = match property.as_ref() {
-                                            Node::Identifier { name, .. } => name,
-                                            _ => panic!("Expected identifier")
+                                        let (object_name, property_name) = match member_names(object, property) {
+                                            Ok(names) => names,
+                                            Err(error) => {
+                                                context.errors.push(error);
+                                                return false;
+                                            }
                                         };
 
                                         let result = lookup_module_symbol(&object_name, &property_name, &context.symbol_table, &modules);
@@ -219,7 +239,7 @@ impl SemanticAnalyzer {
                                                 let symbol = *symbol;
 
                                                 if let SymbolInfo::Function { .. } = symbol {
-                                                    context.errors.push(format!("Cannot assign function \"{}\" to a variable, {:?}", property_name, position));
+                                                    context.errors.push(Diagnostic::error(FUNCTION_AS_VALUE, format!("Cannot assign function \"{}\" to a variable", property_name)).with_span(*position));
                                                 }
                                             }
                                             Err(_) => {}
@@ -233,18 +253,28 @@ impl SemanticAnalyzer {
                     }
 
                     Node::VariableDeclarationStmt {
+                        id,
                         initializer,
+                        specifier,
                         position,
                         ..
                     } => {
                         match traverse_stage {
                             ASTTraverseStage::Enter => {
+                                if *specifier == VariableSpecifier::Const && is_constant_expr(initializer, &context.symbol_table, &context.constant_symbols) {
+                                    if let Node::Identifier { name, .. } = id.as_ref() {
+                                        if let Some(symbol) = context.symbol_table.lookup(name) {
+                                            context.constant_symbols.insert(*symbol.id());
+                                        }
+                                    }
+                                }
+
                                 match initializer.as_ref() {
                                     Node::Identifier { name, .. } => {
                                         match context.symbol_table.lookup(name) {
                                             Some(symbol_info) => {
                                                 if let SymbolInfo::Function { .. } = symbol_info {
-                                                    context.errors.push(format!("Cannot assign function \"{}\" to a variable, {:?}", name, position));
+                                                    context.errors.push(Diagnostic::error(FUNCTION_AS_VALUE, format!("Cannot assign function \"{}\" to a variable", name)).with_span(*position));
                                                 }
                                             }
                                             None => {}
@@ -256,14 +286,12 @@ impl SemanticAnalyzer {
                                         property,
                                         ..
                                     } => {
-                                        let object_name = match object.as_ref() {
-                                            Node::Identifier { name, .. } => name,
-                                            _ => panic!("Expected identifier")
-                                        };
-
-                                        let property_name = match property.as_ref() {
-                                            Node::Identifier { name, .. } => name,
-                                            _ => panic!("Expected identifier")
+                                        let (object_name, property_name) = match member_names(object, property) {
+                                            Ok(names) => names,
+                                            Err(error) => {
+                                                context.errors.push(error);
+                                                return false;
+                                            }
                                         };
 
                                         let result = lookup_module_symbol(&object_name, &property_name, &context.symbol_table, &modules);
@@ -273,7 +301,7 @@ impl SemanticAnalyzer {
                                                 let symbol = *symbol;
 
                                                 if let SymbolInfo::Function { .. } = symbol {
-                                                    context.errors.push(format!("Cannot assign function \"{}\" to a variable, {:?}", property_name, position));
+                                                    context.errors.push(Diagnostic::error(FUNCTION_AS_VALUE, format!("Cannot assign function \"{}\" to a variable", property_name)).with_span(*position));
                                                 }
                                             }
                                             Err(_) => {}
@@ -299,7 +327,7 @@ impl SemanticAnalyzer {
                                         match context.symbol_table.lookup(name) {
                                             Some(symbol_info) => {
                                                 if let SymbolInfo::Function { .. } = symbol_info {
-                                                    context.errors.push(format!("Cannot use function \"{}\" as a variable, {:?}", name, position));
+                                                    context.errors.push(Diagnostic::error(FUNCTION_AS_VALUE, format!("Cannot use function \"{}\" as a variable", name)).with_span(*position));
                                                 }
                                             }
                                             None => {}
@@ -313,7 +341,7 @@ impl SemanticAnalyzer {
                                         match context.symbol_table.lookup(name) {
                                             Some(symbol_info) => {
                                                 if let SymbolInfo::Function { .. } = symbol_info {
-                                                    context.errors.push(format!("Cannot use function \"{}\" as a variable, {:?}", name, position));
+                                                    context.errors.push(Diagnostic::error(FUNCTION_AS_VALUE, format!("Cannot use function \"{}\" as a variable", name)).with_span(*position));
                                                 }
                                             }
                                             None => {}
@@ -324,14 +352,12 @@ impl SemanticAnalyzer {
                                         property,
                                         ..
                                     } => {
-                                        let object_name = match object.as_ref() {
-                                            Node::Identifier { name, .. } => name,
-                                            _ => panic!("Expected identifier")
-                                        };
-
-                                        let property_name = match property.as_ref() {
-                                            Node::Identifier { name, .. } => name,
-                                            _ => panic!("Expected identifier")
+                                        let (object_name, property_name) = match member_names(object, property) {
+                                            Ok(names) => names,
+                                            Err(error) => {
+                                                context.errors.push(error);
+                                                return false;
+                                            }
                                         };
 
                                         let result = lookup_module_symbol(&object_name, &property_name, &context.symbol_table, &modules);
@@ -341,7 +367,7 @@ impl SemanticAnalyzer {
                                                 let symbol = *symbol;
 
                                                 if let SymbolInfo::Function { .. } = symbol {
-                                                    context.errors.push(format!("Cannot assign function \"{}\" to a variable, {:?}", property_name, position));
+                                                    context.errors.push(Diagnostic::error(FUNCTION_AS_VALUE, format!("Cannot assign function \"{}\" to a variable", property_name)).with_span(*position));
                                                 }
                                             }
                                             Err(_) => {}
@@ -354,27 +380,125 @@ impl SemanticAnalyzer {
                         }
                     }
 
+                    Node::ForStmt {
+                        from,
+                        to,
+                        position,
+                        ..
+                    } => {
+                        match traverse_stage {
+                            ASTTraverseStage::Enter => {
+                                if !is_constant_expr(from, &context.symbol_table, &context.constant_symbols) || !is_constant_expr(to, &context.symbol_table, &context.constant_symbols) {
+                                    context.errors.push(Diagnostic::error(NON_CONSTANT_LOOP_BOUNDS, "For loop bounds must be compile-time constants".to_string()).with_span(*position));
+                                }
+
+                                if let Err(error) = context.symbol_table.enter_next_scope() {
+                                    context.errors.push(error.at_node(node));
+                                }
+                            }
+                            ASTTraverseStage::Exit => {
+                                if let Err(error) = context.symbol_table.exit_scope() {
+                                    context.errors.push(error.at_node(node));
+                                }
+                            }
+                        }
+                    }
+
+                    Node::ConditionalExpr {
+                        test,
+                        consequent,
+                        alternate,
+                        position,
+                    } => {
+                        match traverse_stage {
+                            ASTTraverseStage::Enter => {
+                                for operand in [test, consequent, alternate] {
+                                    if let Node::Identifier { name, .. } = operand.as_ref() {
+                                        if let Some(SymbolInfo::Function { .. }) = context.symbol_table.lookup(name) {
+                                            context.errors.push(Diagnostic::error(FUNCTION_AS_VALUE, format!("Cannot use function \"{}\" as a variable", name)).with_span(*position));
+                                        }
+                                    }
+                                }
+                            }
+                            ASTTraverseStage::Exit => {}
+                        }
+                    }
+
+                    Node::ExpressionStmt {
+                        child,
+                        ..
+                    } => {
+                        match traverse_stage {
+                            ASTTraverseStage::Enter => {
+                                context.call_as_statement = matches!(child.as_ref(), Node::FnCallExpr { .. });
+                            }
+                            ASTTraverseStage::Exit => {}
+                        }
+                    }
+
+                    Node::NamedImportStatement {
+                        names,
+                        path,
+                        ..
+                    } => {
+                        match traverse_stage {
+                            ASTTraverseStage::Enter => {
+                                for name in names.iter() {
+                                    if let Node::Identifier { name, position } = name {
+                                        // The name is bound to a declaration of this module or to an earlier import
+                                        let bound_elsewhere = match context.symbol_table.lookup(name) {
+                                            Some(SymbolInfo::Function { origin: SymbolOrigin::ImportedModule { module }, .. }) => module != path,
+                                            Some(_) => true,
+                                            None => false,
+                                        };
+
+                                        if bound_elsewhere {
+                                            context.errors.push(Diagnostic::error(NAMED_IMPORT_CLASH, format!("Cannot import \"{}\" from module \"{}\", the name is already declared in this module", name, path)).with_span(*position));
+                                            continue;
+                                        }
+
+                                        match lookup_exported_symbol(path, name, modules) {
+                                            Ok(symbol) => {
+                                                if !matches!(symbol, SymbolInfo::Function { .. }) {
+                                                    context.errors.push(Diagnostic::error(INVALID_NAMED_IMPORT, format!("Cannot import \"{}\" from module \"{}\", only functions can be imported by name", name, path)).with_span(*position));
+                                                }
+                                            }
+                                            Err(error) => {
+                                                context.errors.push(error.with_span(*position));
+                                            }
+                                        }
+                                    }
+                                }
+                            }
+                            ASTTraverseStage::Exit => {}
+                        }
+                    }
+
                     Node::FnCallExpr {
                         callee, args, position, ..
                     } => {
                         match traverse_stage {
                             ASTTraverseStage::Enter => {
+                                let call_as_statement = context.call_as_statement;
+                                context.call_as_statement = false;
+
                                 let function_name = match callee.as_ref() {
                                     Node::Identifier { name, .. } => name.to_string(),
                                     Node::MemberExpr {object, property, ..} => {
-                                        let object_name = match object.as_ref() {
-                                            Node::Identifier { name, .. } => name.to_string(),
-                                            _ => panic!("Expected identifier")
-                                        };
-
-                                        let property_name = match property.as_ref() {
-                                            Node::Identifier { name, .. } => name.to_string(),
-                                            _ => panic!("Expected identifier")
+                                        let (object_name, property_name) = match member_names(object, property) {
+                                            Ok(names) => names,
+                                            Err(error) => {
+                                                context.errors.push(error);
+                                                return false;
+                                            }
                                         };
 
                                         format!("{}.{}", object_name, property_name)
                                     }
-                                    _ => panic!("Expected function name")
+                                    node => {
+                                        context.errors.push(internal_error(node, "a function name"));
+                                        return false;
+                                    }
                                 };
 
                                 let function_symbol = match callee.as_ref() {
@@ -382,25 +506,27 @@ impl SemanticAnalyzer {
                                         let symbol = context.symbol_table.lookup(name);
 
                                         match symbol {
+                                            // Named imports are resolved against the module they come from
+                                            Some(SymbolInfo::Function { origin: SymbolOrigin::ImportedModule { module }, .. }) => {
+                                                lookup_exported_symbol(module, name, modules).map_err(Some)
+                                            }
                                             Some(symbol) => {
                                                 Ok(symbol)
                                             }
                                             None => {
-                                                Err("".to_string())
+                                                Err(None)
                                             }
                                         }
                                     },
                                     Node::MemberExpr { object, property,  .. } => {
                                         context.skip_module_check_once = true;
 
-                                        let object_name = match object.as_ref() {
-                                            Node::Identifier { name, .. } => name,
-                                            _ => panic!("Expected identifier")
-                                        };
-
-                                        let property_name = match property.as_ref() {
-                                            Node::Identifier { name, .. } => name,
-                                            _ => panic!("Expected identifier")
+                                        let (object_name, property_name) = match member_names(object, property) {
+                                            Ok(names) => names,
+                                            Err(error) => {
+                                                context.errors.push(error);
+                                                return false;
+                                            }
                                         };
 
                                         let symbol = lookup_module_symbol(&object_name, &property_name, &context.symbol_table, &modules);
@@ -411,11 +537,14 @@ impl SemanticAnalyzer {
                                                 Ok(symbol)
                                             }
                                             Err(error) => {
-                                                Err(error)
+                                                Err(Some(error))
                                             }
                                         }
                                     }
-                                    _ => panic!("Expected identifier")
+                                    node => {
+                                        context.errors.push(internal_error(node, "a function name"));
+                                        return false;
+                                    }
                                 };
 
                                 match function_symbol {
@@ -423,24 +552,29 @@ impl SemanticAnalyzer {
                                         match symbol {
                                             SymbolInfo::Function {
                                                 parameters,
+                                                returns_value,
                                                 ..
                                             } => {
                                                 if args.len() != parameters.len() {
-                                                    context.errors.push(format!("Function \"{}\" expects {} arguments, but {} were provided, {:?}", function_name, parameters.len(), args.len(), position));
+                                                    context.errors.push(Diagnostic::error(ARGUMENT_COUNT, format!("Function \"{}\" expects {} arguments, but {} were provided", function_name, parameters.len(), args.len())).with_span(*position));
+                                                }
+
+                                                if !returns_value && !call_as_statement {
+                                                    context.errors.push(Diagnostic::error(MISSING_RETURN_VALUE, format!("Function \"{}\" does not return a value", function_name)).with_span(*position));
                                                 }
                                             }
                                             _ => {
-                                                context.errors.push(format!("\"{}\" is not a function, {:?}", function_name, position));
+                                                context.errors.push(Diagnostic::error(NOT_A_FUNCTION, format!("\"{}\" is not a function", function_name)).with_span(*position));
                                             }
                                         }
                                     }
                                     Err(error) => {
+                                        let message = match error {
+                                            Some(error) => format!("Function \"{}\" does not exist ({})", function_name, error.message),
+                                            None => format!("Function \"{}\" does not exist", function_name),
+                                        };
 
-                                        if error.len() > 0 {
-                                            context.errors.push(format!("Function \"{}\" does not exist ({}), {:?}", function_name, error, position));
-                                        } else {
-                                            context.errors.push(format!("Function \"{}\" does not exist, {:?}", function_name, position));
-                                        }
+                                        context.errors.push(Diagnostic::error(UNKNOWN_NAME, message).with_span(*position));
                                     }
                                 }
 
@@ -465,14 +599,12 @@ impl SemanticAnalyzer {
                                     return false;
                                 }
 
-                                let object_name = match object.as_ref() {
-                                    Node::Identifier { name, .. } => name,
-                                    _ => panic!("Expected identifier")
-                                };
-
-                                let property_name = match property.as_ref() {
-                                    Node::Identifier { name, .. } => name,
-                                    _ => panic!("Expected identifier")
+                                let (object_name, property_name) = match member_names(object, property) {
+                                    Ok(names) => names,
+                                    Err(error) => {
+                                        context.errors.push(error);
+                                        return false;
+                                    }
                                 };
 
                                 // let formatted = format!("{}.{}", object_name, property_name);
@@ -486,7 +618,7 @@ impl SemanticAnalyzer {
                                 match result {
                                     Ok(_) => {}
                                     Err(error) => {
-                                        context.errors.push(format!("{}, {:?}", error, position));
+                                        context.errors.push(error.with_span(*position));
                                     }
                                 }
 
@@ -510,7 +642,7 @@ impl SemanticAnalyzer {
                                         match context.symbol_table.lookup(name) {
                                             Some(symbol_info) => {
                                                 if !symbol_info.is_output() {
-                                                    context.errors.push(format!("Cannot connect \"{}\" to an input, declare it using \"output\" instead, {:?}", name, position));
+                                                    context.errors.push(Diagnostic::error(INVALID_CONNECTION, format!("Cannot connect \"{}\" to an input, declare it using \"output\" instead", name)).with_span(*position));
                                                 }
                                             }
                                             None => {}
@@ -521,14 +653,12 @@ impl SemanticAnalyzer {
                                         property,
                                         ..
                                     } => {
-                                        let object_name = match object.as_ref() {
-                                            Node::Identifier { name, .. } => name,
-                                            _ => panic!("Expected identifier")
-                                        };
-
-                                        let property_name = match property.as_ref() {
-                                            Node::Identifier { name, .. } => name,
-                                            _ => panic!("Expected identifier")
+                                        let (object_name, property_name) = match member_names(object, property) {
+                                            Ok(names) => names,
+                                            Err(error) => {
+                                                context.errors.push(error);
+                                                return false;
+                                            }
                                         };
 
                                         let result = lookup_module_symbol(&object_name, &property_name, &context.symbol_table, &modules);
@@ -538,7 +668,7 @@ impl SemanticAnalyzer {
                                                 let symbol = *symbol;
 
                                                 if !symbol.is_output() {
-                                                    context.errors.push(format!("Cannot connect \"{}\" to an input, declare it using \"output\" instead, {:?}", property_name, position));
+                                                    context.errors.push(Diagnostic::error(INVALID_CONNECTION, format!("Cannot connect \"{}\" to an input, declare it using \"output\" instead", property_name)).with_span(*position));
                                                 }
                                             }
                                             Err(_) => {}
@@ -552,7 +682,7 @@ impl SemanticAnalyzer {
                                         match context.symbol_table.lookup(name) {
                                             Some(symbol_info) => {
                                                 if !symbol_info.is_input() {
-                                                    context.errors.push(format!("Cannot connect to \"{}\", declare it using \"input\" instead, {:?}", name, position));
+                                                    context.errors.push(Diagnostic::error(INVALID_CONNECTION, format!("Cannot connect to \"{}\", declare it using \"input\" instead", name)).with_span(*position));
                                                 }
                                             }
                                             None => {}
@@ -563,14 +693,12 @@ impl SemanticAnalyzer {
                                         property,
                                         ..
                                     } => {
-                                        let object_name = match object.as_ref() {
-                                            Node::Identifier { name, .. } => name,
-                                            _ => panic!("Expected identifier")
-                                        };
-
-                                        let property_name = match property.as_ref() {
-                                            Node::Identifier { name, .. } => name,
-                                            _ => panic!("Expected identifier")
+                                        let (object_name, property_name) = match member_names(object, property) {
+                                            Ok(names) => names,
+                                            Err(error) => {
+                                                context.errors.push(error);
+                                                return false;
+                                            }
                                         };
 
                                         let result = lookup_module_symbol(&object_name, &property_name, &context.symbol_table, &modules);
@@ -580,7 +708,7 @@ impl SemanticAnalyzer {
                                                 let symbol = *symbol;
 
                                                 if !symbol.is_input() {
-                                                    context.errors.push(format!("Cannot connect \"{}\" to an output, declare it using \"input\" instead, {:?}", property_name, position));
+                                                    context.errors.push(Diagnostic::error(INVALID_CONNECTION, format!("Cannot connect \"{}\" to an output, declare it using \"input\" instead", property_name)).with_span(*position));
                                                 }
                                             }
                                             Err(_) => {}
@@ -611,9 +739,7 @@ impl SemanticAnalyzer {
 
         for mut result in validation_result {
             if result.errors.len() > 0 {
-                result.errors = result.errors.into_iter().map(|error| -> String {
-                    format!("[Module \"{}\"]: {}", result.module_name, error)
-                }).collect();
+                result.errors = result.errors.into_iter().map(|error| error.with_file(&result.module_name)).collect();
 
                 errors.append(&mut result.errors);
             }
@@ -631,11 +757,33 @@ impl SemanticAnalyzer {
     }
 }
 
-fn lookup_module_symbol<'a>(object_name: &str, property_name: &str, symbol_table: &SymbolTable, modules: &'a IndexMap<String, ModuleData>) -> Result<Box<&'a SymbolInfo>, String> {
+// Numbers, constants initialized with numbers, and arithmetic on them
+fn is_constant_expr(node: &Node, symbol_table: &SymbolTable, constant_symbols: &HashSet<Uuid>) -> bool {
+    match node {
+        Node::Number { .. } => true,
+        // Time and frequency literals depend on the sample rate, which is only known at runtime
+        Node::UnitNumber { unit, .. } => matches!(unit, Unit::Decibels | Unit::Samples),
+        Node::Identifier { name, .. } => {
+            match symbol_table.lookup(name) {
+                Some(symbol) => constant_symbols.contains(symbol.id()),
+                None => false,
+            }
+        }
+        Node::UnaryExpr { op: Operator::Plus | Operator::Minus, child, .. } => {
+            is_constant_expr(child, symbol_table, constant_symbols)
+        }
+        Node::BinaryExpr { op: Operator::Plus | Operator::Minus | Operator::Mul | Operator::Div | Operator::Mod | Operator::Pow, lhs, rhs, .. } => {
+            is_constant_expr(lhs, symbol_table, constant_symbols) && is_constant_expr(rhs, symbol_table, constant_symbols)
+        }
+        _ => false,
+    }
+}
+
+fn lookup_module_symbol<'a>(object_name: &str, property_name: &str, symbol_table: &SymbolTable, modules: &'a IndexMap<String, ModuleData>) -> Result<Box<&'a SymbolInfo>, Diagnostic> {
     let module_symbol = symbol_table.lookup(object_name);
 
     if module_symbol.is_none() {
-        return Err(format!("Cannot find module \"{}\"", object_name));
+        return Err(Diagnostic::error(UNKNOWN_NAME, format!("Cannot find module \"{}\"", object_name)));
     }
 
     let module_symbol = module_symbol.unwrap();
@@ -643,14 +791,18 @@ fn lookup_module_symbol<'a>(object_name: &str, property_name: &str, symbol_table
     let module_path = match module_symbol {
         SymbolInfo::ImportedModule { path: module, .. } => module,
         _ => {
-            return Err(format!("Cannot find module \"{}\"", object_name))
+            return Err(Diagnostic::error(UNKNOWN_NAME, format!("Cannot find module \"{}\"", object_name)))
         }
     };
 
+    lookup_exported_symbol(module_path, property_name, modules).map(Box::new)
+}
+
+fn lookup_exported_symbol<'a>(module_path: &str, property_name: &str, modules: &'a IndexMap<String, ModuleData>) -> Result<&'a SymbolInfo, Diagnostic> {
     let module_data = modules.get(module_path);
 
     if module_data.is_none() {
-        return Err(format!("Cannot find module \"{}\"", module_path));
+        return Err(Diagnostic::error(UNKNOWN_NAME, format!("Cannot find module \"{}\"", module_path)));
     }
 
     let module_data = module_data.unwrap();
@@ -658,20 +810,29 @@ fn lookup_module_symbol<'a>(object_name: &str, property_name: &str, symbol_table
     let symbol = module_data.symbol_table.lookup(property_name);
 
     if symbol.is_none() {
-        return Err(format!("Cannot find name \"{}\" in module \"{}\"", property_name, module_path));
+        return Err(Diagnostic::error(UNKNOWN_NAME, format!("Cannot find name \"{}\" in module \"{}\"", property_name, module_path)));
     }
 
     let symbol = symbol.unwrap();
 
     if symbol.is_private() {
-        return Err(format!("Cannot access private symbol \"{}\" in module \"{}\"", property_name, module_path));
+        return Err(Diagnostic::error(PRIVATE_SYMBOL, format!("Cannot access private symbol \"{}\" in module \"{}\"", property_name, module_path)));
     }
 
-    Ok(Box::new(symbol))
+    Ok(symbol)
+}
+
+// Module members are only ever accessed by name ("Module.name")
+fn member_names<'a>(object: &'a Node, property: &'a Node) -> Result<(&'a String, &'a String), Diagnostic> {
+    match (object, property) {
+        (Node::Identifier { name: object_name, .. }, Node::Identifier { name: property_name, .. }) => Ok((object_name, property_name)),
+        (Node::Identifier { .. }, node) | (node, _) => Err(internal_error(node, "an identifier")),
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::diagnostic::{Diagnostic, NAMED_IMPORT_CLASH};
     use indexmap::IndexMap;
     use crate::lexer::Lexer;
     use crate::module_data::ModuleData;
@@ -679,6 +840,18 @@ mod tests {
     use crate::semantic::SemanticAnalyzer;
     use crate::symbol_table::SymbolTable;
 
+    // Flattens diagnostics so the expectations can spell out the module, message and span at once
+    fn error_strings(diagnostics: Vec<Diagnostic>) -> Vec<String> {
+        diagnostics.iter().map(|diagnostic| {
+            let module = match &diagnostic.file {
+                Some(file) => format!("[Module \"{}\"]: ", file),
+                None => "".to_string(),
+            };
+
+            format!("{}{}, {:?}", module, diagnostic.message, diagnostic.span.unwrap())
+        }).collect()
+    }
+
     #[test]
     fn test_semantic_analyzer_lotion() {
         let code = "
@@ -753,14 +926,14 @@ mod tests {
 
         let result = semantic.validate_semantics(&mut modules);
 
-        let errors = result.unwrap_err();
+        let errors = error_strings(result.unwrap_err());
 
         println!("{:#?}", errors);
 
         assert_eq!(errors.len(), 3);
-        assert_eq!(errors[0], "[Module \"main\"]: Function \"bar\" does not exist, Position { start: 120, end: 126, line: 8, column: 23 }");
-        assert_eq!(errors[1], "[Module \"main\"]: \"foo\" is not a function, Position { start: 147, end: 153, line: 9, column: 23 }");
-        assert_eq!(errors[2], "[Module \"main\"]: Function \"baz\" expects 2 arguments, but 1 were provided, Position { start: 175, end: 182, line: 11, column: 24 }");
+        assert_eq!(errors[0], "[Module \"main\"]: Function \"bar\" does not exist, Position { start: 120, end: 125, line: 8, column: 18 }");
+        assert_eq!(errors[1], "[Module \"main\"]: \"foo\" is not a function, Position { start: 147, end: 152, line: 9, column: 18 }");
+        assert_eq!(errors[2], "[Module \"main\"]: Function \"baz\" expects 2 arguments, but 1 were provided, Position { start: 175, end: 181, line: 11, column: 18 }");
     }
 
     #[test]
@@ -796,11 +969,11 @@ mod tests {
 
         let result = semantic.validate_semantics(&mut modules);
 
-        let errors = result.unwrap_err();
+        let errors = error_strings(result.unwrap_err());
 
         assert_eq!(errors.len(), 1);
 
-        assert_eq!(errors[0], "[Module \"main\"]: Cannot find name \"c\", Position { start: 129, end: 131, line: 8, column: 27 }");
+        assert_eq!(errors[0], "[Module \"main\"]: Cannot find name \"c\", Position { start: 129, end: 130, line: 8, column: 26 }");
     }
 
     #[test]
@@ -832,10 +1005,10 @@ mod tests {
 
         assert!(result.is_err());
 
-        let errors = result.unwrap_err();
+        let errors = error_strings(result.unwrap_err());
 
         assert_eq!(errors.len(), 1);
-        assert_eq!(errors[0], "[Module \"main\"]: Cannot find name \"a\", Position { start: 23, end: 25, line: 2, column: 21 }");
+        assert_eq!(errors[0], "[Module \"main\"]: Cannot find name \"a\", Position { start: 23, end: 24, line: 2, column: 20 }");
     }
 
     #[test]
@@ -867,10 +1040,10 @@ mod tests {
 
         let result = semantic.validate_semantics(&mut modules);
 
-        let errors = result.unwrap_err();
+        let errors = error_strings(result.unwrap_err());
 
         assert_eq!(errors.len(), 1);
-        assert_eq!(errors[0], "[Module \"main\"]: Cannot find name \"a\", Position { start: 58, end: 60, line: 3, column: 30 }");
+        assert_eq!(errors[0], "[Module \"main\"]: Cannot find name \"a\", Position { start: 58, end: 59, line: 3, column: 29 }");
     }
 
     #[test]
@@ -1030,20 +1203,20 @@ mod tests {
 
         assert!(result.is_err());
 
-        let errors = result.unwrap_err();
+        let errors = error_strings(result.unwrap_err());
 
         println!("{:#?}", errors);
 
         assert_eq!(errors.len(), 8);
 
         assert_eq!(errors[0], "[Module \"main\"]: Cannot find name \"in1\", Position { start: 182, end: 185, line: 10, column: 29 }");
-        assert_eq!(errors[1], "[Module \"main\"]: Cannot connect to \"out\", declare it using \"input\" instead, Position { start: 227, end: 284, line: 11, column: 17 }");
+        assert_eq!(errors[1], "[Module \"main\"]: Cannot connect to \"out\", declare it using \"input\" instead, Position { start: 227, end: 238, line: 11, column: 17 }");
         assert_eq!(errors[2], "[Module \"main\"]: Cannot find name \"in1\", Position { start: 227, end: 230, line: 11, column: 17 }");
-        assert_eq!(errors[3], "[Module \"main\"]: Cannot connect \"b\" to an input, declare it using \"output\" instead, Position { start: 412, end: 513, line: 17, column: 17 }");
-        assert_eq!(errors[4], "[Module \"main\"]: Cannot connect to \"b\", declare it using \"input\" instead, Position { start: 510, end: 576, line: 19, column: 17 }");
-        assert_eq!(errors[5], "[Module \"main\"]: Cannot connect \"in\" to an input, declare it using \"output\" instead, Position { start: 574, end: 666, line: 21, column: 17 }");
-        assert_eq!(errors[6], "[Module \"main\"]: Cannot connect to \"out\", declare it using \"input\" instead, Position { start: 574, end: 666, line: 21, column: 17 }");
-        assert_eq!(errors[7], "[Module \"main\"]: Cannot connect \"out\" to an output, declare it using \"input\" instead, Position { start: 663, end: 935, line: 23, column: 13 }");
+        assert_eq!(errors[3], "[Module \"main\"]: Cannot connect \"b\" to an input, declare it using \"output\" instead, Position { start: 412, end: 420, line: 17, column: 17 }");
+        assert_eq!(errors[4], "[Module \"main\"]: Cannot connect to \"b\", declare it using \"input\" instead, Position { start: 510, end: 519, line: 19, column: 17 }");
+        assert_eq!(errors[5], "[Module \"main\"]: Cannot connect \"in\" to an input, declare it using \"output\" instead, Position { start: 574, end: 584, line: 21, column: 17 }");
+        assert_eq!(errors[6], "[Module \"main\"]: Cannot connect to \"out\", declare it using \"input\" instead, Position { start: 574, end: 584, line: 21, column: 17 }");
+        assert_eq!(errors[7], "[Module \"main\"]: Cannot connect \"out\" to an output, declare it using \"input\" instead, Position { start: 663, end: 681, line: 23, column: 17 }");
     }
 
     #[test]
@@ -1217,7 +1390,7 @@ buffer foo[10] = |i| {
 
         assert!(result.is_err());
 
-        let errors = result.unwrap_err();
+        let errors = error_strings(result.unwrap_err());
 
         assert_eq!(errors.len(), 2);
     }
@@ -1279,15 +1452,15 @@ buffer foo[10] = |i| {
 
         assert!(result.is_err());
 
-        let errors = result.unwrap_err();
+        let errors = error_strings(result.unwrap_err());
 
         println!("{:#?}", errors);
 
         assert_eq!(errors.len(), 3);
 
-        assert_eq!(errors[0], "[Module \"main\"]: Function \"Module.getSomethingElse\" does not exist (Cannot find name \"getSomethingElse\" in module \"./module.meph\"), Position { start: 145, end: 171, line: 6, column: 43 }");
-        assert_eq!(errors[1], "[Module \"main\"]: Cannot find name \"bar\" in module \"./module.meph\", Position { start: 224, end: 235, line: 8, column: 28 }");
-        assert_eq!(errors[2], "[Module \"./module.meph\"]: Cannot find name \"b\", Position { start: 167, end: 169, line: 10, column: 19 }");
+        assert_eq!(errors[0], "[Module \"main\"]: Function \"Module.getSomethingElse\" does not exist (Cannot find name \"getSomethingElse\" in module \"./module.meph\"), Position { start: 145, end: 170, line: 6, column: 18 }");
+        assert_eq!(errors[1], "[Module \"main\"]: Cannot find name \"bar\" in module \"./module.meph\", Position { start: 224, end: 234, line: 8, column: 18 }");
+        assert_eq!(errors[2], "[Module \"./module.meph\"]: Cannot find name \"b\", Position { start: 167, end: 168, line: 10, column: 18 }");
     }
 
     #[test]
@@ -1329,11 +1502,11 @@ buffer foo[10] = |i| {
 
         assert!(result.is_err());
 
-        let errors = result.unwrap_err();
+        let errors = error_strings(result.unwrap_err());
 
         assert_eq!(errors.len(), 2);
-        assert_eq!(errors[0], "[Module \"main\"]: Cannot have more than one process block, Position { start: 77, end: 148, line: 6, column: 13 }");
-        assert_eq!(errors[1], "[Module \"main\"]: Cannot have more than one process block, Position { start: 141, end: 204, line: 10, column: 13 }");
+        assert_eq!(errors[0], "[Module \"main\"]: Cannot have more than one process block, Position { start: 77, end: 127, line: 6, column: 13 }");
+        assert_eq!(errors[1], "[Module \"main\"]: Cannot have more than one process block, Position { start: 141, end: 191, line: 10, column: 13 }");
     }
 
     #[test]
@@ -1386,13 +1559,13 @@ buffer foo[10] = |i| {
 
         assert!(result.is_err());
 
-        let errors = result.unwrap_err();
+        let errors = error_strings(result.unwrap_err());
 
         println!("{:#?}", errors);
 
         assert_eq!(errors.len(), 2);
-        assert_eq!(errors[0], "[Module \"main\"]: Cannot have more than one connect block, Position { start: 229, end: 297, line: 14, column: 13 }");
-        assert_eq!(errors[1], "[Module \"main\"]: Cannot have more than one connect block, Position { start: 290, end: 350, line: 18, column: 13 }");
+        assert_eq!(errors[0], "[Module \"main\"]: Cannot have more than one connect block, Position { start: 229, end: 276, line: 14, column: 13 }");
+        assert_eq!(errors[1], "[Module \"main\"]: Cannot have more than one connect block, Position { start: 290, end: 337, line: 18, column: 13 }");
     }
 
     #[test]
@@ -1426,12 +1599,12 @@ buffer foo[10] = |i| {
 
         assert!(result.is_err());
 
-        let errors = result.unwrap_err();
+        let errors = error_strings(result.unwrap_err());
 
         println!("{:#?}", errors);
 
         assert_eq!(errors.len(), 1);
-        assert_eq!(errors[0], "[Module \"main\"]: Cannot assign to constant \"a\", Position { start: 38, end: 57, line: 3, column: 13 }");
+        assert_eq!(errors[0], "[Module \"main\"]: Cannot assign to constant \"a\", Position { start: 38, end: 44, line: 3, column: 13 }");
     }
 
     #[test]
@@ -1472,14 +1645,14 @@ buffer foo[10] = |i| {
 
         assert!(result.is_err());
 
-        let errors = result.unwrap_err();
+        let errors = error_strings(result.unwrap_err());
 
         println!("{:#?}", errors);
 
         assert_eq!(errors.len(), 3);
-        assert_eq!(errors[0], "[Module \"main\"]: Cannot assign function \"foo\" to a variable, Position { start: 77, end: 103, line: 6, column: 13 }");
-        assert_eq!(errors[1], "[Module \"main\"]: Cannot assign function \"foo\" to a variable, Position { start: 102, end: 124, line: 7, column: 13 }");
-        assert_eq!(errors[2], "[Module \"main\"]: Cannot use function \"foo\" as a variable, Position { start: 127, end: 135, line: 8, column: 20 }");
+        assert_eq!(errors[0], "[Module \"main\"]: Cannot assign function \"foo\" to a variable, Position { start: 77, end: 89, line: 6, column: 13 }");
+        assert_eq!(errors[1], "[Module \"main\"]: Cannot assign function \"foo\" to a variable, Position { start: 102, end: 110, line: 7, column: 13 }");
+        assert_eq!(errors[2], "[Module \"main\"]: Cannot use function \"foo\" as a variable, Position { start: 127, end: 134, line: 8, column: 15 }");
     }
 
     #[test]
@@ -1625,12 +1798,12 @@ buffer foo[10] = |i| {
 
         assert!(result.is_err());
 
-        let errors = result.unwrap_err();
+        let errors = error_strings(result.unwrap_err());
 
         println!("{:#?}", errors);
 
         assert_eq!(errors.len(), 1);
-        assert_eq!(errors[0], "[Module \"main\"]: Cannot assign to constant \"foo\", Position { start: 77, end: 98, line: 6, column: 13 }");
+        assert_eq!(errors[0], "[Module \"main\"]: Cannot assign to constant \"foo\", Position { start: 77, end: 85, line: 6, column: 13 }");
     }
 
     #[test]
@@ -1684,12 +1857,418 @@ buffer foo[10] = |i| {
 
         assert!(result.is_err());
 
-        let errors = result.unwrap_err();
+        let errors = error_strings(result.unwrap_err());
 
         assert_eq!(errors.len(), 2);
 
-        // assert_eq!(errors[0], "[Module \"main\"]: Function \"Module.getSomethingElse\" does not exist (Cannot find name \"getSomethingElse\" in module \"./module.meph\"), Position { start: 145, end: 171, line: 6, column: 43 }");
-        // assert_eq!(errors[1], "[Module \"main\"]: Cannot find name \"bar\" in module \"./module.meph\", Position { start: 224, end: 235, line: 8, column: 28 }");
+        // assert_eq!(errors[0], "[Module \"main\"]: Function \"Module.getSomethingElse\" does not exist (Cannot find name \"getSomethingElse\" in module \"./module.meph\"), Position { start: 145, end: 170, line: 6, column: 18 }");
+        // assert_eq!(errors[1], "[Module \"main\"]: Cannot find name \"bar\" in module \"./module.meph\", Position { start: 224, end: 234, line: 8, column: 18 }");
         // assert_eq!(errors[2], "[Module \"./module.meph\"]: Cannot find name \"b\", Position { start: 164, end: 166, line: 10, column: 19 }");
     }
+
+    #[test]
+    fn test_conditional_expr() {
+        let code = "
+            fn foo() {
+                return 1;
+            }
+
+            let a = 1;
+            let b = a ? foo : bar;
+            ".to_string();
+
+        let lexer = Lexer::new();
+        let tokens = lexer.tokenize(code);
+
+        let mut parser = Parser::new();
+        let mut ast = parser.parse(tokens);
+
+        let symbol_table = SymbolTable::from_ast(&mut ast).unwrap();
+
+        let mut semantic = SemanticAnalyzer::new();
+
+        let mut modules = IndexMap::new();
+
+        let module_data = ModuleData {
+            ast,
+            symbol_table,
+            errors: vec![],
+        };
+
+        modules.insert("main".to_string(), module_data);
+
+        let result = semantic.validate_semantics(&mut modules);
+
+        assert!(result.is_err());
+
+        let errors = error_strings(result.unwrap_err());
+
+        println!("{:#?}", errors);
+
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].starts_with("[Module \"main\"]: Cannot use function \"foo\" as a variable"));
+        assert!(errors[1].starts_with("[Module \"main\"]: Cannot find name \"bar\""));
+    }
+
+    #[test]
+    fn test_for_stmt() {
+        let code = "
+            const N = 4;
+            const M = N * 2 - 1;
+            let acc = 0;
+
+            fn sum() {
+                let s = 0;
+                for i in 0..M {
+                    s = s + i;
+                }
+                return s;
+            }
+
+            process {
+                for i in -N..N {
+                    acc = acc + i;
+                }
+            }
+            ".to_string();
+
+        let lexer = Lexer::new();
+        let tokens = lexer.tokenize(code);
+
+        let mut parser = Parser::new();
+        let mut ast = parser.parse(tokens);
+
+        let symbol_table = SymbolTable::from_ast(&mut ast).unwrap();
+
+        let mut semantic = SemanticAnalyzer::new();
+
+        let mut modules = IndexMap::new();
+
+        let module_data = ModuleData {
+            ast,
+            symbol_table,
+            errors: vec![],
+        };
+
+        modules.insert("main".to_string(), module_data);
+
+        let result = semantic.validate_semantics(&mut modules);
+
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_for_stmt_non_constant_bounds() {
+        let code = "
+            input n = 0;
+            let m = 4;
+            const K = m;
+            let acc = 0;
+
+            process {
+                for i in 0..n {
+                    acc = acc + i;
+                }
+
+                for i in 0..m {
+                    i = 1;
+                }
+
+                for i in 0..K {
+                    acc = acc + i;
+                }
+            }
+            ".to_string();
+
+        let lexer = Lexer::new();
+        let tokens = lexer.tokenize(code);
+
+        let mut parser = Parser::new();
+        let mut ast = parser.parse(tokens);
+
+        let symbol_table = SymbolTable::from_ast(&mut ast).unwrap();
+
+        let mut semantic = SemanticAnalyzer::new();
+
+        let mut modules = IndexMap::new();
+
+        let module_data = ModuleData {
+            ast,
+            symbol_table,
+            errors: vec![],
+        };
+
+        modules.insert("main".to_string(), module_data);
+
+        let result = semantic.validate_semantics(&mut modules);
+
+        assert!(result.is_err());
+
+        let errors = error_strings(result.unwrap_err());
+
+        println!("{:#?}", errors);
+
+        assert_eq!(errors.len(), 4);
+        assert!(errors[0].starts_with("[Module \"main\"]: For loop bounds must be compile-time constants"));
+        assert!(errors[1].starts_with("[Module \"main\"]: For loop bounds must be compile-time constants"));
+        assert!(errors[2].starts_with("[Module \"main\"]: Cannot assign to constant \"i\""));
+        assert!(errors[3].starts_with("[Module \"main\"]: For loop bounds must be compile-time constants"));
+    }
+
+    #[test]
+    fn test_void_functions() {
+        let code = "
+            buffer b[4] = |i| {
+                return 0;
+            };
+            let acc = 0;
+
+            fn reset(x) {
+                buf_clear(b);
+                acc = x;
+            }
+
+            process {
+                buf_push(b, 1);
+                reset(0);
+                acc = buf_read(b, 0);
+            }
+            ".to_string();
+
+        let lexer = Lexer::new();
+        let tokens = lexer.tokenize(code);
+
+        let mut parser = Parser::new();
+        let mut ast = parser.parse(tokens);
+
+        let symbol_table = SymbolTable::from_ast(&mut ast).unwrap();
+
+        let mut semantic = SemanticAnalyzer::new();
+
+        let mut modules = IndexMap::new();
+
+        let module_data = ModuleData {
+            ast,
+            symbol_table,
+            errors: vec![],
+        };
+
+        modules.insert("main".to_string(), module_data);
+
+        let result = semantic.validate_semantics(&mut modules);
+
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_void_function_as_value() {
+        let code = "
+            buffer b[4] = |i| {
+                return 0;
+            };
+
+            fn reset() {
+                buf_clear(b);
+            }
+
+            process {
+                let x = buf_push(b, 1);
+                x = reset() + 1;
+                buf_put(b, 0, buf_resize(b, 8));
+            }
+            ".to_string();
+
+        let lexer = Lexer::new();
+        let tokens = lexer.tokenize(code);
+
+        let mut parser = Parser::new();
+        let mut ast = parser.parse(tokens);
+
+        let symbol_table = SymbolTable::from_ast(&mut ast).unwrap();
+
+        let mut semantic = SemanticAnalyzer::new();
+
+        let mut modules = IndexMap::new();
+
+        let module_data = ModuleData {
+            ast,
+            symbol_table,
+            errors: vec![],
+        };
+
+        modules.insert("main".to_string(), module_data);
+
+        let result = semantic.validate_semantics(&mut modules);
+
+        assert!(result.is_err());
+
+        let errors = error_strings(result.unwrap_err());
+
+        println!("{:#?}", errors);
+
+        assert_eq!(errors.len(), 3);
+        assert!(errors[0].starts_with("[Module \"main\"]: Function \"buf_push\" does not return a value"));
+        assert!(errors[1].starts_with("[Module \"main\"]: Function \"reset\" does not return a value"));
+        assert!(errors[2].starts_with("[Module \"main\"]: Function \"buf_resize\" does not return a value"));
+    }
+
+    #[test]
+    fn test_compound_assignment() {
+        let code = "
+        const foo = 10;
+        input bar = 0;
+        let baz = 0;
+
+        foo += 1;
+        foo -= 1;
+        foo *= 2;
+        foo /= 2;
+        bar += 1;
+        baz += bar;
+        ".to_string();
+
+        let lexer = Lexer::new();
+        let tokens = lexer.tokenize(code);
+
+        let mut parser = Parser::new();
+        let mut ast = parser.parse(tokens);
+
+        let symbol_table = SymbolTable::from_ast(&mut ast).unwrap();
+
+        let mut semantic = SemanticAnalyzer::new();
+
+        let module_data = ModuleData {
+            ast,
+            symbol_table,
+            errors: vec![],
+        };
+
+        let mut modules = IndexMap::new();
+        modules.insert("main".to_string(), module_data);
+
+        let result = semantic.validate_semantics(&mut modules);
+
+        assert!(result.is_err());
+
+        let errors = error_strings(result.unwrap_err());
+
+        println!("{:#?}", errors);
+
+        assert_eq!(errors.len(), 5);
+        assert_eq!(errors[0], "[Module \"main\"]: Cannot assign to constant \"foo\", Position { start: 78, end: 87, line: 6, column: 9 }");
+        assert!(errors[1].starts_with("[Module \"main\"]: Cannot assign to constant \"foo\""));
+        assert!(errors[2].starts_with("[Module \"main\"]: Cannot assign to constant \"foo\""));
+        assert!(errors[3].starts_with("[Module \"main\"]: Cannot assign to constant \"foo\""));
+        assert!(errors[4].starts_with("[Module \"main\"]: Cannot assign to constant \"bar\""));
+    }
+
+    #[test]
+    fn test_named_imports() {
+        let main_code = "
+            import { clamp, helper, LIMIT, missing } from \"./lib.meph\";
+
+            output out = 0;
+
+            process {
+                out = clamp(out, 1);
+            }
+            ".to_string();
+
+        let module_code = "
+            export const LIMIT = 1;
+
+            fn helper(x) {
+                return x;
+            }
+
+            export fn clamp(x, lo, hi) {
+                return helper(x > lo ? x : lo) * LIMIT;
+            }
+        ".to_string();
+
+        let lexer = Lexer::new();
+        let main_tokens = lexer.tokenize(main_code);
+        let module_tokens = lexer.tokenize(module_code);
+
+        let mut parser = Parser::new();
+        let mut main_ast = parser.parse(main_tokens);
+        let mut module_ast = parser.parse(module_tokens);
+
+        assert_eq!(main_ast.errors.len(), 0);
+
+        let main_symbol_table = SymbolTable::from_ast(&mut main_ast).unwrap();
+        let module_symbol_table = SymbolTable::from_ast(&mut module_ast).unwrap();
+
+        let mut semantic = SemanticAnalyzer::new();
+
+        let mut modules = IndexMap::new();
+        modules.insert("main".to_string(), ModuleData {
+            ast: main_ast,
+            symbol_table: main_symbol_table,
+            errors: vec![],
+        });
+        modules.insert("./lib.meph".to_string(), ModuleData {
+            ast: module_ast,
+            symbol_table: module_symbol_table,
+            errors: vec![],
+        });
+
+        let result = semantic.validate_semantics(&mut modules);
+
+        assert_eq!(error_strings(result.unwrap_err()), vec![
+            "[Module \"main\"]: Cannot access private symbol \"helper\" in module \"./lib.meph\", Position { start: 29, end: 35, line: 2, column: 26 }".to_string(),
+            "[Module \"main\"]: Cannot import \"LIMIT\" from module \"./lib.meph\", only functions can be imported by name, Position { start: 37, end: 42, line: 2, column: 33 }".to_string(),
+            "[Module \"main\"]: Cannot find name \"missing\" in module \"./lib.meph\", Position { start: 44, end: 51, line: 2, column: 39 }".to_string(),
+            "[Module \"main\"]: Function \"clamp\" expects 3 arguments, but 2 were provided, Position { start: 147, end: 160, line: 7, column: 21 }".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn test_named_import_clashes_with_local() {
+        let main_code = "
+            import { clamp, missing } from \"./lib.meph\";
+
+            fn clamp(x) {
+                return x;
+            }
+            ".to_string();
+
+        let module_code = "
+            export fn clamp(x, lo, hi) {
+                return x;
+            }
+        ".to_string();
+
+        let lexer = Lexer::new();
+        let main_tokens = lexer.tokenize(main_code);
+        let module_tokens = lexer.tokenize(module_code);
+
+        let mut parser = Parser::new();
+        let mut main_ast = parser.parse(main_tokens);
+        let mut module_ast = parser.parse(module_tokens);
+
+        // The local function keeps the name
+        let main_symbol_table = SymbolTable::from_ast(&mut main_ast).unwrap();
+        let module_symbol_table = SymbolTable::from_ast(&mut module_ast).unwrap();
+
+        let mut modules = IndexMap::new();
+        modules.insert("main".to_string(), ModuleData {
+            ast: main_ast,
+            symbol_table: main_symbol_table,
+            errors: vec![],
+        });
+        modules.insert("./lib.meph".to_string(), ModuleData {
+            ast: module_ast,
+            symbol_table: module_symbol_table,
+            errors: vec![],
+        });
+
+        let errors = SemanticAnalyzer::new().validate_semantics(&mut modules).unwrap_err();
+
+        assert_eq!(errors[0].code, NAMED_IMPORT_CLASH);
+        assert_eq!(error_strings(errors), vec![
+            "[Module \"main\"]: Cannot import \"clamp\" from module \"./lib.meph\", the name is already declared in this module, Position { start: 22, end: 27, line: 2, column: 20 }".to_string(),
+            "[Module \"main\"]: Cannot find name \"missing\" in module \"./lib.meph\", Position { start: 29, end: 36, line: 2, column: 26 }".to_string(),
+        ]);
+    }
 }
@@ -2,6 +2,7 @@ use std::collections::HashMap;
 
 use uuid::Uuid;
 
+use crate::diagnostic::{Diagnostic, DUPLICATE_DECLARATION, INTERNAL_ERROR, internal_error};
 use crate::lexer::token::Position;
 use crate::parser::ast::{AST, ASTTraverseStage, Node, traverse_ast, VariableSpecifier};
 
@@ -42,6 +43,7 @@ pub enum SymbolInfo {
     Function {
         id: Uuid,
         parameters: Vec<String>,
+        returns_value: bool,
         visibility: SymbolVisibility,
         origin: SymbolOrigin,
         position: Position,
@@ -314,12 +316,12 @@ impl SymbolTable {
         // Buffer functions
         symbol_table.define_stdlib_fn("buf_new", vec!["length"]);
         symbol_table.define_stdlib_fn("buf_read", vec!["buffer", "index"]);
-        symbol_table.define_stdlib_fn("buf_push", vec!["buffer", "value"]);
+        symbol_table.define_stdlib_void_fn("buf_push", vec!["buffer", "value"]);
         symbol_table.define_stdlib_fn("buf_pop", vec!["buffer"]);
         symbol_table.define_stdlib_fn("buf_length", vec!["buffer"]);
-        symbol_table.define_stdlib_fn("buf_clear", vec!["buffer"]);
-        symbol_table.define_stdlib_fn("buf_put", vec!["buffer", "index", "value"]);
-        symbol_table.define_stdlib_fn("buf_resize", vec!["buffer", "size"]);
+        symbol_table.define_stdlib_void_fn("buf_clear", vec!["buffer"]);
+        symbol_table.define_stdlib_void_fn("buf_put", vec!["buffer", "index", "value"]);
+        symbol_table.define_stdlib_void_fn("buf_resize", vec!["buffer", "size"]);
 
         symbol_table
     }
@@ -341,11 +343,21 @@ impl SymbolTable {
     }
 
     fn define_stdlib_fn(&mut self, name: &str, parameters: Vec<&str>) {
+        self.define_stdlib_function(name, parameters, true);
+    }
+
+    // Functions that are called for their side effects only, e.g. buffer mutations
+    fn define_stdlib_void_fn(&mut self, name: &str, parameters: Vec<&str>) {
+        self.define_stdlib_function(name, parameters, false);
+    }
+
+    fn define_stdlib_function(&mut self, name: &str, parameters: Vec<&str>, returns_value: bool) {
         if let Ok(()) = self.insert(
             name.to_string(),
             SymbolInfo::Function {
                 id: Uuid::new_v4(),
                 parameters: parameters.iter().map(|s| s.to_string()).collect(),
+                returns_value,
                 visibility: SymbolVisibility::Private,
                 origin: SymbolOrigin::StandardLibrary,
                 position: Position::new(),
@@ -375,16 +387,18 @@ impl SymbolTable {
         }
     }
 
-    pub fn from_ast(ast: &mut AST) -> Result<Self, Vec<String>> {
+    pub fn from_ast(ast: &mut AST) -> Result<Self, Vec<Diagnostic>> {
         struct Context {
             symbol_table: SymbolTable,
             public_visibility: bool,
-            errors: Vec<String>,
+            named_imports: Vec<(String, SymbolInfo)>,
+            errors: Vec<Diagnostic>,
         }
 
         let mut context = Context {
             symbol_table: SymbolTable::new(),
             public_visibility: false,
+            named_imports: Vec::new(),
             errors: Vec::new(),
         };
 
@@ -399,7 +413,9 @@ impl SymbolTable {
                             context.symbol_table.create_and_enter_scope();
                         }
                         ASTTraverseStage::Exit => {
-                            context.symbol_table.exit_scope();
+                            if let Err(error) = context.symbol_table.exit_scope() {
+                                context.errors.push(error.at_node(node));
+                            }
                         }
                     }
                 }
@@ -424,6 +440,7 @@ impl SymbolTable {
                     id,
                     initializer: _,
                     specifier,
+                    attributes: _,
                     position: _,
                 } => {
                     match traverse_stage {
@@ -527,7 +544,42 @@ impl SymbolTable {
                             }
                         }
                         ASTTraverseStage::Exit => {
-                            context.symbol_table.exit_scope();
+                            if let Err(error) = context.symbol_table.exit_scope() {
+                                context.errors.push(error.at_node(node));
+                            }
+                        }
+                    }
+                }
+
+                Node::ForStmt {
+                    id,
+                    ..
+                } => {
+                    match traverse_stage {
+                        ASTTraverseStage::Enter => {
+                            context.symbol_table.create_and_enter_scope();
+
+                            // The loop variable lives in its own scope and cannot be assigned to
+                            if let Node::Identifier { name, position } = id.as_mut() {
+                                match context.symbol_table.insert(name.clone(), SymbolInfo::Variable {
+                                    id: Uuid::new_v4(),
+                                    visibility: SymbolVisibility::Private,
+                                    origin: SymbolOrigin::Local,
+                                    position: *position,
+                                    specifier: VariableSpecifier::Const,
+                                    constant: true,
+                                }) {
+                                    Ok(_) => {}
+                                    Err(err) => {
+                                        context.errors.push(err);
+                                    }
+                                }
+                            }
+                        }
+                        ASTTraverseStage::Exit => {
+                            if let Err(error) = context.symbol_table.exit_scope() {
+                                context.errors.push(error.at_node(node));
+                            }
                         }
                     }
                 }
@@ -535,11 +587,14 @@ impl SymbolTable {
                 Node::FunctionDeclarationStmt {
                     id,
                     params,
-                    body: _,
+                    body,
+                    attributes: _,
                     position: _,
                 } => {
                     match traverse_stage {
                         ASTTraverseStage::Enter => {
+                            let returns_value = contains_return_stmt(body);
+
                             if let Node::Identifier { name, position } = id.as_mut() {
                                 let visibility = if context.public_visibility {
                                     SymbolVisibility::Public
@@ -547,19 +602,22 @@ impl SymbolTable {
                                     SymbolVisibility::Private
                                 };
 
+                                let mut parameters = vec![];
+
+                                for param in params.iter() {
+                                    match param {
+                                        Node::FunctionParameter { id, .. } => match id.as_ref() {
+                                            Node::Identifier { name, .. } => parameters.push(name.clone()),
+                                            id => context.errors.push(internal_error(id, "an identifier in the function parameter list")),
+                                        },
+                                        param => context.errors.push(internal_error(param, "a function parameter")),
+                                    }
+                                }
+
                                 match context.symbol_table.insert(name.clone(), SymbolInfo::Function {
                                     id: Uuid::new_v4(),
-                                    parameters: params.iter().map(|param| {
-                                        if let Node::FunctionParameter { id, .. } = param {
-                                            if let Node::Identifier { name, .. } = id.as_ref() {
-                                                name.clone()
-                                            } else {
-                                                panic!("[COMPILER ERROR] Expected identifier in function parameter list");
-                                            }
-                                        } else {
-                                            panic!("[COMPILER ERROR] Expected identifier in function parameter list");
-                                        }
-                                    }).collect(),
+                                    parameters,
+                                    returns_value,
                                     visibility,
                                     origin: SymbolOrigin::Local,
                                     position: position.clone(),
@@ -591,7 +649,9 @@ impl SymbolTable {
                             }
                         }
                         ASTTraverseStage::Exit => {
-                            context.symbol_table.exit_scope();
+                            if let Err(error) = context.symbol_table.exit_scope() {
+                                context.errors.push(error.at_node(node));
+                            }
                         }
                     }
                 }
@@ -599,6 +659,7 @@ impl SymbolTable {
                 Node::ParameterDeclarationStmt {
                     id,
                     fields: _,
+                    attributes: _,
                     position: _
                 } => {
                     match traverse_stage {
@@ -620,9 +681,36 @@ impl SymbolTable {
                     }
                 }
 
+                // Imported names are bound as functions of the imported module once the module's own
+                // declarations are known. Their parameters are resolved against that module during semantic analysis
+                Node::NamedImportStatement {
+                    names,
+                    path,
+                    position: _
+                } => {
+                    match traverse_stage {
+                        ASTTraverseStage::Enter => {
+                            for name in names {
+                                if let Node::Identifier { name, position } = name {
+                                    context.named_imports.push((name.clone(), SymbolInfo::Function {
+                                        id: Uuid::new_v4(),
+                                        parameters: vec![],
+                                        returns_value: true,
+                                        visibility: SymbolVisibility::Private,
+                                        origin: SymbolOrigin::ImportedModule { module: path.clone() },
+                                        position: *position,
+                                    }));
+                                }
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+
                 Node::ImportStatement {
                     id,
                     path,
+                    attributes: _,
                     position: _
                 } => {
                     match traverse_stage {
@@ -649,6 +737,11 @@ impl SymbolTable {
             false
         }, &mut context);
 
+        // A name that is already declared keeps its declaration, the semantic analysis reports the import
+        for (name, info) in context.named_imports {
+            let _ = context.symbol_table.insert_into_global_scope(name, info);
+        }
+
         if context.errors.len() > 0 {
             return Err(context.errors);
         }
@@ -678,53 +771,61 @@ impl SymbolTable {
         self.current_scope_index = 0;
     }
 
-    pub fn enter_next_scope(&mut self) {
-        if self.traversed_scopes >= self.scopes.len() - 1 {
-            panic!("Attempted to enter a scope that doesn't exist! {}", self.traversed_scopes + 1);
+    // Scopes are entered in the order they were created, so running out of scopes means a pass
+    // walks the tree differently from the one that built the table
+    pub fn enter_next_scope(&mut self) -> Result<(), Diagnostic> {
+        if self.traversed_scopes + 1 >= self.scopes.len() {
+            return Err(Diagnostic::error(
+                INTERNAL_ERROR,
+                format!("Internal compiler error: attempted to enter scope {} that doesn't exist", self.traversed_scopes + 1),
+            ));
         }
 
         self.traversed_scopes += 1;
         let new_scope_index = self.traversed_scopes;
 
         self.current_scope_index = new_scope_index;
+
+        Ok(())
     }
 
-    pub fn exit_scope(&mut self) {
-        if let Some(parent_index) = self.scopes[self.current_scope_index].parent {
+    pub fn exit_scope(&mut self) -> Result<(), Diagnostic> {
+        if let Some(parent_index) = self.scopes.get(self.current_scope_index).and_then(|scope| scope.parent) {
             self.current_scope_index = parent_index;
+            Ok(())
         } else {
-            panic!("Attempted to exit the global scope!");
+            Err(Diagnostic::error(INTERNAL_ERROR, "Internal compiler error: attempted to exit the global scope".to_string()))
         }
     }
 
-    pub fn insert(&mut self, name: String, mut info: SymbolInfo) -> Result<(), String> {
+    pub fn insert(&mut self, name: String, mut info: SymbolInfo) -> Result<(), Diagnostic> {
         if let Some(current_scope) = self.scopes.get_mut(self.current_scope_index) {
 
             // Check if the symbol already exists in the current scope
             if current_scope.symbols.contains_key(&name) {
-                return Err(format!("'{}' is already declared in the current scope, {:?}", name, info.position()));
+                return Err(Diagnostic::error(DUPLICATE_DECLARATION, format!("'{}' is already declared in the current scope", name)).with_span(*info.position()));
             }
 
             current_scope.symbols.insert(name, info);
 
             Ok(())
         } else {
-            Err("[COMPILER ERROR]: No active scope to insert symbol".to_string())
+            Err(Diagnostic::error(INTERNAL_ERROR, "No active scope to insert symbol".to_string()))
         }
     }
 
-    pub fn insert_into_global_scope(&mut self, name: String, mut info: SymbolInfo) -> Result<(), String> {
+    pub fn insert_into_global_scope(&mut self, name: String, mut info: SymbolInfo) -> Result<(), Diagnostic> {
         if let Some(global_scope) = self.scopes.get_mut(0) {
             // Check if the symbol already exists in the global scope
             if global_scope.symbols.contains_key(&name) {
-                return Err(format!("'{}' is already declared in the global scope, {:?}", name, info.position()));
+                return Err(Diagnostic::error(DUPLICATE_DECLARATION, format!("'{}' is already declared in the global scope", name)).with_span(*info.position()));
             }
 
             global_scope.symbols.insert(name, info);
 
             Ok(())
         } else {
-            Err("[COMPILER ERROR]: No global scope to insert symbol".to_string())
+            Err(Diagnostic::error(INTERNAL_ERROR, "No global scope to insert symbol".to_string()))
         }
     }
 
@@ -818,7 +919,7 @@ impl SymbolTable {
                     }
                 }
             } else {
-                panic!("Error: Invalid scope index {}", current_scope_index);
+                return None;
             }
         }
     }
@@ -832,11 +933,26 @@ impl SymbolTable {
                 return None;
             }
         } else {
-            panic!("Error: Invalid scope index {}", scope_index);
+            None
         }
     }
 }
 
+// A function without any return statement is void
+fn contains_return_stmt(body: &mut Node) -> bool {
+    let mut found = false;
+
+    traverse_ast(body, &mut |traverse_stage, node, found: &mut bool| {
+        if let (ASTTraverseStage::Enter, Node::ReturnStmt { .. }) = (traverse_stage, node) {
+            *found = true;
+        }
+
+        false
+    }, &mut found);
+
+    found
+}
+
 #[cfg(test)]
 mod tests {
     use crate::lexer::Lexer;
@@ -870,13 +986,14 @@ mod tests {
             SymbolInfo::Function {
                 id: Uuid::new_v4(),
                 parameters: vec!["a".to_string(), "b".to_string()],
+                returns_value: true,
                 visibility: SymbolVisibility::Private,
                 origin: SymbolOrigin::Local,
                 position: Position::new(),
             },
         ).unwrap();
 
-        symbol_table.exit_scope();
+        symbol_table.exit_scope().unwrap();
 
         symbol_table.create_and_enter_scope();
 
@@ -892,7 +1009,7 @@ mod tests {
             },
         ).unwrap();
 
-        symbol_table.exit_scope();
+        symbol_table.exit_scope().unwrap();
 
         let symbol = symbol_table.lookup("foo");
         assert!(symbol.is_some());
@@ -907,19 +1024,19 @@ mod tests {
         let symbol = symbol_table.lookup("foo");
         assert!(symbol.is_some());
 
-        symbol_table.enter_next_scope();
+        symbol_table.enter_next_scope().unwrap();
 
         let symbol = symbol_table.lookup("bar");
         assert!(symbol.is_some());
 
-        symbol_table.exit_scope();
+        symbol_table.exit_scope().unwrap();
 
-        symbol_table.enter_next_scope();
+        symbol_table.enter_next_scope().unwrap();
 
         let symbol = symbol_table.lookup("baz");
         assert!(symbol.is_some());
 
-        symbol_table.exit_scope();
+        symbol_table.exit_scope().unwrap();
 
         println!("{:#?}", symbol_table);
     }
@@ -965,7 +1082,7 @@ mod tests {
             assert!(symbol.is_none());
 
             // Enter the function scope
-            symbol_table.enter_next_scope();
+            symbol_table.enter_next_scope().unwrap();
 
             let symbol = symbol_table.lookup("PI");
             assert!(symbol.is_some());
@@ -976,7 +1093,7 @@ mod tests {
             let symbol = symbol_table.lookup("a");
             assert!(symbol.is_none()); // Checking that the function body tries to access an undefined variable
 
-            symbol_table.exit_scope();
+            symbol_table.exit_scope().unwrap();
 
             // Check that the export declaration has been processed
             let symbol = symbol_table.lookup("exported_variable");
@@ -991,7 +1108,7 @@ mod tests {
             }
 
             // Enter the process block scope
-            symbol_table.enter_next_scope();
+            symbol_table.enter_next_scope().unwrap();
             let symbol = symbol_table.lookup("PI");
             assert!(symbol.is_some());
 
@@ -1068,7 +1185,7 @@ buffer foo[10] = |i| {
 
         assert!(symbol.is_some());
 
-        symbol_table.enter_next_scope();
+        symbol_table.enter_next_scope().unwrap();
 
         println!("{:#?}", symbol_table);
 
@@ -1131,7 +1248,7 @@ fn someFn() {
         let is_constant = symbol.unwrap().is_constant();
         assert!(is_constant);
 
-        symbol_table.enter_next_scope();
+        symbol_table.enter_next_scope().unwrap();
 
         let symbol = symbol_table.lookup("i");
         assert!(symbol.is_some());
@@ -1210,7 +1327,7 @@ fn someFn() {
         assert!(symbol.is_none());
 
         // Enter the if block scope
-        symbol_table.enter_next_scope();
+        symbol_table.enter_next_scope().unwrap();
 
         let symbol = symbol_table.lookup("foo");
         assert!(symbol.is_some());
@@ -1219,10 +1336,10 @@ fn someFn() {
         assert!(symbol.is_some());
 
         // Exit the if block scope
-        symbol_table.exit_scope();
+        symbol_table.exit_scope().unwrap();
 
         // Enter the else block scope
-        symbol_table.enter_next_scope();
+        symbol_table.enter_next_scope().unwrap();
 
         let symbol = symbol_table.lookup("foo");
         assert!(symbol.is_some());
@@ -1231,7 +1348,7 @@ fn someFn() {
         assert!(symbol.is_some());
 
         // Exit the else block scope
-        symbol_table.exit_scope();
+        symbol_table.exit_scope().unwrap();
     }
 
     #[test]
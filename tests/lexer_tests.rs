@@ -260,6 +260,69 @@ input gain = 0;
         assert_eq!(tokens[13].token_type, TokenType::EOF);
     }
 
+    #[test]
+    fn test_tokenize_numeric_literals() {
+        let tokenizer = Lexer::new();
+
+        let tokens = tokenizer.tokenize("1e-3 2.5E4 0x7F 48_000 1.5 0..10"
+            .to_string());
+
+        assert_eq!(tokens.len(), 9);
+        assert_eq!(tokens[0].token_type, TokenType::NUMBER);
+        assert_eq!(tokens[0].literal, "1e-3");
+        assert_eq!(tokens[1].token_type, TokenType::NUMBER);
+        assert_eq!(tokens[1].literal, "2.5E4");
+        assert_eq!(tokens[2].token_type, TokenType::NUMBER);
+        assert_eq!(tokens[2].literal, "0x7F");
+        assert_eq!(tokens[3].token_type, TokenType::NUMBER);
+        assert_eq!(tokens[3].literal, "48_000");
+        assert_eq!(tokens[4].token_type, TokenType::NUMBER);
+        assert_eq!(tokens[4].literal, "1.5");
+        assert_eq!(tokens[5].token_type, TokenType::NUMBER);
+        assert_eq!(tokens[5].literal, "0");
+        assert_eq!(tokens[6].token_type, TokenType::RANGE);
+        assert_eq!(tokens[7].token_type, TokenType::NUMBER);
+        assert_eq!(tokens[7].literal, "10");
+        assert_eq!(tokens[8].token_type, TokenType::EOF);
+    }
+
+    #[test]
+    fn test_tokenize_malformed_numeric_literals() {
+        let tokenizer = Lexer::new();
+
+        // Malformed literals are lexed as a single NUMBER token so that the parser can report them
+        let tokens = tokenizer.tokenize("1e 0x 0xZZ 48_ 1__0"
+            .to_string());
+
+        assert_eq!(tokens.len(), 6);
+        assert_eq!(tokens[0].literal, "1e");
+        assert_eq!(tokens[1].literal, "0x");
+        assert_eq!(tokens[2].literal, "0xZZ");
+        assert_eq!(tokens[3].literal, "48_");
+        assert_eq!(tokens[4].literal, "1__0");
+        assert!(tokens[..5].iter().all(|t| t.token_type == TokenType::NUMBER));
+        assert_eq!(tokens[5].token_type, TokenType::EOF);
+    }
+
+    #[test]
+    fn test_tokenize_unit_literals() {
+        let tokenizer = Lexer::new();
+
+        let tokens = tokenizer.tokenize("10ms 2s 440hz -6db 64smp 1e-3s"
+            .to_string());
+
+        assert_eq!(tokens.len(), 8);
+        assert_eq!(tokens[0].literal, "10ms");
+        assert_eq!(tokens[1].literal, "2s");
+        assert_eq!(tokens[2].literal, "440hz");
+        assert_eq!(tokens[3].token_type, TokenType::MINUS);
+        assert_eq!(tokens[4].literal, "6db");
+        assert_eq!(tokens[5].literal, "64smp");
+        assert_eq!(tokens[6].literal, "1e-3s");
+        assert!(tokens.iter().filter(|t| t.token_type != TokenType::MINUS && t.token_type != TokenType::EOF).all(|t| t.token_type == TokenType::NUMBER));
+        assert_eq!(tokens[7].token_type, TokenType::EOF);
+    }
+
     #[test]
     fn tokenize_connections() {
         let tokenizer = Lexer::new();
@@ -0,0 +1,64 @@
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::panic;
+    use std::path::{Path, PathBuf};
+
+    use mephisto::codegen::CodeGenerator;
+    use mephisto::codegen::codegen_js::JSCodeGenerator;
+    use mephisto::codegen::codegen_wat::WATCodeGenerator;
+    use mephisto::diagnostic::MODULE_NOT_FOUND;
+    use mephisto::Mephisto;
+    use mephisto::module_loader::{BuiltinFileLoader, NativeFileLoader};
+
+    fn malformed_programs() -> Vec<PathBuf> {
+        let directory = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/malformed");
+
+        let mut paths: Vec<PathBuf> = fs::read_dir(directory).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|extension| extension == "mephisto"))
+            .collect();
+
+        paths.sort();
+        paths
+    }
+
+    fn compile(path: &Path, code_generator: Box<dyn CodeGenerator>) {
+        let mut mephisto = Mephisto::new(BuiltinFileLoader::new(NativeFileLoader::default()));
+
+        let result = mephisto.compile(path.to_str().unwrap(), code_generator);
+
+        assert!(result.is_err(), "{} compiled without errors", path.display());
+    }
+
+    // Every program in tests/malformed must be rejected with diagnostics, never with a panic
+    #[test]
+    fn test_malformed_programs_do_not_panic() {
+        let paths = malformed_programs();
+        assert!(!paths.is_empty());
+
+        let mut panicked = vec![];
+
+        for path in paths.iter() {
+            let js = panic::catch_unwind(|| compile(path, Box::new(JSCodeGenerator::new())));
+            let wat = panic::catch_unwind(|| compile(path, Box::new(WATCodeGenerator::new())));
+
+            if js.is_err() || wat.is_err() {
+                panicked.push(path.display().to_string());
+            }
+        }
+
+        assert!(panicked.is_empty(), "Failed on: {:#?}", panicked);
+    }
+
+    #[test]
+    fn test_paths_without_file_name_are_rejected() {
+        for path in ["..", "/", "tests/malformed/.."] {
+            let mut mephisto = Mephisto::new(BuiltinFileLoader::new(NativeFileLoader::default()));
+
+            let errors = mephisto.compile(path, Box::new(JSCodeGenerator::new())).unwrap_err();
+
+            assert_eq!(errors.iter().map(|error| error.code).collect::<Vec<_>>(), vec![MODULE_NOT_FOUND], "{}", path);
+        }
+    }
+}